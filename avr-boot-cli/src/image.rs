@@ -0,0 +1,357 @@
+//! Loading flash images from Intel HEX, S-record, ELF, `avr_boot::image` container and raw binary files
+//!
+//! HEX and S-record files are decoded with the same streaming decoders the bootloader uses. Only the `PT_LOAD`
+//! segments of an ELF file which land in program memory are used, so EEPROM and fuse sections are left out.
+
+use avr_boot::image::{self as container, Header};
+use avr_boot::{ihex, srec};
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+/// avr-gcc places data memory at this offset in the ELF address space, and everything else above it
+const DATA_MEMORY_OFFSET: u32 = 0x80_0000;
+
+/// Longest record the HEX and S-record decoders accept
+const RECORD_LENGTH: usize = 256;
+
+const PT_LOAD: u32 = 1;
+
+/// A contiguous flash image, with any gaps filled with `0xff`
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Image {
+    /// Byte address of the first byte
+    pub start: u32,
+    pub data: Vec<u8>,
+    /// The header of a container file, which says what device the image was built for
+    pub header: Option<Header>,
+}
+
+/// Why an image couldn't be loaded
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// A HEX record on the given line couldn't be decoded
+    Hex(usize, ihex::Error),
+    /// An S-record on the given line couldn't be decoded
+    Srec(usize, srec::Error),
+    /// The file isn't a 32 bit little endian ELF file, or is truncated
+    Elf(&'static str),
+    /// The container header or application is damaged
+    Container(container::Error),
+    /// There was no data to program
+    Empty,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{e}"),
+            Error::Hex(line, e) => write!(f, "bad HEX record on line {line}: {e:?}"),
+            Error::Srec(line, e) => write!(f, "bad S-record on line {line}: {e:?}"),
+            Error::Elf(reason) => write!(f, "bad ELF file: {reason}"),
+            Error::Container(e) => write!(f, "bad image container: {e:?}"),
+            Error::Empty => write!(f, "the image is empty"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl Image {
+    /// Load an image, picking the format from the file's extension. Anything unrecognised is a raw binary.
+    pub fn load(path: &Path) -> Result<Image, Error> {
+        let contents = std::fs::read(path)?;
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(str::to_lowercase);
+
+        match extension.as_deref() {
+            Some("hex" | "ihex" | "ihx") => Image::from_hex(&contents),
+            Some("srec" | "s19" | "s28" | "s37" | "mot") => Image::from_srec(&contents),
+            Some("elf") => Image::from_elf(&contents),
+            Some("avrb") => Image::from_container(&contents),
+            _ => Image::from_binary(contents),
+        }
+    }
+
+    /// A raw binary, which starts at address 0
+    pub fn from_binary(data: Vec<u8>) -> Result<Image, Error> {
+        if data.is_empty() {
+            return Err(Error::Empty);
+        }
+        Ok(Image {
+            start: 0,
+            data,
+            header: None,
+        })
+    }
+
+    /// The application in a container, after checking it is intact
+    pub fn from_container(contents: &[u8]) -> Result<Image, Error> {
+        let (header, application) = container::parse(contents).map_err(Error::Container)?;
+        let mut image = Image::from_binary(application.to_vec())?;
+        image.start = header.address.into();
+        image.header = Some(header);
+        Ok(image)
+    }
+
+    pub fn from_hex(contents: &[u8]) -> Result<Image, Error> {
+        let mut buffer = [0; RECORD_LENGTH];
+        let mut decoder = ihex::Decoder::new(&mut buffer);
+        let mut segments = Vec::new();
+        let mut line = 1;
+
+        for character in contents {
+            match decoder.push(*character) {
+                Ok(Some(ihex::Record::Data {
+                    address,
+                    data,
+                    wrapped,
+                })) => {
+                    segments.push((u32::from(address), data.to_vec()));
+                    if let Some((address, data)) = wrapped {
+                        segments.push((u32::from(address), data.to_vec()));
+                    }
+                }
+                Ok(Some(ihex::Record::EndOfFile)) => break,
+                Ok(_) => {}
+                Err(e) => return Err(Error::Hex(line, e)),
+            }
+            if *character == b'\n' {
+                line += 1;
+            }
+        }
+
+        Image::from_segments(segments)
+    }
+
+    pub fn from_srec(contents: &[u8]) -> Result<Image, Error> {
+        let mut buffer = [0; RECORD_LENGTH];
+        let mut decoder = srec::Decoder::new(&mut buffer);
+        let mut segments = Vec::new();
+        let mut line = 1;
+
+        for character in contents {
+            match decoder.push(*character) {
+                Ok(Some(srec::Record::Data { address, data })) => {
+                    segments.push((u32::from(address), data.to_vec()));
+                }
+                Ok(Some(srec::Record::End(_))) => break,
+                Ok(_) => {}
+                Err(e) => return Err(Error::Srec(line, e)),
+            }
+            if *character == b'\n' {
+                line += 1;
+            }
+        }
+
+        Image::from_segments(segments)
+    }
+
+    /// The loadable program memory segments of a 32 bit little endian ELF file, placed at their physical addresses
+    pub fn from_elf(contents: &[u8]) -> Result<Image, Error> {
+        if contents.get(..4) != Some(b"\x7fELF") {
+            return Err(Error::Elf("missing ELF magic number"));
+        }
+        if contents.get(4..6) != Some(&[1, 1]) {
+            return Err(Error::Elf("not a 32 bit little endian file"));
+        }
+
+        let program_headers = word(contents, 28)? as usize;
+        let header_size = half(contents, 42)? as usize;
+        let header_count = half(contents, 44)? as usize;
+
+        let mut segments = Vec::new();
+        for i in 0..header_count {
+            let header = program_headers + i * header_size;
+            let kind = word(contents, header)?;
+            let offset = word(contents, header + 4)? as usize;
+            let address = word(contents, header + 12)?;
+            let size = word(contents, header + 16)? as usize;
+
+            if kind != PT_LOAD || size == 0 || address >= DATA_MEMORY_OFFSET {
+                continue;
+            }
+            let data = contents
+                .get(offset..offset + size)
+                .ok_or(Error::Elf("segment extends past the end of the file"))?;
+            segments.push((address, data.to_vec()));
+        }
+
+        Image::from_segments(segments)
+    }
+
+    /// Place each `(address, data)` segment into a single image
+    fn from_segments(segments: Vec<(u32, Vec<u8>)>) -> Result<Image, Error> {
+        let start = segments.iter().map(|(address, _)| *address).min();
+        let end = segments
+            .iter()
+            .map(|(address, data)| address + data.len() as u32)
+            .max();
+        let (Some(start), Some(end)) = (start, end) else {
+            return Err(Error::Empty);
+        };
+
+        let mut data = vec![0xff; (end - start) as usize];
+        for (address, segment) in segments {
+            let offset = (address - start) as usize;
+            data[offset..offset + segment.len()].copy_from_slice(&segment);
+        }
+        Ok(Image {
+            start,
+            data,
+            header: None,
+        })
+    }
+
+    /// Address of the byte after the end of the image
+    pub fn end(&self) -> u32 {
+        self.start + self.data.len() as u32
+    }
+
+    /// Pad the image with `0xff` so that it starts and ends on a page boundary
+    pub fn pad_to_pages(&mut self, page_size: usize) {
+        let before = self.start as usize % page_size;
+        if before > 0 {
+            self.data.splice(..0, std::iter::repeat_n(0xff, before));
+            self.start -= before as u32;
+        }
+        self.data
+            .resize(self.data.len().next_multiple_of(page_size), 0xff);
+    }
+}
+
+fn half(contents: &[u8], offset: usize) -> Result<u16, Error> {
+    contents
+        .get(offset..offset + 2)
+        .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+        .ok_or(Error::Elf("truncated header"))
+}
+
+fn word(contents: &[u8], offset: usize) -> Result<u32, Error> {
+    contents
+        .get(offset..offset + 4)
+        .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .ok_or(Error::Elf("truncated header"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A minimal ELF file, with a load segment for each `(physical address, data)`
+    fn elf(segments: &[(u32, &[u8])]) -> Vec<u8> {
+        let mut file = vec![0; 52];
+        file[..6].copy_from_slice(b"\x7fELF\x01\x01");
+        file[28..32].copy_from_slice(&52u32.to_le_bytes());
+        file[42..44].copy_from_slice(&32u16.to_le_bytes());
+        file[44..46].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+
+        let mut offset = 52 + 32 * segments.len();
+        let mut contents: Vec<u8> = Vec::new();
+        for (address, data) in segments {
+            let mut header = [0; 32];
+            header[..4].copy_from_slice(&PT_LOAD.to_le_bytes());
+            header[4..8].copy_from_slice(&(offset as u32).to_le_bytes());
+            header[8..12].copy_from_slice(&address.to_le_bytes());
+            header[12..16].copy_from_slice(&address.to_le_bytes());
+            header[16..20].copy_from_slice(&(data.len() as u32).to_le_bytes());
+            file.extend(header);
+            contents.extend(*data);
+            offset += data.len();
+        }
+        file.extend(contents);
+        file
+    }
+
+    #[test]
+    fn it_loads_a_hex_file_with_gaps() {
+        let hex = b":020000040001F9\r\n:020010000102EB\r\n:03001400030400E2\r\n:00000001FF\r\n";
+
+        let image = Image::from_hex(hex).unwrap();
+
+        assert_eq!(
+            image,
+            Image {
+                start: 0x1_0010,
+                data: vec![1, 2, 0xff, 0xff, 3, 4, 0],
+                header: None,
+            }
+        );
+    }
+
+    #[test]
+    fn it_reports_the_line_of_a_bad_record() {
+        let hex = b":020010000102EB\n:02001200030400\n";
+
+        assert!(matches!(
+            Image::from_hex(hex),
+            Err(Error::Hex(2, ihex::Error::Checksum))
+        ));
+    }
+
+    #[test]
+    fn it_rejects_s_records_beyond_24_bits() {
+        let srec = b"S30701234567DDEE5D\n";
+
+        assert!(matches!(
+            Image::from_srec(srec),
+            Err(Error::Srec(1, srec::Error::OutOfRange(0x0123_4567)))
+        ));
+    }
+
+    #[test]
+    fn it_loads_the_flash_segments_of_an_elf_file() {
+        let file = elf(&[(0, &[1, 2, 3, 4]), (0x81_0000, &[9, 9]), (6, &[5, 6])]);
+
+        let image = Image::from_elf(&file).unwrap();
+
+        assert_eq!(
+            image,
+            Image {
+                start: 0,
+                data: vec![1, 2, 3, 4, 0xff, 0xff, 5, 6],
+                header: None,
+            }
+        );
+    }
+
+    #[test]
+    fn it_unpacks_a_container() {
+        let packed = avr_boot_host::image::pack([0x1e, 0x95, 0x0f], 128, 0x200, &[1, 2, 3], 5);
+
+        let image = Image::from_container(&packed).unwrap();
+        assert_eq!(image.start, 0x200);
+        assert_eq!(image.data, vec![1, 2, 3]);
+        assert_eq!(image.header.unwrap().app_version, 5);
+
+        assert!(matches!(
+            Image::from_container(&packed[..packed.len() - 1]),
+            Err(Error::Container(container::Error::Truncated))
+        ));
+    }
+
+    #[test]
+    fn it_pads_to_whole_pages() {
+        let mut image = Image {
+            start: 0x102,
+            data: vec![1; 0x100],
+            header: None,
+        };
+
+        image.pad_to_pages(0x80);
+
+        assert_eq!(image.start, 0x100);
+        assert_eq!(image.data.len(), 0x180);
+        assert_eq!(&image.data[..2], &[0xff, 0xff]);
+        assert!(image.data[0x102..].iter().all(|b| *b == 0xff));
+    }
+}
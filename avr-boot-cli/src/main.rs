@@ -0,0 +1,574 @@
+//! Flash an AVR over a serial port, through any of the avr-boot bootloader protocols
+//!
+//! Usage: `avr-boot-cli --port /dev/ttyUSB0 --mcu atmega328p write app.hex`
+//!
+//! Images are split into pages using the MCU's parameters from `avr-boot-devices`, and the device signature is
+//! checked against them when the protocol can read it. Progress is reported on stderr.
+//!
+//! The `ihex` and `srec` protocols send a HEX or S-record file as it is, to a bootloader which loads the text as it
+//! arrives. The port uses XON/XOFF flow control, so sending pauses while the bootloader writes each page.
+//!
+//! `pack` wraps an image in the `avr_boot::image` container format, for bootloaders which check the image before
+//! erasing anything. It doesn't need a serial port.
+
+use avr_boot::FlashRange;
+use avr_boot::image::Header;
+use avr_boot_devices::Device;
+use avr_boot_host::{Error, Programmer, avr109, framed, stk500v1, stk500v2, xmodem};
+use image::Image;
+use serialport::{FlowControl, SerialPort};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{env, fs, process};
+
+mod image;
+
+const USAGE: &str = "usage: avr-boot-cli [options] <command>
+
+commands:
+    write <file>     erase, write and verify an image (.hex, .srec, .elf, .avrb, or anything else as a raw binary)
+    verify <file>    compare an image with the contents of flash
+    read <file>      read flash into a raw binary file
+    erase            erase the application
+    pack <in> <out>  put an image in an .avrb container, which records the MCU it was built for
+
+options:
+    -P, --port <path>        serial port (required, except to pack)
+    -p, --mcu <name>         MCU, e.g. atmega328p (required)
+    -c, --protocol <name>    stk500v1 (default), stk500v2, avr109, framed, xmodem, ymodem, ihex or srec
+    -b, --baud <rate>        baud rate (default 115200)
+    -n, --node <address>     node address, for the framed protocol on a multi-drop bus
+    -t, --timeout <ms>       how long to wait for each response (default 1000)
+    -l, --length <bytes>     how much to read (default the whole flash)
+    -a, --app-version <n>    application version to put in a container or trailer (default 0)
+        --trailer <end>      after writing, store a header at the end of the application, which ends at <end>,
+                             for bootloaders which validate the application before starting it
+        --no-erase           don't erase before writing
+        --no-verify          don't read back after writing";
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Protocol {
+    Stk500v1,
+    Stk500v2,
+    Avr109,
+    Framed,
+    Xmodem,
+    Ymodem,
+    Ihex,
+    Srec,
+}
+
+impl Protocol {
+    /// Whether the file is sent as it is, for the bootloader to decode
+    fn is_text(self) -> bool {
+        matches!(self, Protocol::Ihex | Protocol::Srec)
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum Command {
+    Write(PathBuf),
+    Verify(PathBuf),
+    Read(PathBuf),
+    Erase,
+    Pack(PathBuf, PathBuf),
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct Options {
+    port: Option<String>,
+    mcu: String,
+    protocol: Protocol,
+    baud: u32,
+    node: Option<u8>,
+    timeout: Duration,
+    length: Option<usize>,
+    app_version: u32,
+    trailer: Option<u32>,
+    erase: bool,
+    verify: bool,
+    command: Command,
+}
+
+fn main() {
+    let options = match parse(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{message}\n\n{USAGE}");
+            process::exit(2);
+        }
+    };
+
+    if let Err(message) = run(&options) {
+        eprintln!("error: {message}");
+        process::exit(1);
+    }
+}
+
+fn parse(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut port = None;
+    let mut mcu = None;
+    let mut protocol = Protocol::Stk500v1;
+    let mut baud = 115_200;
+    let mut node = None;
+    let mut timeout = Duration::from_millis(1000);
+    let mut length = None;
+    let mut app_version = 0;
+    let mut trailer = None;
+    let mut erase = true;
+    let mut verify = true;
+    let mut positional = Vec::new();
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{arg} needs a value"));
+        match arg.as_str() {
+            "-P" | "--port" => port = Some(value()?),
+            "-p" | "--mcu" => mcu = Some(value()?),
+            "-c" | "--protocol" => {
+                protocol = match value()?.as_str() {
+                    "stk500v1" | "arduino" => Protocol::Stk500v1,
+                    "stk500v2" | "wiring" => Protocol::Stk500v2,
+                    "avr109" | "butterfly" => Protocol::Avr109,
+                    "framed" => Protocol::Framed,
+                    "xmodem" => Protocol::Xmodem,
+                    "ymodem" => Protocol::Ymodem,
+                    "ihex" => Protocol::Ihex,
+                    "srec" => Protocol::Srec,
+                    other => return Err(format!("unknown protocol '{other}'")),
+                }
+            }
+            "-b" | "--baud" => baud = number(&arg, value()?)?,
+            "-n" | "--node" => node = Some(number(&arg, value()?)?),
+            "-t" | "--timeout" => timeout = Duration::from_millis(number(&arg, value()?)?),
+            "-l" | "--length" => length = Some(number(&arg, value()?)?),
+            "-a" | "--app-version" => app_version = number(&arg, value()?)?,
+            "--trailer" => trailer = Some(number(&arg, value()?)?),
+            "--no-erase" => erase = false,
+            "--no-verify" => verify = false,
+            "-h" | "--help" => return Err(String::new()),
+            _ if arg.starts_with('-') => return Err(format!("unknown option '{arg}'")),
+            _ => positional.push(arg),
+        }
+    }
+
+    let command = match positional.as_slice() {
+        [command, file] if command == "write" => Command::Write(file.into()),
+        [command, file] if command == "verify" => Command::Verify(file.into()),
+        [command, file] if command == "read" => Command::Read(file.into()),
+        [command] if command == "erase" => Command::Erase,
+        [command, input, output] if command == "pack" => Command::Pack(input.into(), output.into()),
+        [] => return Err("no command given".to_string()),
+        _ => return Err(format!("can't understand '{}'", positional.join(" "))),
+    };
+    if node.is_some() && protocol != Protocol::Framed {
+        return Err("--node only applies to the framed protocol".to_string());
+    }
+    if protocol.is_text() && !matches!(command, Command::Write(_) | Command::Pack(..)) {
+        return Err("the ihex and srec protocols can only write".to_string());
+    }
+    if protocol.is_text() && trailer.is_some() {
+        return Err("--trailer doesn't apply to the ihex and srec protocols".to_string());
+    }
+    if port.is_none() && !matches!(command, Command::Pack(..)) {
+        return Err("--port is required".to_string());
+    }
+
+    Ok(Options {
+        port,
+        mcu: mcu.ok_or("--mcu is required")?,
+        protocol,
+        baud,
+        node,
+        timeout,
+        length,
+        app_version,
+        trailer,
+        erase,
+        verify,
+        command,
+    })
+}
+
+fn number<N: std::str::FromStr>(option: &str, value: String) -> Result<N, String> {
+    let parsed = match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).map(|n| n.to_string()).ok(),
+        None => Some(value.clone()),
+    };
+    parsed
+        .and_then(|n| n.parse().ok())
+        .ok_or(format!("{option} needs a number, not '{value}'"))
+}
+
+fn run(options: &Options) -> Result<(), Box<dyn std::error::Error>> {
+    let device = avr_boot_devices::device(&options.mcu)?;
+    if let Command::Pack(input, output) = &options.command {
+        return pack(input, output, &device, options.app_version);
+    }
+
+    let path = options.port.as_deref().unwrap_or_default();
+    let flow_control = if options.protocol.is_text() {
+        FlowControl::Software
+    } else {
+        FlowControl::None
+    };
+    let port = serialport::new(path, options.baud)
+        .timeout(options.timeout)
+        .flow_control(flow_control)
+        .open()
+        .map_err(|e| format!("can't open {path}: {e}"))?;
+    if let Command::Write(path) = &options.command
+        && options.protocol.is_text()
+    {
+        return send_text(options.protocol, path, &device, port);
+    }
+    let mut programmer = connect(options, port);
+
+    check_signature(&mut *programmer, &device)?;
+
+    match &options.command {
+        Command::Write(path) => {
+            let mut image = load(path, &device)?;
+            image.pad_to_pages(device.page_size);
+            let trailer = match options.trailer {
+                Some(end) => Some(trailer(&image, &device, end, options.app_version)?),
+                None => None,
+            };
+
+            if options.erase {
+                match programmer.erase() {
+                    Ok(()) => eprintln!("Erased"),
+                    // Every page gets erased as it is written anyway
+                    Err(Error::Unsupported(_)) => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+
+            eprintln!("Writing {} bytes at {:#06x}", image.data.len(), image.start);
+            programmer.write(image.start, &image.data, device.page_size)?;
+            // Last, so an interrupted write never leaves a trailer which matches
+            if let Some((address, page)) = trailer {
+                eprintln!("Writing the trailer at {address:#06x}");
+                programmer.write(address, &page, device.page_size)?;
+            }
+
+            if options.verify {
+                match verify(&mut *programmer, &image) {
+                    Err(Error::Unsupported(_)) => {
+                        eprintln!("Not verifying, the protocol can't read flash")
+                    }
+                    result => result?,
+                }
+            }
+        }
+        Command::Verify(path) => verify(&mut *programmer, &load(path, &device)?)?,
+        Command::Read(path) => {
+            let length = options.length.unwrap_or(device.flash_size as usize);
+            eprintln!("Reading {length} bytes");
+            let data = programmer.read(0, length)?;
+            fs::write(path, data)?;
+        }
+        Command::Erase => {
+            programmer.erase()?;
+            eprintln!("Erased");
+        }
+        Command::Pack(..) => unreachable!(),
+    }
+
+    programmer.finish()?;
+    Ok(())
+}
+
+fn connect(options: &Options, port: Box<dyn SerialPort>) -> Box<dyn Programmer> {
+    match options.protocol {
+        Protocol::Stk500v1 => Box::new(stk500v1::Client::new(port)),
+        Protocol::Stk500v2 => Box::new(stk500v2::Client::new(port)),
+        Protocol::Avr109 => Box::new(avr109::Client::new(port)),
+        Protocol::Framed => match options.node {
+            Some(node) => Box::new(framed::Client::new(port).with_address(node)),
+            None => Box::new(framed::Client::new(port)),
+        },
+        Protocol::Xmodem => Box::new(xmodem::Sender::xmodem(port)),
+        Protocol::Ymodem => {
+            let name = match &options.command {
+                Command::Write(path) => path.file_name().map(|name| name.to_string_lossy()),
+                _ => None,
+            };
+            Box::new(xmodem::Sender::ymodem(
+                port,
+                &name.unwrap_or("image.bin".into()),
+            ))
+        }
+        Protocol::Ihex | Protocol::Srec => unreachable!(),
+    }
+}
+
+/// Send a HEX or S-record file as it is, to a bootloader which loads the text as it arrives
+///
+/// The file is decoded first, so a bad record or an image which doesn't fit is reported before anything is sent.
+/// The OS honours the bootloader's XON/XOFF, pausing while it writes each page.
+fn send_text(
+    protocol: Protocol,
+    path: &Path,
+    device: &Device,
+    mut port: Box<dyn SerialPort>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let text = fs::read(path)?;
+    let image = match protocol {
+        Protocol::Ihex => Image::from_hex(&text),
+        _ => Image::from_srec(&text),
+    }
+    .map_err(|e| format!("can't load {}: {e}", path.display()))?;
+    check(path, &image, device)?;
+
+    eprintln!(
+        "Sending {} bytes at {:#06x}, as text",
+        image.data.len(),
+        image.start
+    );
+    port.write_all(&text)?;
+    port.flush()?;
+    eprintln!("Not verifying, the protocol can't read flash");
+    Ok(())
+}
+
+/// Make sure the bootloader is running on the MCU the image was built for
+fn check_signature(programmer: &mut dyn Programmer, device: &Device) -> Result<(), String> {
+    let signature = programmer.signature().map_err(|e| e.to_string())?;
+    match (signature, device.signature) {
+        (Some(found), Some(expected)) if found != expected => Err(format!(
+            "signature {} doesn't match {}, which is {}",
+            hex(&found),
+            device.name,
+            hex(&expected)
+        )),
+        (Some(found), _) => {
+            eprintln!("Found signature {}", hex(&found));
+            Ok(())
+        }
+        (None, _) => Ok(()),
+    }
+}
+
+/// Wrap an image in a container for `device`
+fn pack(
+    input: &Path,
+    output: &Path,
+    device: &Device,
+    app_version: u32,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let image = load(input, device)?;
+    let signature = device
+        .signature
+        .ok_or(format!("there's no signature for {}", device.name))?;
+
+    let packed = avr_boot_host::image::pack(
+        signature,
+        device.page_size,
+        image.start,
+        &image.data,
+        app_version,
+    );
+    eprintln!(
+        "Packed {} bytes at {:#06x}, version {app_version}",
+        image.data.len(),
+        image.start
+    );
+    fs::write(output, packed)?;
+    Ok(())
+}
+
+/// The trailer page for an application range ending at `end`, describing `image`
+fn trailer(
+    image: &Image,
+    device: &Device,
+    end: u32,
+    app_version: u32,
+) -> Result<(u32, Vec<u8>), String> {
+    let signature = device
+        .signature
+        .ok_or(format!("there's no signature for {}", device.name))?;
+    if !end.is_multiple_of(device.page_size as u32) || end > device.flash_size {
+        return Err(format!("the trailer can't go at the end of {end:#06x}"));
+    }
+
+    let header = Header::new(
+        signature,
+        device.page_size as u16,
+        image.start,
+        &image.data,
+        app_version,
+    );
+    let (address, page) = avr_boot_host::image::trailer_page(&header, end, device.page_size);
+    if image.end() > address {
+        return Err(format!(
+            "the image ends at {:#06x}, past the start of the trailer page at {address:#06x}",
+            image.end()
+        ));
+    }
+    Ok((address, page))
+}
+
+/// Load an image, and check it fits in flash, and that a container was built for `device`
+fn load(path: &Path, device: &Device) -> Result<Image, Box<dyn std::error::Error>> {
+    let image = Image::load(path).map_err(|e| format!("can't load {}: {e}", path.display()))?;
+    check(path, &image, device)?;
+    Ok(image)
+}
+
+/// Check an image loaded from `path` fits in flash, and that a container was built for `device`
+fn check(path: &Path, image: &Image, device: &Device) -> Result<(), String> {
+    if let (Some(header), Some(signature)) = (&image.header, device.signature) {
+        let flash = FlashRange::new(0u16, device.flash_size);
+        header
+            .check_target(signature, device.page_size, flash)
+            .map_err(|e| format!("{} isn't for {}: {e:?}", path.display(), device.name))?;
+    }
+    if image.end() > device.flash_size {
+        return Err(format!(
+            "the image ends at {:#06x}, past the end of flash at {:#06x}",
+            image.end(),
+            device.flash_size
+        ));
+    }
+    Ok(())
+}
+
+/// Read back the area covered by `image`, and compare
+fn verify(programmer: &mut dyn Programmer, image: &Image) -> Result<(), Error> {
+    eprintln!("Verifying {} bytes", image.data.len());
+    let read = programmer.read(image.start, image.data.len())?;
+    if read.len() != image.data.len() {
+        eprintln!(
+            "Verification failed: read {} bytes, expected {}",
+            read.len(),
+            image.data.len()
+        );
+        return Err(Error::Failed);
+    }
+
+    match read.iter().zip(&image.data).position(|(a, b)| a != b) {
+        Some(offset) => {
+            eprintln!(
+                "Verification failed at {:#06x}: expected {:#04x}, found {:#04x}",
+                image.start + offset as u32,
+                image.data[offset],
+                read[offset]
+            );
+            Err(Error::Failed)
+        }
+        None => {
+            eprintln!("Verified");
+            Ok(())
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> impl Iterator<Item = String> {
+        line.split_whitespace().map(String::from)
+    }
+
+    #[test]
+    fn it_parses_a_write() {
+        let options = parse(args(
+            "-P /dev/ttyUSB0 -p atmega328p -c avr109 -b 57600 write app.hex",
+        ))
+        .unwrap();
+
+        assert_eq!(
+            options,
+            Options {
+                port: Some("/dev/ttyUSB0".to_string()),
+                mcu: "atmega328p".to_string(),
+                protocol: Protocol::Avr109,
+                baud: 57600,
+                node: None,
+                timeout: Duration::from_millis(1000),
+                length: None,
+                app_version: 0,
+                trailer: None,
+                erase: true,
+                verify: true,
+                command: Command::Write("app.hex".into()),
+            }
+        );
+    }
+
+    #[test]
+    fn it_parses_hex_numbers() {
+        let options = parse(args(
+            "-P p -p m -c framed --node 0x12 -l 0x800 read out.bin",
+        ))
+        .unwrap();
+
+        assert_eq!(options.node, Some(0x12));
+        assert_eq!(options.length, Some(0x800));
+        assert_eq!(options.command, Command::Read("out.bin".into()));
+    }
+
+    #[test]
+    fn it_packs_without_a_port() {
+        let options = parse(args("-p atmega328p -a 7 pack app.hex app.avrb")).unwrap();
+
+        assert_eq!(options.port, None);
+        assert_eq!(options.app_version, 7);
+        assert_eq!(
+            options.command,
+            Command::Pack("app.hex".into(), "app.avrb".into())
+        );
+    }
+
+    #[test]
+    fn it_rejects_bad_arguments() {
+        assert!(parse(args("-p m write app.hex")).is_err());
+        assert!(parse(args("-P p -p m write")).is_err());
+        assert!(parse(args("-P p -p m -c kermit erase")).is_err());
+        assert!(parse(args("-P p -p m --node 1 erase")).is_err());
+        assert!(parse(args("-P p -p m -c ihex read out.bin")).is_err());
+        assert!(parse(args("-P p -p m -c srec --trailer 0x1000 write app.srec")).is_err());
+    }
+
+    /// Reads back what was written, but stops short of the end
+    struct ShortRead;
+
+    impl Programmer for ShortRead {
+        fn signature(&mut self) -> Result<Option<[u8; 3]>, Error> {
+            Ok(None)
+        }
+
+        fn erase(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn write(&mut self, _: u32, _: &[u8], _: usize) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn read(&mut self, _: u32, length: usize) -> Result<Vec<u8>, Error> {
+            Ok(vec![0xaa; length - 1])
+        }
+
+        fn finish(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn it_fails_to_verify_a_short_read() {
+        let image = Image {
+            start: 0,
+            data: vec![0xaa; 4],
+            header: None,
+        };
+
+        assert!(matches!(verify(&mut ShortRead, &image), Err(Error::Failed)));
+    }
+}
@@ -0,0 +1,398 @@
+//! Run the flasher against protocol engines on the other end of a pseudo-terminal pair
+
+use avr_boot::protocol::{
+    Serial, Status, avr109::Avr109, framed::Framed, stk500v1::Stk500v1, stk500v2::Stk500v2,
+    ymodem::Ymodem,
+};
+use avr_boot::{FlashRange, SPM_PAGESIZE_BYTES, ihex, srec};
+use avr_boot_host::sim::MemoryFlash;
+use serialport::{SerialPort, TTYPort};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::process::{Command, Output};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use std::{env, fs, process};
+
+/// How long the device waits for the next byte before giving up, once the flasher has gone
+const PATIENCE: Duration = Duration::from_secs(2);
+
+/// The device's end of the pseudo-terminal
+struct Pty(TTYPort);
+
+impl Serial for Pty {
+    type Error = ();
+
+    fn read(&mut self) -> Result<u8, ()> {
+        let started = Instant::now();
+        let mut byte = [0];
+        loop {
+            match self.0.read(&mut byte) {
+                Ok(1) => return Ok(byte[0]),
+                Err(e) if e.kind() != io::ErrorKind::TimedOut => return Err(()),
+                _ if started.elapsed() > PATIENCE => return Err(()),
+                _ => {}
+            }
+        }
+    }
+
+    fn write(&mut self, byte: u8) -> Result<(), ()> {
+        self.0.write_all(&[byte]).map_err(drop)
+    }
+}
+
+/// A simulated bootloader, and the path the flasher should open to talk to it
+struct Device {
+    path: String,
+    handle: JoinHandle<MemoryFlash>,
+    /// Held open so the device's reads time out, rather than fail, while the flasher isn't connected
+    _slave: TTYPort,
+}
+
+impl Device {
+    fn spawn(run: impl FnOnce(Pty, MemoryFlash) -> MemoryFlash + Send + 'static) -> Device {
+        let (mut master, slave) = TTYPort::pair().unwrap();
+        master.set_timeout(Duration::from_millis(50)).unwrap();
+        let path = slave.name().unwrap();
+        let handle = thread::spawn(move || run(Pty(master), MemoryFlash::new()));
+        Device {
+            path,
+            handle,
+            _slave: slave,
+        }
+    }
+
+    /// Run the flasher for an atmega1280, which the host build of avr-boot simulates
+    fn flash(&self, args: &[&str]) -> Output {
+        Command::new(env!("CARGO_BIN_EXE_avr-boot-cli"))
+            .args(["--port", &self.path, "--mcu", "atmega1280"])
+            .args(args)
+            .output()
+            .unwrap()
+    }
+
+    fn join(self) -> MemoryFlash {
+        self.handle.join().unwrap()
+    }
+}
+
+/// Runs a protocol engine until the flasher has finished with it
+type Bootloader = fn(Pty, MemoryFlash) -> MemoryFlash;
+
+fn application() -> FlashRange {
+    FlashRange::new(0u16, 0x1_f000u32)
+}
+
+/// A file in the temp directory, unique to this test process
+fn temp(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("avr-boot-cli-{}-{name}", process::id()))
+}
+
+fn image(length: usize) -> Vec<u8> {
+    (0..length).map(|i| (i * 7 + 3) as u8).collect()
+}
+
+/// An Intel HEX file holding `data` at `address`, in 16 byte records
+fn hex(address: u32, data: &[u8]) -> String {
+    let mut hex = String::new();
+    let mut extended = None;
+    for (i, chunk) in data.chunks(16).enumerate() {
+        let address = address + (i * 16) as u32;
+        let upper = (address >> 16) as u16;
+        if extended != Some(upper) {
+            hex += &record(4, 0, &upper.to_be_bytes());
+            extended = Some(upper);
+        }
+        hex += &record(0, address as u16, chunk);
+    }
+    hex + &record(1, 0, &[])
+}
+
+fn record(kind: u8, address: u16, data: &[u8]) -> String {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend(address.to_be_bytes());
+    bytes.push(kind);
+    bytes.extend(data);
+    let checksum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_sub(*b));
+    bytes.push(checksum);
+    let digits: String = bytes.iter().map(|b| format!("{b:02X}")).collect();
+    format!(":{digits}\n")
+}
+
+/// An S-record file holding `data` at `address`, in 16 byte S2 records
+fn srec(address: u32, data: &[u8]) -> String {
+    let mut srec = String::new();
+    for (i, chunk) in data.chunks(16).enumerate() {
+        let address = address + (i * 16) as u32;
+        srec += &s_record(2, &address.to_be_bytes()[1..], chunk);
+    }
+    srec + &s_record(8, &[0, 0, 0], &[])
+}
+
+fn s_record(kind: u8, address: &[u8], data: &[u8]) -> String {
+    let mut bytes = vec![(address.len() + data.len() + 1) as u8];
+    bytes.extend(address);
+    bytes.extend(data);
+    let checksum = !bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    bytes.push(checksum);
+    let digits: String = bytes.iter().map(|b| format!("{b:02X}")).collect();
+    format!("S{kind}{digits}\n")
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn it_writes_and_verifies_a_hex_file_with_each_protocol() {
+    let protocols: [(&str, Bootloader); 4] = [
+        ("stk500v1", |pty, flash| {
+            let mut stk = Stk500v1::new(pty, flash);
+            stk.run().ok();
+            stk.release().1
+        }),
+        ("stk500v2", |pty, flash| {
+            let mut stk = Stk500v2::new(pty, flash);
+            stk.run().ok();
+            stk.release().1
+        }),
+        ("avr109", |pty, flash| {
+            let mut avr109 = Avr109::new(pty, flash, application());
+            avr109.run().ok();
+            avr109.release().1
+        }),
+        ("framed", |pty, flash| {
+            let mut framed = Framed::new(pty, flash, application());
+            framed.run().ok();
+            framed.release().1
+        }),
+    ];
+    // Straddles the 64k boundary, and starts part way through a page
+    let address = 0x1_0000 - SPM_PAGESIZE_BYTES as u32 + 0x10;
+    let data = image(SPM_PAGESIZE_BYTES * 2);
+    let path = temp("app.hex");
+    fs::write(&path, hex(address, &data)).unwrap();
+
+    for (protocol, run) in protocols {
+        let device = Device::spawn(run);
+
+        let output = device.flash(&["--protocol", protocol, "write", path.to_str().unwrap()]);
+        let flash = device.join();
+
+        assert!(output.status.success(), "{protocol}: {}", stderr(&output));
+        assert!(stderr(&output).contains("Verified"), "{protocol}");
+        let start = address as usize;
+        assert_eq!(
+            &flash.memory[start..start + data.len()],
+            &data[..],
+            "{protocol}"
+        );
+        assert_eq!(flash.memory[start - 1], 0xff, "{protocol}");
+    }
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn it_streams_hex_and_s_record_text_to_the_loaders() {
+    let protocols: [(&str, &str, Bootloader); 2] = [
+        ("ihex", "app.hex", |mut pty, flash| {
+            let mut buffer = [0; 32];
+            let mut loader = ihex::Loader::new(flash, application(), &mut buffer);
+            while let Ok(character) = pty.read() {
+                if loader.push(&mut pty, character) == Ok(Status::Done) {
+                    break;
+                }
+            }
+            loader.release()
+        }),
+        ("srec", "app.srec", |mut pty, flash| {
+            let mut buffer = [0; 32];
+            let mut loader = srec::Loader::new(flash, application(), &mut buffer);
+            while let Ok(character) = pty.read() {
+                if loader.push(&mut pty, character) == Ok(Status::Done) {
+                    break;
+                }
+            }
+            loader.release()
+        }),
+    ];
+    let address = 0x1_0000 - SPM_PAGESIZE_BYTES as u32 + 0x10;
+    let data = image(SPM_PAGESIZE_BYTES * 4);
+
+    for (protocol, name, run) in protocols {
+        let path = temp(name);
+        let text = match protocol {
+            "ihex" => hex(address, &data),
+            _ => srec(address, &data),
+        };
+        fs::write(&path, text).unwrap();
+        let device = Device::spawn(run);
+
+        let output = device.flash(&["--protocol", protocol, "write", path.to_str().unwrap()]);
+        let flash = device.join();
+        fs::remove_file(path).unwrap();
+
+        assert!(output.status.success(), "{protocol}: {}", stderr(&output));
+        let start = address as usize;
+        assert_eq!(
+            &flash.memory[start..start + data.len()],
+            &data[..],
+            "{protocol}"
+        );
+    }
+}
+
+#[test]
+fn it_writes_a_binary_with_ymodem() {
+    let device = Device::spawn(|pty, flash| {
+        let mut buffer = [0; 1024];
+        let mut ymodem = Ymodem::new(pty, flash, application(), &mut buffer);
+        let file = ymodem.run().unwrap().unwrap();
+        assert_eq!(file.length, 2 * SPM_PAGESIZE_BYTES as u32);
+        ymodem.release().1
+    });
+    let data = image(SPM_PAGESIZE_BYTES + 10);
+    let path = temp("app.bin");
+    fs::write(&path, &data).unwrap();
+
+    let output = device.flash(&["--protocol", "ymodem", "write", path.to_str().unwrap()]);
+    let flash = device.join();
+    fs::remove_file(path).unwrap();
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("Not verifying"));
+    assert_eq!(&flash.memory[..data.len()], &data[..]);
+}
+
+#[test]
+fn it_reads_back_into_a_binary_file() {
+    let device = Device::spawn(|pty, mut flash| {
+        flash.memory[..0x400].copy_from_slice(&image(0x400));
+        let mut avr109 = Avr109::new(pty, flash, application());
+        avr109.run().ok();
+        avr109.release().1
+    });
+    let path = temp("read.bin");
+
+    let output = device.flash(&[
+        "--protocol",
+        "avr109",
+        "--length",
+        "0x400",
+        "read",
+        path.to_str().unwrap(),
+    ]);
+    device.join();
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(fs::read(&path).unwrap(), image(0x400));
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn it_reports_a_protocol_which_cannot_erase() {
+    let device = Device::spawn(|pty, flash| {
+        let mut stk = Stk500v1::new(pty, flash);
+        stk.run().ok();
+        stk.release().1
+    });
+
+    let output = device.flash(&["erase"]);
+    device.join();
+
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("erasing isn't supported"));
+}
+
+#[test]
+fn it_refuses_to_flash_the_wrong_mcu() {
+    let device = Device::spawn(|pty, flash| {
+        let mut stk = Stk500v2::new(pty, flash);
+        stk.run().ok();
+        stk.release().1
+    });
+    let path = temp("wrong.bin");
+    fs::write(&path, image(16)).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_avr-boot-cli"))
+        .args(["--port", &device.path, "--mcu", "atmega328p"])
+        .args(["--protocol", "stk500v2", "write", path.to_str().unwrap()])
+        .output()
+        .unwrap();
+    let flash = device.join();
+    fs::remove_file(path).unwrap();
+
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("doesn't match atmega328p"));
+    assert!(flash.memory.iter().all(|b| *b == 0xff));
+}
+
+#[test]
+fn it_only_writes_a_packed_image_to_the_mcu_it_was_packed_for() {
+    let hex_path = temp("packed.hex");
+    fs::write(&hex_path, hex(0x100, &image(100))).unwrap();
+    let pack = |mcu: &str, output: &PathBuf| {
+        Command::new(env!("CARGO_BIN_EXE_avr-boot-cli"))
+            .args(["--mcu", mcu, "--app-version", "3", "pack"])
+            .args([&hex_path, output])
+            .output()
+            .unwrap()
+    };
+    let ours = temp("ours.avrb");
+    let theirs = temp("theirs.avrb");
+    assert!(pack("atmega1280", &ours).status.success());
+    assert!(pack("atmega328p", &theirs).status.success());
+
+    let mut results = Vec::new();
+    for path in [&theirs, &ours] {
+        let device = Device::spawn(|pty, flash| {
+            let mut avr109 = Avr109::new(pty, flash, application());
+            avr109.run().ok();
+            avr109.release().1
+        });
+        let output = device.flash(&["--protocol", "avr109", "write", path.to_str().unwrap()]);
+        results.push((output, device.join()));
+    }
+    for path in [hex_path, ours, theirs] {
+        fs::remove_file(path).unwrap();
+    }
+
+    let (output, flash) = &results[0];
+    assert!(!output.status.success());
+    assert!(stderr(output).contains("isn't for atmega1280: WrongDevice"));
+    assert!(flash.memory.iter().all(|b| *b == 0xff));
+
+    let (output, flash) = &results[1];
+    assert!(output.status.success(), "{}", stderr(output));
+    assert_eq!(&flash.memory[0x100..0x100 + 100], &image(100)[..]);
+}
+
+#[test]
+fn it_writes_a_trailer_the_bootloader_validates() {
+    let device = Device::spawn(|pty, flash| {
+        let mut stk = Stk500v2::new(pty, flash);
+        stk.run().ok();
+        stk.release().1
+    });
+    let data = image(1000);
+    let path = temp("trailer.bin");
+    fs::write(&path, &data).unwrap();
+
+    let output = device.flash(&[
+        "--protocol",
+        "stk500v2",
+        "--app-version",
+        "9",
+        "--trailer",
+        "0x1f000",
+        "write",
+        path.to_str().unwrap(),
+    ]);
+    let flash = device.join();
+    fs::remove_file(path).unwrap();
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    let header = avr_boot::image::validate(&flash, application()).unwrap();
+    assert_eq!(header.app_version, 9);
+    assert_eq!(u32::from(header.address), 0);
+}
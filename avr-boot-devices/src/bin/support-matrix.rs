@@ -0,0 +1,138 @@
+//! Report the self programming capabilities of every MCU known to `avr-mcu`
+//!
+//! Usage: `cargo run --bin support-matrix -- SUPPORT.md support.json`
+//!
+//! The output format is picked from each file's extension. Results are written to files rather than stdout,
+//! because `avr-mcu` logs to stdout while loading its pack files.
+
+use avr_boot_devices::{Device, Error};
+use std::{env, fs, path::Path, process};
+
+fn main() {
+    let outputs: Vec<String> = env::args().skip(1).collect();
+    if outputs.is_empty() {
+        eprintln!("usage: support-matrix <output.md|output.json>...");
+        process::exit(1);
+    }
+
+    let mut devices: Vec<(String, Result<Device, Error>)> = avr_boot_devices::all().collect();
+    devices.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    for output in outputs {
+        let path = Path::new(&output);
+        let contents = match path.extension().and_then(|e| e.to_str()) {
+            Some("md") => markdown(&devices),
+            Some("json") => json(&devices),
+            _ => {
+                eprintln!("can't tell the format of '{output}', expected a .md or .json extension");
+                process::exit(1);
+            }
+        };
+
+        fs::write(path, contents).unwrap_or_else(|e| panic!("failed to write {output}: {e}"));
+    }
+}
+
+fn markdown(devices: &[(String, Result<Device, Error>)]) -> String {
+    let mut out = String::new();
+
+    out.push_str("# Self programming support matrix\n\n");
+    out.push_str("Generated by running `cargo run --bin support-matrix -- ../SUPPORT.md ../support.json` in `avr-boot-devices`.\n\n");
+    out.push_str(
+        "| MCU | Register | Flash | Page | RWW | RAMPZ | CTPB | SIGRD | Memory mapped |\n",
+    );
+    out.push_str(
+        "|-----|----------|------:|-----:|:---:|:-----:|:----:|:-----:|:-------------:|\n",
+    );
+
+    for (_, device) in devices {
+        if let Ok(device) = device {
+            out.push_str(&format!(
+                "| {} | {} | {} | {} | {} | {} | {} | {} | {} |\n",
+                device.name,
+                device.spm.name,
+                device.flash_size,
+                device.page_size,
+                tick(device.spm.rww_enable.is_some()),
+                tick(device.rampz.is_some()),
+                tick(device.spm.clear_page_buffer.is_some()),
+                tick(device.spm.signature_read.is_some()),
+                tick(device.spm.is_memory_mapped()),
+            ));
+        }
+    }
+
+    out.push_str("\n## No self programming\n\n");
+    for (name, device) in devices {
+        if let Err(e) = device {
+            out.push_str(&format!("* {name}: {e}\n"));
+        }
+    }
+
+    out
+}
+
+fn tick(value: bool) -> &'static str {
+    if value { "✓" } else { "" }
+}
+
+fn json(devices: &[(String, Result<Device, Error>)]) -> String {
+    let entries: Vec<String> = devices
+        .iter()
+        .map(|(name, device)| match device {
+            Ok(device) => format!(
+                concat!(
+                    "  {{\"name\": {}, \"self_programming\": true, \"register\": {}, ",
+                    "\"flash_size\": {}, \"page_size\": {}, \"rww\": {}, \"rampz\": {}, ",
+                    "\"ctpb\": {}, \"sigrd\": {}, \"memory_mapped\": {}}}"
+                ),
+                string(&device.name),
+                string(&device.spm.name),
+                device.flash_size,
+                device.page_size,
+                device.spm.rww_enable.is_some(),
+                device.rampz.is_some(),
+                device.spm.clear_page_buffer.is_some(),
+                device.spm.signature_read.is_some(),
+                device.spm.is_memory_mapped(),
+            ),
+            Err(e) => format!(
+                "  {{\"name\": {}, \"self_programming\": false, \"reason\": {}}}",
+                string(name),
+                string(&e.to_string())
+            ),
+        })
+        .collect();
+
+    format!("[\n{}\n]\n", entries.join(",\n"))
+}
+
+/// A JSON string literal holding `value`
+fn string(value: &str) -> String {
+    let mut out = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_escapes_json_strings() {
+        assert_eq!(string("atmega328p"), "\"atmega328p\"");
+        assert_eq!(
+            string("no \"SPMCSR\" in C:\\packs\n\t"),
+            "\"no \\\"SPMCSR\\\" in C:\\\\packs\\n\\u0009\""
+        );
+    }
+}
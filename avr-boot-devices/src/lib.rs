@@ -0,0 +1,398 @@
+//! Self programming parameters for AVR MCUs
+//!
+//! This is the same device discovery that `avr-boot` runs in its build script, packaged up so that
+//! host side tools (flashers, image packers etc.) can work from exactly the same numbers as the firmware.
+//!
+//! # Example
+//! ```rust
+//! let device = avr_boot_devices::device("atmega328p").unwrap();
+//!
+//! assert_eq!(128, device.page_size);
+//! assert_eq!(32768, device.flash_size);
+//! assert_eq!(Some([0x1e, 0x95, 0x0f]), device.signature);
+//! ```
+
+use avr_mcu::Mcu;
+use std::fmt;
+
+mod signatures;
+
+/// Everything needed to self-program a single MCU
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Device {
+    /// Lower case MCU name, as used by `avr-mcu` and `-Ctarget-cpu`
+    pub name: String,
+    /// The 3 signature bytes, if known
+    pub signature: Option<[u8; 3]>,
+    /// Total size of the program memory, in bytes
+    pub flash_size: u32,
+    /// Size of the SPM page buffer, in bytes
+    pub page_size: usize,
+    /// The SPM control register, and the values written to it for each operation
+    pub spm: SpmControl,
+    /// Data space address of the RAMPZ register, only present for MCUs with 64k of flash or more
+    pub rampz: Option<u8>,
+    /// Possible boot section sizes, as selected by the BOOTSZ fuses. Empty if the MCU has no boot section.
+    pub boot_sections: Vec<BootSection>,
+    /// The EEPROM and its control registers, if the MCU has one
+    pub eeprom: Option<EepromControl>,
+}
+
+/// The SPM control register layout
+///
+/// Apart from `address`, each field is the full value written to the register to perform the operation,
+/// i.e. it already includes the SPM enable bit.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SpmControl {
+    /// Either `SPMCSR` or `SPMCR`
+    pub name: String,
+    /// Data space address of the register
+    pub address: u16,
+    /// Fill the page buffer
+    pub page_fill: u8,
+    /// Erase a page
+    pub page_erase: u8,
+    /// Write the page buffer to program memory
+    pub page_write: u8,
+    /// Re-enable the RWW section, only present for MCUs with a RWW section
+    pub rww_enable: Option<u8>,
+    /// Set the boot lock bits
+    pub lock_bits_set: u8,
+    /// Clear the temporary page buffer
+    pub clear_page_buffer: Option<u8>,
+    /// Read the signature row
+    pub signature_read: Option<u8>,
+}
+
+impl SpmControl {
+    /// The highest data space address reachable by `in` / `out`
+    const MAX_IO_ADDRESS: u16 = 0x5f;
+
+    /// True if the register is outside of the I/O space, and so can't be written with `out`
+    pub fn is_memory_mapped(&self) -> bool {
+        self.address > Self::MAX_IO_ADDRESS
+    }
+}
+
+/// The EEPROM control registers
+///
+/// Addresses are data space addresses. The `*_enable` fields are bit masks within the control register.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct EepromControl {
+    /// Size of the EEPROM, in bytes
+    pub size: u32,
+    /// EEAR, or EEARL
+    pub address_low: u16,
+    /// EEARH, only present if the address register is 16 bits wide
+    pub address_high: Option<u16>,
+    /// EEDR
+    pub data: u16,
+    /// EECR
+    pub control: u16,
+    /// EERE
+    pub read_enable: u8,
+    /// EEMPE, or EEMWE on older MCUs
+    pub master_write_enable: u8,
+    /// EEPE, or EEWE on older MCUs
+    pub write_enable: u8,
+}
+
+/// One of the boot section sizes selectable by the BOOTSZ fuses
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct BootSection {
+    /// Size of the boot section, in bytes
+    pub size: u32,
+    /// Byte address of the start of the boot section
+    pub start: u32,
+    /// The value of the BOOTSZ fuse bits which selects this size
+    pub fuse_value: u8,
+}
+
+/// Reasons a device can't be described
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Error {
+    /// `avr-mcu` has no pack file for this name
+    UnknownMcu(String),
+    /// Neither SPMCSR nor SPMCR exist, the MCU can't self-program (or uses the newer NVM controller)
+    NoSpmControlRegister,
+    /// The SPM control register is missing a bitfield needed for self-programming
+    MissingBitfield(&'static str),
+    /// There is no page size for the flash segment
+    NoPageSize,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::UnknownMcu(name) => write!(f, "unknown mcu '{name}'"),
+            Error::NoSpmControlRegister => write!(f, "could not find SPMCSR or SPMCR register"),
+            Error::MissingBitfield(name) => write!(f, "could not find {name} bitfield"),
+            Error::NoPageSize => write!(f, "failed to find page size"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Look up a device by name, e.g. `atmega328p`
+pub fn device(name: &str) -> Result<Device, Error> {
+    let name = name.to_lowercase();
+
+    if !avr_mcu::microcontroller_names().contains(&name) {
+        return Err(Error::UnknownMcu(name));
+    }
+
+    from_mcu(&avr_mcu::microcontroller(&name))
+}
+
+/// Every device known to `avr-mcu`, paired with its name
+pub fn all() -> impl Iterator<Item = (String, Result<Device, Error>)> {
+    avr_mcu::microcontrollers()
+        .iter()
+        .map(|mcu| (mcu.device.name.to_lowercase(), from_mcu(mcu)))
+}
+
+/// Extract the self programming parameters from an `avr-mcu` description
+pub fn from_mcu(mcu: &Mcu) -> Result<Device, Error> {
+    let name = mcu.device.name.to_lowercase();
+
+    let spm_reg = mcu
+        .registers()
+        .find(|r| r.name == "SPMCSR" || r.name == "SPMCR")
+        .ok_or(Error::NoSpmControlRegister)?;
+
+    let bitfield = |names: &[&str]| {
+        spm_reg
+            .bitfields
+            .iter()
+            .find(|b| names.contains(&b.name.as_str()))
+            .map(|b| b.mask as u8)
+    };
+
+    let spm_enable =
+        bitfield(&["SPMEN", "SELFPRGEN"]).ok_or(Error::MissingBitfield("spm enable"))?;
+    let page_erase = bitfield(&["PGERS"]).ok_or(Error::MissingBitfield("page erase"))?;
+    let page_write = bitfield(&["PGWRT"]).ok_or(Error::MissingBitfield("page write"))?;
+    let blb_set =
+        bitfield(&["LBSET", "BLBSET", "RFLB"]).ok_or(Error::MissingBitfield("blb set"))?;
+    let rww_enable = bitfield(&["ASRE", "RWWSRE"]);
+    let ctpb = bitfield(&["CTPB"]);
+    let sigrd = bitfield(&["SIGRD", "RSIG"]);
+
+    let prog_space = mcu
+        .device
+        .address_spaces
+        .iter()
+        .find(|space| space.name == "prog")
+        .ok_or(Error::NoPageSize)?;
+
+    let page_size = prog_space
+        .segments
+        .iter()
+        .find(|seg| seg.name == "FLASH")
+        .and_then(|seg| seg.page_size)
+        .ok_or(Error::NoPageSize)?;
+
+    let rampz = if prog_space.size > 0xffff {
+        mcu.registers()
+            .find(|r| r.name == "RAMPZ" || r.name == "RAMPZ0")
+            .map(|r| r.offset as u8)
+    } else {
+        None
+    };
+
+    Ok(Device {
+        signature: signature(&name),
+        name,
+        flash_size: prog_space.size,
+        page_size: page_size as usize,
+        spm: SpmControl {
+            name: spm_reg.name.clone(),
+            address: spm_reg.offset as u16,
+            page_fill: spm_enable,
+            page_erase: spm_enable | page_erase,
+            page_write: spm_enable | page_write,
+            rww_enable: rww_enable.map(|mask| mask | spm_enable),
+            lock_bits_set: spm_enable | blb_set,
+            clear_page_buffer: ctpb.map(|mask| mask | spm_enable),
+            signature_read: sigrd.map(|mask| mask | spm_enable),
+        },
+        rampz,
+        boot_sections: boot_sections(mcu),
+        eeprom: eeprom(mcu),
+    })
+}
+
+fn eeprom(mcu: &Mcu) -> Option<EepromControl> {
+    let size = mcu
+        .device
+        .address_spaces
+        .iter()
+        .find(|space| space.name == "eeprom")?
+        .size;
+
+    let register = |names: &[&str]| mcu.registers().find(|r| names.contains(&r.name.as_str()));
+
+    let address = register(&["EEAR", "EEARL"])?;
+    let address_high = match register(&["EEARH"]) {
+        Some(high) => Some(high.offset as u16),
+        None if address.size > 1 => Some(address.offset as u16 + 1),
+        None => None,
+    };
+    let data = register(&["EEDR"])?;
+    let control = register(&["EECR"])?;
+
+    let bitfield = |names: &[&str]| {
+        control
+            .bitfields
+            .iter()
+            .find(|b| names.contains(&b.name.as_str()))
+            .map(|b| b.mask as u8)
+    };
+
+    Some(EepromControl {
+        size,
+        address_low: address.offset as u16,
+        address_high,
+        data: data.offset as u16,
+        control: control.offset as u16,
+        read_enable: bitfield(&["EERE"])?,
+        master_write_enable: bitfield(&["EEMPE", "EEMWE"])?,
+        write_enable: bitfield(&["EEPE", "EEWE"])?,
+    })
+}
+
+fn signature(name: &str) -> Option<[u8; 3]> {
+    signatures::SIGNATURES
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, signature)| *signature)
+}
+
+/// BOOTSZ values are named like `256W_3F00`: the size in words, and the start word address in hex
+fn boot_sections(mcu: &Mcu) -> Vec<BootSection> {
+    let mut sections: Vec<BootSection> = mcu
+        .modules
+        .iter()
+        .filter(|module| module.name == "FUSE")
+        .flat_map(|module| &module.value_groups)
+        .filter(|group| group.name.ends_with("BOOTSZ"))
+        .flat_map(|group| &group.values)
+        .filter_map(|value| {
+            let (size, start) = value.name.split_once("W_")?;
+            Some(BootSection {
+                size: size.parse::<u32>().ok()? * 2,
+                start: u32::from_str_radix(start, 16).ok()? * 2,
+                fuse_value: value.value as u8,
+            })
+        })
+        .collect();
+
+    sections.sort_by_key(|section| section.size);
+    sections.dedup();
+    sections
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_describes_atmega328p() {
+        let device = device("atmega328p").unwrap();
+
+        assert_eq!(0x57, device.spm.address);
+        assert_eq!(0x01, device.spm.page_fill);
+        assert_eq!(0x03, device.spm.page_erase);
+        assert_eq!(0x05, device.spm.page_write);
+        assert_eq!(Some(0x11), device.spm.rww_enable);
+        assert_eq!(0x09, device.spm.lock_bits_set);
+        assert_eq!(Some(0x21), device.spm.signature_read);
+        assert_eq!(None, device.rampz);
+        assert!(!device.spm.is_memory_mapped());
+    }
+
+    #[test]
+    fn it_describes_the_eeprom() {
+        let eeprom = device("atmega328p").unwrap().eeprom.unwrap();
+
+        assert_eq!(1024, eeprom.size);
+        assert_eq!(
+            (0x41, Some(0x42)),
+            (eeprom.address_low, eeprom.address_high)
+        );
+        assert_eq!((0x40, 0x3f), (eeprom.data, eeprom.control));
+        assert_eq!(
+            (0x01, 0x04, 0x02),
+            (
+                eeprom.read_enable,
+                eeprom.master_write_enable,
+                eeprom.write_enable
+            )
+        );
+    }
+
+    #[test]
+    fn it_lists_boot_sections() {
+        let device = device("atmega328p").unwrap();
+
+        assert_eq!(
+            vec![
+                BootSection {
+                    size: 512,
+                    start: 0x7e00,
+                    fuse_value: 3
+                },
+                BootSection {
+                    size: 1024,
+                    start: 0x7c00,
+                    fuse_value: 2
+                },
+                BootSection {
+                    size: 2048,
+                    start: 0x7800,
+                    fuse_value: 1
+                },
+                BootSection {
+                    size: 4096,
+                    start: 0x7000,
+                    fuse_value: 0
+                },
+            ],
+            device.boot_sections
+        );
+    }
+
+    #[test]
+    fn it_finds_rampz_on_extended_devices() {
+        let device = device("atmega2560").unwrap();
+
+        assert_eq!(Some(0x5b), device.rampz);
+        assert_eq!(256, device.page_size);
+        assert_eq!(0x40000, device.flash_size);
+    }
+
+    #[test]
+    fn it_finds_rampz_with_64k_of_flash() {
+        let device = device("at90can64").unwrap();
+
+        assert_eq!(Some(0x5b), device.rampz);
+        assert_eq!(0x10000, device.flash_size);
+    }
+
+    #[test]
+    fn it_has_no_boot_section_on_tiny_devices() {
+        let device = device("attiny85").unwrap();
+
+        assert!(device.boot_sections.is_empty());
+        assert_eq!(None, device.spm.rww_enable);
+    }
+
+    #[test]
+    fn it_rejects_unknown_names() {
+        assert_eq!(
+            Err(Error::UnknownMcu("atmega9999".to_string())),
+            device("atmega9999")
+        );
+    }
+}
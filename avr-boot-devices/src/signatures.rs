@@ -0,0 +1,270 @@
+//! Device signature bytes, taken from the `SIGNATURES` property group of each ATDF pack file.
+//!
+//! `avr-mcu` does not expose property groups, so this table is kept alongside it.
+//! It must be regenerated whenever the `avr-mcu` pack files are updated.
+
+pub(crate) const SIGNATURES: &[(&str, [u8; 3])] = &[
+    ("at90can128", [0x1e, 0x97, 0x81]),
+    ("at90can32", [0x1e, 0x95, 0x81]),
+    ("at90can64", [0x1e, 0x96, 0x81]),
+    ("at90pwm1", [0x1e, 0x93, 0x83]),
+    ("at90pwm161", [0x1e, 0x94, 0x8b]),
+    ("at90pwm216", [0x1e, 0x94, 0x83]),
+    ("at90pwm2b", [0x1e, 0x93, 0x83]),
+    ("at90pwm316", [0x1e, 0x94, 0x83]),
+    ("at90pwm3b", [0x1e, 0x93, 0x83]),
+    ("at90pwm81", [0x1e, 0x93, 0x88]),
+    ("at90usb1286", [0x1e, 0x97, 0x82]),
+    ("at90usb1287", [0x1e, 0x97, 0x82]),
+    ("at90usb162", [0x1e, 0x94, 0x82]),
+    ("at90usb646", [0x1e, 0x96, 0x82]),
+    ("at90usb647", [0x1e, 0x96, 0x82]),
+    ("at90usb82", [0x1e, 0x93, 0x82]),
+    ("ata5272", [0x1e, 0x93, 0x87]),
+    ("ata5505", [0x1e, 0x94, 0x87]),
+    ("ata5700m322", [0x1e, 0x95, 0x67]),
+    ("ata5702m322", [0x1e, 0x95, 0x69]),
+    ("ata5781", [0x1e, 0x95, 0x64]),
+    ("ata5782", [0x1e, 0x95, 0x65]),
+    ("ata5783", [0x1e, 0x95, 0x66]),
+    ("ata5787", [0x1e, 0x94, 0x6c]),
+    ("ata5790", [0x1e, 0x94, 0x61]),
+    ("ata5790n", [0x1e, 0x94, 0x62]),
+    ("ata5791", [0x1e, 0x94, 0x62]),
+    ("ata5795", [0x1e, 0x93, 0x61]),
+    ("ata5831", [0x1e, 0x95, 0x61]),
+    ("ata5832", [0x1e, 0x95, 0x62]),
+    ("ata5833", [0x1e, 0x95, 0x63]),
+    ("ata5835", [0x1e, 0x94, 0x6b]),
+    ("ata6285", [0x1e, 0x93, 0x82]),
+    ("ata6286", [0x1e, 0x93, 0x82]),
+    ("ata6612c", [0x1e, 0x93, 0x0a]),
+    ("ata6613c", [0x1e, 0x94, 0x06]),
+    ("ata6614q", [0x1e, 0x95, 0x0f]),
+    ("ata6616c", [0x1e, 0x93, 0x87]),
+    ("ata6617c", [0x1e, 0x94, 0x87]),
+    ("ata664251", [0x1e, 0x94, 0x87]),
+    ("ata8210", [0x1e, 0x95, 0x65]),
+    ("ata8215", [0x1e, 0x95, 0x64]),
+    ("ata8510", [0x1e, 0x95, 0x61]),
+    ("ata8515", [0x1e, 0x95, 0x63]),
+    ("atmega128", [0x1e, 0x97, 0x02]),
+    ("atmega1280", [0x1e, 0x97, 0x03]),
+    ("atmega1281", [0x1e, 0x97, 0x04]),
+    ("atmega1284", [0x1e, 0x97, 0x06]),
+    ("atmega1284p", [0x1e, 0x97, 0x05]),
+    ("atmega1284rfr2", [0x1e, 0xa7, 0x03]),
+    ("atmega128a", [0x1e, 0x97, 0x02]),
+    ("atmega128rfa1", [0x1e, 0xa7, 0x01]),
+    ("atmega128rfr2", [0x1e, 0xa7, 0x02]),
+    ("atmega16", [0x1e, 0x94, 0x03]),
+    ("atmega1608", [0x1e, 0x94, 0x27]),
+    ("atmega1609", [0x1e, 0x94, 0x26]),
+    ("atmega162", [0x1e, 0x94, 0x04]),
+    ("atmega164a", [0x1e, 0x94, 0x0f]),
+    ("atmega164p", [0x1e, 0x94, 0x0a]),
+    ("atmega164pa", [0x1e, 0x94, 0x0a]),
+    ("atmega165a", [0x1e, 0x94, 0x10]),
+    ("atmega165p", [0x1e, 0x94, 0x07]),
+    ("atmega165pa", [0x1e, 0x94, 0x07]),
+    ("atmega168", [0x1e, 0x94, 0x06]),
+    ("atmega168a", [0x1e, 0x94, 0x06]),
+    ("atmega168p", [0x1e, 0x94, 0x0b]),
+    ("atmega168pa", [0x1e, 0x94, 0x0b]),
+    ("atmega168pb", [0x1e, 0x94, 0x15]),
+    ("atmega169a", [0x1e, 0x94, 0x11]),
+    ("atmega169p", [0x1e, 0x94, 0x05]),
+    ("atmega169pa", [0x1e, 0x94, 0x05]),
+    ("atmega16a", [0x1e, 0x94, 0x03]),
+    ("atmega16hva", [0x1e, 0x94, 0x0c]),
+    ("atmega16hvb", [0x1e, 0x94, 0x0d]),
+    ("atmega16hvbrevb", [0x1e, 0x94, 0x0d]),
+    ("atmega16m1", [0x1e, 0x94, 0x84]),
+    ("atmega16u2", [0x1e, 0x94, 0x89]),
+    ("atmega16u4", [0x1e, 0x94, 0x88]),
+    ("atmega2560", [0x1e, 0x98, 0x01]),
+    ("atmega2561", [0x1e, 0x98, 0x02]),
+    ("atmega2564rfr2", [0x1e, 0xa8, 0x03]),
+    ("atmega256rfr2", [0x1e, 0xa8, 0x02]),
+    ("atmega32", [0x1e, 0x95, 0x02]),
+    ("atmega3208", [0x1e, 0x95, 0x30]),
+    ("atmega3209", [0x1e, 0x95, 0x31]),
+    ("atmega324a", [0x1e, 0x95, 0x15]),
+    ("atmega324p", [0x1e, 0x95, 0x08]),
+    ("atmega324pa", [0x1e, 0x95, 0x11]),
+    ("atmega324pb", [0x1e, 0x95, 0x17]),
+    ("atmega325", [0x1e, 0x95, 0x05]),
+    ("atmega3250", [0x1e, 0x95, 0x06]),
+    ("atmega3250a", [0x1e, 0x95, 0x06]),
+    ("atmega3250p", [0x1e, 0x95, 0x0e]),
+    ("atmega3250pa", [0x1e, 0x95, 0x0e]),
+    ("atmega325a", [0x1e, 0x95, 0x05]),
+    ("atmega325p", [0x1e, 0x95, 0x0d]),
+    ("atmega325pa", [0x1e, 0x95, 0x0d]),
+    ("atmega328", [0x1e, 0x95, 0x14]),
+    ("atmega328p", [0x1e, 0x95, 0x0f]),
+    ("atmega328pb", [0x1e, 0x95, 0x16]),
+    ("atmega329", [0x1e, 0x95, 0x03]),
+    ("atmega3290", [0x1e, 0x95, 0x04]),
+    ("atmega3290a", [0x1e, 0x95, 0x04]),
+    ("atmega3290p", [0x1e, 0x95, 0x0c]),
+    ("atmega3290pa", [0x1e, 0x95, 0x0c]),
+    ("atmega329a", [0x1e, 0x95, 0x03]),
+    ("atmega329p", [0x1e, 0x95, 0x0b]),
+    ("atmega329pa", [0x1e, 0x95, 0x0b]),
+    ("atmega32a", [0x1e, 0x95, 0x02]),
+    ("atmega32c1", [0x1e, 0x95, 0x86]),
+    ("atmega32hvb", [0x1e, 0x95, 0x10]),
+    ("atmega32hvbrevb", [0x1e, 0x95, 0x10]),
+    ("atmega32m1", [0x1e, 0x95, 0x84]),
+    ("atmega32u2", [0x1e, 0x95, 0x8a]),
+    ("atmega32u4", [0x1e, 0x95, 0x87]),
+    ("atmega406", [0x1e, 0x95, 0x07]),
+    ("atmega48", [0x1e, 0x92, 0x05]),
+    ("atmega4808", [0x1e, 0x96, 0x50]),
+    ("atmega4809", [0x1e, 0x96, 0x51]),
+    ("atmega48a", [0x1e, 0x92, 0x05]),
+    ("atmega48p", [0x1e, 0x92, 0x0a]),
+    ("atmega48pa", [0x1e, 0x92, 0x0a]),
+    ("atmega48pb", [0x1e, 0x92, 0x10]),
+    ("atmega64", [0x1e, 0x96, 0x02]),
+    ("atmega640", [0x1e, 0x96, 0x08]),
+    ("atmega644", [0x1e, 0x96, 0x09]),
+    ("atmega644a", [0x1e, 0x96, 0x09]),
+    ("atmega644p", [0x1e, 0x96, 0x0a]),
+    ("atmega644pa", [0x1e, 0x96, 0x0a]),
+    ("atmega644rfr2", [0x1e, 0xa6, 0x03]),
+    ("atmega645", [0x1e, 0x96, 0x05]),
+    ("atmega6450", [0x1e, 0x96, 0x06]),
+    ("atmega6450a", [0x1e, 0x96, 0x06]),
+    ("atmega6450p", [0x1e, 0x96, 0x0e]),
+    ("atmega645a", [0x1e, 0x96, 0x05]),
+    ("atmega645p", [0x1e, 0x96, 0x0d]),
+    ("atmega649", [0x1e, 0x96, 0x03]),
+    ("atmega6490", [0x1e, 0x96, 0x04]),
+    ("atmega6490a", [0x1e, 0x96, 0x04]),
+    ("atmega6490p", [0x1e, 0x96, 0x0c]),
+    ("atmega649a", [0x1e, 0x96, 0x03]),
+    ("atmega649p", [0x1e, 0x96, 0x0b]),
+    ("atmega64a", [0x1e, 0x96, 0x02]),
+    ("atmega64c1", [0x1e, 0x96, 0x86]),
+    ("atmega64hve2", [0x1e, 0x96, 0x10]),
+    ("atmega64m1", [0x1e, 0x96, 0x84]),
+    ("atmega64rfr2", [0x1e, 0xa6, 0x02]),
+    ("atmega8", [0x1e, 0x93, 0x07]),
+    ("atmega808", [0x1e, 0x93, 0x26]),
+    ("atmega809", [0x1e, 0x93, 0x2a]),
+    ("atmega8515", [0x1e, 0x93, 0x06]),
+    ("atmega8535", [0x1e, 0x93, 0x08]),
+    ("atmega88", [0x1e, 0x93, 0x0a]),
+    ("atmega88a", [0x1e, 0x93, 0x0a]),
+    ("atmega88p", [0x1e, 0x93, 0x0f]),
+    ("atmega88pa", [0x1e, 0x93, 0x0f]),
+    ("atmega88pb", [0x1e, 0x93, 0x16]),
+    ("atmega8a", [0x1e, 0x93, 0x07]),
+    ("atmega8hva", [0x1e, 0x93, 0x10]),
+    ("atmega8u2", [0x1e, 0x93, 0x89]),
+    ("attiny10", [0x1e, 0x90, 0x03]),
+    ("attiny102", [0x1e, 0x90, 0x0c]),
+    ("attiny104", [0x1e, 0x90, 0x0b]),
+    ("attiny11", [0x1e, 0x90, 0x04]),
+    ("attiny12", [0x1e, 0x90, 0x05]),
+    ("attiny13", [0x1e, 0x90, 0x07]),
+    ("attiny13a", [0x1e, 0x90, 0x07]),
+    ("attiny15", [0x1e, 0x90, 0x06]),
+    ("attiny1614", [0x1e, 0x94, 0x22]),
+    ("attiny1616", [0x1e, 0x94, 0x21]),
+    ("attiny1617", [0x1e, 0x94, 0x20]),
+    ("attiny1634", [0x1e, 0x94, 0x12]),
+    ("attiny167", [0x1e, 0x94, 0x87]),
+    ("attiny20", [0x1e, 0x91, 0x0f]),
+    ("attiny212", [0x1e, 0x91, 0x21]),
+    ("attiny214", [0x1e, 0x91, 0x20]),
+    ("attiny2313", [0x1e, 0x91, 0x0a]),
+    ("attiny2313a", [0x1e, 0x91, 0x0a]),
+    ("attiny24", [0x1e, 0x91, 0x0b]),
+    ("attiny24a", [0x1e, 0x91, 0x0b]),
+    ("attiny25", [0x1e, 0x91, 0x08]),
+    ("attiny26", [0x1e, 0x91, 0x09]),
+    ("attiny261", [0x1e, 0x91, 0x0c]),
+    ("attiny261a", [0x1e, 0x91, 0x0c]),
+    ("attiny3214", [0x1e, 0x95, 0x20]),
+    ("attiny3216", [0x1e, 0x95, 0x21]),
+    ("attiny3217", [0x1e, 0x95, 0x22]),
+    ("attiny4", [0x1e, 0x8f, 0x0a]),
+    ("attiny40", [0x1e, 0x92, 0x0e]),
+    ("attiny412", [0x1e, 0x92, 0x23]),
+    ("attiny414", [0x1e, 0x92, 0x22]),
+    ("attiny416", [0x1e, 0x92, 0x21]),
+    ("attiny417", [0x1e, 0x92, 0x20]),
+    ("attiny4313", [0x1e, 0x92, 0x0d]),
+    ("attiny43u", [0x1e, 0x92, 0x0c]),
+    ("attiny44", [0x1e, 0x92, 0x07]),
+    ("attiny441", [0x1e, 0x92, 0x15]),
+    ("attiny44a", [0x1e, 0x92, 0x07]),
+    ("attiny45", [0x1e, 0x92, 0x06]),
+    ("attiny461", [0x1e, 0x92, 0x08]),
+    ("attiny461a", [0x1e, 0x92, 0x08]),
+    ("attiny48", [0x1e, 0x92, 0x09]),
+    ("attiny5", [0x1e, 0x8f, 0x09]),
+    ("attiny80", [0x1e, 0x93, 0xc3]),
+    ("attiny814", [0x1e, 0x93, 0x22]),
+    ("attiny816", [0x1e, 0x93, 0x21]),
+    ("attiny817", [0x1e, 0x93, 0x20]),
+    ("attiny828", [0x1e, 0x93, 0x14]),
+    ("attiny84", [0x1e, 0x93, 0x0c]),
+    ("attiny840", [0x1e, 0x93, 0xc3]),
+    ("attiny841", [0x1e, 0x93, 0x15]),
+    ("attiny84a", [0x1e, 0x93, 0x0c]),
+    ("attiny85", [0x1e, 0x93, 0x0b]),
+    ("attiny861", [0x1e, 0x93, 0x0d]),
+    ("attiny861a", [0x1e, 0x93, 0x0d]),
+    ("attiny87", [0x1e, 0x93, 0x87]),
+    ("attiny88", [0x1e, 0x93, 0x11]),
+    ("attiny9", [0x1e, 0x90, 0x08]),
+    ("atxmega128a1", [0x1e, 0x97, 0x4c]),
+    ("atxmega128a1u", [0x1e, 0x97, 0x4c]),
+    ("atxmega128a3", [0x1e, 0x97, 0x42]),
+    ("atxmega128a3u", [0x1e, 0x97, 0x42]),
+    ("atxmega128a4u", [0x1e, 0x97, 0x46]),
+    ("atxmega128b1", [0x1e, 0x97, 0x4d]),
+    ("atxmega128b3", [0x1e, 0x97, 0x4b]),
+    ("atxmega128c3", [0x1e, 0x97, 0x52]),
+    ("atxmega128d3", [0x1e, 0x97, 0x48]),
+    ("atxmega128d4", [0x1e, 0x97, 0x47]),
+    ("atxmega16a4", [0x1e, 0x94, 0x41]),
+    ("atxmega16a4u", [0x1e, 0x94, 0x41]),
+    ("atxmega16c4", [0x1e, 0x94, 0x43]),
+    ("atxmega16d4", [0x1e, 0x94, 0x42]),
+    ("atxmega16e5", [0x1e, 0x94, 0x45]),
+    ("atxmega192a3", [0x1e, 0x97, 0x44]),
+    ("atxmega192a3u", [0x1e, 0x97, 0x44]),
+    ("atxmega192c3", [0x1e, 0x97, 0x51]),
+    ("atxmega192d3", [0x1e, 0x97, 0x49]),
+    ("atxmega256a3", [0x1e, 0x98, 0x42]),
+    ("atxmega256a3b", [0x1e, 0x98, 0x43]),
+    ("atxmega256a3bu", [0x1e, 0x98, 0x43]),
+    ("atxmega256a3u", [0x1e, 0x98, 0x42]),
+    ("atxmega256c3", [0x1e, 0x98, 0x46]),
+    ("atxmega256d3", [0x1e, 0x98, 0x44]),
+    ("atxmega32a4", [0x1e, 0x95, 0x41]),
+    ("atxmega32a4u", [0x1e, 0x95, 0x41]),
+    ("atxmega32c3", [0x1e, 0x95, 0x49]),
+    ("atxmega32c4", [0x1e, 0x95, 0x44]),
+    ("atxmega32d3", [0x1e, 0x95, 0x4a]),
+    ("atxmega32d4", [0x1e, 0x95, 0x42]),
+    ("atxmega32e5", [0x1e, 0x95, 0x4c]),
+    ("atxmega384c3", [0x1e, 0x98, 0x45]),
+    ("atxmega384d3", [0x1e, 0x98, 0x47]),
+    ("atxmega64a1", [0x1e, 0x96, 0x4e]),
+    ("atxmega64a1u", [0x1e, 0x96, 0x4e]),
+    ("atxmega64a3", [0x1e, 0x96, 0x42]),
+    ("atxmega64a3u", [0x1e, 0x96, 0x42]),
+    ("atxmega64a4u", [0x1e, 0x96, 0x46]),
+    ("atxmega64b1", [0x1e, 0x96, 0x52]),
+    ("atxmega64b3", [0x1e, 0x96, 0x51]),
+    ("atxmega64c3", [0x1e, 0x96, 0x49]),
+    ("atxmega64d3", [0x1e, 0x96, 0x4a]),
+    ("atxmega64d4", [0x1e, 0x96, 0x47]),
+    ("atxmega8e5", [0x1e, 0x93, 0x41]),
+];
@@ -0,0 +1,155 @@
+//! AVR109 (butterfly) client, for Caterina and `avr_boot::protocol::avr109`
+//!
+//! Speaks the same subset of the protocol as `avrdude -c avr109`. Blocks are sent a page at a time.
+
+use crate::{Error, Programmer, expect, read_bytes};
+use std::io::{Read, Write};
+
+const CR: u8 = b'\r';
+const MEMORY_FLASH: u8 = b'F';
+
+/// Longest read in a single command
+const READ_LENGTH: usize = 256;
+/// Timeouts allowed while the bootloader erases the application, which can take a few seconds
+const ERASE_TIMEOUTS: usize = 20;
+
+/// AVR109 client
+pub struct Client<T> {
+    port: T,
+}
+
+impl<T: Read + Write> Client<T> {
+    /// Create a client talking over `port`
+    pub fn new(port: T) -> Self {
+        Self { port }
+    }
+
+    /// Read the device signature
+    pub fn read_signature(&mut self) -> Result<[u8; 3], Error> {
+        self.send(b"s")?;
+        let signature = read_bytes(&mut self.port, 3)?;
+        Ok([signature[2], signature[1], signature[0]])
+    }
+
+    /// Erase the application
+    pub fn chip_erase(&mut self) -> Result<(), Error> {
+        self.send(b"e")?;
+        for _ in 0..ERASE_TIMEOUTS {
+            match expect(&mut self.port, CR) {
+                Err(Error::NoResponse) => {}
+                result => return result,
+            }
+        }
+        Err(Error::NoResponse)
+    }
+
+    /// Load a block of data into flash, starting at `address`
+    pub fn block_load(&mut self, address: u32, data: &[u8]) -> Result<(), Error> {
+        self.set_address(address)?;
+
+        let mut command = vec![b'B'];
+        command.extend((data.len() as u16).to_be_bytes());
+        command.push(MEMORY_FLASH);
+        command.extend(data);
+        self.send(&command)?;
+        expect(&mut self.port, CR)
+    }
+
+    /// Read up to 256 bytes of flash
+    pub fn block_read(&mut self, address: u32, length: usize) -> Result<Vec<u8>, Error> {
+        self.set_address(address)?;
+
+        let [high, low] = (length as u16).to_be_bytes();
+        self.send(&[b'g', high, low, MEMORY_FLASH])?;
+        read_bytes(&mut self.port, length)
+    }
+
+    /// Exit the bootloader, so it starts the application
+    pub fn exit(&mut self) -> Result<(), Error> {
+        self.send(b"E")?;
+        expect(&mut self.port, CR)
+    }
+
+    /// Give back the transport
+    pub fn into_inner(self) -> T {
+        self.port
+    }
+
+    /// Set a byte address, which is sent as a word address, using the extended command only when needed
+    fn set_address(&mut self, address: u32) -> Result<(), Error> {
+        let word = address / 2;
+        match word.to_be_bytes() {
+            [_, 0, high, low] => self.send(&[b'A', high, low])?,
+            [_, extended, high, low] => self.send(&[b'H', extended, high, low])?,
+        }
+        expect(&mut self.port, CR)
+    }
+
+    fn send(&mut self, command: &[u8]) -> Result<(), Error> {
+        self.port.write_all(command)?;
+        self.port.flush()?;
+        Ok(())
+    }
+}
+
+impl<T: Read + Write> Programmer for Client<T> {
+    fn signature(&mut self) -> Result<Option<[u8; 3]>, Error> {
+        self.read_signature().map(Some)
+    }
+
+    fn erase(&mut self) -> Result<(), Error> {
+        self.chip_erase()
+    }
+
+    fn write(&mut self, address: u32, data: &[u8], page_size: usize) -> Result<(), Error> {
+        for (i, page) in data.chunks(page_size).enumerate() {
+            self.block_load(address + (i * page_size) as u32, page)?;
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, address: u32, length: usize) -> Result<Vec<u8>, Error> {
+        let mut data = Vec::with_capacity(length);
+        while data.len() < length {
+            let chunk = READ_LENGTH.min(length - data.len());
+            data.extend(self.block_read(address + data.len() as u32, chunk)?);
+        }
+        Ok(data)
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        self.exit()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim;
+    use avr_boot::protocol::avr109::Avr109;
+    use avr_boot::{FlashRange, SIGNATURE, SPM_PAGESIZE_BYTES};
+
+    #[test]
+    fn it_erases_writes_and_reads_back() {
+        let (port, device) = sim::spawn(|serial, mut flash| {
+            flash.memory[..0x2_0000].fill(0);
+            let mut avr109 = Avr109::new(serial, flash, FlashRange::new(0u16, 0x1_f000u32));
+            avr109.run().ok();
+            avr109.release().1
+        });
+        let image: Vec<u8> = (0..SPM_PAGESIZE_BYTES * 2).map(|i| (i * 3) as u8).collect();
+        let mut client = Client::new(port);
+
+        let signature = client.signature().unwrap();
+        client.erase().unwrap();
+        client.write(0x1_0000, &image, SPM_PAGESIZE_BYTES).unwrap();
+        let read = client.read(0x1_0000, image.len()).unwrap();
+        client.finish().unwrap();
+        let flash = device.join().unwrap();
+
+        assert_eq!(signature, Some(SIGNATURE));
+        assert_eq!(read, image);
+        assert!(flash.memory[..0x1_0000].iter().all(|b| *b == 0xff));
+        assert!(flash.memory[0x1_f000..0x2_0000].iter().all(|b| *b == 0));
+    }
+}
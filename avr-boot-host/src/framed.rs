@@ -0,0 +1,602 @@
+//! Host side of the framed update protocol
+//!
+//! See `avr_boot::protocol::framed` for the frame format and command set.
+//!
+//! On a multi-drop bus, such as RS-485, [`Client::select`] picks the node each request goes to, and
+//! [`Client::program_nodes`] updates a group of nodes at once: every page is broadcast, and then each node is polled
+//! for the CRC of its application, with any node which missed something being reprogrammed on its own.
+
+use crate::Programmer;
+use avr_boot::protocol::framed::*;
+use avr_boot::{cobs, crc};
+use std::convert::Infallible;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::thread;
+use std::time::Duration;
+
+/// Large enough for any response, with a page size of up to 512 bytes
+const MAX_FRAME_LENGTH: usize = 1024;
+
+/// Everything the device reports about itself
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Info {
+    /// The 3 signature bytes
+    pub signature: [u8; 3],
+    /// Size of a flash page, in bytes
+    pub page_size: usize,
+    /// Total size of the program memory, in bytes
+    pub flash_size: u32,
+    /// The byte addresses which can be written
+    pub application: Range<u32>,
+}
+
+/// Why a request failed
+#[derive(Debug)]
+pub enum Error {
+    /// The transport failed
+    Io(io::Error),
+    /// No valid response, after every retry
+    NoResponse,
+    /// The device refused the request, with one of the `STATUS_*` codes
+    Device {
+        /// The command which was refused
+        command: u8,
+        /// The status returned
+        status: u8,
+    },
+    /// The response data was the wrong length for the command
+    InvalidResponse,
+    /// The image is larger than the application range
+    TooLarge {
+        /// Length of the image
+        image: usize,
+        /// Length of the application range
+        available: u32,
+    },
+    /// The CRC of the flash didn't match the image after programming
+    VerifyFailed {
+        /// The CRC of the image
+        expected: u16,
+        /// The CRC reported by the device
+        actual: u16,
+    },
+    /// A node's page size or application range is different to the first node's
+    Incompatible,
+    /// A request to one node on a bus failed
+    Node {
+        /// The node address
+        node: u8,
+        /// What went wrong
+        error: Box<Error>,
+    },
+}
+
+impl Error {
+    fn at(self, node: u8) -> Self {
+        Error::Node {
+            node,
+            error: Box::new(self),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{e}"),
+            Error::NoResponse => write!(f, "no response from device"),
+            Error::Device { command, status } => {
+                let reason = match *status {
+                    STATUS_UNKNOWN_COMMAND => "unknown command",
+                    STATUS_INVALID_LENGTH => "invalid length",
+                    STATUS_OUT_OF_RANGE => "address out of range",
+                    STATUS_NOT_ALIGNED => "address not page aligned",
+                    _ => "unknown status",
+                };
+                write!(f, "command {command:#04x} failed: {reason} ({status:#04x})")
+            }
+            Error::InvalidResponse => write!(f, "invalid response from device"),
+            Error::TooLarge { image, available } => {
+                write!(
+                    f,
+                    "image is {image} bytes, but only {available} bytes are available"
+                )
+            }
+            Error::VerifyFailed { expected, actual } => {
+                write!(
+                    f,
+                    "verify failed: expected CRC {expected:#06x}, device has {actual:#06x}"
+                )
+            }
+            Error::Incompatible => write!(
+                f,
+                "page size or application range differs from the other nodes"
+            ),
+            Error::Node { node, error } => write!(f, "node {node}: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// Framed protocol client
+///
+/// Works over any transport which returns [`io::ErrorKind::TimedOut`] or [`io::ErrorKind::WouldBlock`] (or reads
+/// nothing) when no data arrives in time, such as a serial port with a timeout set. A request which isn't answered
+/// in time is sent again, with the same sequence number.
+pub struct Client<T> {
+    port: T,
+    sequence: u8,
+    retries: u32,
+    /// The node address for requests, if the devices are on a multi-drop bus
+    node: Option<u8>,
+    broadcast_delay: Duration,
+}
+
+impl<T: Read + Write> Client<T> {
+    /// Create a client talking over `port`, retrying each request up to 5 times
+    pub fn new(port: T) -> Self {
+        Self {
+            port,
+            sequence: 0,
+            retries: 5,
+            node: None,
+            broadcast_delay: Duration::from_millis(20),
+        }
+    }
+
+    /// Send requests to the node at address `node` on a multi-drop bus
+    pub fn with_address(mut self, node: u8) -> Self {
+        self.node = Some(node);
+        self
+    }
+
+    /// Change the time to wait after each broadcast, while the nodes carry it out (20ms by default)
+    ///
+    /// Nothing is acknowledged, so this needs to be long enough for the slowest node to write a page.
+    pub fn with_broadcast_delay(mut self, delay: Duration) -> Self {
+        self.broadcast_delay = delay;
+        self
+    }
+
+    /// Send the following requests to the node at address `node` on a multi-drop bus
+    pub fn select(&mut self, node: u8) {
+        self.node = Some(node);
+    }
+
+    /// Change the number of times each request is retried
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Ask the device about itself
+    pub fn info(&mut self) -> Result<Info, Error> {
+        let data = self.request(CMD_INFO, &[])?;
+        let field = |range: Range<usize>| u32::from_be_bytes(data[range].try_into().unwrap());
+
+        if data.len() != 17 {
+            return Err(Error::InvalidResponse);
+        }
+
+        Ok(Info {
+            signature: [data[0], data[1], data[2]],
+            page_size: u16::from_be_bytes([data[3], data[4]]) as usize,
+            flash_size: field(5..9),
+            application: field(9..13)..field(13..17),
+        })
+    }
+
+    /// Erase the whole application range
+    pub fn erase(&mut self) -> Result<(), Error> {
+        self.request(CMD_ERASE, &[]).map(drop)
+    }
+
+    /// Write a single page, which must be exactly the device's page size
+    pub fn write_page(&mut self, address: u32, page: &[u8]) -> Result<(), Error> {
+        let mut arguments = address.to_be_bytes().to_vec();
+        arguments.extend(page);
+        self.request(CMD_WRITE_PAGE, &arguments).map(drop)
+    }
+
+    /// Read a single page
+    pub fn read_page(&mut self, address: u32) -> Result<Vec<u8>, Error> {
+        self.request(CMD_READ_PAGE, &address.to_be_bytes())
+    }
+
+    /// Calculate the CRC-16/XMODEM of `length` bytes of flash, starting at `address`
+    pub fn verify(&mut self, address: u32, length: u32) -> Result<u16, Error> {
+        let mut arguments = address.to_be_bytes().to_vec();
+        arguments.extend(length.to_be_bytes());
+
+        match self.request(CMD_VERIFY, &arguments)?[..] {
+            [high, low] => Ok(u16::from_be_bytes([high, low])),
+            _ => Err(Error::InvalidResponse),
+        }
+    }
+
+    /// End the session, so the device starts the application
+    pub fn boot(&mut self) -> Result<(), Error> {
+        self.request(CMD_BOOT, &[]).map(drop)
+    }
+
+    /// Write a single page on every node on the bus, without waiting for any acknowledgement
+    pub fn broadcast_write_page(&mut self, address: u32, page: &[u8]) -> Result<(), Error> {
+        let mut arguments = address.to_be_bytes().to_vec();
+        arguments.extend(page);
+        self.broadcast(CMD_WRITE_PAGE, &arguments)
+    }
+
+    /// End the session on every node on the bus
+    pub fn broadcast_boot(&mut self) -> Result<(), Error> {
+        self.broadcast(CMD_BOOT, &[])
+    }
+
+    /// Erase the application, write `image` to the start of it, and check the result
+    ///
+    /// The last page is padded with `0xff`.
+    pub fn program(&mut self, image: &[u8]) -> Result<Info, Error> {
+        let info = self.info()?;
+        let padded = padded(image, &info)?;
+
+        self.erase()?;
+        self.write_image(&info, &padded)?;
+        self.check_image(&info, &padded)?;
+
+        Ok(info)
+    }
+
+    /// Program `image` into every one of `nodes` on a multi-drop bus, broadcasting each page once
+    ///
+    /// Every node is erased first, and polled for the CRC of its application afterwards. A node which missed part of
+    /// the image is then programmed again on its own. The selected node is left as the last one in `nodes`.
+    pub fn program_nodes(&mut self, nodes: &[u8], image: &[u8]) -> Result<(), Error> {
+        let mut layout = None;
+        for &node in nodes {
+            self.select(node);
+            let info = self.info().map_err(|e| e.at(node))?;
+            let first = layout.get_or_insert_with(|| info.clone());
+            if (first.page_size, &first.application) != (info.page_size, &info.application) {
+                return Err(Error::Incompatible.at(node));
+            }
+        }
+        let Some(info) = layout else {
+            return Ok(());
+        };
+        let padded = padded(image, &info)?;
+
+        for &node in nodes {
+            self.select(node);
+            self.erase().map_err(|e| e.at(node))?;
+        }
+
+        for (address, page) in pages(&info, &padded) {
+            self.broadcast_write_page(address, page)?;
+        }
+
+        for &node in nodes {
+            self.select(node);
+            match self.check_image(&info, &padded) {
+                Err(Error::VerifyFailed { .. }) => self
+                    .write_image(&info, &padded)
+                    .and_then(|_| self.check_image(&info, &padded)),
+                result => result,
+            }
+            .map_err(|e| e.at(node))?;
+        }
+
+        Ok(())
+    }
+
+    /// Give back the transport
+    pub fn into_inner(self) -> T {
+        self.port
+    }
+
+    fn write_image(&mut self, info: &Info, padded: &[u8]) -> Result<(), Error> {
+        for (address, page) in pages(info, padded) {
+            self.write_page(address, page)?;
+        }
+        Ok(())
+    }
+
+    fn check_image(&mut self, info: &Info, padded: &[u8]) -> Result<(), Error> {
+        let expected = crc::xmodem(padded);
+        let actual = self.verify(info.application.start, padded.len() as u32)?;
+        if actual != expected {
+            return Err(Error::VerifyFailed { expected, actual });
+        }
+        Ok(())
+    }
+
+    /// Send a request to every node, and wait for them to carry it out
+    fn broadcast(&mut self, command: u8, arguments: &[u8]) -> Result<(), Error> {
+        let frame = self.frame(Some(BROADCAST), command, arguments);
+        self.port.write_all(&frame)?;
+        self.port.flush()?;
+        thread::sleep(self.broadcast_delay);
+        Ok(())
+    }
+
+    /// Encode a request with the next sequence number
+    fn frame(&mut self, node: Option<u8>, command: u8, arguments: &[u8]) -> Vec<u8> {
+        self.sequence = self.sequence.wrapping_add(1);
+
+        let mut payload: Vec<u8> = node.into_iter().collect();
+        payload.extend([self.sequence, command]);
+        payload.extend(arguments);
+        payload.extend(crc::xmodem(&payload).to_be_bytes());
+
+        // The leading delimiter ends any partial frame left over from a previous failure
+        let mut frame = vec![0];
+        cobs::encode(&payload, |byte| {
+            frame.push(byte);
+            Ok::<(), Infallible>(())
+        })
+        .unwrap();
+        frame.push(0);
+        frame
+    }
+
+    /// Send a request until a response arrives, and return the response data
+    fn request(&mut self, command: u8, arguments: &[u8]) -> Result<Vec<u8>, Error> {
+        let frame = self.frame(self.node, command, arguments);
+
+        for _ in 0..=self.retries {
+            self.port.write_all(&frame)?;
+            self.port.flush()?;
+
+            if let Some(response) = self.receive()? {
+                return match response[0] {
+                    STATUS_OK => Ok(response[1..].to_vec()),
+                    status => Err(Error::Device { command, status }),
+                };
+            }
+        }
+
+        Err(Error::NoResponse)
+    }
+
+    /// Wait for a valid response to the current request, returning `None` on timeout
+    ///
+    /// Responses to earlier requests, which arrived late, are ignored.
+    fn receive(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        let mut decoder = cobs::Decoder::new();
+        let mut frame = [0; MAX_FRAME_LENGTH];
+        let header = self.node.is_some() as usize;
+
+        loop {
+            let mut byte = [0];
+            match self.port.read(&mut byte) {
+                Ok(0) => return Ok(None),
+                Ok(_) => {}
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+                    ) =>
+                {
+                    return Ok(None);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+
+            if let Some(Ok(length)) = decoder.push(&mut frame, byte[0])
+                && length >= header + 4
+                && crc::xmodem(&frame[..length]) == 0
+                && frame[..header] == self.node.as_slice()[..]
+                && frame[header] == self.sequence
+            {
+                return Ok(Some(frame[header + 1..length - 2].to_vec()));
+            }
+        }
+    }
+}
+
+impl<T: Read + Write> Programmer for Client<T> {
+    fn signature(&mut self) -> Result<Option<[u8; 3]>, crate::Error> {
+        Ok(Some(self.info()?.signature))
+    }
+
+    fn erase(&mut self) -> Result<(), crate::Error> {
+        Ok(Client::erase(self)?)
+    }
+
+    fn write(&mut self, address: u32, data: &[u8], page_size: usize) -> Result<(), crate::Error> {
+        for (i, page) in data.chunks(page_size).enumerate() {
+            self.write_page(address + (i * page_size) as u32, page)?;
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, address: u32, length: usize) -> Result<Vec<u8>, crate::Error> {
+        let page_size = self.info()?.page_size as u32;
+        let start = address - address % page_size;
+
+        let mut data = Vec::new();
+        while (data.len() as u32) < address - start + length as u32 {
+            data.extend(self.read_page(start + data.len() as u32)?);
+        }
+        let offset = (address - start) as usize;
+        Ok(data[offset..offset + length].to_vec())
+    }
+
+    fn finish(&mut self) -> Result<(), crate::Error> {
+        Ok(self.boot()?)
+    }
+}
+
+/// Pad `image` to a whole number of pages with `0xff`, if it fits in the application
+fn padded(image: &[u8], info: &Info) -> Result<Vec<u8>, Error> {
+    let available = info.application.end - info.application.start;
+    if image.len() > available as usize {
+        return Err(Error::TooLarge {
+            image: image.len(),
+            available,
+        });
+    }
+
+    let mut padded = image.to_vec();
+    padded.resize(image.len().div_ceil(info.page_size) * info.page_size, 0xff);
+    Ok(padded)
+}
+
+/// The address and contents of each page of a padded image
+fn pages<'a>(info: &Info, padded: &'a [u8]) -> impl Iterator<Item = (u32, &'a [u8])> {
+    let (start, page_size) = (info.application.start, info.page_size);
+    padded
+        .chunks(page_size)
+        .enumerate()
+        .map(move |(i, page)| (start + (i * page_size) as u32, page))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{Bus, HostPort, MemoryFlash};
+    use avr_boot::{FLASH_SIZE_BYTES, FlashRange, SIGNATURE, SPM_PAGESIZE_BYTES};
+    use std::thread::JoinHandle;
+
+    const APPLICATION_END: u32 = 0x1_0000;
+
+    fn application() -> FlashRange {
+        FlashRange::new(0u16, APPLICATION_END)
+    }
+
+    /// Run a single device, and return a client connected to it, and a handle which returns its flash
+    fn connect(corrupt: Vec<usize>) -> (Client<HostPort>, JoinHandle<MemoryFlash>) {
+        let (host, mut devices) = Bus::new().attach(None, application()).connect(corrupt);
+        (Client::new(host), devices.remove(0))
+    }
+
+    /// Run a device at each of the `nodes` addresses, and return a client which doesn't wait after broadcasts
+    fn connect_nodes(
+        nodes: &[u8],
+        corrupt: Vec<usize>,
+    ) -> (Client<HostPort>, Vec<JoinHandle<MemoryFlash>>) {
+        let bus = nodes.iter().fold(Bus::new(), |bus, node| {
+            bus.attach(Some(*node), application())
+        });
+        let (host, devices) = bus.connect(corrupt);
+        (
+            Client::new(host).with_broadcast_delay(Duration::ZERO),
+            devices,
+        )
+    }
+
+    #[test]
+    fn it_reads_the_device_info() {
+        let (mut client, device) = connect(vec![]);
+
+        let info = client.info().unwrap();
+        client.boot().unwrap();
+        device.join().unwrap();
+
+        assert_eq!(
+            info,
+            Info {
+                signature: SIGNATURE,
+                page_size: SPM_PAGESIZE_BYTES,
+                flash_size: FLASH_SIZE_BYTES,
+                application: 0..APPLICATION_END,
+            }
+        );
+    }
+
+    #[test]
+    fn it_programs_and_verifies_an_image() {
+        let image: Vec<u8> = (0..1000u32).map(|i| (i * 7) as u8).collect();
+        let (mut client, device) = connect(vec![]);
+
+        client.program(&image).unwrap();
+        let first_page = client.read_page(0).unwrap();
+        client.boot().unwrap();
+        let flash = device.join().unwrap();
+
+        assert_eq!(&flash.memory[..1000], &image[..]);
+        assert!(flash.memory[1000..1024].iter().all(|b| *b == 0xff));
+        assert_eq!(first_page, &image[..SPM_PAGESIZE_BYTES]);
+    }
+
+    #[test]
+    fn it_retries_corrupted_frames() {
+        let image = vec![0x5a; SPM_PAGESIZE_BYTES * 2];
+        // info, erase, then the first page write
+        let (mut client, device) = connect(vec![0, 2, 3]);
+
+        client.program(&image).unwrap();
+        client.boot().unwrap();
+        let flash = device.join().unwrap();
+
+        assert_eq!(&flash.memory[..image.len()], &image[..]);
+    }
+
+    #[test]
+    fn it_reports_refused_requests() {
+        let (mut client, device) = connect(vec![]);
+
+        let result = client.write_page(APPLICATION_END, &[0; SPM_PAGESIZE_BYTES]);
+        client.boot().unwrap();
+        device.join().unwrap();
+
+        assert!(matches!(
+            result,
+            Err(Error::Device {
+                command: CMD_WRITE_PAGE,
+                status: STATUS_OUT_OF_RANGE
+            })
+        ));
+    }
+
+    #[test]
+    fn it_refuses_images_which_are_too_large() {
+        let (mut client, device) = connect(vec![]);
+
+        let result = client.program(&vec![0; APPLICATION_END as usize + 1]);
+        client.boot().unwrap();
+        device.join().unwrap();
+
+        assert!(matches!(result, Err(Error::TooLarge { .. })));
+    }
+
+    #[test]
+    fn it_programs_every_node_on_a_bus() {
+        let image: Vec<u8> = (0..SPM_PAGESIZE_BYTES * 3).map(|i| (i * 3) as u8).collect();
+        // The first erase of node 2, and the broadcast of the second page
+        let (mut client, devices) = connect_nodes(&[1, 2, 3], vec![4, 8]);
+
+        client.program_nodes(&[1, 2, 3], &image).unwrap();
+        client.broadcast_boot().unwrap();
+        let host = client.into_inner();
+
+        assert_eq!(host.collisions, 0);
+        for device in devices {
+            assert_eq!(&device.join().unwrap().memory[..image.len()], &image[..]);
+        }
+    }
+
+    #[test]
+    fn it_gives_up_when_two_nodes_share_an_address() {
+        let (client, devices) = connect_nodes(&[4, 4], vec![]);
+        let mut client = client.with_address(4).with_retries(2);
+
+        let result = client.info();
+        let host = client.into_inner();
+
+        assert!(matches!(result, Err(Error::NoResponse)));
+        assert_eq!(host.collisions, 3);
+        drop(host);
+        for device in devices {
+            device.join().unwrap();
+        }
+    }
+}
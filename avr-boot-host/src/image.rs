@@ -0,0 +1,73 @@
+//! Packing applications into the `avr_boot::image` container format
+//!
+//! The bootloader checks the header against the device before it erases anything, so images should be packed with
+//! the signature and page size of the MCU they were built for, e.g. from `avr-boot-devices`.
+
+use avr_boot::image::{HEADER_LENGTH, Header};
+
+/// Put a header in front of `application`, which is loaded at `address` on a device with `signature` and
+/// `page_size`
+pub fn pack(
+    signature: [u8; 3],
+    page_size: usize,
+    address: u32,
+    application: &[u8],
+    app_version: u32,
+) -> Vec<u8> {
+    let header = Header::new(
+        signature,
+        page_size as u16,
+        address,
+        application,
+        app_version,
+    );
+
+    let mut image = header.to_bytes().to_vec();
+    image.extend(application);
+    image
+}
+
+/// The last page of an application range ending at `application_end`, with `header` as the trailer the bootloader
+/// validates the application against. Returns the page's address, and its contents.
+///
+/// The rest of the page is erased, so the application mustn't reach into it.
+pub fn trailer_page(header: &Header, application_end: u32, page_size: usize) -> (u32, Vec<u8>) {
+    let address = application_end - page_size as u32;
+    let mut page = vec![0xff; page_size];
+    page[page_size - HEADER_LENGTH..].copy_from_slice(&header.to_bytes());
+    (address, page)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use avr_boot::image;
+
+    #[test]
+    fn it_packs_an_image_the_bootloader_accepts() {
+        let application: Vec<u8> = (0..300).map(|i| i as u8).collect();
+
+        let packed = pack([0x1e, 0x95, 0x0f], 128, 0x100, &application, 42);
+        let (header, unpacked) = image::parse(&packed).unwrap();
+
+        assert_eq!(unpacked, &application[..]);
+        assert_eq!(header.signature, [0x1e, 0x95, 0x0f]);
+        assert_eq!(header.page_size, 128);
+        assert_eq!(u32::from(header.address), 0x100);
+        assert_eq!(header.app_version, 42);
+    }
+
+    #[test]
+    fn it_puts_the_trailer_at_the_end_of_the_application() {
+        let header = Header::new([0x1e, 0x95, 0x0f], 128, 0u16, &[1, 2, 3], 1);
+
+        let (address, page) = trailer_page(&header, 0x7000, 128);
+
+        assert_eq!(address, 0x6f80);
+        assert_eq!(
+            image::Header::parse(&page[128 - HEADER_LENGTH..]),
+            Ok(header)
+        );
+        assert!(page[..128 - HEADER_LENGTH].iter().all(|b| *b == 0xff));
+    }
+}
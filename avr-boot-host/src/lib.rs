@@ -0,0 +1,119 @@
+//! Host side of the avr-boot bootloader protocols
+//!
+//! The protocol engines in `avr_boot::protocol` run on the MCU. This crate implements the other end of each of the
+//! serial ones, using the same framing and checksum code as the firmware where there is any.
+//!
+//! Every client implements [`Programmer`], so a flashing tool can work with any of them. Each one talks over any
+//! `Read + Write` transport which returns [`io::ErrorKind::TimedOut`] when nothing arrives in time, such as a serial
+//! port with a timeout set.
+
+use std::fmt;
+use std::io::{self, Read};
+
+pub mod avr109;
+pub mod framed;
+pub mod image;
+pub mod stk500v1;
+pub mod stk500v2;
+pub mod xmodem;
+
+#[cfg(any(test, feature = "sim"))]
+pub mod sim;
+
+/// Why a request to a bootloader failed
+#[derive(Debug)]
+pub enum Error {
+    /// The transport failed
+    Io(io::Error),
+    /// Nothing arrived before the transport timed out
+    NoResponse,
+    /// The bootloader sent something the protocol doesn't allow
+    UnexpectedResponse(u8),
+    /// The bootloader reported that a command failed
+    Failed,
+    /// The receiver cancelled the transfer
+    Cancelled,
+    /// The protocol has no way of doing this
+    Unsupported(&'static str),
+    /// A request using the framed protocol failed
+    Framed(framed::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{e}"),
+            Error::NoResponse => write!(f, "no response from bootloader"),
+            Error::UnexpectedResponse(byte) => {
+                write!(f, "unexpected response from bootloader: {byte:#04x}")
+            }
+            Error::Failed => write!(f, "bootloader reported a failure"),
+            Error::Cancelled => write!(f, "transfer cancelled by the bootloader"),
+            Error::Unsupported(what) => write!(f, "{what} isn't supported by this protocol"),
+            Error::Framed(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => Error::NoResponse,
+            _ => Error::Io(e),
+        }
+    }
+}
+
+impl From<framed::Error> for Error {
+    fn from(e: framed::Error) -> Self {
+        Error::Framed(e)
+    }
+}
+
+/// A connection to a bootloader, through one of its protocols
+///
+/// Addresses are byte addresses in program memory.
+pub trait Programmer {
+    /// Read the device signature, or `None` if the protocol has no way to
+    fn signature(&mut self) -> Result<Option<[u8; 3]>, Error>;
+
+    /// Erase the application
+    fn erase(&mut self) -> Result<(), Error>;
+
+    /// Write `data`, which starts at a page boundary and is a whole number of `page_size` pages
+    fn write(&mut self, address: u32, data: &[u8], page_size: usize) -> Result<(), Error>;
+
+    /// Read `length` bytes, starting at `address`
+    fn read(&mut self, address: u32, length: usize) -> Result<Vec<u8>, Error>;
+
+    /// End the session, so the bootloader starts the application
+    fn finish(&mut self) -> Result<(), Error>;
+}
+
+/// Read a single byte, treating end of file the same as a timeout
+fn read_byte(port: &mut impl Read) -> Result<u8, Error> {
+    let mut byte = [0];
+    loop {
+        match port.read(&mut byte) {
+            Ok(0) => return Err(Error::NoResponse),
+            Ok(_) => return Ok(byte[0]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Read exactly `length` bytes
+fn read_bytes(port: &mut impl Read, length: usize) -> Result<Vec<u8>, Error> {
+    (0..length).map(|_| read_byte(port)).collect()
+}
+
+/// Read a single byte, and check it is `expected`
+fn expect(port: &mut impl Read, expected: u8) -> Result<(), Error> {
+    match read_byte(port)? {
+        byte if byte == expected => Ok(()),
+        byte => Err(Error::UnexpectedResponse(byte)),
+    }
+}
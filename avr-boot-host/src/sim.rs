@@ -0,0 +1,332 @@
+//! Simulated devices, running the real protocol engines from `avr_boot` on their own threads
+//!
+//! Enabled with the `sim` feature, for testing host tools without hardware.
+//!
+//! [`spawn`] connects a single device to the host with a point to point link, like a USB serial adapter.
+//!
+//! A [`Bus`] is a half duplex multi-drop bus, for the framed protocol. The host's requests reach every node. Nodes
+//! send whole frames, and if more than one node answers the same request, the host receives garbage instead, as it
+//! would when two RS-485 transmitters drive the bus at once.
+//!
+//! [`fault`] has memories which can lose power part way through an update, for testing the update logic itself.
+
+pub mod fault;
+
+use avr_boot::flash::Flash;
+use avr_boot::protocol::{Serial, framed::Framed};
+use avr_boot::{Address, FLASH_SIZE_BYTES, FlashRange, SPM_PAGESIZE_BYTES};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::sync::mpsc::{Receiver, Sender, channel};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// How long the host waits for the first response before timing out
+const RESPONSE_TIMEOUT: Duration = Duration::from_millis(200);
+/// How long the bus has to be quiet after a response, before it is delivered
+const QUIET_TIME: Duration = Duration::from_millis(20);
+
+/// Program memory for a simulated device, which starts off erased
+///
+/// Like the real thing, writes can only clear bits, so a page must be erased before it is written.
+pub struct MemoryFlash {
+    /// The whole of program memory
+    pub memory: Vec<u8>,
+    buffer: Vec<u16>,
+}
+
+impl Default for MemoryFlash {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryFlash {
+    /// Create an erased program memory, the size of the MCU `avr_boot` was built for
+    pub fn new() -> Self {
+        Self {
+            memory: vec![0xff; FLASH_SIZE_BYTES as usize],
+            buffer: vec![0xffff; SPM_PAGESIZE_BYTES / 2],
+        }
+    }
+
+    /// Erase the first `length` bytes of the page containing `address`
+    fn erase(&mut self, address: Address, length: usize) {
+        let start = u32::from(address.into_page_aligned()) as usize;
+        self.memory[start..start + length].fill(0xff);
+    }
+
+    /// Program the first `words` words of the page buffer into the page containing `address`, then clear the buffer
+    fn program(&mut self, address: Address, words: usize) {
+        let start = u32::from(address.into_page_aligned()) as usize;
+        for (i, word) in self.buffer[..words].iter().enumerate() {
+            let [low, high] = word.to_le_bytes();
+            self.memory[start + i * 2] &= low;
+            self.memory[start + i * 2 + 1] &= high;
+        }
+        self.clear_buffer();
+    }
+
+    fn clear_buffer(&mut self) {
+        self.buffer.fill(0xffff);
+    }
+}
+
+impl Flash for MemoryFlash {
+    fn erase_page(&mut self, address: Address) {
+        self.erase(address, SPM_PAGESIZE_BYTES);
+    }
+
+    fn fill_page(&mut self, address: Address, data: u16) {
+        self.buffer[address.word() as usize / 2] = data;
+    }
+
+    fn write_page(&mut self, address: Address) {
+        self.program(address, SPM_PAGESIZE_BYTES / 2);
+    }
+
+    fn rww_enable(&mut self) {}
+
+    fn read_byte(&self, address: Address) -> u8 {
+        self.memory[u32::from(address) as usize]
+    }
+}
+
+/// A device's end of a point to point link
+pub struct DevicePort {
+    rx: Receiver<u8>,
+    tx: Sender<u8>,
+}
+
+impl Serial for DevicePort {
+    type Error = ();
+
+    /// Wait for the next byte, failing once the host has gone away
+    fn read(&mut self) -> Result<u8, ()> {
+        self.rx.recv().map_err(drop)
+    }
+
+    fn write(&mut self, byte: u8) -> Result<(), ()> {
+        self.tx.send(byte).map_err(drop)
+    }
+}
+
+/// The host's end of a point to point link, where reads time out
+pub struct LinkPort {
+    rx: Receiver<u8>,
+    tx: Sender<u8>,
+}
+
+impl Read for LinkPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        buf[0] = self
+            .rx
+            .recv_timeout(RESPONSE_TIMEOUT)
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?;
+        Ok(1)
+    }
+}
+
+impl Write for LinkPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for byte in buf {
+            self.tx
+                .send(*byte)
+                .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Create a point to point link, returning the host's end and the device's end
+pub fn link() -> (LinkPort, DevicePort) {
+    let (host_tx, device_rx) = channel();
+    let (device_tx, host_rx) = channel();
+    (
+        LinkPort {
+            rx: host_rx,
+            tx: host_tx,
+        },
+        DevicePort {
+            rx: device_rx,
+            tx: device_tx,
+        },
+    )
+}
+
+/// Run a device on another thread, connected to the host with a point to point link
+///
+/// `run` is given the device's end of the link, and an erased flash, and should give back the flash once the
+/// protocol engine has finished. The handle returns it to the host.
+pub fn spawn(
+    run: impl FnOnce(DevicePort, MemoryFlash) -> MemoryFlash + Send + 'static,
+) -> (LinkPort, JoinHandle<MemoryFlash>) {
+    let (host, device) = link();
+    (host, thread::spawn(move || run(device, MemoryFlash::new())))
+}
+
+/// A node's connection to a bus
+struct NodePort {
+    rx: Receiver<u8>,
+    line: Sender<Vec<u8>>,
+    frame: Vec<u8>,
+}
+
+impl Serial for NodePort {
+    type Error = ();
+
+    fn read(&mut self) -> Result<u8, ()> {
+        self.rx.recv().map_err(drop)
+    }
+
+    fn write(&mut self, byte: u8) -> Result<(), ()> {
+        self.frame.push(byte);
+        if byte == 0 {
+            self.line.send(mem::take(&mut self.frame)).map_err(drop)?;
+        }
+        Ok(())
+    }
+}
+
+/// The host's connection to a bus
+pub struct HostPort {
+    nodes: Vec<Sender<u8>>,
+    line: Receiver<Vec<u8>>,
+    received: VecDeque<u8>,
+    /// Numbers of the requests to corrupt, counting from 0
+    corrupt: Vec<usize>,
+    requests: usize,
+    /// Number of times more than one node answered
+    pub collisions: usize,
+}
+
+impl Read for HostPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.received.is_empty() {
+            let first = self
+                .line
+                .recv_timeout(RESPONSE_TIMEOUT)
+                .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?;
+            let others: Vec<_> =
+                std::iter::from_fn(|| self.line.recv_timeout(QUIET_TIME).ok()).collect();
+
+            if others.is_empty() {
+                self.received.extend(first);
+            } else {
+                self.collisions += 1;
+                let length = others.iter().map(Vec::len).fold(first.len(), usize::max);
+                self.received.extend(std::iter::repeat_n(0x55, length - 1));
+                self.received.push_back(0);
+            }
+        }
+
+        buf[0] = self.received.pop_front().unwrap();
+        Ok(1)
+    }
+}
+
+impl Write for HostPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut frame = buf.to_vec();
+        if self.corrupt.contains(&self.requests) {
+            frame[3] ^= 0x01;
+        }
+        self.requests += 1;
+
+        // Nodes which have finished have stopped listening
+        for node in &self.nodes {
+            frame.iter().try_for_each(|byte| node.send(*byte)).ok();
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A bus with devices attached, waiting for the host
+pub struct Bus {
+    nodes: Vec<Sender<u8>>,
+    devices: Vec<JoinHandle<MemoryFlash>>,
+    line: (Sender<Vec<u8>>, Receiver<Vec<u8>>),
+}
+
+impl Default for Bus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Bus {
+    /// Create an empty bus
+    pub fn new() -> Self {
+        Self {
+            nodes: Vec::new(),
+            devices: Vec::new(),
+            line: channel(),
+        }
+    }
+
+    /// Start a device running the framed protocol, at address `node`, or as the only device if `None`
+    pub fn attach(mut self, node: Option<u8>, application: FlashRange) -> Self {
+        let (tx, rx) = channel();
+        let port = NodePort {
+            rx,
+            line: self.line.0.clone(),
+            frame: Vec::new(),
+        };
+
+        self.nodes.push(tx);
+        self.devices.push(thread::spawn(move || {
+            let framed = Framed::new(port, MemoryFlash::new(), application);
+            let mut framed = match node {
+                Some(node) => framed.with_address(node),
+                None => framed,
+            };
+            framed.run().ok();
+            framed.release().1
+        }));
+        self
+    }
+
+    /// Connect the host, which corrupts the requests numbered in `corrupt`
+    ///
+    /// Returns the host's port, and handles which give back each device's flash once it has finished.
+    pub fn connect(self, corrupt: Vec<usize>) -> (HostPort, Vec<JoinHandle<MemoryFlash>>) {
+        let host = HostPort {
+            nodes: self.nodes,
+            line: self.line.1,
+            received: VecDeque::new(),
+            corrupt,
+            requests: 0,
+            collisions: 0,
+        };
+        (host, self.devices)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_only_clears_bits_when_writing_a_page() {
+        let mut flash = MemoryFlash::new();
+        let address = Address::from(SPM_PAGESIZE_BYTES as u16);
+
+        flash.fill_page(address, 0x0ff0);
+        flash.write_page(address);
+        flash.fill_page(address, 0x3c3c);
+        flash.write_page(address);
+
+        assert_eq!(flash.read_page(address)[0], 0x0c30);
+        flash.erase_page(address);
+        assert_eq!(flash.read_page(address)[0], 0xffff);
+    }
+}
@@ -0,0 +1,465 @@
+//! Flash and EEPROM which can lose power at any step, for proving update logic survives it
+//!
+//! [`FaultyFlash`] and [`FaultyEeprom`] share a [`Power`] supply, which records every operation they do. The supply
+//! can be cut after any of them, or part way through one which changes memory, leaving what the real hardware would:
+//!
+//! * an interrupted page erase has only erased the first half of the page
+//! * an interrupted page write has only programmed the first half of the page buffer
+//! * an interrupted EEPROM write has erased the byte, but not written it
+//! * the page buffer is lost, however far it had been filled
+//!
+//! When the power goes, the operation panics with [`PowerLost`], unwinding out of the code under test just as a reset
+//! would stop it. [`every_power_cut`] runs an update once to find all of its operations, then again from the start
+//! for every possible cut, checking the device recovers each time.
+
+use super::MemoryFlash;
+use avr_boot::eeprom::Eeprom;
+use avr_boot::flash::Flash;
+use avr_boot::{Address, DataPage, EEPROM_SIZE_BYTES, SPM_PAGESIZE_BYTES};
+use std::cell::RefCell;
+use std::ops::{Deref, DerefMut};
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+use std::sync::Once;
+
+/// Something the simulated MCU did to its memory
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Operation {
+    Erase(Address),
+    Fill(Address),
+    Write(Address),
+    RwwEnable,
+    EepromWrite(u16),
+}
+
+impl Operation {
+    /// Whether the operation takes long enough to be caught part way through
+    pub fn can_tear(&self) -> bool {
+        matches!(
+            self,
+            Operation::Erase(_) | Operation::Write(_) | Operation::EepromWrite(_)
+        )
+    }
+}
+
+/// When the power is cut, as an index into the operations since it was last restored
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Cut {
+    /// Part way through the operation
+    During(usize),
+    /// Once the operation has finished, before the next one starts
+    After(usize),
+}
+
+/// The panic payload when the power is cut
+#[derive(Debug)]
+pub struct PowerLost;
+
+#[derive(Default)]
+struct Supply {
+    operations: Vec<Operation>,
+    cut: Option<Cut>,
+    boots: usize,
+}
+
+/// What happens to an operation
+enum Outcome {
+    Complete,
+    Torn,
+    Last,
+}
+
+/// The power supply shared by a simulated device's memories
+#[derive(Clone, Default)]
+pub struct Power(Rc<RefCell<Supply>>);
+
+impl Power {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Arrange for the power to be cut at `cut`
+    pub fn cut_at(&self, cut: Cut) {
+        self.0.borrow_mut().cut = Some(cut);
+    }
+
+    /// Power up again, forgetting any cut and the operations recorded so far
+    pub fn restore(&self) {
+        let mut supply = self.0.borrow_mut();
+        supply.operations.clear();
+        supply.cut = None;
+        supply.boots += 1;
+    }
+
+    /// Every operation since the power was last restored
+    pub fn operations(&self) -> Vec<Operation> {
+        self.0.borrow().operations.clone()
+    }
+
+    fn boots(&self) -> usize {
+        self.0.borrow().boots
+    }
+
+    fn operate(&self, operation: Operation) -> Outcome {
+        let mut supply = self.0.borrow_mut();
+        supply.operations.push(operation);
+        let index = supply.operations.len() - 1;
+
+        match supply.cut {
+            Some(Cut::During(i)) if i == index && operation.can_tear() => Outcome::Torn,
+            Some(Cut::During(i) | Cut::After(i)) if i == index => Outcome::Last,
+            _ => Outcome::Complete,
+        }
+    }
+}
+
+fn lose_power() -> ! {
+    panic::panic_any(PowerLost)
+}
+
+/// Program memory on a [`Power`] supply, which starts off erased
+///
+/// This is a [`MemoryFlash`], which it derefs to, with every operation recorded and liable to be interrupted.
+pub struct FaultyFlash {
+    flash: MemoryFlash,
+    power: Power,
+    boot: usize,
+}
+
+impl FaultyFlash {
+    pub fn new(power: &Power) -> Self {
+        Self {
+            flash: MemoryFlash::new(),
+            power: power.clone(),
+            boot: power.boots(),
+        }
+    }
+
+    /// Record an operation, first dropping the page buffer if the power has been off since the last one
+    fn operate(&mut self, operation: Operation) -> Outcome {
+        if self.boot != self.power.boots() {
+            self.flash.clear_buffer();
+            self.boot = self.power.boots();
+        }
+        self.power.operate(operation)
+    }
+}
+
+impl Deref for FaultyFlash {
+    type Target = MemoryFlash;
+
+    fn deref(&self) -> &MemoryFlash {
+        &self.flash
+    }
+}
+
+impl DerefMut for FaultyFlash {
+    fn deref_mut(&mut self) -> &mut MemoryFlash {
+        &mut self.flash
+    }
+}
+
+impl Flash for FaultyFlash {
+    fn erase_page(&mut self, address: Address) {
+        match self.operate(Operation::Erase(address)) {
+            Outcome::Complete => self.flash.erase_page(address),
+            Outcome::Torn => {
+                self.flash.erase(address, SPM_PAGESIZE_BYTES / 2);
+                lose_power();
+            }
+            Outcome::Last => {
+                self.flash.erase_page(address);
+                lose_power();
+            }
+        }
+    }
+
+    fn fill_page(&mut self, address: Address, data: u16) {
+        let outcome = self.operate(Operation::Fill(address));
+        self.flash.fill_page(address, data);
+        if let Outcome::Last = outcome {
+            lose_power();
+        }
+    }
+
+    fn write_page(&mut self, address: Address) {
+        let outcome = self.operate(Operation::Write(address));
+        let words = match outcome {
+            Outcome::Torn => SPM_PAGESIZE_BYTES / 4,
+            _ => SPM_PAGESIZE_BYTES / 2,
+        };
+        self.flash.program(address, words);
+        if let Outcome::Torn | Outcome::Last = outcome {
+            lose_power();
+        }
+    }
+
+    fn rww_enable(&mut self) {
+        if let Outcome::Last = self.operate(Operation::RwwEnable) {
+            lose_power();
+        }
+    }
+
+    fn read_byte(&self, address: Address) -> u8 {
+        self.flash.read_byte(address)
+    }
+
+    fn read_into(&self, address: Address, buf: &mut [u8]) {
+        self.flash.read_into(address, buf)
+    }
+
+    fn read_page(&self, address: Address) -> DataPage {
+        self.flash.read_page(address)
+    }
+}
+
+/// EEPROM on a [`Power`] supply, which starts off erased
+pub struct FaultyEeprom {
+    pub memory: Vec<u8>,
+    power: Power,
+}
+
+impl FaultyEeprom {
+    pub fn new(power: &Power) -> Self {
+        Self {
+            memory: vec![0xff; EEPROM_SIZE_BYTES as usize],
+            power: power.clone(),
+        }
+    }
+}
+
+impl Eeprom for FaultyEeprom {
+    fn read_byte(&self, address: u16) -> u8 {
+        self.memory[address as usize]
+    }
+
+    fn write_byte(&mut self, address: u16, data: u8) {
+        match self.power.operate(Operation::EepromWrite(address)) {
+            Outcome::Complete => self.memory[address as usize] = data,
+            Outcome::Torn => {
+                self.memory[address as usize] = 0xff;
+                lose_power();
+            }
+            Outcome::Last => {
+                self.memory[address as usize] = data;
+                lose_power();
+            }
+        }
+    }
+}
+
+/// Prove an update survives losing power at any point
+///
+/// `setup` builds a device on the given supply, and `update` runs the code under test on it. The update is run once
+/// without a fault, to find every operation it does. Then, for each point where the power could be cut, a fresh
+/// device is set up, the update is run until the power goes, and `check` is called once it is back on. `check`
+/// should boot the device as it would after a reset, and assert it recovered.
+///
+/// Returns the number of power cuts tried. Any other panic in `update` is passed on.
+pub fn every_power_cut<T>(
+    setup: impl Fn(&Power) -> T,
+    update: impl Fn(&mut T),
+    check: impl Fn(&mut T, Cut),
+) -> usize {
+    silence_power_cuts();
+
+    let power = Power::new();
+    let mut device = setup(&power);
+    power.restore();
+    update(&mut device);
+
+    let cuts: Vec<Cut> = power
+        .operations()
+        .iter()
+        .enumerate()
+        .flat_map(|(i, operation)| {
+            operation
+                .can_tear()
+                .then_some(Cut::During(i))
+                .into_iter()
+                .chain([Cut::After(i)])
+        })
+        .collect();
+
+    for cut in &cuts {
+        let power = Power::new();
+        let mut device = setup(&power);
+        power.restore();
+        power.cut_at(*cut);
+
+        match panic::catch_unwind(AssertUnwindSafe(|| update(&mut device))) {
+            Ok(()) => panic!("the update finished without reaching {cut:?}"),
+            Err(payload) if payload.is::<PowerLost>() => {}
+            Err(payload) => panic::resume_unwind(payload),
+        }
+
+        power.restore();
+        check(&mut device, *cut);
+    }
+
+    cuts.len()
+}
+
+/// Keep the panic message for every power cut out of the test output
+fn silence_power_cuts() {
+    static HOOK: Once = Once::new();
+    HOOK.call_once(|| {
+        let default = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if !info.payload().is::<PowerLost>() {
+                default(info);
+            }
+        }));
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use avr_boot::FlashRange;
+    use avr_boot::image::{self, Header};
+    use avr_boot::journal::{FlashLog, Transfer};
+    use avr_boot::slots::{Slots, State};
+
+    const PAGE: usize = SPM_PAGESIZE_BYTES;
+
+    type Device = Slots<FaultyFlash, FaultyEeprom>;
+
+    fn application() -> FlashRange {
+        FlashRange::new(0u16, 0x2000u16)
+    }
+
+    /// Write version `version` of an application, linked for the running slot, into `slot`
+    fn write(flash: &mut FaultyFlash, slot: FlashRange, version: u8, length: usize) {
+        let data = vec![version; length];
+        let header = Header::for_target(application().start, &data, version as u32);
+        let start = u32::from(slot.start) as usize;
+        flash.memory[start..start + length].copy_from_slice(&data);
+        image::write_trailer(flash, slot, &header);
+    }
+
+    /// A device running version 1, with version 2 staged and pending
+    fn updating(power: &Power) -> Device {
+        let mut slots = Slots::new(
+            FaultyFlash::new(power),
+            FaultyEeprom::new(power),
+            application(),
+            0,
+        );
+        let (running, staging) = (slots.running(), slots.staging());
+        let (mut flash, eeprom) = slots.release();
+        write(&mut flash, running, 1, PAGE * 3);
+        write(&mut flash, staging, 2, PAGE * 2 + 0x80);
+
+        slots = Slots::new(flash, eeprom, application(), 0);
+        slots.mark_pending();
+        slots
+    }
+
+    /// Whatever was interrupted, one version is running and the other is still staged
+    fn check_versions(slots: &mut Device, cut: Cut) {
+        let running = slots.boot().unwrap_or_else(|e| panic!("{cut:?}: {e:?}"));
+        let staged = slots
+            .check_staged()
+            .unwrap_or_else(|e| panic!("{cut:?}: {e:?}"));
+        let mut versions = [running.app_version, staged.app_version];
+        versions.sort();
+        assert_eq!(versions, [1, 2], "{cut:?}");
+    }
+
+    #[test]
+    fn it_leaves_half_a_page_after_an_interrupted_write() {
+        let power = Power::new();
+        let mut flash = FaultyFlash::new(&power);
+        let data = DataPage([0; PAGE / 2]);
+        power.cut_at(Cut::During(1 + PAGE / 2));
+
+        let lost = panic::catch_unwind(AssertUnwindSafe(|| {
+            flash.store_page(Address::from(PAGE as u16), &data)
+        }));
+
+        assert!(lost.unwrap_err().is::<PowerLost>());
+        assert!(flash.memory[PAGE..PAGE + PAGE / 2].iter().all(|b| *b == 0));
+        assert!(
+            flash.memory[PAGE + PAGE / 2..PAGE * 2]
+                .iter()
+                .all(|b| *b == 0xff)
+        );
+        assert_eq!(
+            power.operations()[..2],
+            [
+                Operation::Erase(Address::from(PAGE as u16)),
+                Operation::Fill(Address::from(0u16))
+            ]
+        );
+    }
+
+    #[test]
+    fn it_drops_the_page_buffer_when_the_power_goes() {
+        let power = Power::new();
+        let mut flash = FaultyFlash::new(&power);
+        flash.fill_page(Address::from(0u16), 0x1234);
+
+        power.restore();
+        flash.write_page(Address::from(0u16));
+
+        assert_eq!(&flash.memory[..2], &[0xff, 0xff]);
+    }
+
+    #[test]
+    fn it_finishes_a_copy_journaled_in_flash_after_any_power_cut() {
+        let copy = Transfer::copy(
+            FlashRange::new(0u16, (PAGE * 2) as u16),
+            Address::from((PAGE * 4) as u16),
+        );
+        let log = FlashLog::new(Address::from((PAGE * 8) as u16));
+
+        let cuts = every_power_cut(
+            |power| {
+                let mut flash = FaultyFlash::new(power);
+                for (i, byte) in flash.memory[..PAGE * 2].iter_mut().enumerate() {
+                    *byte = i as u8;
+                }
+                flash
+            },
+            |flash| copy.run(flash, &mut { log }).unwrap(),
+            |flash, cut| {
+                copy.run(flash, &mut { log }).unwrap();
+                assert_eq!(
+                    flash.memory[..PAGE * 2],
+                    flash.memory[PAGE * 4..PAGE * 6],
+                    "{cut:?}"
+                );
+            },
+        );
+
+        assert!(cuts > 2 * (PAGE / 2));
+    }
+
+    #[test]
+    fn it_always_boots_a_whole_application_when_installing_is_interrupted() {
+        every_power_cut(
+            updating,
+            |slots| {
+                slots.boot().unwrap();
+            },
+            check_versions,
+        );
+    }
+
+    #[test]
+    fn it_always_boots_a_whole_application_when_rolling_back_is_interrupted() {
+        every_power_cut(
+            |power| {
+                let mut slots = updating(power).with_max_attempts(1);
+                slots.boot().unwrap();
+                assert_eq!(slots.state(), State::Trial(1));
+                slots
+            },
+            |slots| {
+                slots.boot().unwrap();
+            },
+            check_versions,
+        );
+    }
+}
@@ -0,0 +1,199 @@
+//! STK500v1 client, for Optiboot and `avr_boot::protocol::stk500v1`
+//!
+//! Speaks the same subset of the protocol as `avrdude -c arduino`. There is no erase: Optiboot erases each page as
+//! it is written.
+
+use crate::{Error, Programmer, expect, read_bytes};
+use std::io::{Read, Write};
+
+const STK_OK: u8 = 0x10;
+const STK_INSYNC: u8 = 0x14;
+const CRC_EOP: u8 = 0x20;
+
+const STK_GET_SYNC: u8 = 0x30;
+const STK_LEAVE_PROGMODE: u8 = 0x51;
+const STK_LOAD_ADDRESS: u8 = 0x55;
+const STK_UNIVERSAL: u8 = 0x56;
+const STK_PROG_PAGE: u8 = 0x64;
+const STK_READ_PAGE: u8 = 0x74;
+const STK_READ_SIGN: u8 = 0x75;
+
+const AVR_OP_LOAD_EXT_ADDR: u8 = 0x4d;
+
+/// Attempts at getting in sync, which can take a few goes while the MCU comes out of reset
+const SYNC_ATTEMPTS: usize = 5;
+/// Longest read in a single command
+const READ_LENGTH: usize = 256;
+
+/// STK500v1 client
+pub struct Client<T> {
+    port: T,
+    synced: bool,
+    /// The extended address byte last sent with a universal command
+    extended: u8,
+}
+
+impl<T: Read + Write> Client<T> {
+    /// Create a client talking over `port`
+    pub fn new(port: T) -> Self {
+        Self {
+            port,
+            synced: false,
+            extended: 0,
+        }
+    }
+
+    /// Get in sync with the bootloader, which happens automatically before the first command
+    pub fn sync(&mut self) -> Result<(), Error> {
+        for _ in 0..SYNC_ATTEMPTS {
+            match self.command(&[STK_GET_SYNC], 0) {
+                Ok(_) => {
+                    self.synced = true;
+                    return Ok(());
+                }
+                Err(Error::NoResponse | Error::UnexpectedResponse(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Err(Error::NoResponse)
+    }
+
+    /// Read the device signature
+    pub fn read_signature(&mut self) -> Result<[u8; 3], Error> {
+        let signature = self.command(&[STK_READ_SIGN], 3)?;
+        Ok([signature[0], signature[1], signature[2]])
+    }
+
+    /// Write a single page, at a page aligned address
+    pub fn write_page(&mut self, address: u32, page: &[u8]) -> Result<(), Error> {
+        self.load_address(address)?;
+
+        let [high, low] = (page.len() as u16).to_be_bytes();
+        let mut command = vec![STK_PROG_PAGE, high, low, b'F'];
+        command.extend(page);
+        self.command(&command, 0).map(drop)
+    }
+
+    /// Read up to 256 bytes
+    pub fn read_page(&mut self, address: u32, length: usize) -> Result<Vec<u8>, Error> {
+        self.load_address(address)?;
+
+        let [high, low] = (length as u16).to_be_bytes();
+        self.command(&[STK_READ_PAGE, high, low, b'F'], length)
+    }
+
+    /// Leave programming mode, so the bootloader starts the application
+    pub fn leave(&mut self) -> Result<(), Error> {
+        self.command(&[STK_LEAVE_PROGMODE], 0).map(drop)
+    }
+
+    /// Give back the transport
+    pub fn into_inner(self) -> T {
+        self.port
+    }
+
+    /// Load a byte address, which is sent as a word address, with a universal command for the extended byte
+    fn load_address(&mut self, address: u32) -> Result<(), Error> {
+        let word = address / 2;
+        let extended = (word >> 16) as u8;
+        if extended != self.extended {
+            self.command(&[STK_UNIVERSAL, AVR_OP_LOAD_EXT_ADDR, 0, extended, 0], 1)?;
+            self.extended = extended;
+        }
+
+        let [low, high, ..] = word.to_le_bytes();
+        self.command(&[STK_LOAD_ADDRESS, low, high], 0).map(drop)
+    }
+
+    /// Send a command, and return the `length` bytes of the response between INSYNC and OK
+    fn command(&mut self, command: &[u8], length: usize) -> Result<Vec<u8>, Error> {
+        if !self.synced && command[0] != STK_GET_SYNC {
+            self.sync()?;
+        }
+
+        self.port.write_all(command)?;
+        self.port.write_all(&[CRC_EOP])?;
+        self.port.flush()?;
+
+        expect(&mut self.port, STK_INSYNC)?;
+        let response = read_bytes(&mut self.port, length)?;
+        expect(&mut self.port, STK_OK)?;
+        Ok(response)
+    }
+}
+
+impl<T: Read + Write> Programmer for Client<T> {
+    fn signature(&mut self) -> Result<Option<[u8; 3]>, Error> {
+        self.read_signature().map(Some)
+    }
+
+    fn erase(&mut self) -> Result<(), Error> {
+        Err(Error::Unsupported("erasing"))
+    }
+
+    fn write(&mut self, address: u32, data: &[u8], page_size: usize) -> Result<(), Error> {
+        for (i, page) in data.chunks(page_size).enumerate() {
+            self.write_page(address + (i * page_size) as u32, page)?;
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, address: u32, length: usize) -> Result<Vec<u8>, Error> {
+        let mut data = Vec::with_capacity(length);
+        while data.len() < length {
+            let chunk = READ_LENGTH.min(length - data.len());
+            data.extend(self.read_page(address + data.len() as u32, chunk)?);
+        }
+        Ok(data)
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        self.leave()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim;
+    use avr_boot::protocol::stk500v1::Stk500v1;
+    use avr_boot::{SIGNATURE, SPM_PAGESIZE_BYTES};
+
+    #[test]
+    fn it_writes_and_reads_back_above_64k() {
+        let (port, device) = sim::spawn(|serial, flash| {
+            let mut stk = Stk500v1::new(serial, flash);
+            stk.run().ok();
+            stk.release().1
+        });
+        let image: Vec<u8> = (0..SPM_PAGESIZE_BYTES * 2).map(|i| i as u8).collect();
+        let mut client = Client::new(port);
+
+        let signature = client.signature().unwrap();
+        client.write(0x1_0000, &image, SPM_PAGESIZE_BYTES).unwrap();
+        client
+            .write(0, &image[..SPM_PAGESIZE_BYTES], SPM_PAGESIZE_BYTES)
+            .unwrap();
+        let read = client.read(0x1_0000, image.len()).unwrap();
+        client.finish().unwrap();
+        let flash = device.join().unwrap();
+
+        assert_eq!(signature, Some(SIGNATURE));
+        assert_eq!(read, image);
+        assert_eq!(&flash.memory[0x1_0000..0x1_0000 + image.len()], &image[..]);
+        assert_eq!(
+            &flash.memory[..SPM_PAGESIZE_BYTES],
+            &image[..SPM_PAGESIZE_BYTES]
+        );
+    }
+
+    #[test]
+    fn it_cannot_erase() {
+        let (port, _) = sim::link();
+
+        assert!(matches!(
+            Client::new(port).erase(),
+            Err(Error::Unsupported(_))
+        ));
+    }
+}
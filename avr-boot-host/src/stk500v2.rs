@@ -0,0 +1,234 @@
+//! STK500v2 client, for stk500boot and `avr_boot::protocol::stk500v2`
+//!
+//! Speaks the same subset of the protocol as `avrdude -c wiring`. The bootloader acknowledges a chip erase without
+//! doing anything, so erasing isn't offered.
+
+use crate::{Error, Programmer, read_byte, read_bytes};
+use std::io::{Read, Write};
+
+const MESSAGE_START: u8 = 0x1b;
+const TOKEN: u8 = 0x0e;
+
+const CMD_SIGN_ON: u8 = 0x01;
+const CMD_LOAD_ADDRESS: u8 = 0x06;
+const CMD_LEAVE_PROGMODE_ISP: u8 = 0x11;
+const CMD_PROGRAM_FLASH_ISP: u8 = 0x13;
+const CMD_READ_FLASH_ISP: u8 = 0x14;
+const CMD_READ_SIGNATURE_ISP: u8 = 0x1b;
+
+const STATUS_CMD_OK: u8 = 0x00;
+
+const EXTENDED_ADDRESS_FLAG: u32 = 0x8000_0000;
+/// Page mode, with the delays and instructions avrdude sends, which the bootloader ignores
+const PROGRAM_FLASH_PARAMETERS: [u8; 7] = [0xc1, 0x0a, 0x40, 0x4c, 0x20, 0x00, 0x00];
+
+/// Attempts at each message, which is sent again after a timeout or a bad checksum
+const ATTEMPTS: usize = 3;
+/// Longest read in a single message
+const READ_LENGTH: usize = 256;
+
+/// STK500v2 client
+pub struct Client<T> {
+    port: T,
+    sequence: u8,
+    signed_on: bool,
+}
+
+impl<T: Read + Write> Client<T> {
+    /// Create a client talking over `port`
+    pub fn new(port: T) -> Self {
+        Self {
+            port,
+            sequence: 0,
+            signed_on: false,
+        }
+    }
+
+    /// Sign on, and return the programmer name, which happens automatically before the first message
+    pub fn sign_on(&mut self) -> Result<Vec<u8>, Error> {
+        let answer = self.message(&[CMD_SIGN_ON])?;
+        self.signed_on = true;
+        Ok(answer[3..].to_vec())
+    }
+
+    /// Read the device signature
+    pub fn read_signature(&mut self) -> Result<[u8; 3], Error> {
+        let mut signature = [0; 3];
+        for (i, byte) in signature.iter_mut().enumerate() {
+            *byte = self.message(&[CMD_READ_SIGNATURE_ISP, 0, 0, 0, i as u8])?[2];
+        }
+        Ok(signature)
+    }
+
+    /// Write a single page, at a page aligned address
+    pub fn write_page(&mut self, address: u32, page: &[u8]) -> Result<(), Error> {
+        self.load_address(address)?;
+
+        let mut message = vec![CMD_PROGRAM_FLASH_ISP];
+        message.extend((page.len() as u16).to_be_bytes());
+        message.extend(PROGRAM_FLASH_PARAMETERS);
+        message.extend(page);
+        self.message(&message).map(drop)
+    }
+
+    /// Read up to 256 bytes
+    pub fn read_flash(&mut self, address: u32, length: usize) -> Result<Vec<u8>, Error> {
+        self.load_address(address)?;
+
+        let [high, low] = (length as u16).to_be_bytes();
+        let answer = self.message(&[CMD_READ_FLASH_ISP, high, low, 0x20])?;
+        answer
+            .get(2..2 + length)
+            .map(<[u8]>::to_vec)
+            .ok_or(Error::Failed)
+    }
+
+    /// Leave programming mode, so the bootloader starts the application
+    pub fn leave(&mut self) -> Result<(), Error> {
+        self.message(&[CMD_LEAVE_PROGMODE_ISP, 0x01, 0x01])
+            .map(drop)
+    }
+
+    /// Give back the transport
+    pub fn into_inner(self) -> T {
+        self.port
+    }
+
+    /// Load a byte address, which is sent as a word address
+    fn load_address(&mut self, address: u32) -> Result<(), Error> {
+        let mut word = address / 2;
+        if word > 0xffff {
+            word |= EXTENDED_ADDRESS_FLAG;
+        }
+
+        let mut message = vec![CMD_LOAD_ADDRESS];
+        message.extend(word.to_be_bytes());
+        self.message(&message).map(drop)
+    }
+
+    /// Send a message until a valid answer arrives, and return the answer's body
+    fn message(&mut self, body: &[u8]) -> Result<Vec<u8>, Error> {
+        if !self.signed_on && body[0] != CMD_SIGN_ON {
+            self.sign_on()?;
+        }
+
+        self.sequence = self.sequence.wrapping_add(1);
+        let [size_high, size_low] = (body.len() as u16).to_be_bytes();
+        let mut message = vec![MESSAGE_START, self.sequence, size_high, size_low, TOKEN];
+        message.extend(body);
+        message.push(message.iter().fold(0, |checksum, byte| checksum ^ byte));
+
+        let mut result = Err(Error::NoResponse);
+        for _ in 0..ATTEMPTS {
+            self.port.write_all(&message)?;
+            self.port.flush()?;
+
+            result = self.answer();
+            match &result {
+                Ok(answer) if answer.first() != Some(&body[0]) => {
+                    return Err(Error::UnexpectedResponse(answer[0]));
+                }
+                Ok(answer) if answer.get(1) != Some(&STATUS_CMD_OK) => return Err(Error::Failed),
+                Ok(_) => return result,
+                Err(Error::NoResponse | Error::UnexpectedResponse(_)) => {}
+                Err(_) => return result,
+            }
+        }
+        result
+    }
+
+    /// Read an answer to the current message, and return its body
+    fn answer(&mut self) -> Result<Vec<u8>, Error> {
+        loop {
+            while read_byte(&mut self.port)? != MESSAGE_START {}
+
+            let header = [
+                read_byte(&mut self.port)?,
+                read_byte(&mut self.port)?,
+                read_byte(&mut self.port)?,
+                read_byte(&mut self.port)?,
+            ];
+            let [sequence, size_high, size_low, token] = header;
+            if token != TOKEN {
+                return Err(Error::UnexpectedResponse(token));
+            }
+
+            let size = u16::from_be_bytes([size_high, size_low]) as usize;
+            let body = read_bytes(&mut self.port, size)?;
+            let checksum = read_byte(&mut self.port)?;
+            let expected = body
+                .iter()
+                .chain(&header)
+                .fold(MESSAGE_START, |checksum, byte| checksum ^ byte);
+
+            if checksum != expected {
+                return Err(Error::UnexpectedResponse(checksum));
+            }
+            // An answer to an earlier attempt, which arrived late
+            if sequence == self.sequence {
+                return Ok(body);
+            }
+        }
+    }
+}
+
+impl<T: Read + Write> Programmer for Client<T> {
+    fn signature(&mut self) -> Result<Option<[u8; 3]>, Error> {
+        self.read_signature().map(Some)
+    }
+
+    fn erase(&mut self) -> Result<(), Error> {
+        Err(Error::Unsupported("erasing"))
+    }
+
+    fn write(&mut self, address: u32, data: &[u8], page_size: usize) -> Result<(), Error> {
+        for (i, page) in data.chunks(page_size).enumerate() {
+            self.write_page(address + (i * page_size) as u32, page)?;
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, address: u32, length: usize) -> Result<Vec<u8>, Error> {
+        let mut data = Vec::with_capacity(length);
+        while data.len() < length {
+            let chunk = READ_LENGTH.min(length - data.len());
+            data.extend(self.read_flash(address + data.len() as u32, chunk)?);
+        }
+        Ok(data)
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        self.leave()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim;
+    use avr_boot::protocol::stk500v2::Stk500v2;
+    use avr_boot::{SIGNATURE, SPM_PAGESIZE_BYTES};
+
+    #[test]
+    fn it_writes_and_reads_back_above_64k() {
+        let (port, device) = sim::spawn(|serial, flash| {
+            let mut stk = Stk500v2::new(serial, flash);
+            stk.run().ok();
+            stk.release().1
+        });
+        let image: Vec<u8> = (0..SPM_PAGESIZE_BYTES * 2).map(|i| (i * 5) as u8).collect();
+        let mut client = Client::new(port);
+
+        let name = client.sign_on().unwrap();
+        let signature = client.signature().unwrap();
+        client.write(0x1_0000, &image, SPM_PAGESIZE_BYTES).unwrap();
+        let read = client.read(0x1_0000, image.len()).unwrap();
+        client.finish().unwrap();
+        let flash = device.join().unwrap();
+
+        assert_eq!(name, b"AVRISP_2");
+        assert_eq!(signature, Some(SIGNATURE));
+        assert_eq!(read, image);
+        assert_eq!(&flash.memory[0x1_0000..0x1_0000 + image.len()], &image[..]);
+    }
+}
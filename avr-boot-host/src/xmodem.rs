@@ -0,0 +1,226 @@
+//! XMODEM and YMODEM sender, for `avr_boot::protocol::xmodem` and `avr_boot::protocol::ymodem`
+//!
+//! The image is always written from the start of the application, and there is no way to read it back. XMODEM uses
+//! 128 byte blocks, with a CRC if the receiver asks for one, and YMODEM uses 1K blocks after a header with the length.
+//! The last block is padded with `0xff` rather than `0x1a`, so the rest of the last page stays erased.
+
+use crate::{Error, Programmer, read_byte};
+use avr_boot::crc;
+use std::io::{Read, Write};
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+const CRC_MODE: u8 = b'C';
+
+const BLOCK_SIZE: usize = 128;
+const BLOCK_SIZE_1K: usize = 1024;
+
+/// Attempts at each block before giving up
+const ATTEMPTS: usize = 10;
+/// Timeouts allowed while waiting for the receiver to start
+const START_TIMEOUTS: usize = 30;
+
+/// XMODEM or YMODEM sender
+pub struct Sender<T> {
+    port: T,
+    /// The file name for the YMODEM header, or `None` for XMODEM
+    name: Option<String>,
+}
+
+impl<T: Read + Write> Sender<T> {
+    /// Create an XMODEM sender talking over `port`
+    pub fn xmodem(port: T) -> Self {
+        Self { port, name: None }
+    }
+
+    /// Create a YMODEM sender talking over `port`, which sends `name` in the header
+    pub fn ymodem(port: T, name: &str) -> Self {
+        Self {
+            port,
+            name: Some(name.to_string()),
+        }
+    }
+
+    /// Send an image, once the receiver is ready
+    pub fn send(&mut self, image: &[u8]) -> Result<(), Error> {
+        let mut crc = self.wait_for_start()?;
+
+        let block_size = match self.name.clone() {
+            Some(name) => {
+                let mut header = name.into_bytes();
+                header.push(0);
+                header.extend(image.len().to_string().bytes());
+                header.push(0);
+                self.send_block(0, &header, BLOCK_SIZE, true)?;
+                crc = self.wait_for_start()?;
+                BLOCK_SIZE_1K
+            }
+            None => BLOCK_SIZE,
+        };
+
+        for (i, block) in image.chunks(block_size).enumerate() {
+            self.send_block((i + 1) as u8, block, block_size, crc)?;
+        }
+        self.end()?;
+
+        // YMODEM ends the batch with an empty header
+        if self.name.is_some() {
+            self.wait_for_start()?;
+            self.send_block(0, &[], BLOCK_SIZE, true)?;
+        }
+        Ok(())
+    }
+
+    /// Give back the transport
+    pub fn into_inner(self) -> T {
+        self.port
+    }
+
+    /// Wait for the receiver to ask for the first block, and return whether it wants a CRC
+    fn wait_for_start(&mut self) -> Result<bool, Error> {
+        let mut timeouts = 0;
+        loop {
+            match read_byte(&mut self.port) {
+                Ok(CRC_MODE) => return Ok(true),
+                Ok(NAK) => return Ok(false),
+                Ok(CAN) => return Err(Error::Cancelled),
+                Ok(_) => {}
+                Err(Error::NoResponse) if timeouts < START_TIMEOUTS => timeouts += 1,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Send a block, padded to `size` bytes, until the receiver acknowledges it
+    fn send_block(&mut self, number: u8, data: &[u8], size: usize, crc: bool) -> Result<(), Error> {
+        let mut block = data.to_vec();
+        block.resize(size, if number == 0 { 0 } else { 0xff });
+
+        let start = if size == BLOCK_SIZE_1K { STX } else { SOH };
+        let mut packet = vec![start, number, !number];
+        packet.extend(&block);
+        if crc {
+            packet.extend(crc::xmodem(&block).to_be_bytes());
+        } else {
+            packet.push(block.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)));
+        }
+
+        self.until_acknowledged(&packet)
+    }
+
+    fn end(&mut self) -> Result<(), Error> {
+        self.until_acknowledged(&[EOT])
+    }
+
+    /// Send `packet` until it is acknowledged, sending it again after a NAK or a timeout
+    fn until_acknowledged(&mut self, packet: &[u8]) -> Result<(), Error> {
+        for _ in 0..ATTEMPTS {
+            self.port.write_all(packet)?;
+            self.port.flush()?;
+
+            match read_byte(&mut self.port) {
+                Ok(ACK) => return Ok(()),
+                Ok(CAN) => return Err(Error::Cancelled),
+                Ok(_) | Err(Error::NoResponse) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Err(Error::NoResponse)
+    }
+}
+
+impl<T: Read + Write> Programmer for Sender<T> {
+    fn signature(&mut self) -> Result<Option<[u8; 3]>, Error> {
+        Ok(None)
+    }
+
+    fn erase(&mut self) -> Result<(), Error> {
+        Err(Error::Unsupported("erasing"))
+    }
+
+    fn write(&mut self, address: u32, data: &[u8], _page_size: usize) -> Result<(), Error> {
+        if address != 0 {
+            return Err(Error::Unsupported(
+                "writing anywhere but the start of flash",
+            ));
+        }
+        self.send(data)
+    }
+
+    fn read(&mut self, _address: u32, _length: usize) -> Result<Vec<u8>, Error> {
+        Err(Error::Unsupported("reading"))
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim;
+    use avr_boot::FlashRange;
+    use avr_boot::protocol::{xmodem::Xmodem, ymodem::Ymodem};
+
+    fn image(length: usize) -> Vec<u8> {
+        (0..length).map(|i| (i * 7 + 1) as u8).collect()
+    }
+
+    #[test]
+    fn it_sends_with_xmodem() {
+        let (port, device) = sim::spawn(|serial, flash| {
+            let mut buffer = [0; BLOCK_SIZE];
+            let mut xmodem =
+                Xmodem::new(serial, flash, FlashRange::new(0u16, 0x8000u16), &mut buffer);
+            xmodem.run().unwrap();
+            xmodem.release().1
+        });
+        let image = image(300);
+
+        Sender::xmodem(port).send(&image).unwrap();
+        let flash = device.join().unwrap();
+
+        assert_eq!(&flash.memory[..300], &image[..]);
+        assert!(flash.memory[300..384].iter().all(|b| *b == 0xff));
+    }
+
+    #[test]
+    fn it_sends_with_ymodem() {
+        let (port, device) = sim::spawn(|serial, flash| {
+            let mut buffer = [0; BLOCK_SIZE_1K];
+            let mut ymodem =
+                Ymodem::new(serial, flash, FlashRange::new(0u16, 0x8000u16), &mut buffer);
+            let file = ymodem.run().unwrap().unwrap();
+            assert_eq!(file.length, 1500);
+            ymodem.release().1
+        });
+        let image = image(1500);
+
+        Sender::ymodem(port, "app.bin").send(&image).unwrap();
+        let flash = device.join().unwrap();
+
+        assert_eq!(&flash.memory[..1500], &image[..]);
+        assert!(flash.memory[1500..2048].iter().all(|b| *b == 0xff));
+    }
+
+    #[test]
+    fn it_stops_when_the_receiver_cancels() {
+        let (port, device) = sim::spawn(|serial, flash| {
+            let mut buffer = [0; BLOCK_SIZE];
+            let mut xmodem =
+                Xmodem::new(serial, flash, FlashRange::new(0u16, 0x100u16), &mut buffer);
+            assert!(xmodem.run().is_err());
+            xmodem.release().1
+        });
+
+        let result = Sender::xmodem(port).send(&image(1024));
+        device.join().unwrap();
+
+        assert!(matches!(result, Err(Error::Cancelled)));
+    }
+}
@@ -1,43 +1,85 @@
-struct McuInfo {
-    spmcsr: u8,
-    page_erase: u8,
-    page_write: u8,
-    page_fill: u8,
-    rww_enable: Option<u8>,
-    boot_lock_bits_set: u8,
-    rampz: Option<u8>,
-    page_size: usize,
-}
-
 fn main() {
     println!("cargo:rustc-link-arg=-lc");
     println!("cargo::rustc-check-cfg=cfg(rww_enable)");
     println!("cargo::rustc-check-cfg=cfg(extended_addressing)");
+    println!("cargo::rustc-check-cfg=cfg(elpm)");
+    println!("cargo::rustc-check-cfg=cfg(eeprom)");
+    println!("cargo::rustc-check-cfg=cfg(eeprom_address_high)");
+    println!("cargo::rustc-check-cfg=cfg(signature_read)");
 
     let info = get_mcu_info();
 
-    println!("cargo:rustc-env=AVR_BOOT_SPMCSR={}", info.spmcsr);
-    println!("cargo:rustc-env=AVR_BOOT_PAGE_ERASE={}", info.page_erase);
-    println!("cargo:rustc-env=AVR_BOOT_PAGE_WRITE={}", info.page_write);
-    println!("cargo:rustc-env=AVR_BOOT_PAGE_FILL={}", info.page_fill);
+    println!("cargo:rustc-env=AVR_BOOT_SPMCSR={}", info.spm.address);
+    println!(
+        "cargo:rustc-env=AVR_BOOT_PAGE_ERASE={}",
+        info.spm.page_erase
+    );
+    println!(
+        "cargo:rustc-env=AVR_BOOT_PAGE_WRITE={}",
+        info.spm.page_write
+    );
+    println!("cargo:rustc-env=AVR_BOOT_PAGE_FILL={}", info.spm.page_fill);
 
-    if let Some(rww_enable) = info.rww_enable {
+    if let Some(rww_enable) = info.spm.rww_enable {
         println!("cargo:rustc-env=AVR_BOOT_RWW_ENABLE={rww_enable}");
         println!("cargo:rustc-cfg=rww_enable");
     }
 
+    if let Some(signature_read) = info.spm.signature_read {
+        println!("cargo:rustc-env=AVR_BOOT_SIGNATURE_READ={signature_read}");
+        println!("cargo:rustc-cfg=signature_read");
+    }
+
     println!(
         "cargo:rustc-env=AVR_BOOT_LOCK_BITS_SET={}",
-        info.boot_lock_bits_set
+        info.spm.lock_bits_set
     );
     println!("cargo:rustc-env=AVR_BOOT_SPM_PAGESIZE={}", info.page_size);
+    println!("cargo:rustc-env=AVR_BOOT_FLASH_SIZE={}", info.flash_size);
+
+    // The protocols all report the signature, so there is nothing useful to build without it
+    let [sig0, sig1, sig2] = info.signature.unwrap_or_else(|| {
+        panic!(
+            "no signature is known for {}, add it to the table in avr-boot-devices/src/signatures.rs",
+            info.name
+        )
+    });
+    println!(
+        "cargo:rustc-env=AVR_BOOT_SIGNATURE={}",
+        u32::from_be_bytes([0, sig0, sig1, sig2])
+    );
     if let Some(rampz) = info.rampz {
         println!("cargo:rustc-cfg=extended_addressing");
         println!("cargo:rustc-env=AVR_RAMPZ={rampz}");
+        // 64k parts have RAMPZ, but no `elpm`, and reach all their flash with `lpm`
+        if info.flash_size > 0x1_0000 {
+            println!("cargo:rustc-cfg=elpm");
+        }
+    }
+
+    match info.eeprom {
+        Some(eeprom) => {
+            println!("cargo:rustc-cfg=eeprom");
+            println!("cargo:rustc-env=AVR_BOOT_EEPROM_SIZE={}", eeprom.size);
+            println!("cargo:rustc-env=AVR_BOOT_EEARL={}", eeprom.address_low);
+            if let Some(high) = eeprom.address_high {
+                println!("cargo:rustc-cfg=eeprom_address_high");
+                println!("cargo:rustc-env=AVR_BOOT_EEARH={high}");
+            }
+            println!("cargo:rustc-env=AVR_BOOT_EEDR={}", eeprom.data);
+            println!("cargo:rustc-env=AVR_BOOT_EECR={}", eeprom.control);
+            println!("cargo:rustc-env=AVR_BOOT_EERE={}", eeprom.read_enable);
+            println!(
+                "cargo:rustc-env=AVR_BOOT_EEMPE={}",
+                eeprom.master_write_enable
+            );
+            println!("cargo:rustc-env=AVR_BOOT_EEPE={}", eeprom.write_enable);
+        }
+        None => println!("cargo:rustc-env=AVR_BOOT_EEPROM_SIZE=0"),
     }
 }
 
-fn get_mcu_info() -> McuInfo {
+fn get_mcu_info() -> avr_boot_devices::Device {
     const DEFAULT_MCU_FOR_NON_AVR_DOCS: &str = "atmega1280";
 
     let current_mcu = if avr_mcu::current::is_compiling_for_avr() {
@@ -46,71 +88,5 @@ fn get_mcu_info() -> McuInfo {
         avr_mcu::microcontroller(DEFAULT_MCU_FOR_NON_AVR_DOCS)
     };
 
-    let spm_reg = current_mcu
-        .registers()
-        .find(|r| r.name == "SPMCSR" || r.name == "SPMCR")
-        .expect("could not find SPMCSR or SPMCR register");
-
-    let spm_enable = spm_reg
-        .bitfields
-        .iter()
-        .find(|b| b.name == "SPMEN" || b.name == "SELFPRGEN")
-        .expect("could not find spm enable bitfield");
-
-    let page_erase = spm_reg
-        .bitfields
-        .iter()
-        .find(|b| b.name == "PGERS")
-        .expect("could not find page erase bitfield");
-
-    let page_write = spm_reg
-        .bitfields
-        .iter()
-        .find(|b| b.name == "PGWRT")
-        .expect("could not find page write bitfield");
-
-    let rww_enable = spm_reg
-        .bitfields
-        .iter()
-        .find(|b| b.name == "ASRE" || b.name == "RWWSRE");
-
-    let blb_set = spm_reg
-        .bitfields
-        .iter()
-        .find(|b| b.name == "LBSET" || b.name == "BLBSET" || b.name == "RFLB")
-        .expect("could not find blb set bitfield");
-
-    let prog_space = current_mcu
-        .device
-        .address_spaces
-        .iter()
-        .find(|space| space.name == "prog")
-        .expect("could not find prog address space");
-
-    let page_size = prog_space
-        .segments
-        .iter()
-        .find(|seg| seg.name == "FLASH")
-        .and_then(|seg| seg.page_size)
-        .expect("failed to find page size");
-
-    let rampz = if prog_space.size > 0xffff {
-        current_mcu
-            .registers()
-            .find(|r| r.name == "RAMPZ" || r.name == "RAMPZ0")
-            .map(|r| r.offset as u8)
-    } else {
-        None
-    };
-
-    McuInfo {
-        spmcsr: spm_reg.offset as u8,
-        page_erase: (spm_enable.mask | page_erase.mask) as u8,
-        page_write: (spm_enable.mask | page_write.mask) as u8,
-        page_fill: spm_enable.mask as u8,
-        rww_enable: rww_enable.map(|e| (e.mask | spm_enable.mask) as u8),
-        boot_lock_bits_set: (spm_enable.mask | blb_set.mask) as u8,
-        rampz,
-        page_size: page_size as usize,
-    }
+    avr_boot_devices::from_mcu(&current_mcu).unwrap_or_else(|e| panic!("{e}"))
 }
@@ -1,3 +1,4 @@
+use core::cmp::Ordering;
 use core::convert::From;
 
 /// 16 or 24 bit program memory address
@@ -17,34 +18,168 @@ impl Address {
     const PCWORD_MASK: u16 = (crate::SPM_PAGESIZE_BYTES - 1) as u16;
     const PCPAGE_MASK: u16 = !Self::PCWORD_MASK;
 
-    fn new(base: u32) -> Self {
+    /// The highest address representable in 24 bits
+    const MAX: u32 = 0xff_ffff;
+
+    const fn new(base: u32) -> Self {
         Self {
             base: base as u16,
             ramp: (base >> 16) as u8,
         }
     }
 
+    /// Create an address in a `const` context, where `From` can't be used
+    ///
+    /// Only the lower 24 bits are used. See [`const_page_address!`](crate::const_page_address)
+    /// for a version which is checked at compile time.
+    ///
+    /// # Example
+    /// ```rust
+    /// use avr_boot::Address;
+    ///
+    /// const APP_START: Address = Address::from_u32_const(0x1000);
+    /// assert_eq!(Address::from(0x1000u16), APP_START);
+    /// ```
+    pub const fn from_u32_const(address: u32) -> Self {
+        Self::new(address)
+    }
+
+    /// Create an address from a 16 bit value in a `const` context
+    pub const fn from_u16_const(address: u16) -> Self {
+        Self::new(address as u32)
+    }
+
+    /// The full address as a u32, usable in a `const` context
+    pub const fn to_u32(self) -> u32 {
+        self.base as u32 + ((self.ramp as u32) << 16)
+    }
+
+    /// The address of the start of the nth page
+    ///
+    /// # Example
+    /// ```rust
+    /// use avr_boot::{Address, SPM_PAGESIZE_BYTES};
+    ///
+    /// let address = Address::from_page_number(3);
+    /// assert_eq!(3 * SPM_PAGESIZE_BYTES as u32, address.into());
+    /// ```
+    pub const fn from_page_number(page: u16) -> Self {
+        Self::new(page as u32 * crate::SPM_PAGESIZE_BYTES as u32)
+    }
+
+    /// The number of the page this address is in, counting from 0 at the start of program memory
+    pub const fn page_number(&self) -> u16 {
+        (self.to_u32() / crate::SPM_PAGESIZE_BYTES as u32) as u16
+    }
+
     /// Mask off the PCWORD part of the address, leaving only PCPAGE.  
     ///
     /// The resulting address is aligned to the start of the page.
-    pub fn into_page_aligned(self) -> Self {
+    pub const fn into_page_aligned(self) -> Self {
         Self {
             base: self.base & Self::PCPAGE_MASK,
             ramp: self.ramp,
         }
     }
 
+    /// True if the PCWORD part of the address is zero
+    pub const fn is_page_aligned(&self) -> bool {
+        self.word() == 0
+    }
+
+    /// The start of the page following the one this address is in
+    ///
+    /// # Example
+    /// ```rust
+    /// use avr_boot::{Address, SPM_PAGESIZE_BYTES};
+    ///
+    /// let address = Address::from(0xffffu32 - 3);
+    /// assert_eq!(0x10000u32, address.next_page().into());
+    /// ```
+    pub fn next_page(self) -> Self {
+        Self::new(u32::from(self.into_page_aligned()) + crate::SPM_PAGESIZE_BYTES as u32)
+    }
+
+    /// Add a byte offset, returning `None` if the result doesn't fit in 24 bits
+    ///
+    /// Carries into the extended byte, so works correctly across the 64k boundary.
+    pub fn checked_add(self, offset: u32) -> Option<Self> {
+        u32::from(self)
+            .checked_add(offset)
+            .filter(|address| *address <= Self::MAX)
+            .map(Self::new)
+    }
+
+    /// Subtract a byte offset, returning `None` if the result would be below zero
+    pub fn checked_sub(self, offset: u32) -> Option<Self> {
+        u32::from(self).checked_sub(offset).map(Self::new)
+    }
+
     /// The word byte index within the page: technically PCWORD << 1
-    pub fn word(&self) -> u16 {
+    pub const fn word(&self) -> u16 {
         self.base & Self::PCWORD_MASK
     }
 
     /// The extended byte of the address, usually written to RAMPZ on MCUs with extended addressing
-    pub fn ramp(&self) -> u8 {
+    pub const fn ramp(&self) -> u8 {
         self.ramp
     }
 }
 
+/// Create a page aligned [`Address`] from a literal, checked at compile time
+///
+/// Compilation fails if the address is not aligned to the start of a page,
+/// or is beyond the end of the target MCU's program memory.
+///
+/// # Example
+/// ```rust
+/// use avr_boot::{const_page_address, Address, SPM_PAGESIZE_BYTES};
+///
+/// const CONFIG_PAGE: Address = const_page_address!(0x1000);
+/// assert!(CONFIG_PAGE.is_page_aligned());
+/// ```
+///
+/// ```compile_fail
+/// use avr_boot::{const_page_address, Address};
+///
+/// const NOT_ALIGNED: Address = const_page_address!(0x1001);
+/// ```
+///
+/// ```compile_fail
+/// use avr_boot::{const_page_address, Address, FLASH_SIZE_BYTES};
+///
+/// const TOO_HIGH: Address = const_page_address!(FLASH_SIZE_BYTES);
+/// ```
+#[macro_export]
+macro_rules! const_page_address {
+    ($address:expr) => {
+        const {
+            let address: u32 = $address;
+            assert!(
+                address % $crate::SPM_PAGESIZE_BYTES as u32 == 0,
+                "address is not page aligned"
+            );
+            assert!(
+                address < $crate::FLASH_SIZE_BYTES,
+                "address is beyond the end of program memory"
+            );
+            $crate::Address::from_u32_const(address)
+        }
+    };
+}
+
+impl Ord for Address {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.ramp, self.base).cmp(&(other.ramp, other.base))
+    }
+}
+
+impl PartialOrd for Address {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 impl From<u16> for Address {
     fn from(i: u16) -> Self {
         Self::new(i as u32)
@@ -65,7 +200,7 @@ impl From<u32> for Address {
 
 impl From<Address> for u32 {
     fn from(address: Address) -> u32 {
-        address.base as u32 + ((address.ramp as u32) << 16)
+        address.to_u32()
     }
 }
 
@@ -97,4 +232,43 @@ mod tests {
 
         assert_eq!(address.word(), 17);
     }
+
+    #[test]
+    fn it_counts_pages_across_the_64k_boundary() {
+        let page = (0x10000 / crate::SPM_PAGESIZE_BYTES) as u16;
+        let address = Address::from_page_number(page);
+
+        assert_eq!(u32::from(address), 0x10000);
+        assert_eq!(address.ramp(), 1);
+        assert_eq!(address.page_number(), page);
+        assert_eq!(Address::from(0x1ffffu32).page_number(), page * 2 - 1);
+    }
+
+    #[test]
+    fn it_moves_to_the_next_page() {
+        let address = Address::from(crate::SPM_PAGESIZE_BYTES as u32 + 5);
+
+        assert_eq!(
+            address.next_page(),
+            Address::new(crate::SPM_PAGESIZE_BYTES as u32 * 2)
+        );
+        assert!(address.next_page().is_page_aligned());
+        assert!(!address.is_page_aligned());
+    }
+
+    #[test]
+    fn it_adds_and_subtracts_offsets() {
+        let address = Address::from(0xfff0u16);
+
+        assert_eq!(address.checked_add(0x20), Some(Address::new(0x10010)));
+        assert_eq!(address.checked_sub(0xfff0), Some(Address::new(0)));
+        assert_eq!(address.checked_sub(0xfff1), None);
+        assert_eq!(Address::new(0xff_ffff).checked_add(1), None);
+    }
+
+    #[test]
+    fn it_orders_by_extended_byte_first() {
+        assert!(Address::new(0x1_0000) > Address::new(0xffff));
+        assert!(Address::new(0x100) < Address::new(0x200));
+    }
 }
@@ -52,6 +52,22 @@ impl PageBuffer {
         }
     }
 
+    /// Create a new PageBuffer in a `const` context
+    ///
+    /// # Example
+    /// ```rust
+    /// use avr_boot::{const_page_address, PageBuffer};
+    ///
+    /// let buff = const { PageBuffer::new_const(const_page_address!(0x1000)) };
+    /// assert_eq!(0x1000u16, buff.address().into());
+    /// ```
+    /// The page address will be aligned downwards to the nearest starting page address
+    pub const fn new_const(address: Address) -> PageBuffer {
+        PageBuffer {
+            address: address.into_page_aligned(),
+        }
+    }
+
     /// Get the base page address to be operated on
     ///
     /// # Example
@@ -147,7 +163,7 @@ impl PageBuffer {
     /// buff.store();
     /// ```
     pub fn fill_from_iter(&self, i: impl IntoIterator<Item = u16>) {
-        for (word, value) in self.iter().zip(i.into_iter()) {
+        for (word, value) in self.iter().zip(i) {
             word.set(value);
         }
     }
@@ -0,0 +1,133 @@
+//! Checksums of ranges of program memory
+//!
+//! Each function reads the range a page at a time through [`Flash::read_into`], which on the MCU sets RAMPZ once per
+//! 64k segment and streams the bytes with `lpm`/`elpm`, so ranges can cross the 64k boundary and start or end part
+//! way through a page.
+//!
+//! # Example
+//! ```rust
+//! use avr_boot::{FlashRange, checksum, flash::Spm};
+//!
+//! // On the host, program memory reads as erased
+//! let range = FlashRange::new(0u16, 4u16);
+//! assert_eq!(0x99cf, checksum::crc16_xmodem(&Spm, range));
+//! assert_eq!(0xffff_ffff, checksum::crc32(&Spm, range));
+//! assert_eq!(0xfc, checksum::sum8(&Spm, range));
+//! ```
+
+use crate::flash::Flash;
+use crate::{Address, FlashRange, SPM_PAGESIZE_BYTES, crc};
+
+/// Feed the bytes in `range` to `f`, a page at a time
+pub fn fold<T>(
+    flash: &impl Flash,
+    range: FlashRange,
+    init: T,
+    mut f: impl FnMut(T, &[u8]) -> T,
+) -> T {
+    let mut buffer = [0; SPM_PAGESIZE_BYTES];
+    let mut state = init;
+    let mut address = range.start;
+
+    while address < range.end {
+        let length = u32::from(address.next_page()).min(range.end.into()) - u32::from(address);
+        let chunk = &mut buffer[..length as usize];
+        flash.read_into(address, chunk);
+        state = f(state, chunk);
+
+        address = Address::from(u32::from(address) + length);
+    }
+    state
+}
+
+/// The CRC-16/XMODEM of `range`, the same as [`crc::xmodem`] and the framed protocol's verify command
+pub fn crc16_xmodem(flash: &impl Flash, range: FlashRange) -> u16 {
+    fold(flash, range, 0, |crc, bytes| {
+        bytes
+            .iter()
+            .fold(crc, |crc, byte| crc::xmodem_update(crc, *byte))
+    })
+}
+
+/// The CRC-32 of `range`, the same as [`crc::crc32`]
+pub fn crc32(flash: &impl Flash, range: FlashRange) -> u32 {
+    fold(flash, range, 0, |crc, bytes| {
+        bytes
+            .iter()
+            .fold(crc, |crc, byte| crc::crc32_update(crc, *byte))
+    })
+}
+
+/// The sum of the bytes in `range`, modulo 256, like the original XMODEM checksum
+pub fn sum8(flash: &impl Flash, range: FlashRange) -> u8 {
+    fold(flash, range, 0, |sum, bytes| {
+        bytes
+            .iter()
+            .fold(sum, |sum: u8, byte| sum.wrapping_add(*byte))
+    })
+}
+
+/// The sum of the bytes in `range`, modulo 65536
+pub fn sum16(flash: &impl Flash, range: FlashRange) -> u16 {
+    fold(flash, range, 0, |sum, bytes| {
+        bytes
+            .iter()
+            .fold(sum, |sum: u16, byte| sum.wrapping_add(*byte as u16))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::mock::MockFlash;
+    use std::vec::Vec;
+
+    fn flash() -> MockFlash {
+        let mut flash = MockFlash::new();
+        for (i, byte) in flash.memory.iter_mut().enumerate() {
+            *byte = (i * 7 + i / 251) as u8;
+        }
+        flash
+    }
+
+    #[test]
+    fn it_matches_the_checksums_of_the_same_bytes() {
+        let flash = flash();
+        // Crosses the 64k boundary, and starts and ends part way through a page
+        let (start, end) = (0xfe13, 0x1_0a85);
+        let range = FlashRange::new(start as u32, end as u32);
+        let bytes = &flash.memory[start..end];
+
+        assert_eq!(crc16_xmodem(&flash, range), crc::xmodem(bytes));
+        assert_eq!(crc32(&flash, range), crc::crc32(bytes));
+        assert_eq!(
+            sum8(&flash, range),
+            bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+        );
+        assert_eq!(
+            sum16(&flash, range),
+            bytes.iter().map(|b| *b as u16).fold(0, u16::wrapping_add)
+        );
+    }
+
+    #[test]
+    fn it_reads_a_page_at_a_time() {
+        let flash = flash();
+        let range = FlashRange::new(0x10u16, (SPM_PAGESIZE_BYTES * 2 + 1) as u16);
+
+        let chunks = fold(&flash, range, Vec::new(), |mut chunks, bytes| {
+            chunks.push(bytes.len());
+            chunks
+        });
+
+        assert_eq!(chunks, [SPM_PAGESIZE_BYTES - 0x10, SPM_PAGESIZE_BYTES, 1]);
+    }
+
+    #[test]
+    fn it_checksums_an_empty_range_as_nothing() {
+        let range = FlashRange::new(0x100u16, 0x100u16);
+
+        assert_eq!(crc32(&flash(), range), 0);
+        assert_eq!(sum16(&flash(), range), 0);
+    }
+}
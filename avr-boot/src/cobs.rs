@@ -0,0 +1,199 @@
+//! Consistent Overhead Byte Stuffing
+//!
+//! COBS removes every zero byte from a frame, at a cost of at most one byte in 254, so a zero can be used to mark
+//! the end of each frame on the wire. A receiver which loses its place only needs to wait for the next zero.
+//!
+//! # Example
+//! ```rust
+//! use avr_boot::cobs;
+//!
+//! let mut encoded = [0; 8];
+//! let mut length = 0;
+//! cobs::encode(&[0x11, 0x00, 0x22], |byte| {
+//!     encoded[length] = byte;
+//!     length += 1;
+//!     Ok::<(), ()>(())
+//! })
+//! .unwrap();
+//! assert_eq!(&[0x02, 0x11, 0x02, 0x22], &encoded[..length]);
+//!
+//! let mut decoder = cobs::Decoder::new();
+//! let mut frame = [0; 8];
+//! for byte in &encoded[..length] {
+//!     assert_eq!(None, decoder.push(&mut frame, *byte));
+//! }
+//! assert_eq!(Some(Ok(3)), decoder.push(&mut frame, 0));
+//! assert_eq!(&[0x11, 0x00, 0x22], &frame[..3]);
+//! ```
+
+/// Longest run of non-zero bytes which can follow a single code byte
+const MAX_RUN: usize = 254;
+
+/// Encode `data`, passing each encoded byte to `write`
+///
+/// The frame delimiter isn't included, so the caller should follow up with a zero.
+pub fn encode<E>(data: &[u8], mut write: impl FnMut(u8) -> Result<(), E>) -> Result<(), E> {
+    let mut rest = data;
+
+    loop {
+        let limit = rest.len().min(MAX_RUN);
+        match rest[..limit].iter().position(|byte| *byte == 0) {
+            Some(run) => {
+                write(run as u8 + 1)?;
+                rest[..run].iter().try_for_each(|byte| write(*byte))?;
+                rest = &rest[run + 1..];
+            }
+            None => {
+                write(limit as u8 + 1)?;
+                rest[..limit].iter().try_for_each(|byte| write(*byte))?;
+                rest = &rest[limit..];
+                if limit < MAX_RUN || rest.is_empty() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// Why a frame couldn't be decoded
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Error {
+    /// The frame was longer than the buffer
+    TooLong,
+    /// The frame ended part way through a run
+    Truncated,
+}
+
+/// Incremental decoder, for frames arriving a byte at a time
+///
+/// Decodes into a buffer supplied with each byte, which should be the same every time.
+#[derive(Copy, Clone, Default, Debug)]
+pub struct Decoder {
+    /// The current code byte, or 0 at the start of a frame
+    code: u8,
+    /// Bytes left in the current run
+    remaining: u8,
+    length: usize,
+    overflow: bool,
+}
+
+impl Decoder {
+    /// Create a decoder, waiting for the start of a frame
+    pub const fn new() -> Self {
+        Self {
+            code: 0,
+            remaining: 0,
+            length: 0,
+            overflow: false,
+        }
+    }
+
+    /// Decode a single byte into `buffer`
+    ///
+    /// Returns the length of the frame once the delimiter arrives. Empty frames are skipped,
+    /// so extra delimiters can be sent to resynchronise.
+    pub fn push(&mut self, buffer: &mut [u8], byte: u8) -> Option<Result<usize, Error>> {
+        if byte == 0 {
+            let frame = *self;
+            *self = Self::new();
+            return match frame {
+                Self { code: 0, .. } => None,
+                Self { overflow: true, .. } => Some(Err(Error::TooLong)),
+                Self { remaining: 1.., .. } => Some(Err(Error::Truncated)),
+                Self { length, .. } => Some(Ok(length)),
+            };
+        }
+
+        if self.remaining == 0 {
+            if self.code != 0 && self.code != 0xff {
+                self.append(buffer, 0);
+            }
+            self.code = byte;
+            self.remaining = byte - 1;
+        } else {
+            self.append(buffer, byte);
+            self.remaining -= 1;
+        }
+
+        None
+    }
+
+    fn append(&mut self, buffer: &mut [u8], byte: u8) {
+        match buffer.get_mut(self.length) {
+            Some(slot) => {
+                *slot = byte;
+                self.length += 1;
+            }
+            None => self.overflow = true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    fn encoded(data: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+        encode(data, |byte| {
+            output.push(byte);
+            Ok::<(), ()>(())
+        })
+        .unwrap();
+        output
+    }
+
+    fn decoded(encoded: &[u8], buffer_length: usize) -> Vec<Result<Vec<u8>, Error>> {
+        let mut decoder = Decoder::new();
+        let mut buffer = vec![0; buffer_length];
+        let mut frames = Vec::new();
+        for byte in encoded {
+            if let Some(result) = decoder.push(&mut buffer, *byte) {
+                frames.push(result.map(|length| buffer[..length].to_vec()));
+            }
+        }
+        frames
+    }
+
+    #[test]
+    fn it_encodes_the_standard_examples() {
+        assert_eq!(encoded(&[]), [0x01]);
+        assert_eq!(encoded(&[0x00]), [0x01, 0x01]);
+        assert_eq!(encoded(&[0x00, 0x00]), [0x01, 0x01, 0x01]);
+        assert_eq!(
+            encoded(&[0x11, 0x22, 0x00, 0x33]),
+            [0x03, 0x11, 0x22, 0x02, 0x33]
+        );
+        assert_eq!(
+            encoded(&[0x11, 0x00, 0x00, 0x00]),
+            [0x02, 0x11, 0x01, 0x01, 0x01]
+        );
+    }
+
+    #[test]
+    fn it_round_trips_long_runs() {
+        for length in [253, 254, 255, 508, 600] {
+            let data: Vec<u8> = (0..length).map(|i| (i % 255 + 1) as u8).collect();
+            let mut frame = encoded(&data);
+
+            assert!(!frame.contains(&0));
+
+            frame.push(0);
+            assert_eq!(decoded(&frame, 1024), [Ok(data)]);
+        }
+    }
+
+    #[test]
+    fn it_skips_empty_frames() {
+        assert_eq!(decoded(&[0, 0, 0x02, 0x11, 0], 8), [Ok(vec![0x11])]);
+    }
+
+    #[test]
+    fn it_reports_bad_frames_and_recovers() {
+        assert_eq!(
+            decoded(&[0x04, 0x11, 0, 0x05, 1, 2, 3, 4, 0, 0x02, 0x22, 0], 3),
+            [Err(Error::Truncated), Err(Error::TooLong), Ok(vec![0x22])]
+        );
+    }
+}
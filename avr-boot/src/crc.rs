@@ -0,0 +1,63 @@
+//! Checksums used by the transfer protocols and image formats
+//!
+//! The update functions work a byte at a time, like their counterparts in avr-libc's `<util/crc16.h>`,
+//! so a checksum can be built up as data arrives.
+
+/// Update a CRC-16/XMODEM (polynomial `0x1021`, initial value `0`), with a single byte
+///
+/// Equivalent to avr-libc's `_crc_xmodem_update`.
+pub const fn xmodem_update(crc: u16, data: u8) -> u16 {
+    let mut crc = crc ^ ((data as u16) << 8);
+    let mut bit = 0;
+    while bit < 8 {
+        crc = if crc & 0x8000 != 0 {
+            (crc << 1) ^ 0x1021
+        } else {
+            crc << 1
+        };
+        bit += 1;
+    }
+    crc
+}
+
+/// The CRC-16/XMODEM of a whole slice
+///
+/// # Example
+/// ```rust
+/// use avr_boot::crc;
+///
+/// assert_eq!(0x31c3, crc::xmodem(b"123456789"));
+/// ```
+pub fn xmodem(data: &[u8]) -> u16 {
+    data.iter().fold(0, |crc, byte| xmodem_update(crc, *byte))
+}
+
+/// Update a CRC-32 (polynomial `0x04c11db7` reflected, as used by zlib and Ethernet), with a single byte
+///
+/// `crc` is a finished CRC, so the first call takes `0` and the result of each call can be used as it is.
+/// It is calculated a bit at a time, without a table, to keep it small.
+pub const fn crc32_update(crc: u32, data: u8) -> u32 {
+    let mut crc = !crc ^ data as u32;
+    let mut bit = 0;
+    while bit < 8 {
+        crc = if crc & 1 != 0 {
+            (crc >> 1) ^ 0xedb8_8320
+        } else {
+            crc >> 1
+        };
+        bit += 1;
+    }
+    !crc
+}
+
+/// The CRC-32 of a whole slice
+///
+/// # Example
+/// ```rust
+/// use avr_boot::crc;
+///
+/// assert_eq!(0xcbf4_3926, crc::crc32(b"123456789"));
+/// ```
+pub fn crc32(data: &[u8]) -> u32 {
+    data.iter().fold(0, |crc, byte| crc32_update(crc, *byte))
+}
@@ -0,0 +1,188 @@
+//! Low level API for reading and writing the EEPROM
+//!
+//! Addresses are byte offsets from the start of the EEPROM, and must be below [`EEPROM_SIZE_BYTES`](crate::EEPROM_SIZE_BYTES).
+//! When not compiling for AVR, or on an MCU without EEPROM, every byte reads as `0xff` and writes do nothing.
+
+use crate::spm;
+
+use cfg_if::cfg_if;
+#[allow(unused_imports)]
+use const_env__value::value_from_env;
+#[allow(unused_imports)]
+use core::arch::asm;
+
+#[cfg(all(target_arch = "avr", eeprom))]
+const EEARL: *mut u8 = value_from_env!("AVR_BOOT_EEARL": u16) as *mut u8;
+#[cfg(all(target_arch = "avr", eeprom, eeprom_address_high))]
+const EEARH: *mut u8 = value_from_env!("AVR_BOOT_EEARH": u16) as *mut u8;
+#[cfg(all(target_arch = "avr", eeprom))]
+const EEDR: *mut u8 = value_from_env!("AVR_BOOT_EEDR": u16) as *mut u8;
+#[cfg(all(target_arch = "avr", eeprom))]
+const EECR: *mut u8 = value_from_env!("AVR_BOOT_EECR": u16) as *mut u8;
+#[cfg(all(target_arch = "avr", eeprom))]
+const EECR_ADDR: u16 = value_from_env!("AVR_BOOT_EECR": u16);
+#[cfg(all(target_arch = "avr", eeprom))]
+const EERE: u8 = value_from_env!("AVR_BOOT_EERE": u8);
+#[cfg(all(target_arch = "avr", eeprom))]
+const EEMPE: u8 = value_from_env!("AVR_BOOT_EEMPE": u8);
+#[cfg(all(target_arch = "avr", eeprom))]
+const EEPE: u8 = value_from_env!("AVR_BOOT_EEPE": u8);
+
+/// Read a single byte from the EEPROM
+#[cfg_attr(not(all(target_arch = "avr", eeprom)), allow(unused_variables))]
+pub fn read_byte(address: u16) -> u8 {
+    busy_wait();
+    cfg_if! {
+        if #[cfg(all(target_arch = "avr", eeprom, not(doc)))] {
+            unsafe {
+                set_address(address);
+                core::ptr::write_volatile(EECR, EERE);
+                core::ptr::read_volatile(EEDR)
+            }
+        } else {
+            0xff
+        }
+    }
+}
+
+/// Erase and write a single byte of the EEPROM
+///
+/// Waits for any previous EEPROM write or SPM operation to complete first, but returns while the write is in progress.
+/// Interrupts are disabled for the few cycles of the timed write sequence.
+#[cfg_attr(not(all(target_arch = "avr", eeprom)), allow(unused_variables))]
+pub fn write_byte(address: u16, data: u8) {
+    busy_wait();
+    spm::busy_wait();
+    cfg_if! {
+        if #[cfg(all(target_arch = "avr", eeprom, not(doc)))] {
+            unsafe {
+                set_address(address);
+                core::ptr::write_volatile(EEDR, data);
+                asm!(
+                    "
+                    in      {sreg},     0x3f
+                    cli
+                    sts     {EECR},     {master}
+                    sts     {EECR},     {write}
+                    out     0x3f,       {sreg}
+                    ",
+                    sreg = out(reg) _,
+                    master = in(reg) EEMPE,
+                    write = in(reg) EEMPE | EEPE,
+                    EECR = const EECR_ADDR,
+                );
+            }
+        }
+    }
+}
+
+/// Write a byte only if it differs from the current contents, saving a write cycle
+pub fn update_byte(address: u16, data: u8) {
+    if read_byte(address) != data {
+        write_byte(address, data);
+    }
+}
+
+/// Wait for the current EEPROM write to complete
+pub fn busy_wait() {
+    cfg_if! {
+        if #[cfg(all(target_arch = "avr", eeprom, not(doc)))] {
+            while unsafe { core::ptr::read_volatile(EECR) } & EEPE != 0 {}
+        }
+    }
+}
+
+#[cfg(all(target_arch = "avr", eeprom, not(doc)))]
+unsafe fn set_address(address: u16) {
+    let [low, _high] = address.to_le_bytes();
+    unsafe {
+        #[cfg(eeprom_address_high)]
+        core::ptr::write_volatile(EEARH, _high);
+        core::ptr::write_volatile(EEARL, low);
+    }
+}
+
+/// Byte access to an EEPROM, mirroring the functions in this module
+///
+/// The protocol engines access EEPROM through this trait, so they can be tested on the host.
+pub trait Eeprom {
+    /// Read a single byte
+    fn read_byte(&self, address: u16) -> u8;
+
+    /// Erase and write a single byte
+    fn write_byte(&mut self, address: u16, data: u8);
+
+    /// Write a byte only if it differs from the current contents
+    fn update_byte(&mut self, address: u16, data: u8) {
+        if self.read_byte(address) != data {
+            self.write_byte(address, data);
+        }
+    }
+}
+
+impl<E: Eeprom> Eeprom for &mut E {
+    fn read_byte(&self, address: u16) -> u8 {
+        (**self).read_byte(address)
+    }
+
+    fn write_byte(&mut self, address: u16, data: u8) {
+        (**self).write_byte(address, data);
+    }
+}
+
+/// The MCU's own EEPROM
+#[derive(Copy, Clone, Default, Debug)]
+pub struct InternalEeprom;
+
+impl Eeprom for InternalEeprom {
+    fn read_byte(&self, address: u16) -> u8 {
+        read_byte(address)
+    }
+
+    fn write_byte(&mut self, address: u16, data: u8) {
+        write_byte(address, data);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod mock {
+    use super::*;
+    use crate::EEPROM_SIZE_BYTES;
+    use std::{vec, vec::Vec};
+
+    /// An in-memory EEPROM, for testing on the host
+    pub(crate) struct MockEeprom {
+        pub memory: Vec<u8>,
+        pub writes: usize,
+    }
+
+    impl MockEeprom {
+        pub fn new() -> Self {
+            Self {
+                memory: vec![0xff; EEPROM_SIZE_BYTES as usize],
+                writes: 0,
+            }
+        }
+    }
+
+    impl Eeprom for MockEeprom {
+        fn read_byte(&self, address: u16) -> u8 {
+            self.memory[address as usize]
+        }
+
+        fn write_byte(&mut self, address: u16, data: u8) {
+            self.memory[address as usize] = data;
+            self.writes += 1;
+        }
+    }
+
+    #[test]
+    fn it_only_updates_changed_bytes() {
+        let mut eeprom = MockEeprom::new();
+
+        eeprom.update_byte(4, 0x12);
+        eeprom.update_byte(4, 0x12);
+
+        assert_eq!((eeprom.memory[4], eeprom.writes), (0x12, 1));
+    }
+}
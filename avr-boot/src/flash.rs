@@ -0,0 +1,182 @@
+//! Program memory backends
+//!
+//! The protocol engines and update helpers in this crate program flash through the [`Flash`] trait rather than
+//! calling [`spm`] directly. On the MCU, use [`Spm`]. On the host, a simulated flash can be swapped in, so the
+//! same logic can be tested without any hardware.
+
+use crate::{Address, DataPage, SPM_PAGESIZE_WORDS, lpm, spm};
+
+/// Page based access to program memory, mirroring the functions in [`spm`] and [`lpm`]
+pub trait Flash {
+    /// Erase the page containing `address`
+    fn erase_page(&mut self, address: Address);
+
+    /// Write a word to the page buffer. Only the PCWORD part of the address is used.
+    fn fill_page(&mut self, address: Address, data: u16);
+
+    /// Write the page buffer to the page containing `address`
+    fn write_page(&mut self, address: Address);
+
+    /// Re-enable the RWW section after programming, so it can be read again
+    fn rww_enable(&mut self);
+
+    /// Read a single byte of program memory
+    fn read_byte(&self, address: Address) -> u8;
+
+    /// Fill `buf` with program memory, starting at `address`
+    fn read_into(&self, address: Address, buf: &mut [u8]) {
+        let mut address = address;
+        for byte in buf.iter_mut() {
+            *byte = self.read_byte(address);
+            address = address.checked_add(1).unwrap_or(address);
+        }
+    }
+
+    /// Store a whole page into program memory by erasing the page, filling the buffer,
+    /// and writing the buffer to the program memory.
+    fn store_page(&mut self, address: Address, data: &DataPage) {
+        let page_address = address.into_page_aligned();
+
+        self.erase_page(page_address);
+        for (i, word) in data.iter().enumerate() {
+            self.fill_page(Address::from((i * 2) as u16), *word);
+        }
+        self.write_page(page_address);
+        self.rww_enable();
+    }
+
+    /// Read a whole page of program memory
+    fn read_page(&self, address: Address) -> DataPage {
+        let page_address = address.into_page_aligned();
+        let mut page = DataPage([0xffff; SPM_PAGESIZE_WORDS]);
+
+        for (i, word) in page.0.iter_mut().enumerate() {
+            let offset = (i * 2) as u32;
+            let low = page_address
+                .checked_add(offset)
+                .map_or(0xff, |a| self.read_byte(a));
+            let high = page_address
+                .checked_add(offset + 1)
+                .map_or(0xff, |a| self.read_byte(a));
+            *word = u16::from_le_bytes([low, high]);
+        }
+
+        page
+    }
+}
+
+/// The MCU's own program memory, written using the `spm` instruction
+///
+/// # Example
+/// ```no_run
+/// use avr_boot::{Address, DataPage, flash::{Flash, Spm}};
+///
+/// let data = DataPage(core::array::from_fn(|_| 0x69));
+/// Spm.store_page(Address::from(0x1000u16), &data);
+/// ```
+#[derive(Copy, Clone, Default, Debug)]
+pub struct Spm;
+
+impl Flash for Spm {
+    fn erase_page(&mut self, address: Address) {
+        spm::erase_page(address);
+    }
+
+    fn fill_page(&mut self, address: Address, data: u16) {
+        spm::fill_page(address, data);
+    }
+
+    fn write_page(&mut self, address: Address) {
+        spm::write_page(address);
+    }
+
+    fn rww_enable(&mut self) {
+        spm::rww_enable();
+    }
+
+    fn read_byte(&self, address: Address) -> u8 {
+        lpm::read_byte(address)
+    }
+
+    fn read_into(&self, address: Address, buf: &mut [u8]) {
+        lpm::read_into(address, buf);
+    }
+
+    fn store_page(&mut self, address: Address, data: &DataPage) {
+        spm::store_page(address, data);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod mock {
+    use super::*;
+    use crate::{FLASH_SIZE_BYTES, SPM_PAGESIZE_BYTES};
+    use std::{vec, vec::Vec};
+
+    /// A simple in-memory flash, for testing on the host
+    ///
+    /// Like the real thing, writes can only clear bits, so a page must be erased before it is written.
+    pub(crate) struct MockFlash {
+        pub memory: Vec<u8>,
+        buffer: [u16; SPM_PAGESIZE_WORDS],
+        pub erases: usize,
+        pub writes: usize,
+    }
+
+    impl MockFlash {
+        pub fn new() -> Self {
+            Self {
+                memory: vec![0xff; FLASH_SIZE_BYTES as usize],
+                buffer: [0xffff; SPM_PAGESIZE_WORDS],
+                erases: 0,
+                writes: 0,
+            }
+        }
+
+        pub fn page(&self, address: u32) -> &[u8] {
+            let start = address as usize;
+            &self.memory[start..start + SPM_PAGESIZE_BYTES]
+        }
+    }
+
+    impl Flash for MockFlash {
+        fn erase_page(&mut self, address: Address) {
+            let start = u32::from(address.into_page_aligned()) as usize;
+            self.memory[start..start + SPM_PAGESIZE_BYTES].fill(0xff);
+            self.erases += 1;
+        }
+
+        fn fill_page(&mut self, address: Address, data: u16) {
+            self.buffer[address.word() as usize / 2] = data;
+        }
+
+        fn write_page(&mut self, address: Address) {
+            let start = u32::from(address.into_page_aligned()) as usize;
+            for (i, word) in self.buffer.iter().enumerate() {
+                let [low, high] = word.to_le_bytes();
+                self.memory[start + i * 2] &= low;
+                self.memory[start + i * 2 + 1] &= high;
+            }
+            self.buffer = [0xffff; SPM_PAGESIZE_WORDS];
+            self.writes += 1;
+        }
+
+        fn rww_enable(&mut self) {}
+
+        fn read_byte(&self, address: Address) -> u8 {
+            self.memory[u32::from(address) as usize]
+        }
+    }
+
+    #[test]
+    fn it_stores_and_reads_back_a_page() {
+        let mut flash = MockFlash::new();
+        let address = Address::from(0x1_0000u32);
+        let data = DataPage(core::array::from_fn(|i| i as u16));
+
+        flash.store_page(address, &data);
+
+        assert_eq!(*flash.read_page(address), *data);
+        assert_eq!((flash.erases, flash.writes), (1, 1));
+    }
+}
@@ -0,0 +1,481 @@
+//! Streaming Intel HEX decoder
+//!
+//! Decodes a HEX file one character at a time, as it arrives over a serial port, with no allocation.
+//! The [`Decoder`] turns characters into [`Record`]s, and the [`Loader`] writes the data records straight to flash
+//! through a [`FlashWriter`](crate::FlashWriter), so a HEX file can be uploaded from a terminal program with no host tool.
+//!
+//! Extended segment (02) and extended linear (04) address records set the upper address bits, which end up in the
+//! RAMPZ part of the [`Address`], so images for parts with more than 64k of flash can be loaded. Addresses beyond
+//! 24 bits are rejected. As the specification requires, a data record which runs past the end of a 64k segment
+//! wraps around to the start of the same segment. The [`Loader`] refuses those, as they go back to a page it has
+//! already written, and avr-objcopy never writes them.
+//!
+//! Nothing can be received while a page is programmed, so the [`Loader`] uses software flow control: it sends
+//! [`XOFF`](crate::protocol::XOFF) before writing a page, and [`XON`](crate::protocol::XON) afterwards. Set the
+//! terminal program to XON/XOFF flow control.
+//!
+//! # Example
+//! ```no_run
+//! use avr_boot::{FlashRange, ihex::Loader, flash::Spm, protocol::{Serial, Status}};
+//!
+//! # fn run(mut serial: impl Serial) -> Result<(), ()> {
+//! let mut buffer = [0; 32];
+//! let mut loader = Loader::new(Spm, FlashRange::application(4096), &mut buffer);
+//! loop {
+//!     let character = serial.read().map_err(|_| ())?;
+//!     match loader.push(&mut serial, character) {
+//!         Ok(Status::Continue) => {}
+//!         Ok(Status::Done) => break,
+//!         Err(_) => { /* report the error, and start again */ }
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::flash::Flash;
+use crate::protocol::{Serial, Status};
+use crate::writer::{RecordWriter, Refused};
+use crate::{Address, FlashRange};
+
+const DATA: u8 = 0x00;
+const END_OF_FILE: u8 = 0x01;
+const EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+const START_SEGMENT_ADDRESS: u8 = 0x03;
+const EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+const START_LINEAR_ADDRESS: u8 = 0x05;
+
+/// Length of the byte count, address and record type at the start of each record
+const HEADER_LENGTH: usize = 4;
+
+/// Size of the segment a record's offset wraps around within
+const SEGMENT_LENGTH: u32 = 0x1_0000;
+
+/// The address after the last one an [`Address`] can hold
+const ADDRESS_LIMIT: u32 = 0x100_0000;
+
+/// Why a record couldn't be decoded or loaded
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Error {
+    /// A character other than a hex digit was found within a record
+    InvalidCharacter(u8),
+    /// The record checksum didn't match
+    Checksum,
+    /// A data record was longer than the buffer
+    RecordTooLong,
+    /// A record had the wrong length for its type, or an unknown type
+    InvalidRecord,
+    /// Data was addressed beyond 24 bits, or outside the application range
+    OutOfRange(u32),
+    /// Data went back to a page which had already been written
+    OutOfOrder(u32),
+}
+
+impl From<Refused> for Error {
+    fn from(refused: Refused) -> Self {
+        match refused {
+            Refused::OutOfRange(address) => Error::OutOfRange(address),
+            Refused::OutOfOrder(address) => Error::OutOfOrder(address),
+        }
+    }
+}
+
+/// A decoded record
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Record<'a> {
+    /// Data to be written at `address`, with any extended address already applied
+    Data {
+        /// The address of the first byte
+        address: Address,
+        /// The data bytes, up to the end of the 64k segment
+        data: &'a [u8],
+        /// Any bytes past the end of the segment, with the address they wrap around to at its start
+        wrapped: Option<(Address, &'a [u8])>,
+    },
+    /// The execution start address, from a start linear address (05) or start segment address (03) record
+    StartAddress(u32),
+    /// The end of the file
+    EndOfFile,
+}
+
+/// Intel HEX decoder
+///
+/// Characters between records, such as line endings, are ignored.
+pub struct Decoder<'a> {
+    buffer: &'a mut [u8],
+    header: [u8; HEADER_LENGTH],
+    /// Number of bytes decoded in the current record, or `None` while waiting for the next `:`
+    position: Option<usize>,
+    high_nibble: Option<u8>,
+    checksum: u8,
+    base: u32,
+}
+
+impl<'a> Decoder<'a> {
+    /// Create a new decoder, using `buffer` to hold each record's data until its checksum has been checked
+    ///
+    /// avr-objcopy writes 16 bytes per record, and other tools commonly use up to 32.
+    pub fn new(buffer: &'a mut [u8]) -> Self {
+        Self {
+            buffer,
+            header: [0; HEADER_LENGTH],
+            position: None,
+            high_nibble: None,
+            checksum: 0,
+            base: 0,
+        }
+    }
+
+    /// Decode a single character, returning a record when one is complete
+    ///
+    /// After an error, the rest of the record is skipped.
+    pub fn push(&mut self, character: u8) -> Result<Option<Record<'_>>, Error> {
+        let Some(position) = self.position else {
+            if character == b':' {
+                self.start_record();
+            }
+            return Ok(None);
+        };
+
+        let Some(nibble) = (character as char).to_digit(16) else {
+            self.position = None;
+            return Err(Error::InvalidCharacter(character));
+        };
+
+        let Some(high) = self.high_nibble.take() else {
+            self.high_nibble = Some(nibble as u8);
+            return Ok(None);
+        };
+
+        let byte = (high << 4) | nibble as u8;
+        self.checksum = self.checksum.wrapping_add(byte);
+        self.position = Some(position + 1);
+
+        if position < HEADER_LENGTH {
+            self.header[position] = byte;
+            if position == 0 && byte as usize > self.buffer.len() {
+                self.position = None;
+                return Err(Error::RecordTooLong);
+            }
+            return Ok(None);
+        }
+
+        if position < HEADER_LENGTH + self.header[0] as usize {
+            self.buffer[position - HEADER_LENGTH] = byte;
+            Ok(None)
+        } else {
+            self.position = None;
+            if self.checksum != 0 {
+                return Err(Error::Checksum);
+            }
+            self.finish_record()
+        }
+    }
+
+    fn start_record(&mut self) {
+        self.position = Some(0);
+        self.high_nibble = None;
+        self.checksum = 0;
+    }
+
+    fn finish_record(&mut self) -> Result<Option<Record<'_>>, Error> {
+        let [length, offset_high, offset_low, record_type] = self.header;
+        let offset = u16::from_be_bytes([offset_high, offset_low]);
+        let data = &self.buffer[..length as usize];
+
+        let record = match (record_type, data) {
+            (DATA, _) => {
+                let wraps_at = (SEGMENT_LENGTH - offset as u32).min(data.len() as u32);
+                let (data, wrapped) = data.split_at(wraps_at as usize);
+                Record::Data {
+                    address: checked_address(self.base + offset as u32, data.len())?,
+                    data,
+                    wrapped: match wrapped {
+                        [] => None,
+                        _ => Some((checked_address(self.base, wrapped.len())?, wrapped)),
+                    },
+                }
+            }
+            (END_OF_FILE, []) => Record::EndOfFile,
+            (EXTENDED_SEGMENT_ADDRESS, [high, low]) => {
+                self.base = (u16::from_be_bytes([*high, *low]) as u32) << 4;
+                return Ok(None);
+            }
+            (EXTENDED_LINEAR_ADDRESS, [high, low]) => {
+                self.base = (u16::from_be_bytes([*high, *low]) as u32) << 16;
+                return Ok(None);
+            }
+            (START_SEGMENT_ADDRESS, [cs_high, cs_low, ip_high, ip_low]) => {
+                let segment = u16::from_be_bytes([*cs_high, *cs_low]) as u32;
+                let pointer = u16::from_be_bytes([*ip_high, *ip_low]) as u32;
+                Record::StartAddress((segment << 4) + pointer)
+            }
+            (START_LINEAR_ADDRESS, [a, b, c, d]) => {
+                Record::StartAddress(u32::from_be_bytes([*a, *b, *c, *d]))
+            }
+            _ => return Err(Error::InvalidRecord),
+        };
+
+        Ok(Some(record))
+    }
+}
+
+/// The address of `length` bytes at `address`, if they all fit in 24 bits
+fn checked_address(address: u32, length: usize) -> Result<Address, Error> {
+    match address.checked_add(length as u32) {
+        Some(end) if address < ADDRESS_LIMIT && end <= ADDRESS_LIMIT => Ok(Address::from(address)),
+        _ => Err(Error::OutOfRange(address)),
+    }
+}
+
+/// Loads a HEX file into flash, as it is decoded
+///
+/// Records must arrive in ascending address order, as written by avr-objcopy, because each page is written as soon as
+/// the data moves on to the next one. A record which goes back to a page already written is refused.
+pub struct Loader<'a, F> {
+    decoder: Decoder<'a>,
+    writer: RecordWriter<F>,
+}
+
+impl<'a, F: Flash> Loader<'a, F> {
+    /// Create a new loader, writing to `application` in `flash`, and using `buffer` to decode records
+    pub fn new(flash: F, application: FlashRange, buffer: &'a mut [u8]) -> Self {
+        Self {
+            decoder: Decoder::new(buffer),
+            writer: RecordWriter::new(flash, application),
+        }
+    }
+
+    /// Decode a single character received on `serial`, writing out any complete data record
+    ///
+    /// A page takes several milliseconds to program, during which nothing is received, so
+    /// [`XOFF`](crate::protocol::XOFF) is sent on `serial` before a page is written, and
+    /// [`XON`](crate::protocol::XON) once it's done. The sender must honour them.
+    ///
+    /// Returns [`Status::Done`] once the end of file record has been loaded, and everything has been written.
+    pub fn push<S: Serial>(&mut self, serial: &mut S, character: u8) -> Result<Status, Error> {
+        match self.decoder.push(character)? {
+            Some(Record::Data {
+                address,
+                data,
+                wrapped,
+            }) => {
+                if let Some((address, _)) = wrapped {
+                    return Err(Error::OutOfOrder(address.into()));
+                }
+                self.writer.write(serial, address, data)?;
+            }
+            Some(Record::EndOfFile) => {
+                self.writer.finish(serial);
+                return Ok(Status::Done);
+            }
+            Some(Record::StartAddress(_)) | None => {}
+        }
+
+        Ok(Status::Continue)
+    }
+
+    /// Flush any buffered data, and give back the flash backend
+    pub fn release(self) -> F {
+        self.writer.release()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::mock::MockFlash;
+    use crate::protocol::mock::MockSerial;
+    use crate::protocol::{XOFF, XON};
+    use std::vec::Vec;
+
+    /// The address and data of each record, or the error
+    type Decoded = Result<(Option<Address>, Vec<u8>), Error>;
+
+    fn decode(text: &[u8]) -> Vec<Decoded> {
+        let mut buffer = [0; 16];
+        let mut decoder = Decoder::new(&mut buffer);
+        let mut records = Vec::new();
+        for character in text {
+            match decoder.push(*character) {
+                Ok(Some(Record::Data {
+                    address,
+                    data,
+                    wrapped,
+                })) => {
+                    records.push(Ok((Some(address), data.to_vec())));
+                    if let Some((address, data)) = wrapped {
+                        records.push(Ok((Some(address), data.to_vec())));
+                    }
+                }
+                Ok(Some(Record::StartAddress(address))) => {
+                    records.push(Ok((None, address.to_be_bytes().to_vec())))
+                }
+                Ok(Some(Record::EndOfFile)) => records.push(Ok((None, Vec::new()))),
+                Ok(None) => {}
+                Err(e) => records.push(Err(e)),
+            }
+        }
+        records
+    }
+
+    fn load(text: &[u8]) -> (Result<Status, Error>, MockFlash) {
+        let mut buffer = [0; 16];
+        let mut serial = MockSerial::new(&[]);
+        let mut loader = Loader::new(
+            MockFlash::new(),
+            FlashRange::new(0u16, 0x1_8000u32),
+            &mut buffer,
+        );
+        for character in text {
+            match loader.push(&mut serial, *character) {
+                Ok(Status::Continue) => {}
+                result => return (result, loader.release()),
+            }
+        }
+        (Ok(Status::Continue), loader.release())
+    }
+
+    #[test]
+    fn it_decodes_data_records() {
+        let records = decode(b":100000000C9434000C9446000C9446000C9446006A\r\n:00000001FF\r\n");
+
+        let data = [
+            0x0c, 0x94, 0x34, 0x00, 0x0c, 0x94, 0x46, 0x00, 0x0c, 0x94, 0x46, 0x00, 0x0c, 0x94,
+            0x46, 0x00,
+        ];
+        assert_eq!(
+            records,
+            [
+                Ok((Some(Address::from(0u16)), data.to_vec())),
+                Ok((None, Vec::new()))
+            ]
+        );
+    }
+
+    #[test]
+    fn it_applies_extended_addresses() {
+        let records =
+            decode(b":020000040001F9\n:02100000AABB89\n:020000021000EC\n:02000000CCDD55\n");
+
+        assert_eq!(
+            records,
+            [
+                Ok((Some(Address::from(0x1_1000u32)), vec![0xaa, 0xbb])),
+                Ok((Some(Address::from(0x1_0000u32)), vec![0xcc, 0xdd]))
+            ]
+        );
+    }
+
+    #[test]
+    fn it_wraps_a_record_within_its_segment() {
+        let records = decode(b":020000040001F9\n:04FFFE0001020304F5\n");
+
+        assert_eq!(
+            records,
+            [
+                Ok((Some(Address::from(0x1_fffeu32)), vec![1, 2])),
+                Ok((Some(Address::from(0x1_0000u32)), vec![3, 4]))
+            ]
+        );
+    }
+
+    #[test]
+    fn it_rejects_addresses_beyond_24_bits() {
+        let records = decode(b":020000040100F9\n:0100000055AA\n");
+
+        assert_eq!(records, [Err(Error::OutOfRange(0x100_0000))]);
+    }
+
+    #[test]
+    fn it_decodes_the_start_address() {
+        let records = decode(b":0400000500000C34B7\n");
+
+        assert_eq!(records, [Ok((None, vec![0x00, 0x00, 0x0c, 0x34]))]);
+    }
+
+    #[test]
+    fn it_rejects_bad_records_and_recovers() {
+        let records = decode(b":0100000055AB\n:01000000xx\n:110000000000000000000000000000000000000000EF\n:0100000055AA\n");
+
+        assert_eq!(
+            records,
+            [
+                Err(Error::Checksum),
+                Err(Error::InvalidCharacter(b'x')),
+                Err(Error::RecordTooLong),
+                Ok((Some(Address::from(0u16)), vec![0x55]))
+            ]
+        );
+    }
+
+    #[test]
+    fn it_loads_into_flash() {
+        let (result, flash) =
+            load(b":02FFFE000102FE\n:020000040001F9\n:020000000304F7\n:00000001FF\n");
+
+        assert_eq!(result, Ok(Status::Done));
+        assert_eq!(&flash.memory[0xfffe..0x1_0002], &[1, 2, 3, 4]);
+        assert_eq!(flash.writes, 2);
+    }
+
+    #[test]
+    fn it_refuses_a_wrapped_record_rather_than_erase_the_start_of_the_segment() {
+        let (result, flash) = load(b":02000000AABB99\n:04FFFE0001020304F5\n:00000001FF\n");
+
+        assert_eq!(result, Err(Error::OutOfOrder(0)));
+        assert_eq!(&flash.memory[..2], &[0xaa, 0xbb]);
+        assert_eq!(flash.memory[0xfffe], 0xff);
+    }
+
+    #[test]
+    fn it_refuses_records_going_back_to_a_written_page() {
+        let (result, flash) = load(b":02010000CCDD54\n:02000000AABB99\n");
+
+        assert_eq!(result, Err(Error::OutOfOrder(0)));
+        assert_eq!(&flash.memory[0x100..0x102], &[0xcc, 0xdd]);
+        assert_eq!(flash.writes, 1);
+    }
+
+    #[test]
+    fn it_pauses_the_sender_while_writing_pages() {
+        let text = b":02FFFE000102FE\n:020000040001F9\n:020000000304F7\n:00000001FF\n";
+        let mut buffer = [0; 16];
+        let mut serial = MockSerial::new(&[]);
+        let mut loader = Loader::new(
+            MockFlash::new(),
+            FlashRange::new(0u16, 0x1_8000u32),
+            &mut buffer,
+        );
+
+        // Record which characters XOFF and XON were sent after, and how many pages were written by then
+        let mut events = Vec::new();
+        for (index, character) in text.iter().enumerate() {
+            let sent = serial.output.len();
+            loader.push(&mut serial, *character).unwrap();
+            if serial.output.len() > sent {
+                events.push((index, serial.output[sent..].to_vec()));
+            }
+        }
+
+        let ends: Vec<usize> = text
+            .iter()
+            .enumerate()
+            .filter(|(_, character)| **character == b'\n')
+            .map(|(index, _)| index - 1)
+            .collect();
+        assert_eq!(
+            events,
+            [(ends[2], vec![XOFF, XON]), (ends[3], vec![XOFF, XON])]
+        );
+        assert_eq!(loader.release().writes, 2);
+    }
+
+    #[test]
+    fn it_refuses_data_outside_the_application() {
+        let (result, flash) = load(b":020000040001F9\n:0280000001027B\n");
+
+        assert_eq!(result, Err(Error::OutOfRange(0x1_8000)));
+        assert_eq!(flash.writes, 0);
+    }
+}
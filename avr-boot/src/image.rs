@@ -0,0 +1,427 @@
+//! Firmware image container format
+//!
+//! An image is a fixed size [`Header`] followed by the application, which is loaded at the header's address.
+//! The header says which device the image was built for, so a bootloader can refuse an image for the wrong MCU, one
+//! built for a different page size, or one which was cut short, before it erases a single page.
+//!
+//! | Offset | Size | Field                                                      |
+//! |-------:|-----:|------------------------------------------------------------|
+//! |      0 |    4 | [`MAGIC`], `AVRB`                                          |
+//! |      4 |    1 | Format [`VERSION`]                                         |
+//! |      5 |    3 | Device signature                                           |
+//! |      8 |    2 | Page size                                                  |
+//! |     10 |    2 | Reserved, zero                                             |
+//! |     12 |    4 | Load address                                               |
+//! |     16 |    4 | Length of the application                                  |
+//! |     20 |    4 | Application version                                        |
+//! |     24 |    4 | CRC-32 of the application                                  |
+//! |     28 |    4 | CRC-32 of the header up to here                            |
+//!
+//! Multi-byte fields are little endian. The header has its own CRC, so it can be trusted as soon as it arrives,
+//! before the rest of the image.
+//!
+//! # Validating the application at boot
+//! Once an application has been written, its header can be stored as a trailer in the last [`HEADER_LENGTH`] bytes
+//! of the application range, with [`write_trailer`] or by the host tool. At reset, [`validate`] reads the trailer
+//! back and checks the CRC of the application in flash, so the bootloader only starts an application which was
+//! completely written. An update which was interrupted part way through leaves either no trailer, or one which no
+//! longer matches, and the bootloader stays put, ready for another go.
+//!
+//! ```no_run
+//! use avr_boot::{FlashRange, flash::Spm, image};
+//!
+//! let application = FlashRange::application(4096);
+//! if image::validate(&Spm, application).is_ok() {
+//!     // jump to the application
+//! }
+//! // otherwise, wait for a new one
+//! ```
+//!
+//! # Example
+//! ```rust
+//! use avr_boot::{FlashRange, image::{self, Header}};
+//!
+//! let application = [0x0c, 0x94, 0x34, 0x00];
+//! let header = Header::for_target(0u16, &application, 3);
+//!
+//! let mut packed = header.to_bytes().to_vec();
+//! packed.extend(application);
+//!
+//! let (header, payload) = image::parse(&packed).unwrap();
+//! header.check_device(FlashRange::application(4096)).unwrap();
+//! assert_eq!(payload, application);
+//! assert_eq!(header.app_version, 3);
+//! ```
+
+use crate::flash::Flash;
+use crate::{Address, FlashRange, SIGNATURE, SPM_PAGESIZE_BYTES, checksum, crc, spm};
+
+/// The first 4 bytes of every image
+pub const MAGIC: [u8; 4] = *b"AVRB";
+
+/// The version of the header layout written by [`Header::to_bytes`]
+pub const VERSION: u8 = 1;
+
+/// Length of the header, which the application follows
+pub const HEADER_LENGTH: usize = 32;
+
+/// Why an image was rejected
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Error {
+    /// Fewer bytes than the header, or the header's length, were given
+    Truncated,
+    /// The image doesn't start with [`MAGIC`]
+    BadMagic,
+    /// The header was written by a newer version of the format
+    UnsupportedVersion(u8),
+    /// The header is corrupt
+    HeaderCrc,
+    /// The image was built for the device with this signature
+    WrongDevice([u8; 3]),
+    /// The image was built for a device with this page size
+    WrongPageSize(u16),
+    /// The application doesn't fit in the application range
+    OutOfRange,
+    /// The application is corrupt
+    Crc,
+}
+
+/// Describes the application in an image
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Header {
+    /// Signature of the device the image was built for
+    pub signature: [u8; 3],
+    /// Page size of the device the image was built for
+    pub page_size: u16,
+    /// Where the application is loaded
+    pub address: Address,
+    /// Length of the application in bytes
+    pub length: u32,
+    /// Version of the application, for the application's own use
+    pub app_version: u32,
+    /// CRC-32 of the application
+    pub crc: u32,
+}
+
+impl Header {
+    /// Describe `application`, to be loaded at `address` on a device with `signature` and `page_size`
+    pub fn new(
+        signature: [u8; 3],
+        page_size: u16,
+        address: impl Into<Address>,
+        application: &[u8],
+        app_version: u32,
+    ) -> Self {
+        Self {
+            signature,
+            page_size,
+            address: address.into(),
+            length: application.len() as u32,
+            app_version,
+            crc: crc::crc32(application),
+        }
+    }
+
+    /// Describe `application`, to be loaded at `address` on the current MCU target
+    pub fn for_target(address: impl Into<Address>, application: &[u8], app_version: u32) -> Self {
+        Self::new(
+            SIGNATURE,
+            SPM_PAGESIZE_BYTES as u16,
+            address,
+            application,
+            app_version,
+        )
+    }
+
+    /// Parse the header at the start of `bytes`, checking its magic number, version and CRC
+    pub fn parse(bytes: &[u8]) -> Result<Header, Error> {
+        let header: &[u8; HEADER_LENGTH] = bytes
+            .get(..HEADER_LENGTH)
+            .and_then(|header| header.try_into().ok())
+            .ok_or(Error::Truncated)?;
+
+        if header[..4] != MAGIC {
+            return Err(Error::BadMagic);
+        }
+        if header[4] != VERSION {
+            return Err(Error::UnsupportedVersion(header[4]));
+        }
+        if crc::crc32(&header[..28]) != word(header, 28) {
+            return Err(Error::HeaderCrc);
+        }
+        // Addresses are only 24 bits, so anything bigger can't be in range
+        if header[15] != 0 {
+            return Err(Error::OutOfRange);
+        }
+
+        Ok(Header {
+            signature: [header[5], header[6], header[7]],
+            page_size: u16::from_le_bytes([header[8], header[9]]),
+            address: Address::from(word(header, 12)),
+            length: word(header, 16),
+            app_version: word(header, 20),
+            crc: word(header, 24),
+        })
+    }
+
+    /// The header as it is stored at the start of an image
+    pub fn to_bytes(&self) -> [u8; HEADER_LENGTH] {
+        let mut header = [0; HEADER_LENGTH];
+        header[..4].copy_from_slice(&MAGIC);
+        header[4] = VERSION;
+        header[5..8].copy_from_slice(&self.signature);
+        header[8..10].copy_from_slice(&self.page_size.to_le_bytes());
+        header[12..16].copy_from_slice(&u32::from(self.address).to_le_bytes());
+        header[16..20].copy_from_slice(&self.length.to_le_bytes());
+        header[20..24].copy_from_slice(&self.app_version.to_le_bytes());
+        header[24..28].copy_from_slice(&self.crc.to_le_bytes());
+        let crc = crc::crc32(&header[..28]);
+        header[28..].copy_from_slice(&crc.to_le_bytes());
+        header
+    }
+
+    /// Check the image was built for a device with `signature` and `page_size`, and that it fits in `application`
+    pub fn check_target(
+        &self,
+        signature: [u8; 3],
+        page_size: usize,
+        application: FlashRange,
+    ) -> Result<(), Error> {
+        if self.signature != signature {
+            return Err(Error::WrongDevice(self.signature));
+        }
+        if self.page_size as usize != page_size {
+            return Err(Error::WrongPageSize(self.page_size));
+        }
+
+        let end = u32::from(self.address).checked_add(self.length);
+        if self.address < application.start || end.is_none_or(|end| end > application.end.into()) {
+            return Err(Error::OutOfRange);
+        }
+        Ok(())
+    }
+
+    /// Check the image was built for this device, reading its signature with SIGRD, and fits in `application`
+    pub fn check_device(&self, application: FlashRange) -> Result<(), Error> {
+        self.check_target(spm::read_signature(), SPM_PAGESIZE_BYTES, application)
+    }
+
+    /// Check `application` has the length and CRC the header gives
+    pub fn check_application(&self, application: &[u8]) -> Result<(), Error> {
+        if application.len() != self.length as usize {
+            return Err(Error::Truncated);
+        }
+        if crc::crc32(application) != self.crc {
+            return Err(Error::Crc);
+        }
+        Ok(())
+    }
+
+    /// Check the application in flash has the CRC the header gives
+    pub fn check_flash(&self, flash: &impl Flash) -> Result<(), Error> {
+        self.check_flash_at(flash, self.address)
+    }
+
+    /// Check a copy of the application stored at `address`, rather than where it is loaded, has the CRC the header
+    /// gives
+    pub fn check_flash_at(&self, flash: &impl Flash, address: Address) -> Result<(), Error> {
+        let end = address.checked_add(self.length).ok_or(Error::OutOfRange)?;
+
+        if checksum::crc32(flash, FlashRange::new(address, end)) != self.crc {
+            return Err(Error::Crc);
+        }
+        Ok(())
+    }
+}
+
+/// Split a whole image into its header and application, checking both are intact
+///
+/// Anything after the application, such as padding added by a transfer protocol, is ignored.
+pub fn parse(image: &[u8]) -> Result<(Header, &[u8]), Error> {
+    let header = Header::parse(image)?;
+    let application = image
+        .get(HEADER_LENGTH..HEADER_LENGTH + header.length as usize)
+        .ok_or(Error::Truncated)?;
+    header.check_application(application)?;
+    Ok((header, application))
+}
+
+/// Where the trailer is stored: the last [`HEADER_LENGTH`] bytes of `application`
+pub fn trailer_address(application: FlashRange) -> Address {
+    Address::from(u32::from(application.end) - HEADER_LENGTH as u32)
+}
+
+/// Read the header stored as a trailer at the end of `application`
+pub fn read_trailer(flash: &impl Flash, application: FlashRange) -> Result<Header, Error> {
+    let address = trailer_address(application);
+    let mut header = [0; HEADER_LENGTH];
+    for (i, byte) in header.iter_mut().enumerate() {
+        *byte = flash.read_byte(address.checked_add(i as u32).ok_or(Error::OutOfRange)?);
+    }
+    Header::parse(&header)
+}
+
+/// Store `header` as the trailer at the end of `application`, once the application it describes has been written
+///
+/// The rest of the last page is kept. `application` must end on a page boundary.
+pub fn write_trailer(flash: &mut impl Flash, application: FlashRange, header: &Header) {
+    let address = trailer_address(application);
+    let page = address.into_page_aligned();
+    let offset = (u32::from(address) - u32::from(page)) as usize;
+
+    let mut bytes = [0xff; SPM_PAGESIZE_BYTES];
+    for (i, byte) in bytes[..offset].iter_mut().enumerate() {
+        *byte = flash.read_byte(Address::from(u32::from(page) + i as u32));
+    }
+    bytes[offset..offset + HEADER_LENGTH].copy_from_slice(&header.to_bytes());
+    flash.store_page(page, (&bytes).into());
+}
+
+/// Check the application in flash against the trailer at the end of `application`
+///
+/// The trailer must be intact, be for this device, describe an application which lies below it, and have the same
+/// CRC as the flash. The bootloader should only start the application if this succeeds.
+pub fn validate(flash: &impl Flash, application: FlashRange) -> Result<Header, Error> {
+    let header = read_trailer(flash, application)?;
+    header.check_device(FlashRange::new(
+        application.start,
+        trailer_address(application),
+    ))?;
+    header.check_flash(flash)?;
+    Ok(header)
+}
+
+fn word(header: &[u8; HEADER_LENGTH], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        header[offset],
+        header[offset + 1],
+        header[offset + 2],
+        header[offset + 3],
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::mock::MockFlash;
+    use std::vec::Vec;
+
+    const APPLICATION: [u8; 6] = [1, 2, 3, 4, 5, 6];
+
+    fn packed(header: Header) -> Vec<u8> {
+        let mut image = header.to_bytes().to_vec();
+        image.extend(APPLICATION);
+        image
+    }
+
+    fn range() -> FlashRange {
+        FlashRange::new(0u16, 0x1_f000u32)
+    }
+
+    #[test]
+    fn it_reads_back_what_it_writes() {
+        let header = Header::new([0x1e, 0x95, 0x0f], 128, 0x1_0000u32, &APPLICATION, 7);
+        let mut image = packed(header);
+        image.extend([0xff; 10]);
+
+        assert_eq!(parse(&image), Ok((header, &APPLICATION[..])));
+    }
+
+    #[test]
+    fn it_rejects_a_damaged_header() {
+        let header = Header::for_target(0u16, &APPLICATION, 1);
+
+        let mut image = packed(header);
+        image[0] = b'X';
+        assert_eq!(parse(&image), Err(Error::BadMagic));
+
+        let mut image = packed(header);
+        image[4] = 2;
+        assert_eq!(parse(&image), Err(Error::UnsupportedVersion(2)));
+
+        let mut image = packed(header);
+        image[16] += 1;
+        assert_eq!(parse(&image), Err(Error::HeaderCrc));
+
+        assert_eq!(Header::parse(&image[..31]), Err(Error::Truncated));
+    }
+
+    #[test]
+    fn it_rejects_a_truncated_or_corrupt_application() {
+        let image = packed(Header::for_target(0u16, &APPLICATION, 1));
+        assert_eq!(parse(&image[..image.len() - 1]), Err(Error::Truncated));
+
+        let mut image = image;
+        image[HEADER_LENGTH + 2] ^= 0x80;
+        assert_eq!(parse(&image), Err(Error::Crc));
+    }
+
+    #[test]
+    fn it_rejects_an_image_for_another_device() {
+        let header = Header::new([0x1e, 0x95, 0x0f], 128, 0u16, &APPLICATION, 1);
+        assert_eq!(
+            header.check_device(range()),
+            Err(Error::WrongDevice([0x1e, 0x95, 0x0f]))
+        );
+
+        let header = Header::new(SIGNATURE, 128, 0u16, &APPLICATION, 1);
+        assert_eq!(header.check_device(range()), Err(Error::WrongPageSize(128)));
+    }
+
+    #[test]
+    fn it_rejects_an_image_outside_the_application() {
+        let header = Header::for_target(0x1_effcu32, &APPLICATION, 1);
+        assert_eq!(header.check_device(range()), Err(Error::OutOfRange));
+
+        let header = Header::for_target(0x1_eff8u32, &APPLICATION, 1);
+        assert_eq!(header.check_device(range()), Ok(()));
+
+        let header = Header::for_target(0u16, &APPLICATION, 1);
+        let range = FlashRange::new(0x100u16, 0x1_f000u32);
+        assert_eq!(header.check_device(range), Err(Error::OutOfRange));
+    }
+
+    /// A flash holding `APPLICATION` at 0x100, with a trailer describing it
+    fn programmed() -> MockFlash {
+        let mut flash = MockFlash::new();
+        flash.memory[0x100..0x106].copy_from_slice(&APPLICATION);
+        let header = Header::for_target(0x100u16, &APPLICATION, 1);
+        write_trailer(&mut flash, range(), &header);
+        flash
+    }
+
+    #[test]
+    fn it_validates_an_application_against_its_trailer() {
+        let flash = programmed();
+
+        let header = validate(&flash, range()).unwrap();
+
+        assert_eq!(header.app_version, 1);
+        assert_eq!(&flash.memory[0x1_efe0..0x1_f000], &header.to_bytes());
+        assert!(flash.memory[0x1_ef00..0x1_efe0].iter().all(|b| *b == 0xff));
+    }
+
+    #[test]
+    fn it_rejects_a_partly_written_application() {
+        let mut flash = programmed();
+        flash.memory[0x105] = 0xff;
+
+        assert_eq!(validate(&flash, range()), Err(Error::Crc));
+    }
+
+    #[test]
+    fn it_rejects_flash_without_a_trailer() {
+        let flash = MockFlash::new();
+
+        assert_eq!(validate(&flash, range()), Err(Error::BadMagic));
+    }
+
+    #[test]
+    fn it_rejects_a_trailer_which_overlaps_the_application() {
+        let mut flash = MockFlash::new();
+        let header = Header::for_target(0x1_efe0u32, &[0; 16], 1);
+        write_trailer(&mut flash, range(), &header);
+
+        assert_eq!(validate(&flash, range()), Err(Error::OutOfRange));
+    }
+}
@@ -0,0 +1,613 @@
+//! Multi-page flash transfers which survive a power cut
+//!
+//! A [`Transfer`] copies a range of pages, or swaps two ranges through a spare scratch page, a page at a time. Each
+//! page is moved in phases (erased, written, verified), and the progress is recorded in a [`Log`] after every phase.
+//! If power is lost part way through, running the same transfer again at the next boot picks up from the last
+//! record, redoing at most the phase which was interrupted.
+//!
+//! The log is kept in EEPROM with [`EepromLog`], or in two reserved flash pages with [`FlashLog`]. Each record
+//! carries a sequence number and a CRC, and a new record never overwrites the latest one, so a record torn by a
+//! power cut is ignored in favour of the one before it.
+//!
+//! # Example
+//! ```no_run
+//! use avr_boot::{Address, FlashRange, eeprom::InternalEeprom, flash::Spm};
+//! use avr_boot::journal::{EepromLog, Log, Transfer};
+//!
+//! let staging = FlashRange::new(0x4000u16, 0x6000u16);
+//! let transfer = Transfer::copy(staging, Address::from(0u16));
+//! let mut log = EepromLog::new(InternalEeprom, 0);
+//!
+//! // At every boot, so an interrupted transfer is finished
+//! if log.load(&Spm).is_some() {
+//!     transfer.run(&mut Spm, &mut log).unwrap();
+//!     log.clear(&mut Spm);
+//! }
+//! ```
+
+use crate::crc;
+use crate::eeprom::Eeprom;
+use crate::flash::Flash;
+use crate::{Address, FlashRange, SPM_PAGESIZE_BYTES};
+
+/// Length in bytes of a progress record
+pub const RECORD_LENGTH: usize = 8;
+
+/// How many times a page is written again when it doesn't read back correctly
+const RETRIES: u8 = 2;
+
+/// How far a step has got
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Phase {
+    /// The destination page has been erased
+    Erased = 1,
+    /// The destination page has been written
+    Written = 2,
+    /// The destination page has been read back and matches its source
+    Verified = 3,
+}
+
+/// The last phase completed by a transfer
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Progress {
+    /// Page index within the transfer
+    pub page: u16,
+    /// A copy moves each page in one step, a swap in three
+    pub step: u8,
+    /// The last phase completed for this step
+    pub phase: Phase,
+}
+
+/// Why a transfer couldn't be finished
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Error {
+    /// The page at this address still didn't match its source after being written again
+    Verify(Address),
+}
+
+/// Somewhere to keep the progress of a transfer
+///
+/// The flash is passed in, so the log can share it with the transfer.
+pub trait Log<F> {
+    /// The latest intact record, if there is one
+    fn load(&self, flash: &F) -> Option<Progress>;
+
+    /// Record progress, without disturbing the latest record until the new one is complete
+    fn save(&mut self, flash: &mut F, progress: Progress);
+
+    /// Forget the transfer, once it has finished
+    fn clear(&mut self, flash: &mut F);
+}
+
+/// A log in two record slots of EEPROM, used alternately
+pub struct EepromLog<E> {
+    eeprom: E,
+    address: u16,
+}
+
+impl<E: Eeprom> EepromLog<E> {
+    /// Keep the log in the `2 * RECORD_LENGTH` bytes of EEPROM from `address`
+    pub fn new(eeprom: E, address: u16) -> Self {
+        Self { eeprom, address }
+    }
+
+    /// Give back the EEPROM
+    pub fn release(self) -> E {
+        self.eeprom
+    }
+
+    fn slot(&self, slot: u16) -> u16 {
+        self.address + slot * RECORD_LENGTH as u16
+    }
+
+    fn read(&self, slot: u16) -> Option<(u16, Progress)> {
+        let address = self.slot(slot);
+        let mut record = [0; RECORD_LENGTH];
+        for (i, byte) in record.iter_mut().enumerate() {
+            *byte = self.eeprom.read_byte(address + i as u16);
+        }
+        decode(&record)
+    }
+
+    fn write(&mut self, slot: u16, record: &[u8; RECORD_LENGTH]) {
+        let address = self.slot(slot);
+        for (i, byte) in record.iter().enumerate() {
+            self.eeprom.update_byte(address + i as u16, *byte);
+        }
+    }
+
+    /// The slot and sequence number of the latest intact record
+    fn latest(&self) -> Option<(u16, u16, Progress)> {
+        match (self.read(0), self.read(1)) {
+            (Some((a, p)), Some((b, _))) if newer(a, b) => Some((0, a, p)),
+            (_, Some((b, p))) => Some((1, b, p)),
+            (Some((a, p)), None) => Some((0, a, p)),
+            (None, None) => None,
+        }
+    }
+}
+
+impl<F, E: Eeprom> Log<F> for EepromLog<E> {
+    fn load(&self, _flash: &F) -> Option<Progress> {
+        self.latest().map(|(_, _, progress)| progress)
+    }
+
+    fn save(&mut self, _flash: &mut F, progress: Progress) {
+        let (slot, sequence) = match self.latest() {
+            Some((slot, sequence, _)) => (1 - slot, sequence.wrapping_add(1)),
+            None => (0, 0),
+        };
+        self.write(slot, &encode(sequence, progress));
+    }
+
+    fn clear(&mut self, _flash: &mut F) {
+        // The older record first, so a power cut part way through leaves the latest one
+        let latest = self.latest().map_or(1, |(slot, _, _)| slot);
+        self.write(1 - latest, &[0xff; RECORD_LENGTH]);
+        self.write(latest, &[0xff; RECORD_LENGTH]);
+    }
+}
+
+/// A log in two reserved pages of flash
+///
+/// Records are appended to the erased part of a page, which only clears bits, so a page is only erased when the log
+/// moves on to it from the other one. That keeps flash wear down to one erase per `SPM_PAGESIZE_BYTES / RECORD_LENGTH`
+/// records.
+#[derive(Copy, Clone, Debug)]
+pub struct FlashLog {
+    address: Address,
+}
+
+impl FlashLog {
+    /// Keep the log in the two pages from `address`, which nothing else may use
+    pub fn new(address: Address) -> Self {
+        Self {
+            address: address.into_page_aligned(),
+        }
+    }
+
+    const SLOTS: usize = SPM_PAGESIZE_BYTES / RECORD_LENGTH;
+
+    fn page(&self, page: usize) -> Address {
+        Address::from(u32::from(self.address) + (page * SPM_PAGESIZE_BYTES) as u32)
+    }
+
+    fn read(&self, flash: &impl Flash, page: usize, slot: usize) -> [u8; RECORD_LENGTH] {
+        let mut record = [0; RECORD_LENGTH];
+        let address = u32::from(self.page(page)) + (slot * RECORD_LENGTH) as u32;
+        flash.read_into(Address::from(address), &mut record);
+        record
+    }
+
+    /// How many slots of a page have been written to, intact or not
+    fn used(&self, flash: &impl Flash, page: usize) -> usize {
+        (0..Self::SLOTS)
+            .rev()
+            .find(|slot| self.read(flash, page, *slot) != [0xff; RECORD_LENGTH])
+            .map_or(0, |slot| slot + 1)
+    }
+
+    /// The page and sequence number of the latest intact record
+    fn latest(&self, flash: &impl Flash) -> Option<(usize, u16, Progress)> {
+        let mut latest: Option<(usize, u16, Progress)> = None;
+        for page in 0..2 {
+            for slot in 0..Self::SLOTS {
+                if let Some((sequence, progress)) = decode(&self.read(flash, page, slot))
+                    && latest.is_none_or(|(_, newest, _)| newer(sequence, newest))
+                {
+                    latest = Some((page, sequence, progress));
+                }
+            }
+        }
+        latest
+    }
+
+    /// Program a record into an erased slot, leaving the rest of the page as it is
+    fn write(
+        &self,
+        flash: &mut impl Flash,
+        page: usize,
+        slot: usize,
+        record: &[u8; RECORD_LENGTH],
+    ) {
+        let start = slot * RECORD_LENGTH;
+        for word in 0..SPM_PAGESIZE_BYTES / 2 {
+            let offset = word * 2;
+            let data = if (start..start + RECORD_LENGTH).contains(&offset) {
+                u16::from_le_bytes([record[offset - start], record[offset - start + 1]])
+            } else {
+                0xffff
+            };
+            flash.fill_page(Address::from(offset as u16), data);
+        }
+        flash.write_page(self.page(page));
+        flash.rww_enable();
+    }
+
+    fn erase(&self, flash: &mut impl Flash, page: usize) {
+        flash.erase_page(self.page(page));
+        flash.rww_enable();
+    }
+}
+
+impl<F: Flash> Log<F> for FlashLog {
+    fn load(&self, flash: &F) -> Option<Progress> {
+        self.latest(flash).map(|(_, _, progress)| progress)
+    }
+
+    fn save(&mut self, flash: &mut F, progress: Progress) {
+        let (page, sequence) = match self.latest(flash) {
+            Some((page, sequence, _)) => (page, sequence.wrapping_add(1)),
+            None => (0, 0),
+        };
+        let used = self.used(flash, page);
+
+        if used < Self::SLOTS {
+            self.write(flash, page, used, &encode(sequence, progress));
+        } else {
+            self.erase(flash, 1 - page);
+            self.write(flash, 1 - page, 0, &encode(sequence, progress));
+        }
+    }
+
+    fn clear(&mut self, flash: &mut F) {
+        // The page without the latest record first, so a power cut part way through leaves it
+        let latest = self.latest(flash).map_or(1, |(page, _, _)| page);
+        self.erase(flash, 1 - latest);
+        self.erase(flash, latest);
+    }
+}
+
+/// Whether sequence number `a` comes after `b`, allowing for wrapping
+fn newer(a: u16, b: u16) -> bool {
+    (a.wrapping_sub(b) as i16) > 0
+}
+
+fn encode(sequence: u16, progress: Progress) -> [u8; RECORD_LENGTH] {
+    let [sequence_low, sequence_high] = sequence.to_le_bytes();
+    let [page_low, page_high] = progress.page.to_le_bytes();
+    let mut record = [
+        sequence_low,
+        sequence_high,
+        page_low,
+        page_high,
+        progress.step,
+        progress.phase as u8,
+        0,
+        0,
+    ];
+    let check = crc::xmodem(&record[..6]).to_le_bytes();
+    record[6..].copy_from_slice(&check);
+    record
+}
+
+fn decode(record: &[u8; RECORD_LENGTH]) -> Option<(u16, Progress)> {
+    if crc::xmodem(&record[..6]).to_le_bytes() != record[6..] {
+        return None;
+    }
+    let phase = match record[5] {
+        1 => Phase::Erased,
+        2 => Phase::Written,
+        3 => Phase::Verified,
+        _ => return None,
+    };
+    let progress = Progress {
+        page: u16::from_le_bytes([record[2], record[3]]),
+        step: record[4],
+        phase,
+    };
+    Some((u16::from_le_bytes([record[0], record[1]]), progress))
+}
+
+/// A copy or swap of whole pages
+///
+/// The ranges must not overlap each other, the scratch page, or a [`FlashLog`].
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Transfer {
+    from: Address,
+    to: Address,
+    scratch: Option<Address>,
+    pages: u16,
+}
+
+impl Transfer {
+    /// Copy the pages of `source` to the same number of pages from `destination`
+    pub fn copy(source: FlashRange, destination: Address) -> Self {
+        Self::new(source, destination, None)
+    }
+
+    /// Exchange the pages of `a` with the same number of pages from `b`, through a spare `scratch` page
+    pub fn swap(a: FlashRange, b: Address, scratch: Address) -> Self {
+        Self::new(a, b, Some(scratch.into_page_aligned()))
+    }
+
+    fn new(source: FlashRange, destination: Address, scratch: Option<Address>) -> Self {
+        Self {
+            from: source.start.into_page_aligned(),
+            to: destination.into_page_aligned(),
+            scratch,
+            pages: source.len().div_ceil(SPM_PAGESIZE_BYTES as u32) as u16,
+        }
+    }
+
+    /// Number of pages moved
+    pub fn pages(&self) -> u16 {
+        self.pages
+    }
+
+    fn steps(&self) -> u8 {
+        if self.scratch.is_some() { 3 } else { 1 }
+    }
+
+    /// The source and destination of one step of moving a page
+    fn step(&self, page: u16, step: u8) -> (Address, Address) {
+        let offset = page as u32 * SPM_PAGESIZE_BYTES as u32;
+        let from = Address::from(u32::from(self.from) + offset);
+        let to = Address::from(u32::from(self.to) + offset);
+
+        match (self.scratch, step) {
+            (None, _) => (from, to),
+            (Some(scratch), 0) => (from, scratch),
+            (Some(_), 1) => (to, from),
+            (Some(scratch), _) => (scratch, to),
+        }
+    }
+
+    /// Run the transfer, carrying on from the progress in `log` if there is any
+    ///
+    /// Pages which already match are skipped. The log is left holding the final record, so the caller can record
+    /// that the transfer has finished before clearing it.
+    pub fn run<F: Flash, L: Log<F>>(&self, flash: &mut F, log: &mut L) -> Result<(), Error> {
+        let resume = log.load(flash);
+        let first = resume.map_or(0, |progress| progress.page);
+
+        for page in first..self.pages {
+            let resumed = resume.filter(|progress| progress.page == page);
+            if resumed.is_none() && self.unchanged(flash, page) {
+                continue;
+            }
+            for step in resumed.map_or(0, |progress| progress.step)..self.steps() {
+                let phase = resumed
+                    .filter(|progress| progress.step == step)
+                    .map(|progress| progress.phase);
+                self.move_page(flash, log, page, step, phase)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the page would be the same after the transfer, so needn't be touched
+    fn unchanged(&self, flash: &impl Flash, page: u16) -> bool {
+        let offset = page as u32 * SPM_PAGESIZE_BYTES as u32;
+        let from = Address::from(u32::from(self.from) + offset);
+        let to = Address::from(u32::from(self.to) + offset);
+        flash.read_page(from).0 == flash.read_page(to).0
+    }
+
+    /// Carry a step on from `phase`, which is `None` if it hasn't started
+    fn move_page<F: Flash, L: Log<F>>(
+        &self,
+        flash: &mut F,
+        log: &mut L,
+        page: u16,
+        step: u8,
+        mut phase: Option<Phase>,
+    ) -> Result<(), Error> {
+        let (from, to) = self.step(page, step);
+        let mut retries = 0;
+
+        loop {
+            phase = match phase {
+                None => {
+                    flash.erase_page(to);
+                    flash.rww_enable();
+                    Some(Phase::Erased)
+                }
+                Some(Phase::Erased) => {
+                    let data = flash.read_page(from);
+                    for (i, word) in data.0.iter().enumerate() {
+                        flash.fill_page(Address::from((i * 2) as u16), *word);
+                    }
+                    flash.write_page(to);
+                    flash.rww_enable();
+                    Some(Phase::Written)
+                }
+                Some(Phase::Written) => {
+                    if flash.read_page(from).0 != flash.read_page(to).0 {
+                        if retries == RETRIES {
+                            return Err(Error::Verify(to));
+                        }
+                        retries += 1;
+                        phase = None;
+                        continue;
+                    }
+                    Some(Phase::Verified)
+                }
+                Some(Phase::Verified) => return Ok(()),
+            };
+            if let Some(phase) = phase {
+                log.save(flash, Progress { page, step, phase });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod mock {
+    use super::*;
+
+    /// Stops the transfer after a number of records, as if the power had been cut
+    pub(crate) struct PowerCut<L> {
+        pub log: L,
+        pub saves: usize,
+    }
+
+    impl<F, L: Log<F>> Log<F> for PowerCut<L> {
+        fn load(&self, flash: &F) -> Option<Progress> {
+            self.log.load(flash)
+        }
+
+        fn save(&mut self, flash: &mut F, progress: Progress) {
+            self.log.save(flash, progress);
+            if self.saves == 0 {
+                panic!("power cut");
+            }
+            self.saves -= 1;
+        }
+
+        fn clear(&mut self, flash: &mut F) {
+            self.log.clear(flash);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::PowerCut;
+    use super::*;
+    use crate::eeprom::mock::MockEeprom;
+    use crate::flash::mock::MockFlash;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::vec::Vec;
+
+    const PAGE: usize = SPM_PAGESIZE_BYTES;
+
+    /// Pages 0..4 and 4..8 filled with different patterns, with page 2 the same in both
+    fn flash() -> MockFlash {
+        let mut flash = MockFlash::new();
+        for (i, byte) in flash.memory[..PAGE * 8].iter_mut().enumerate() {
+            *byte = (i / PAGE * 17 + i % 7) as u8;
+        }
+        flash.memory.copy_within(PAGE * 2..PAGE * 3, PAGE * 6);
+        flash
+    }
+
+    fn swap() -> Transfer {
+        Transfer::swap(
+            FlashRange::new(0u16, (PAGE * 4) as u16),
+            Address::from((PAGE * 4) as u16),
+            Address::from((PAGE * 10) as u16),
+        )
+    }
+
+    fn swapped(before: &MockFlash) -> Vec<u8> {
+        let mut memory = before.memory[..PAGE * 8].to_vec();
+        memory.rotate_left(PAGE * 4);
+        memory
+    }
+
+    #[test]
+    fn it_copies_pages_and_skips_those_already_the_same() {
+        let mut flash = flash();
+        let expected = flash.memory[..PAGE * 3].to_vec();
+        let mut log = EepromLog::new(MockEeprom::new(), 0);
+
+        Transfer::copy(
+            FlashRange::new(0u16, (PAGE * 3) as u16),
+            Address::from((PAGE * 4) as u16),
+        )
+        .run(&mut flash, &mut log)
+        .unwrap();
+
+        assert_eq!(&flash.memory[PAGE * 4..PAGE * 7], &expected[..]);
+        assert_eq!(flash.erases, 2);
+        assert_eq!(
+            log.load(&flash),
+            Some(Progress {
+                page: 1,
+                step: 0,
+                phase: Phase::Verified
+            })
+        );
+    }
+
+    #[test]
+    fn it_finishes_a_swap_cut_short_after_any_record() {
+        let before = flash();
+        let mut saves = 0;
+
+        loop {
+            let mut flash = flash();
+            let mut log = PowerCut {
+                log: EepromLog::new(MockEeprom::new(), 0),
+                saves,
+            };
+            let finished =
+                panic::catch_unwind(AssertUnwindSafe(|| swap().run(&mut flash, &mut log)));
+
+            let mut log = log.log;
+            swap().run(&mut flash, &mut log).unwrap();
+            assert_eq!(
+                &flash.memory[..PAGE * 8],
+                &swapped(&before)[..],
+                "cut after {saves}"
+            );
+
+            if finished.is_ok() {
+                break;
+            }
+            saves += 1;
+        }
+        // Three pages, each swapped in three steps of three phases
+        assert_eq!(saves, 27);
+    }
+
+    #[test]
+    fn it_falls_back_to_the_previous_record_when_one_is_torn() {
+        let mut flash = MockFlash::new();
+        let mut log = EepromLog::new(MockEeprom::new(), 0x20);
+        let first = Progress {
+            page: 3,
+            step: 1,
+            phase: Phase::Written,
+        };
+        let second = Progress {
+            page: 3,
+            step: 1,
+            phase: Phase::Verified,
+        };
+        log.save(&mut flash, first);
+        log.save(&mut flash, second);
+
+        let mut eeprom = log.release();
+        eeprom.memory[0x20 + RECORD_LENGTH + 5] = Phase::Erased as u8;
+        let mut log = EepromLog::new(eeprom, 0x20);
+        assert_eq!(log.load(&flash), Some(first));
+
+        log.clear(&mut flash);
+        assert_eq!(log.load(&flash), None);
+    }
+
+    #[test]
+    fn it_appends_flash_records_and_moves_to_the_other_page_when_full() {
+        let mut flash = MockFlash::new();
+        let mut log = FlashLog::new(Address::from((PAGE * 20) as u16));
+        let records = FlashLog::SLOTS as u16 + 2;
+
+        for page in 0..records {
+            let progress = Progress {
+                page,
+                step: 0,
+                phase: Phase::Verified,
+            };
+            log.save(&mut flash, progress);
+            assert_eq!(log.load(&flash), Some(progress));
+        }
+
+        assert_eq!(flash.erases, 1);
+        assert_eq!(flash.page((PAGE * 21) as u32)[RECORD_LENGTH * 2], 0xff);
+        log.clear(&mut flash);
+        assert_eq!(log.load(&flash), None);
+    }
+
+    #[test]
+    fn it_swaps_with_a_flash_log() {
+        let before = flash();
+        let mut flash = flash();
+        let mut log = FlashLog::new(Address::from((PAGE * 20) as u16));
+
+        swap().run(&mut flash, &mut log).unwrap();
+
+        assert_eq!(&flash.memory[..PAGE * 8], &swapped(&before)[..]);
+    }
+}
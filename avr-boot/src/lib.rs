@@ -1,15 +1,40 @@
 #![no_std]
-#![feature(asm_experimental_arch)]
+#![cfg_attr(target_arch = "avr", feature(asm_experimental_arch))]
 #![doc = include_str!("../../README.md")]
 
+#[cfg(test)]
+#[macro_use]
+extern crate std;
+
 mod address;
 mod buffer;
+mod range;
+mod word_address;
+mod writer;
 
 use core::ops::Deref;
 use const_env__value::value_from_env;
+pub mod checksum;
+pub mod cobs;
+pub mod crc;
+pub mod eeprom;
+pub mod flash;
+pub mod ihex;
+pub mod image;
+pub mod journal;
+pub mod lpm;
+pub mod protocol;
+pub mod service;
+pub mod services;
+pub mod slots;
 pub mod spm;
+pub mod srec;
+pub mod staging;
 pub use address::Address;
 pub use buffer::PageBuffer;
+pub use range::{FlashRange, Pages};
+pub use word_address::{OddAddressError, WordAddress, WordAddressRangeError};
+pub use writer::FlashWriter;
 
 /// Total size of the SPM page buffer, for the current MCU target
 pub const SPM_PAGESIZE_BYTES: usize = value_from_env!("AVR_BOOT_SPM_PAGESIZE": usize);
@@ -17,6 +42,22 @@ pub const SPM_PAGESIZE_BYTES: usize = value_from_env!("AVR_BOOT_SPM_PAGESIZE": u
 /// Total length in 16 byte words of the SPM page buffer, for the current MCU target
 pub const SPM_PAGESIZE_WORDS: usize = SPM_PAGESIZE_BYTES / 2;
 
+/// Total size of the program memory, for the current MCU target
+pub const FLASH_SIZE_BYTES: u32 = value_from_env!("AVR_BOOT_FLASH_SIZE": u32);
+
+/// Total size of the EEPROM, for the current MCU target. Zero if it has none.
+pub const EEPROM_SIZE_BYTES: u16 = value_from_env!("AVR_BOOT_EEPROM_SIZE": u16);
+
+/// The 3 device signature bytes, for the current MCU target
+pub const SIGNATURE: [u8; 3] = {
+    let signature = value_from_env!("AVR_BOOT_SIGNATURE": u32);
+    [
+        (signature >> 16) as u8,
+        (signature >> 8) as u8,
+        signature as u8,
+    ]
+};
+
 #[cfg(extended_addressing)]
 #[doc(hidden)]
 pub const RAMPZ: *mut u8 = value_from_env!("AVR_RAMPZ": u8) as *mut u8;
@@ -34,6 +75,8 @@ const PAGE_FILL: u8 = value_from_env!("AVR_BOOT_PAGE_FILL": u8);
 const LOCK_BITS_SET: u8 = value_from_env!("AVR_BOOT_LOCK_BITS_SET": u8);
 #[cfg(all(target_arch = "avr", rww_enable))]
 const RWW_ENABLE: u8 = value_from_env!("AVR_BOOT_RWW_ENABLE": u8);
+#[cfg(all(target_arch = "avr", signature_read))]
+const SIGNATURE_READ: u8 = value_from_env!("AVR_BOOT_SIGNATURE_READ": u8);
 
 /// NewType, an array of memory the same size as the page buffer
 pub struct DataPage(pub [u16; SPM_PAGESIZE_WORDS]);
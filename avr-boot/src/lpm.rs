@@ -0,0 +1,105 @@
+//! Low level API for reading program memory
+//!
+//! Uses `elpm` on MCUs with more than 64k of flash, so any [`Address`] can be read.
+//! When not compiling for AVR every byte reads as `0xff`, like erased flash.
+
+use crate::Address;
+
+use cfg_if::cfg_if;
+#[allow(unused_imports)]
+use core::arch::asm;
+
+/// Read a single byte of program memory
+#[cfg_attr(not(target_arch = "avr"), allow(unused_variables))]
+pub fn read_byte(address: impl Into<Address>) -> u8 {
+    let address: Address = address.into();
+    let z_address: u16 = address.into();
+
+    cfg_if! {
+        if #[cfg(all(target_arch = "avr", elpm, not(doc)))] {
+            let byte: u8;
+            crate::spm::rampz(address.ramp());
+            unsafe {
+                asm!(
+                    "elpm {byte}, Z",
+                    byte = out(reg) byte,
+                    in("Z") z_address,
+                );
+            }
+            byte
+        } else if #[cfg(all(target_arch = "avr", not(doc)))] {
+            let byte: u8;
+            unsafe {
+                asm!(
+                    "lpm {byte}, Z",
+                    byte = out(reg) byte,
+                    in("Z") z_address,
+                );
+            }
+            byte
+        } else {
+            0xff
+        }
+    }
+}
+
+/// Read a little-endian word of program memory
+pub fn read_word(address: impl Into<Address>) -> u16 {
+    let address: Address = address.into();
+    let high = address.checked_add(1).map_or(0xff, read_byte);
+
+    u16::from_le_bytes([read_byte(address), high])
+}
+
+/// Fill `buf` with program memory, starting at `address`
+///
+/// Reads carry across the 64k boundary. RAMPZ is only set once for each 64k segment, and the bytes within it are
+/// read with post-incrementing loads, so this is much quicker than calling [`read_byte`] for each byte.
+pub fn read_into(address: impl Into<Address>, buf: &mut [u8]) {
+    let mut address: Address = address.into();
+    let mut rest = buf;
+
+    while !rest.is_empty() {
+        // Bytes left before the 64k boundary
+        let segment = 0x1_0000 - u16::from(address) as u32;
+        let length = segment.min(rest.len() as u32) as usize;
+        let (chunk, remainder) = rest.split_at_mut(length);
+        read_segment(address, chunk);
+
+        address = address.checked_add(chunk.len() as u32).unwrap_or(address);
+        rest = remainder;
+    }
+}
+
+/// Read bytes which all lie within the same 64k segment as `address`
+#[cfg_attr(not(target_arch = "avr"), allow(unused_variables))]
+fn read_segment(address: Address, buf: &mut [u8]) {
+    cfg_if! {
+        if #[cfg(all(target_arch = "avr", elpm, not(doc)))] {
+            let mut z_address: u16 = address.into();
+            crate::spm::rampz(address.ramp());
+            for byte in buf.iter_mut() {
+                unsafe {
+                    asm!(
+                        "elpm {byte}, Z+",
+                        byte = out(reg) *byte,
+                        inout("Z") z_address,
+                    );
+                }
+            }
+        } else if #[cfg(all(target_arch = "avr", not(doc)))] {
+            let mut z_address: u16 = address.into();
+            for byte in buf.iter_mut() {
+                unsafe {
+                    asm!(
+                        "lpm {byte}, Z+",
+                        byte = out(reg) *byte,
+                        inout("Z") z_address,
+                    );
+                }
+            }
+        } else {
+            buf.fill(0xff);
+        }
+    }
+}
@@ -0,0 +1,230 @@
+//! Bootloader protocol engines
+//!
+//! Each engine talks to the host over a [`Serial`] byte stream, or an [`I2cSlave`] bus, and programs through a
+//! [`Flash`](crate::flash::Flash) backend, so it can run on any UART, USB CDC stack etc., and be tested on the host.
+
+pub mod avr109;
+pub mod framed;
+mod modem;
+pub mod stk500v1;
+pub mod stk500v2;
+pub mod twiboot;
+pub mod xmodem;
+pub mod ymodem;
+
+/// Software flow control character asking the sender to resume
+pub const XON: u8 = 0x11;
+
+/// Software flow control character asking the sender to pause
+pub const XOFF: u8 = 0x13;
+
+/// Whether the programmer is still connected, returned after handling each command
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Status {
+    /// Waiting for the next command
+    Continue,
+    /// The programmer has finished, the application can be started
+    Done,
+}
+
+/// A bidirectional byte stream, such as a UART
+///
+/// Both methods block. Implementations can return an error from [`Serial::read`] on timeout,
+/// which the protocol engines will pass back to the caller.
+pub trait Serial {
+    /// The error type returned by failed reads or writes
+    type Error;
+
+    /// Wait for and return the next byte received
+    fn read(&mut self) -> Result<u8, Self::Error>;
+
+    /// Send a single byte
+    fn write(&mut self, byte: u8) -> Result<(), Self::Error>;
+
+    /// Send every byte in `bytes`
+    fn write_all(&mut self, bytes: &[u8]) -> Result<(), Self::Error> {
+        bytes.iter().try_for_each(|byte| self.write(*byte))
+    }
+}
+
+/// The direction of an I2C transaction, from the master's point of view
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Transaction {
+    /// The master is sending data (SLA+W)
+    Write,
+    /// The master is reading data (SLA+R)
+    Read,
+}
+
+/// The slave side of an I2C (TWI) bus, seen as a series of transactions
+///
+/// All methods block. Implementations are expected to ACK their own address and every byte received.
+pub trait I2cSlave {
+    /// The error type returned by failed bus operations
+    type Error;
+
+    /// Wait until the master addresses this slave, and return the direction of the transaction
+    fn next_transaction(&mut self) -> Result<Transaction, Self::Error>;
+
+    /// Receive the next byte of a write transaction
+    ///
+    /// Returns `None` once the master ends the transaction, with a STOP or a repeated START.
+    fn read(&mut self) -> Result<Option<u8>, Self::Error>;
+
+    /// Send the next byte of a read transaction
+    ///
+    /// Returns `false` if the master NACKed the byte, ending the transaction.
+    fn write(&mut self, byte: u8) -> Result<bool, Self::Error>;
+}
+
+#[cfg(test)]
+pub(crate) mod mock {
+    use super::modem::{BLOCK_SIZE_1K, SOH, STX};
+    use super::{I2cSlave, Serial, Transaction};
+    use crate::crc;
+    use std::{collections::VecDeque, sync::mpsc, time::Duration, vec, vec::Vec};
+
+    /// A scripted byte stream: reads come from `input`, writes are collected in `output`
+    pub(crate) struct MockSerial {
+        pub input: VecDeque<u8>,
+        pub output: Vec<u8>,
+    }
+
+    /// Returned when the script runs out of input
+    #[derive(Debug, PartialEq, Eq)]
+    pub(crate) struct EndOfInput;
+
+    impl MockSerial {
+        pub fn new(input: &[u8]) -> Self {
+            Self {
+                input: input.iter().copied().collect(),
+                output: Vec::new(),
+            }
+        }
+    }
+
+    impl Serial for MockSerial {
+        type Error = EndOfInput;
+
+        fn read(&mut self) -> Result<u8, Self::Error> {
+            self.input.pop_front().ok_or(EndOfInput)
+        }
+
+        fn write(&mut self, byte: u8) -> Result<(), Self::Error> {
+            self.output.push(byte);
+            Ok(())
+        }
+    }
+
+    /// One end of a loopback byte pipe, for running a protocol engine against a host implementation on another thread
+    ///
+    /// Reads time out after a short wait, returning [`EndOfInput`].
+    pub(crate) struct PipeEnd {
+        tx: mpsc::Sender<u8>,
+        rx: mpsc::Receiver<u8>,
+    }
+
+    /// Create a connected pair of pipe ends
+    pub(crate) fn pipe() -> (PipeEnd, PipeEnd) {
+        let (a_tx, b_rx) = mpsc::channel();
+        let (b_tx, a_rx) = mpsc::channel();
+        (
+            PipeEnd { tx: a_tx, rx: a_rx },
+            PipeEnd { tx: b_tx, rx: b_rx },
+        )
+    }
+
+    impl Serial for PipeEnd {
+        type Error = EndOfInput;
+
+        fn read(&mut self) -> Result<u8, Self::Error> {
+            self.rx
+                .recv_timeout(Duration::from_millis(100))
+                .map_err(|_| EndOfInput)
+        }
+
+        fn write(&mut self, byte: u8) -> Result<(), Self::Error> {
+            // The other end may have already finished
+            self.tx.send(byte).ok();
+            Ok(())
+        }
+    }
+
+    /// An XMODEM block, with a CRC or an additive checksum
+    pub(crate) fn packet(number: u8, data: &[u8], crc: bool) -> Vec<u8> {
+        let header = if data.len() == BLOCK_SIZE_1K {
+            STX
+        } else {
+            SOH
+        };
+        let mut packet = vec![header, number, !number];
+        packet.extend(data);
+        if crc {
+            packet.extend(crc::xmodem(data).to_be_bytes());
+        } else {
+            packet.push(data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)));
+        }
+        packet
+    }
+
+    /// One step of a scripted I2C master
+    pub(crate) enum Step {
+        /// Send these bytes, then STOP
+        Write(Vec<u8>),
+        /// Read this many bytes, then NACK
+        Read(usize),
+    }
+
+    /// A scripted I2C master: transactions come from `script`, each read transaction's bytes are collected in `reads`
+    pub(crate) struct MockI2c {
+        pub script: VecDeque<Step>,
+        pub reads: Vec<Vec<u8>>,
+        current: Option<Step>,
+    }
+
+    impl MockI2c {
+        pub fn new(script: impl IntoIterator<Item = Step>) -> Self {
+            Self {
+                script: script.into_iter().collect(),
+                reads: Vec::new(),
+                current: None,
+            }
+        }
+    }
+
+    impl I2cSlave for MockI2c {
+        type Error = EndOfInput;
+
+        fn next_transaction(&mut self) -> Result<Transaction, Self::Error> {
+            let step = self.script.pop_front().ok_or(EndOfInput)?;
+            let transaction = match step {
+                Step::Write(_) => Transaction::Write,
+                Step::Read(_) => {
+                    self.reads.push(Vec::new());
+                    Transaction::Read
+                }
+            };
+            self.current = Some(step);
+            Ok(transaction)
+        }
+
+        fn read(&mut self) -> Result<Option<u8>, Self::Error> {
+            match &mut self.current {
+                Some(Step::Write(remaining)) if remaining.is_empty() => Ok(None),
+                Some(Step::Write(remaining)) => Ok(Some(remaining.remove(0))),
+                _ => panic!("read outside a write transaction"),
+            }
+        }
+
+        fn write(&mut self, byte: u8) -> Result<bool, Self::Error> {
+            match &mut self.current {
+                Some(Step::Read(remaining)) if *remaining > 0 => {
+                    *remaining -= 1;
+                    self.reads.last_mut().unwrap().push(byte);
+                    Ok(*remaining > 0)
+                }
+                _ => panic!("write outside a read transaction"),
+            }
+        }
+    }
+}
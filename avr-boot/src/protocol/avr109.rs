@@ -0,0 +1,313 @@
+//! AVR109 (butterfly) protocol, as spoken by the Caterina bootloader
+//!
+//! This is the protocol used by `avrdude -c avr109` and `avrdude -c butterfly`.
+//! Block loads are written through a [`FlashWriter`], so blocks don't need to line up with pages,
+//! although avrdude always uses the block size reported by `b`, which is [`SPM_PAGESIZE_BYTES`].
+//! A page is written when a block moves on to the next one, or on `E`, `e` or a block read.
+//!
+//! Addresses from `A` are word addresses. Block loads and reads advance the address as they go.
+//!
+//! # Example
+//! ```no_run
+//! use avr_boot::FlashRange;
+//! use avr_boot::flash::Spm;
+//! use avr_boot::protocol::{Serial, avr109::Avr109};
+//!
+//! # struct Uart;
+//! # impl Serial for Uart {
+//! #     type Error = ();
+//! #     fn read(&mut self) -> Result<u8, ()> { Ok(0) }
+//! #     fn write(&mut self, _: u8) -> Result<(), ()> { Ok(()) }
+//! # }
+//! let application = FlashRange::new(0u16, 0x7000u16);
+//! let mut bootloader = Avr109::new(Uart, Spm, application);
+//! bootloader.run().ok();
+//! // jump to the application
+//! ```
+
+use crate::flash::Flash;
+use crate::protocol::{Serial, Status};
+use crate::{Address, FlashRange, FlashWriter, SIGNATURE, SPM_PAGESIZE_BYTES, WordAddress};
+
+const CR: u8 = b'\r';
+const UNKNOWN: u8 = b'?';
+const YES: u8 = b'Y';
+
+const SOFTWARE_ID: &[u8] = b"AVRBOOT";
+const SOFTWARE_VERSION: &[u8] = b"10";
+const PROGRAMMER_TYPE: u8 = b'S';
+const DEVICE_CODE: u8 = 0x44;
+
+const MEMORY_FLASH: u8 = b'F';
+
+/// AVR109 command interpreter
+pub struct Avr109<S, F> {
+    serial: S,
+    writer: FlashWriter<F>,
+    application: FlashRange,
+    address: Address,
+}
+
+impl<S: Serial, F: Flash> Avr109<S, F> {
+    /// Create a new interpreter, talking over `serial` and programming `flash`
+    ///
+    /// `application` is the part of flash erased by the `e` command, normally everything below the bootloader.
+    pub fn new(serial: S, flash: F, application: FlashRange) -> Self {
+        Self {
+            serial,
+            writer: FlashWriter::new(flash),
+            application,
+            address: Address::from(0u16),
+        }
+    }
+
+    /// Handle commands until the programmer sends `E`
+    pub fn run(&mut self) -> Result<(), S::Error> {
+        while self.handle_command()? == Status::Continue {}
+        Ok(())
+    }
+
+    /// Read and handle a single command
+    pub fn handle_command(&mut self) -> Result<Status, S::Error> {
+        match self.serial.read()? {
+            b'A' => {
+                let high = self.serial.read()?;
+                let low = self.serial.read()?;
+                self.address = WordAddress::from(u16::from_be_bytes([high, low])).into();
+                self.serial.write(CR)?;
+            }
+            b'H' => {
+                let mut word = [0; 4];
+                for byte in &mut word[1..] {
+                    *byte = self.serial.read()?;
+                }
+                match WordAddress::try_from(u32::from_be_bytes(word)) {
+                    Ok(address) => {
+                        self.address = address.into();
+                        self.serial.write(CR)?;
+                    }
+                    Err(_) => self.serial.write(UNKNOWN)?,
+                }
+            }
+            b'B' => self.block_load()?,
+            b'g' => self.block_read()?,
+            b'e' => {
+                self.chip_erase();
+                self.serial.write(CR)?;
+            }
+            b's' => {
+                self.serial
+                    .write_all(&[SIGNATURE[2], SIGNATURE[1], SIGNATURE[0]])?;
+            }
+            b'b' => {
+                let [high, low] = (SPM_PAGESIZE_BYTES as u16).to_be_bytes();
+                self.serial.write_all(&[YES, high, low])?;
+            }
+            b'a' => self.serial.write(YES)?,
+            b'S' => self.serial.write_all(SOFTWARE_ID)?,
+            b'V' => self.serial.write_all(SOFTWARE_VERSION)?,
+            b'p' => self.serial.write(PROGRAMMER_TYPE)?,
+            b't' => self.serial.write_all(&[DEVICE_CODE, 0])?,
+            b'T' => {
+                self.serial.read()?;
+                self.serial.write(CR)?;
+            }
+            b'x' | b'y' => {
+                self.serial.read()?;
+                self.serial.write(CR)?;
+            }
+            b'r' | b'F' | b'N' | b'Q' => self.serial.write(0xff)?,
+            b'P' | b'L' => self.serial.write(CR)?,
+            b'E' => {
+                self.writer.flush();
+                self.serial.write(CR)?;
+                return Ok(Status::Done);
+            }
+            // ESC is sent by avrdude to resynchronise, and needs no reply
+            0x1b => {}
+            _ => self.serial.write(UNKNOWN)?,
+        }
+
+        Ok(Status::Continue)
+    }
+
+    /// Give back the serial port and flash backend, after writing out any buffered data
+    pub fn release(self) -> (S, F) {
+        (self.serial, self.writer.release())
+    }
+
+    fn block_load(&mut self) -> Result<(), S::Error> {
+        let length = self.read_length()?;
+        let memory_type = self.serial.read()?;
+
+        if memory_type != MEMORY_FLASH {
+            for _ in 0..length {
+                self.serial.read()?;
+            }
+            return self.serial.write(UNKNOWN);
+        }
+
+        for _ in 0..length {
+            let byte = self.serial.read()?;
+            self.writer.write_byte(self.address, byte);
+            self.address = self.address.checked_add(1).unwrap_or(self.address);
+        }
+
+        self.serial.write(CR)
+    }
+
+    fn block_read(&mut self) -> Result<(), S::Error> {
+        let length = self.read_length()?;
+        let memory_type = self.serial.read()?;
+
+        if memory_type != MEMORY_FLASH {
+            return self.serial.write(UNKNOWN);
+        }
+
+        self.writer.flush();
+        for _ in 0..length {
+            let byte = self.writer.flash().read_byte(self.address);
+            self.serial.write(byte)?;
+            self.address = self.address.checked_add(1).unwrap_or(self.address);
+        }
+        Ok(())
+    }
+
+    fn chip_erase(&mut self) {
+        self.writer.flush();
+        let flash = self.writer.flash_mut();
+        for page in self.application.pages() {
+            flash.erase_page(page);
+        }
+        flash.rww_enable();
+    }
+
+    fn read_length(&mut self) -> Result<usize, S::Error> {
+        let high = self.serial.read()?;
+        let low = self.serial.read()?;
+        Ok(u16::from_be_bytes([high, low]) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::mock::MockFlash;
+    use crate::protocol::mock::{EndOfInput, MockSerial};
+    use std::vec::Vec;
+
+    const PAGE: u32 = SPM_PAGESIZE_BYTES as u32;
+
+    fn run(input: &[u8]) -> (Result<(), EndOfInput>, MockSerial, MockFlash) {
+        run_with(MockFlash::new(), input)
+    }
+
+    fn run_with(flash: MockFlash, input: &[u8]) -> (Result<(), EndOfInput>, MockSerial, MockFlash) {
+        let application = FlashRange::new(0u16, PAGE * 4);
+        let mut avr109 = Avr109::new(MockSerial::new(input), flash, application);
+        let result = avr109.run();
+        let (serial, flash) = avr109.release();
+        (result, serial, flash)
+    }
+
+    fn block(command: u8, data: &[u8]) -> Vec<u8> {
+        let [high, low] = (data.len() as u16).to_be_bytes();
+        let mut input = vec![command, high, low, MEMORY_FLASH];
+        input.extend(data);
+        input
+    }
+
+    #[test]
+    fn it_answers_the_avrdude_preamble() {
+        let (result, serial, _) = run(b"SVpabtTDPsLE");
+
+        let [high, low] = (SPM_PAGESIZE_BYTES as u16).to_be_bytes();
+        let mut expected = b"AVRBOOT10SYY".to_vec();
+        expected.extend([high, low, DEVICE_CODE, 0, CR, CR]);
+        expected.extend([SIGNATURE[2], SIGNATURE[1], SIGNATURE[0], CR, CR]);
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(serial.output, expected);
+    }
+
+    #[test]
+    fn it_loads_blocks_and_reads_them_back() {
+        let data: Vec<u8> = (0..SPM_PAGESIZE_BYTES * 2).map(|i| (i * 3) as u8).collect();
+        let mut input = vec![b'A', 0x00, 0x80];
+        input.extend(block(b'B', &data[..SPM_PAGESIZE_BYTES]));
+        input.extend(block(b'B', &data[SPM_PAGESIZE_BYTES..]));
+        input.extend([b'A', 0x00, 0x80]);
+        input.extend(block(b'g', &[0; 6])[..4].to_vec());
+        input.push(b'E');
+
+        let (_, serial, flash) = run(&input);
+
+        assert_eq!(&flash.memory[0x100..0x100 + data.len()], &data[..]);
+        assert_eq!(&serial.output[..3], &[CR, CR, CR]);
+        assert_eq!(&serial.output[4..10], &data[..6]);
+    }
+
+    #[test]
+    fn it_loads_blocks_smaller_than_a_page() {
+        let data: Vec<u8> = (0..PAGE as usize).map(|i| i as u8).collect();
+        let (first, second) = data.split_at(data.len() / 2);
+        let mut input = vec![b'A', 0x00, 0x00];
+        input.extend(block(b'B', first));
+        input.extend(block(b'B', second));
+        input.push(b'E');
+
+        let (_, serial, flash) = run(&input);
+
+        assert_eq!(serial.output, &[CR, CR, CR, CR]);
+        assert_eq!(flash.page(0), &data[..]);
+        assert_eq!(flash.writes, 1);
+    }
+
+    #[test]
+    fn it_loads_blocks_above_64k() {
+        let mut input = vec![b'H', 0x00, 0x80, 0x00];
+        input.extend(block(b'B', &[0xde, 0xad, 0xbe, 0xef]));
+
+        let (_, _, flash) = run(&input);
+
+        assert_eq!(&flash.memory[0x1_0000..0x1_0004], &[0xde, 0xad, 0xbe, 0xef]);
+    }
+
+    #[test]
+    fn it_rejects_addresses_beyond_24_bits() {
+        let (_, serial, _) = run(&[b'H', 0x80, 0x00, 0x00]);
+
+        assert_eq!(serial.output, &[UNKNOWN]);
+    }
+
+    #[test]
+    fn it_erases_the_application() {
+        let mut flash = MockFlash::new();
+        flash.memory.fill(0);
+
+        let (_, serial, flash) = run_with(flash, b"e");
+
+        assert_eq!(serial.output, &[CR]);
+        assert!(
+            flash.memory[..(PAGE * 4) as usize]
+                .iter()
+                .all(|b| *b == 0xff)
+        );
+        assert_eq!(flash.memory[(PAGE * 4) as usize], 0);
+    }
+
+    #[test]
+    fn it_rejects_eeprom_blocks() {
+        let (_, serial, flash) = run(&[b'B', 0x00, 0x02, b'E', 1, 2]);
+
+        assert_eq!(serial.output, &[UNKNOWN]);
+        assert_eq!(flash.writes, 0);
+    }
+
+    #[test]
+    fn it_rejects_unknown_commands() {
+        let (_, serial, _) = run(b"Z");
+
+        assert_eq!(serial.output, &[UNKNOWN]);
+    }
+}
@@ -0,0 +1,476 @@
+//! Compact framed update protocol, for links where STK500 is awkward
+//!
+//! Designed for half duplex and lossy links, such as RS-485 or radio modules. Each frame is [COBS](crate::cobs)
+//! encoded and ends with a zero byte, so a receiver can always find the start of the next frame.
+//! The `avr-boot-host` crate implements the host side.
+//!
+//! A request is: sequence number, command, arguments, CRC. A response is: the same sequence number, status, data, CRC.
+//! The CRC is a CRC-16/XMODEM of everything before it, big endian, as are all multi-byte values.
+//!
+//! | Command            | Arguments                       | Response data                                                 |
+//! |--------------------|---------------------------------|---------------------------------------------------------------|
+//! | [`CMD_INFO`]       |                                 | signature (3), page size (2), flash size (4), application start (4), application end (4) |
+//! | [`CMD_ERASE`]      |                                 | erases the whole application range                            |
+//! | [`CMD_WRITE_PAGE`] | address (4), exactly one page   |                                                               |
+//! | [`CMD_READ_PAGE`]  | address (4)                     | one page                                                      |
+//! | [`CMD_VERIFY`]     | address (4), length (4)         | CRC-16/XMODEM of the range (2)                                |
+//! | [`CMD_BOOT`]       |                                 | ends the session, so the application can be started           |
+//!
+//! Frames with a bad CRC are dropped without a reply; the host is expected to time out and send the request again,
+//! with the same sequence number. If an erase or page write is repeated like this, it isn't carried out a second time,
+//! the original status is just sent back.
+//!
+//! # Multi-drop buses
+//! Several nodes can share a half duplex bus, such as RS-485, once each is given an address with
+//! [`Framed::with_address`]. Every request and response then starts with the node address, and a node ignores
+//! requests for any other node. Requests sent to [`BROADCAST`] are carried out by every node, but never answered,
+//! so a page can be written to all of them at once, and each node polled with [`CMD_VERIFY`] afterwards.
+//! The [`Serial`] implementation is responsible for switching the transceiver between sending and receiving.
+//!
+//! # Example
+//! ```no_run
+//! use avr_boot::FlashRange;
+//! use avr_boot::flash::Spm;
+//! use avr_boot::protocol::{Serial, framed::Framed};
+//!
+//! # struct Rs485;
+//! # impl Serial for Rs485 {
+//! #     type Error = ();
+//! #     fn read(&mut self) -> Result<u8, ()> { Ok(0) }
+//! #     fn write(&mut self, _: u8) -> Result<(), ()> { Ok(()) }
+//! # }
+//! let mut bootloader = Framed::new(Rs485, Spm, FlashRange::application(4096));
+//! bootloader.run().ok();
+//! // jump to the application
+//! ```
+
+use crate::flash::Flash;
+use crate::protocol::{Serial, Status};
+use crate::{
+    Address, DataPage, FLASH_SIZE_BYTES, FlashRange, SIGNATURE, SPM_PAGESIZE_BYTES, checksum, cobs,
+    crc,
+};
+
+/// Report the device signature, page size, flash size and application range
+pub const CMD_INFO: u8 = 0x01;
+/// Erase the whole application range
+pub const CMD_ERASE: u8 = 0x02;
+/// Write a single page, which must lie within the application range
+pub const CMD_WRITE_PAGE: u8 = 0x03;
+/// Read a single page
+pub const CMD_READ_PAGE: u8 = 0x04;
+/// Calculate the CRC of a range of flash
+pub const CMD_VERIFY: u8 = 0x05;
+/// End the session
+pub const CMD_BOOT: u8 = 0x06;
+
+/// The command was carried out
+pub const STATUS_OK: u8 = 0x00;
+/// The command isn't known
+pub const STATUS_UNKNOWN_COMMAND: u8 = 0x01;
+/// The arguments were the wrong length for the command
+pub const STATUS_INVALID_LENGTH: u8 = 0x02;
+/// The address range is outside the application (for writes) or flash (for reads)
+pub const STATUS_OUT_OF_RANGE: u8 = 0x03;
+/// The address isn't at the start of a page
+pub const STATUS_NOT_ALIGNED: u8 = 0x04;
+
+/// Node address for requests which every node carries out, without replying
+pub const BROADCAST: u8 = 0xff;
+
+/// Sequence number and command/status before the data, CRC after it
+const OVERHEAD: usize = 4;
+/// A page write has the most data: a node address, a 4 byte page address and the page
+const FRAME_LENGTH: usize = 1 + SPM_PAGESIZE_BYTES + 4 + OVERHEAD;
+
+/// Framed protocol handler
+pub struct Framed<S, F> {
+    serial: S,
+    flash: F,
+    application: FlashRange,
+    /// This node's bus address, if there is more than one node
+    node: Option<u8>,
+    /// Sequence number, command and status of the last request, to spot repeats
+    last: Option<(u8, u8, u8)>,
+}
+
+impl<S: Serial, F: Flash> Framed<S, F> {
+    /// Create a new handler, talking over `serial` and programming `application` in `flash`
+    pub fn new(serial: S, flash: F, application: FlashRange) -> Self {
+        Self {
+            serial,
+            flash,
+            application,
+            node: None,
+            last: None,
+        }
+    }
+
+    /// Share a multi-drop bus with other nodes, answering only requests sent to `node`
+    ///
+    /// `node` must not be [`BROADCAST`].
+    pub fn with_address(mut self, node: u8) -> Self {
+        self.node = Some(node);
+        self
+    }
+
+    /// Handle requests until the host sends [`CMD_BOOT`]
+    pub fn run(&mut self) -> Result<(), S::Error> {
+        while self.handle_frame()? == Status::Continue {}
+        Ok(())
+    }
+
+    /// Wait for the next valid request, and answer it
+    pub fn handle_frame(&mut self) -> Result<Status, S::Error> {
+        let mut frame = [0u8; FRAME_LENGTH];
+        let (length, broadcast) = self.receive(&mut frame)?;
+
+        // Skip the node address, which the response starts with too
+        let mut response = [0u8; FRAME_LENGTH];
+        let header = match self.node {
+            Some(node) => {
+                response[0] = node;
+                1
+            }
+            None => 0,
+        };
+        let (sequence, command) = (frame[header], frame[header + 1]);
+        let arguments = &frame[header + 2..length - 2];
+
+        let (status, data_length) = match self.last {
+            Some((last_sequence, last_command, status))
+                if (last_sequence, last_command) == (sequence, command)
+                    && (command == CMD_ERASE || command == CMD_WRITE_PAGE) =>
+            {
+                (status, 0)
+            }
+            _ => self.execute(command, arguments, &mut response[header + 2..]),
+        };
+        self.last = Some((sequence, command, status));
+
+        if !broadcast {
+            response[header] = sequence;
+            response[header + 1] = status;
+            self.reply(&mut response, header + 2 + data_length)?;
+        }
+
+        if command == CMD_BOOT && status == STATUS_OK {
+            return Ok(Status::Done);
+        }
+        Ok(Status::Continue)
+    }
+
+    /// Give back the serial port and flash backend
+    pub fn release(self) -> (S, F) {
+        (self.serial, self.flash)
+    }
+
+    /// Read frames until one for this node decodes with a valid CRC
+    ///
+    /// Returns its length, including the CRC, and whether it was a broadcast.
+    fn receive(&mut self, frame: &mut [u8]) -> Result<(usize, bool), S::Error> {
+        let mut decoder = cobs::Decoder::new();
+        let header = self.node.is_some() as usize;
+
+        loop {
+            if let Some(Ok(length)) = decoder.push(frame, self.serial.read()?)
+                && length >= header + OVERHEAD
+                && crc::xmodem(&frame[..length]) == 0
+            {
+                match self.node {
+                    None => return Ok((length, false)),
+                    Some(_) if frame[0] == BROADCAST => return Ok((length, true)),
+                    Some(node) if frame[0] == node => return Ok((length, false)),
+                    Some(_) => {}
+                }
+            }
+        }
+    }
+
+    fn reply(&mut self, response: &mut [u8], length: usize) -> Result<(), S::Error> {
+        let crc = crc::xmodem(&response[..length]).to_be_bytes();
+        response[length..length + 2].copy_from_slice(&crc);
+
+        cobs::encode(&response[..length + 2], |byte| self.serial.write(byte))?;
+        self.serial.write(0)
+    }
+
+    /// Carry out a command, writing any response data to `data`, and return the status and data length
+    fn execute(&mut self, command: u8, arguments: &[u8], data: &mut [u8]) -> (u8, usize) {
+        let address =
+            |i: usize| Address::from(u32::from_be_bytes(arguments[i..i + 4].try_into().unwrap()));
+
+        match (command, arguments.len()) {
+            (CMD_INFO, 0) => {
+                data[..3].copy_from_slice(&SIGNATURE);
+                data[3..5].copy_from_slice(&(SPM_PAGESIZE_BYTES as u16).to_be_bytes());
+                data[5..9].copy_from_slice(&FLASH_SIZE_BYTES.to_be_bytes());
+                data[9..13].copy_from_slice(&u32::from(self.application.start).to_be_bytes());
+                data[13..17].copy_from_slice(&u32::from(self.application.end).to_be_bytes());
+                (STATUS_OK, 17)
+            }
+            (CMD_ERASE, 0) => {
+                for page in self.application.pages() {
+                    self.flash.erase_page(page);
+                }
+                self.flash.rww_enable();
+                (STATUS_OK, 0)
+            }
+            (CMD_WRITE_PAGE, length) if length == 4 + SPM_PAGESIZE_BYTES => {
+                let address = address(0);
+                let status = self.check_page(address, self.application);
+                if status == STATUS_OK {
+                    let page = DataPage(core::array::from_fn(|i| {
+                        u16::from_le_bytes([arguments[4 + i * 2], arguments[5 + i * 2]])
+                    }));
+                    self.flash.store_page(address, &page);
+                }
+                (status, 0)
+            }
+            (CMD_READ_PAGE, 4) => {
+                let address = address(0);
+                let status = self.check_page(address, whole_flash());
+                if status != STATUS_OK {
+                    return (status, 0);
+                }
+                for (i, byte) in data[..SPM_PAGESIZE_BYTES].iter_mut().enumerate() {
+                    *byte = self
+                        .flash
+                        .read_byte(Address::from(u32::from(address) + i as u32));
+                }
+                (STATUS_OK, SPM_PAGESIZE_BYTES)
+            }
+            (CMD_VERIFY, 8) => {
+                let start = u32::from(address(0));
+                let length = u32::from_be_bytes(arguments[4..8].try_into().unwrap());
+                if start
+                    .checked_add(length)
+                    .is_none_or(|end| end > FLASH_SIZE_BYTES)
+                {
+                    return (STATUS_OUT_OF_RANGE, 0);
+                }
+                let crc =
+                    checksum::crc16_xmodem(&self.flash, FlashRange::new(start, start + length));
+                data[..2].copy_from_slice(&crc.to_be_bytes());
+                (STATUS_OK, 2)
+            }
+            (CMD_BOOT, 0) => (STATUS_OK, 0),
+            (CMD_INFO | CMD_ERASE | CMD_WRITE_PAGE | CMD_READ_PAGE | CMD_VERIFY | CMD_BOOT, _) => {
+                (STATUS_INVALID_LENGTH, 0)
+            }
+            _ => (STATUS_UNKNOWN_COMMAND, 0),
+        }
+    }
+
+    fn check_page(&self, address: Address, range: FlashRange) -> u8 {
+        if !address.is_page_aligned() {
+            STATUS_NOT_ALIGNED
+        } else if range.start <= address && address.next_page() <= range.end {
+            STATUS_OK
+        } else {
+            STATUS_OUT_OF_RANGE
+        }
+    }
+}
+
+/// The whole of program memory
+fn whole_flash() -> FlashRange {
+    FlashRange::new(0u16, FLASH_SIZE_BYTES)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::mock::MockFlash;
+    use crate::protocol::mock::{EndOfInput, MockSerial};
+    use std::vec::Vec;
+
+    const PAGE: u32 = SPM_PAGESIZE_BYTES as u32;
+    const APPLICATION_END: u32 = PAGE * 4;
+
+    fn request(sequence: u8, command: u8, arguments: &[u8]) -> Vec<u8> {
+        let mut payload = vec![sequence, command];
+        payload.extend(arguments);
+        encode(payload)
+    }
+
+    fn addressed(node: u8, sequence: u8, command: u8, arguments: &[u8]) -> Vec<u8> {
+        let mut payload = vec![node, sequence, command];
+        payload.extend(arguments);
+        encode(payload)
+    }
+
+    /// Add the CRC, and encode the frame
+    fn encode(mut payload: Vec<u8>) -> Vec<u8> {
+        payload.extend(crc::xmodem(&payload).to_be_bytes());
+
+        let mut frame = Vec::new();
+        cobs::encode(&payload, |byte| {
+            frame.push(byte);
+            Ok::<(), ()>(())
+        })
+        .unwrap();
+        frame.push(0);
+        frame
+    }
+
+    /// Decode every response frame, checking and removing the CRCs
+    fn responses(output: &[u8]) -> Vec<Vec<u8>> {
+        let mut decoder = cobs::Decoder::new();
+        let mut frame = [0; FRAME_LENGTH];
+        let mut frames = Vec::new();
+        for byte in output {
+            if let Some(length) = decoder.push(&mut frame, *byte) {
+                let length = length.unwrap();
+                assert_eq!(crc::xmodem(&frame[..length]), 0);
+                frames.push(frame[..length - 2].to_vec());
+            }
+        }
+        frames
+    }
+
+    fn run(input: &[u8]) -> (Result<(), EndOfInput>, Vec<Vec<u8>>, MockFlash) {
+        let application = FlashRange::new(0u16, APPLICATION_END);
+        let mut framed = Framed::new(MockSerial::new(input), MockFlash::new(), application);
+        let result = framed.run();
+        let (serial, flash) = framed.release();
+        (result, responses(&serial.output), flash)
+    }
+
+    fn run_node(node: u8, input: &[u8]) -> (Result<(), EndOfInput>, Vec<Vec<u8>>, MockFlash) {
+        let application = FlashRange::new(0u16, APPLICATION_END);
+        let mut framed =
+            Framed::new(MockSerial::new(input), MockFlash::new(), application).with_address(node);
+        let result = framed.run();
+        let (serial, flash) = framed.release();
+        (result, responses(&serial.output), flash)
+    }
+
+    fn write_page(sequence: u8, address: u32, data: &[u8]) -> Vec<u8> {
+        let mut arguments = address.to_be_bytes().to_vec();
+        arguments.extend(data);
+        request(sequence, CMD_WRITE_PAGE, &arguments)
+    }
+
+    #[test]
+    fn it_reports_info() {
+        let (_, responses, _) = run(&request(7, CMD_INFO, &[]));
+
+        let mut expected = vec![7, STATUS_OK, SIGNATURE[0], SIGNATURE[1], SIGNATURE[2]];
+        expected.extend((PAGE as u16).to_be_bytes());
+        expected.extend(FLASH_SIZE_BYTES.to_be_bytes());
+        expected.extend(0u32.to_be_bytes());
+        expected.extend(APPLICATION_END.to_be_bytes());
+        assert_eq!(responses, [expected]);
+    }
+
+    #[test]
+    fn it_writes_reads_and_verifies_a_page() {
+        let data: Vec<u8> = (0..PAGE).map(|i| i as u8).collect();
+        let mut input = write_page(1, PAGE, &data);
+        input.extend(request(2, CMD_READ_PAGE, &PAGE.to_be_bytes()));
+        let mut verify = PAGE.to_be_bytes().to_vec();
+        verify.extend(PAGE.to_be_bytes());
+        input.extend(request(3, CMD_VERIFY, &verify));
+        input.extend(request(4, CMD_BOOT, &[]));
+
+        let (result, responses, flash) = run(&input);
+
+        let mut read = vec![2, STATUS_OK];
+        read.extend(&data);
+        let mut verified = vec![3, STATUS_OK];
+        verified.extend(crc::xmodem(&data).to_be_bytes());
+        assert_eq!(result, Ok(()));
+        assert_eq!(
+            responses,
+            [vec![1, STATUS_OK], read, verified, vec![4, STATUS_OK]]
+        );
+        assert_eq!(flash.page(PAGE), &data[..]);
+    }
+
+    #[test]
+    fn it_protects_everything_outside_the_application() {
+        let data = [0; SPM_PAGESIZE_BYTES];
+        let mut input = write_page(1, APPLICATION_END, &data);
+        input.extend(write_page(2, 2, &data));
+        input.extend(write_page(3, 0, &data[1..]));
+
+        let (_, responses, flash) = run(&input);
+
+        assert_eq!(
+            responses,
+            [
+                vec![1, STATUS_OUT_OF_RANGE],
+                vec![2, STATUS_NOT_ALIGNED],
+                vec![3, STATUS_INVALID_LENGTH]
+            ]
+        );
+        assert_eq!(flash.writes, 0);
+    }
+
+    #[test]
+    fn it_drops_corrupt_frames() {
+        let mut input = request(1, CMD_ERASE, &[]);
+        input[2] ^= 0x40;
+        input.extend(request(2, 0x7f, &[]));
+
+        let (_, responses, flash) = run(&input);
+
+        assert_eq!(responses, [vec![2, STATUS_UNKNOWN_COMMAND]]);
+        assert_eq!(flash.erases, 0);
+    }
+
+    #[test]
+    fn it_does_not_repeat_a_retransmitted_erase() {
+        let mut input = request(1, CMD_ERASE, &[]);
+        input.extend(request(1, CMD_ERASE, &[]));
+        input.extend(request(2, CMD_ERASE, &[]));
+
+        let (_, responses, flash) = run(&input);
+
+        assert_eq!(
+            responses,
+            [vec![1, STATUS_OK], vec![1, STATUS_OK], vec![2, STATUS_OK]]
+        );
+        assert_eq!(flash.erases, 8);
+    }
+
+    #[test]
+    fn it_only_answers_its_own_address() {
+        let mut input = addressed(2, 1, CMD_ERASE, &[]);
+        input.extend(addressed(3, 2, 0x7f, &[]));
+        input.extend(addressed(4, 3, CMD_BOOT, &[]));
+        input.extend(addressed(3, 4, CMD_BOOT, &[]));
+
+        let (result, responses, flash) = run_node(3, &input);
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(
+            responses,
+            [vec![3, 2, STATUS_UNKNOWN_COMMAND], vec![3, 4, STATUS_OK]]
+        );
+        assert_eq!(flash.erases, 0);
+    }
+
+    #[test]
+    fn it_carries_out_broadcasts_without_answering() {
+        let data = [0x5a; SPM_PAGESIZE_BYTES];
+        let mut arguments = PAGE.to_be_bytes().to_vec();
+        arguments.extend(data);
+        let mut input = addressed(BROADCAST, 1, CMD_WRITE_PAGE, &arguments);
+        input.extend(addressed(BROADCAST, 1, CMD_WRITE_PAGE, &arguments));
+        let mut verify = PAGE.to_be_bytes().to_vec();
+        verify.extend(PAGE.to_be_bytes());
+        input.extend(addressed(5, 2, CMD_VERIFY, &verify));
+        input.extend(addressed(BROADCAST, 3, CMD_BOOT, &[]));
+
+        let (result, responses, flash) = run_node(5, &input);
+
+        let mut verified = vec![5, 2, STATUS_OK];
+        verified.extend(crc::xmodem(&data).to_be_bytes());
+        assert_eq!(result, Ok(()));
+        assert_eq!(responses, [verified]);
+        assert_eq!(flash.page(PAGE), &data[..]);
+        assert_eq!(flash.writes, 1);
+    }
+}
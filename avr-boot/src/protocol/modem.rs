@@ -0,0 +1,23 @@
+//! Control characters, block sizes and packets shared by the XMODEM and YMODEM receivers
+
+pub(super) const SOH: u8 = 0x01;
+pub(super) const STX: u8 = 0x02;
+pub(super) const EOT: u8 = 0x04;
+pub(super) const ACK: u8 = 0x06;
+pub(super) const NAK: u8 = 0x15;
+pub(super) const CAN: u8 = 0x18;
+pub(super) const CRC_MODE: u8 = b'C';
+
+pub(super) const BLOCK_SIZE: usize = 128;
+pub(super) const BLOCK_SIZE_1K: usize = 1024;
+
+/// Consecutive errors allowed before giving up
+pub(super) const MAX_ERRORS: u8 = 10;
+
+/// What arrived when a packet was expected
+pub(super) enum Packet {
+    Block { number: u8, length: usize },
+    End,
+    Cancel,
+    Bad,
+}
@@ -0,0 +1,397 @@
+//! STK500v1 protocol, as spoken by Optiboot and the Arduino bootloaders
+//!
+//! This is the protocol used by `avrdude -c arduino`. Only the commands avrdude actually uses are implemented:
+//! everything else is acknowledged and ignored, the same as Optiboot does.
+//!
+//! # Example
+//! ```no_run
+//! use avr_boot::flash::Spm;
+//! use avr_boot::protocol::{Serial, stk500v1::Stk500v1};
+//!
+//! # struct Uart;
+//! # impl Serial for Uart {
+//! #     type Error = ();
+//! #     fn read(&mut self) -> Result<u8, ()> { Ok(0) }
+//! #     fn write(&mut self, _: u8) -> Result<(), ()> { Ok(()) }
+//! # }
+//! let mut bootloader = Stk500v1::new(Uart, Spm);
+//! bootloader.run().ok();
+//! // jump to the application
+//! ```
+
+use crate::flash::Flash;
+use crate::protocol::{Serial, Status};
+use crate::{Address, DataPage, SIGNATURE, SPM_PAGESIZE_BYTES, SPM_PAGESIZE_WORDS, WordAddress};
+
+const STK_OK: u8 = 0x10;
+const STK_FAILED: u8 = 0x11;
+const STK_INSYNC: u8 = 0x14;
+const STK_NOSYNC: u8 = 0x15;
+const CRC_EOP: u8 = 0x20;
+
+const STK_GET_PARAMETER: u8 = 0x41;
+const STK_SET_DEVICE: u8 = 0x42;
+const STK_SET_DEVICE_EXT: u8 = 0x45;
+const STK_LEAVE_PROGMODE: u8 = 0x51;
+const STK_LOAD_ADDRESS: u8 = 0x55;
+const STK_UNIVERSAL: u8 = 0x56;
+const STK_PROG_PAGE: u8 = 0x64;
+const STK_READ_PAGE: u8 = 0x74;
+const STK_READ_SIGN: u8 = 0x75;
+
+const PARM_STK_SW_MAJOR: u8 = 0x81;
+const PARM_STK_SW_MINOR: u8 = 0x82;
+
+const AVR_OP_LOAD_EXT_ADDR: u8 = 0x4d;
+
+const SET_DEVICE_LENGTH: usize = 20;
+const SET_DEVICE_EXT_LENGTH: usize = 5;
+
+/// STK500v1 command handler
+pub struct Stk500v1<S, F> {
+    serial: S,
+    flash: F,
+    address: WordAddress,
+}
+
+impl<S: Serial, F: Flash> Stk500v1<S, F> {
+    /// Software version reported to the programmer
+    pub const VERSION: (u8, u8) = (8, 0);
+
+    /// Create a new handler, talking over `serial` and programming `flash`
+    pub fn new(serial: S, flash: F) -> Self {
+        Self {
+            serial,
+            flash,
+            address: WordAddress::from(0u16),
+        }
+    }
+
+    /// Handle commands until the programmer sends LEAVE_PROGMODE
+    pub fn run(&mut self) -> Result<(), S::Error> {
+        while self.handle_command()? == Status::Continue {}
+        Ok(())
+    }
+
+    /// Read and handle a single command
+    pub fn handle_command(&mut self) -> Result<Status, S::Error> {
+        match self.serial.read()? {
+            STK_GET_PARAMETER => {
+                let value = match self.serial.read()? {
+                    PARM_STK_SW_MAJOR => Self::VERSION.0,
+                    PARM_STK_SW_MINOR => Self::VERSION.1,
+                    _ => 0x03,
+                };
+                self.reply(&[value])?;
+            }
+            STK_SET_DEVICE => {
+                self.skip(SET_DEVICE_LENGTH)?;
+                self.reply(&[])?;
+            }
+            STK_SET_DEVICE_EXT => {
+                self.skip(SET_DEVICE_EXT_LENGTH)?;
+                self.reply(&[])?;
+            }
+            STK_LOAD_ADDRESS => {
+                let low = self.serial.read()?;
+                let high = self.serial.read()?;
+                let extended = u32::from(self.address) & 0xff_0000;
+                // Keeping the current extended part, this is always in range
+                self.address =
+                    WordAddress::try_from(extended | u16::from_le_bytes([low, high]) as u32)
+                        .unwrap_or(self.address);
+                self.reply(&[])?;
+            }
+            STK_UNIVERSAL => {
+                let mut command = [0; 4];
+                self.read_into(&mut command)?;
+                if command[0] == AVR_OP_LOAD_EXT_ADDR {
+                    let word = u32::from(self.address) & 0xffff;
+                    match WordAddress::try_from(((command[2] as u32) << 16) | word) {
+                        Ok(address) => self.address = address,
+                        Err(_) => {
+                            self.reply_status(STK_FAILED)?;
+                            return Ok(Status::Continue);
+                        }
+                    }
+                }
+                self.reply(&[0x00])?;
+            }
+            STK_PROG_PAGE => self.prog_page()?,
+            STK_READ_PAGE => self.read_page()?,
+            STK_READ_SIGN => {
+                self.reply(&SIGNATURE)?;
+            }
+            STK_LEAVE_PROGMODE => {
+                self.reply(&[])?;
+                return Ok(Status::Done);
+            }
+            // GET_SYNC, ENTER_PROGMODE, CHIP_ERASE etc. just need acknowledging
+            _ => {
+                self.reply(&[])?;
+            }
+        }
+
+        Ok(Status::Continue)
+    }
+
+    /// Give back the serial port and flash backend
+    pub fn release(self) -> (S, F) {
+        (self.serial, self.flash)
+    }
+
+    fn prog_page(&mut self) -> Result<(), S::Error> {
+        let length = self.read_length()?;
+        let memory_type = self.serial.read()?;
+
+        if memory_type != b'F' || length > SPM_PAGESIZE_BYTES {
+            self.skip(length)?;
+            return self.reply_status(STK_FAILED);
+        }
+
+        let mut page = DataPage([0xffff; SPM_PAGESIZE_WORDS]);
+        for i in 0..length {
+            let byte = self.serial.read()?;
+            let word = &mut page.0[i / 2];
+            *word = if i % 2 == 0 {
+                (*word & 0xff00) | byte as u16
+            } else {
+                (*word & 0x00ff) | ((byte as u16) << 8)
+            };
+        }
+
+        if self.verify_space()? {
+            self.flash.store_page(self.address.into(), &page);
+            self.serial.write(STK_OK)?;
+        }
+        Ok(())
+    }
+
+    fn read_page(&mut self) -> Result<(), S::Error> {
+        let length = self.read_length()?;
+        let memory_type = self.serial.read()?;
+
+        if !self.verify_space()? {
+            return Ok(());
+        }
+
+        let mut address: Address = self.address.into();
+        for _ in 0..length {
+            let byte = if memory_type == b'F' {
+                self.flash.read_byte(address)
+            } else {
+                0xff
+            };
+            self.serial.write(byte)?;
+            address = address.checked_add(1).unwrap_or(address);
+        }
+
+        self.serial.write(STK_OK)
+    }
+
+    fn read_length(&mut self) -> Result<usize, S::Error> {
+        let high = self.serial.read()?;
+        let low = self.serial.read()?;
+        Ok(u16::from_be_bytes([high, low]) as usize)
+    }
+
+    fn read_into(&mut self, buf: &mut [u8]) -> Result<(), S::Error> {
+        for byte in buf.iter_mut() {
+            *byte = self.serial.read()?;
+        }
+        Ok(())
+    }
+
+    fn skip(&mut self, length: usize) -> Result<(), S::Error> {
+        for _ in 0..length {
+            self.serial.read()?;
+        }
+        Ok(())
+    }
+
+    /// Every command ends with CRC_EOP, reply with INSYNC if it's there, or NOSYNC if not
+    fn verify_space(&mut self) -> Result<bool, S::Error> {
+        if self.serial.read()? == CRC_EOP {
+            self.serial.write(STK_INSYNC)?;
+            Ok(true)
+        } else {
+            self.serial.write(STK_NOSYNC)?;
+            Ok(false)
+        }
+    }
+
+    fn reply(&mut self, data: &[u8]) -> Result<(), S::Error> {
+        if self.verify_space()? {
+            self.serial.write_all(data)?;
+            self.serial.write(STK_OK)?;
+        }
+        Ok(())
+    }
+
+    fn reply_status(&mut self, status: u8) -> Result<(), S::Error> {
+        if self.verify_space()? {
+            self.serial.write(status)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::mock::MockFlash;
+    use crate::protocol::mock::{EndOfInput, MockSerial};
+    use std::vec::Vec;
+
+    fn run(input: &[u8]) -> (Result<(), EndOfInput>, MockSerial, MockFlash) {
+        let mut stk = Stk500v1::new(MockSerial::new(input), MockFlash::new());
+        let result = stk.run();
+        let (serial, flash) = stk.release();
+        (result, serial, flash)
+    }
+
+    fn prog_page(address: u16, data: &[u8]) -> Vec<u8> {
+        let word = (address / 2).to_le_bytes();
+        let length = (data.len() as u16).to_be_bytes();
+
+        let mut command = vec![STK_LOAD_ADDRESS, word[0], word[1], CRC_EOP];
+        command.extend([STK_PROG_PAGE, length[0], length[1], b'F']);
+        command.extend(data);
+        command.push(CRC_EOP);
+        command
+    }
+
+    /// The start of an `avrdude -c arduino` session, as captured from the wire
+    const AVRDUDE_PREAMBLE: &[u8] = &[
+        0x30, 0x20, 0x30, 0x20, 0x30, 0x20, // GET_SYNC x3
+        0x41, 0x81, 0x20, 0x41, 0x82, 0x20, // GET_PARAMETER sw major, minor
+        0x42, 0x86, 0x00, 0x00, 0x01, 0x01, 0x01, 0x01, 0x03, 0xff, 0xff, 0xff, 0xff, 0x00, 0x80,
+        0x04, 0x00, 0x00, 0x00, 0x80, 0x00, 0x20, // SET_DEVICE
+        0x45, 0x05, 0x04, 0xd7, 0xc2, 0x00, 0x20, // SET_DEVICE_EXT
+        0x50, 0x20, // ENTER_PROGMODE
+        0x75, 0x20, // READ_SIGN
+    ];
+
+    #[test]
+    fn it_answers_the_avrdude_preamble() {
+        let mut input = AVRDUDE_PREAMBLE.to_vec();
+        input.extend([STK_LEAVE_PROGMODE, CRC_EOP]);
+
+        let (result, serial, _) = run(&input);
+
+        let mut expected = vec![0x14, 0x10, 0x14, 0x10, 0x14, 0x10];
+        expected.extend([0x14, 8, 0x10, 0x14, 0, 0x10]);
+        expected.extend([0x14, 0x10, 0x14, 0x10, 0x14, 0x10]);
+        expected.extend([0x14, SIGNATURE[0], SIGNATURE[1], SIGNATURE[2], 0x10]);
+        expected.extend([0x14, 0x10]);
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(serial.output, expected);
+    }
+
+    #[test]
+    fn it_programs_and_reads_back_a_page() {
+        let data: Vec<u8> = (0..SPM_PAGESIZE_BYTES).map(|i| i as u8).collect();
+        let length = (SPM_PAGESIZE_BYTES as u16).to_be_bytes();
+        let mut input = prog_page(0x1000, &data);
+        input.extend([STK_READ_PAGE, length[0], length[1], b'F', CRC_EOP]);
+        input.extend([STK_LEAVE_PROGMODE, CRC_EOP]);
+
+        let (result, serial, flash) = run(&input);
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(flash.page(0x1000), &data[..]);
+        assert_eq!(
+            &serial.output[..6],
+            &[0x14, 0x10, 0x14, 0x10, 0x14, data[0]]
+        );
+        assert_eq!(&serial.output[5..5 + data.len()], &data[..]);
+    }
+
+    #[test]
+    fn it_pads_a_short_page_with_ff() {
+        let (_, _, flash) = run(&prog_page(0x200, &[1, 2, 3]));
+
+        assert_eq!(&flash.page(0x200)[..4], &[1, 2, 3, 0xff]);
+        assert!(flash.page(0x200)[4..].iter().all(|b| *b == 0xff));
+    }
+
+    #[test]
+    fn it_keeps_the_extended_address_from_universal() {
+        let mut stk = Stk500v1::new(
+            MockSerial::new(&[
+                STK_UNIVERSAL,
+                AVR_OP_LOAD_EXT_ADDR,
+                0x00,
+                0x01,
+                0x00,
+                CRC_EOP,
+            ]),
+            MockFlash::new(),
+        );
+        stk.address = WordAddress::from(0x1234u16);
+
+        assert_eq!(stk.handle_command(), Ok(Status::Continue));
+        assert_eq!(stk.address, WordAddress::try_from(0x1_1234u32).unwrap());
+        assert_eq!(stk.serial.output, &[0x14, 0x00, 0x10]);
+    }
+
+    #[test]
+    fn it_refuses_an_extended_address_beyond_24_bits() {
+        let mut stk = Stk500v1::new(
+            MockSerial::new(&[
+                STK_UNIVERSAL,
+                AVR_OP_LOAD_EXT_ADDR,
+                0x00,
+                0x80,
+                0x00,
+                CRC_EOP,
+            ]),
+            MockFlash::new(),
+        );
+
+        assert_eq!(stk.handle_command(), Ok(Status::Continue));
+        assert_eq!(stk.address, WordAddress::from(0u16));
+        assert_eq!(stk.serial.output, &[STK_INSYNC, STK_FAILED]);
+    }
+
+    #[test]
+    fn it_programs_above_64k() {
+        let mut input = vec![
+            STK_UNIVERSAL,
+            AVR_OP_LOAD_EXT_ADDR,
+            0x00,
+            0x00,
+            0x00,
+            CRC_EOP,
+        ];
+        input.extend([STK_LOAD_ADDRESS, 0x00, 0x80, CRC_EOP]);
+        input.extend([STK_PROG_PAGE, 0x00, 0x02, b'F', 0xaa, 0x55, CRC_EOP]);
+
+        let (_, _, flash) = run(&input);
+
+        assert_eq!(&flash.page(0x1_0000)[..2], &[0xaa, 0x55]);
+        assert_eq!(flash.page(0x0000)[0], 0xff);
+    }
+
+    #[test]
+    fn it_does_not_write_without_sync() {
+        let mut input = prog_page(0x100, &[1, 2]);
+        *input.last_mut().unwrap() = 0x00;
+
+        let (_, serial, flash) = run(&input);
+
+        assert_eq!(serial.output.last(), Some(&STK_NOSYNC));
+        assert_eq!(flash.writes, 0);
+    }
+
+    #[test]
+    fn it_rejects_eeprom_writes() {
+        let input = [STK_PROG_PAGE, 0x00, 0x02, b'E', 1, 2, CRC_EOP];
+
+        let (_, serial, flash) = run(&input);
+
+        assert_eq!(serial.output, &[STK_INSYNC, STK_FAILED]);
+        assert_eq!(flash.writes, 0);
+    }
+}
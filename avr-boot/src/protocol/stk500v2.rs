@@ -0,0 +1,491 @@
+//! STK500v2 protocol, as spoken by the Arduino Mega (stk500boot) bootloader
+//!
+//! This is the protocol used by `avrdude -c wiring`. Each message is framed as:
+//!
+//! `MESSAGE_START`, sequence number, 16 bit size, `TOKEN`, body, XOR checksum of everything before it.
+//!
+//! Addresses from `CMD_LOAD_ADDRESS` are word addresses. The high bit (`0x80000000`) is set by avrdude on parts
+//! with more than 64k words of flash, to request a "load extended address": here the extended byte is simply
+//! carried in the [`Address`], and written to RAMPZ by the [`Flash`] backend when needed.
+//!
+//! # Example
+//! ```no_run
+//! use avr_boot::flash::Spm;
+//! use avr_boot::protocol::{Serial, stk500v2::Stk500v2};
+//!
+//! # struct Uart;
+//! # impl Serial for Uart {
+//! #     type Error = ();
+//! #     fn read(&mut self) -> Result<u8, ()> { Ok(0) }
+//! #     fn write(&mut self, _: u8) -> Result<(), ()> { Ok(()) }
+//! # }
+//! let mut bootloader = Stk500v2::new(Uart, Spm);
+//! bootloader.run().ok();
+//! // jump to the application
+//! ```
+
+use crate::flash::Flash;
+use crate::protocol::{Serial, Status};
+use crate::{Address, DataPage, SIGNATURE, SPM_PAGESIZE_BYTES, SPM_PAGESIZE_WORDS, WordAddress};
+
+const MESSAGE_START: u8 = 0x1b;
+const TOKEN: u8 = 0x0e;
+
+const CMD_SIGN_ON: u8 = 0x01;
+const CMD_GET_PARAMETER: u8 = 0x03;
+const CMD_LOAD_ADDRESS: u8 = 0x06;
+const CMD_LEAVE_PROGMODE_ISP: u8 = 0x11;
+const CMD_PROGRAM_FLASH_ISP: u8 = 0x13;
+const CMD_READ_FLASH_ISP: u8 = 0x14;
+const CMD_PROGRAM_EEPROM_ISP: u8 = 0x15;
+const CMD_READ_EEPROM_ISP: u8 = 0x16;
+const CMD_READ_FUSE_ISP: u8 = 0x18;
+const CMD_READ_LOCK_ISP: u8 = 0x1a;
+const CMD_READ_SIGNATURE_ISP: u8 = 0x1b;
+const CMD_SPI_MULTI: u8 = 0x1d;
+
+const ANSWER_CKSUM_ERROR: u8 = 0xb0;
+
+const STATUS_CMD_OK: u8 = 0x00;
+const STATUS_CMD_FAILED: u8 = 0xc0;
+const STATUS_CKSUM_ERROR: u8 = 0xc1;
+
+const PARAM_HW_VER: u8 = 0x90;
+const PARAM_SW_MAJOR: u8 = 0x91;
+const PARAM_SW_MINOR: u8 = 0x92;
+
+const SIGN_ON_NAME: &[u8] = b"AVRISP_2";
+const EXTENDED_ADDRESS_FLAG: u32 = 0x8000_0000;
+const SPI_READ_SIGNATURE: u8 = 0x30;
+
+/// CMD_PROGRAM_FLASH_ISP has 10 bytes before the page data
+const PROGRAM_HEADER_LENGTH: usize = 10;
+const BUFFER_LENGTH: usize = SPM_PAGESIZE_BYTES + PROGRAM_HEADER_LENGTH;
+
+/// The longest CMD_READ_FLASH_ISP whose reply, with its 3 extra bytes, still has a 16 bit size
+const MAX_READ_LENGTH: u16 = u16::MAX - 3;
+
+/// STK500v2 message handler
+pub struct Stk500v2<S, F> {
+    serial: S,
+    flash: F,
+    address: Address,
+}
+
+impl<S: Serial, F: Flash> Stk500v2<S, F> {
+    /// Hardware and software versions reported to the programmer, the same as stk500boot
+    pub const VERSION: (u8, u8, u8) = (0x0f, 0x02, 0x0a);
+
+    /// Create a new handler, talking over `serial` and programming `flash`
+    pub fn new(serial: S, flash: F) -> Self {
+        Self {
+            serial,
+            flash,
+            address: Address::from(0u16),
+        }
+    }
+
+    /// Handle messages until the programmer sends CMD_LEAVE_PROGMODE_ISP
+    pub fn run(&mut self) -> Result<(), S::Error> {
+        while self.handle_message()? == Status::Continue {}
+        Ok(())
+    }
+
+    /// Read and handle a single message
+    ///
+    /// Bytes before the next `MESSAGE_START` are discarded.
+    /// A message with a bad checksum or an unexpected token is answered with a checksum error.
+    pub fn handle_message(&mut self) -> Result<Status, S::Error> {
+        while self.serial.read()? != MESSAGE_START {}
+
+        let sequence = self.serial.read()?;
+        let size_high = self.serial.read()?;
+        let size_low = self.serial.read()?;
+        let token = self.serial.read()?;
+        let size = u16::from_be_bytes([size_high, size_low]) as usize;
+
+        let mut checksum = MESSAGE_START ^ sequence ^ size_high ^ size_low ^ token;
+        let mut body = [0u8; BUFFER_LENGTH];
+        for i in 0..size {
+            let byte = self.serial.read()?;
+            checksum ^= byte;
+            if let Some(b) = body.get_mut(i) {
+                *b = byte;
+            }
+        }
+        checksum ^= self.serial.read()?;
+
+        if checksum != 0 || token != TOKEN {
+            self.reply(sequence, &[ANSWER_CKSUM_ERROR, STATUS_CKSUM_ERROR])?;
+            return Ok(Status::Continue);
+        }
+
+        if size > BUFFER_LENGTH || size == 0 {
+            self.reply(sequence, &[body[0], STATUS_CMD_FAILED])?;
+            return Ok(Status::Continue);
+        }
+
+        self.handle_command(sequence, &body[..size])
+    }
+
+    /// Give back the serial port and flash backend
+    pub fn release(self) -> (S, F) {
+        (self.serial, self.flash)
+    }
+
+    fn handle_command(&mut self, sequence: u8, body: &[u8]) -> Result<Status, S::Error> {
+        let command = body[0];
+        let arg = |i: usize| body.get(i).copied().unwrap_or(0);
+
+        match command {
+            CMD_SIGN_ON => {
+                let mut reply = [0; 3 + SIGN_ON_NAME.len()];
+                reply[..3].copy_from_slice(&[command, STATUS_CMD_OK, SIGN_ON_NAME.len() as u8]);
+                reply[3..].copy_from_slice(SIGN_ON_NAME);
+                self.reply(sequence, &reply)?;
+            }
+            CMD_GET_PARAMETER => {
+                let value = match arg(1) {
+                    PARAM_HW_VER => Self::VERSION.0,
+                    PARAM_SW_MAJOR => Self::VERSION.1,
+                    PARAM_SW_MINOR => Self::VERSION.2,
+                    _ => 0,
+                };
+                self.reply(sequence, &[command, STATUS_CMD_OK, value])?;
+            }
+            CMD_LOAD_ADDRESS => {
+                let word = u32::from_be_bytes([arg(1), arg(2), arg(3), arg(4)]);
+                match WordAddress::try_from(word & !EXTENDED_ADDRESS_FLAG) {
+                    Ok(address) => {
+                        self.address = address.into();
+                        self.reply(sequence, &[command, STATUS_CMD_OK])?;
+                    }
+                    Err(_) => self.reply(sequence, &[command, STATUS_CMD_FAILED])?,
+                }
+            }
+            CMD_PROGRAM_FLASH_ISP => {
+                let status = self.program_flash(body);
+                self.reply(sequence, &[command, status])?;
+            }
+            CMD_READ_FLASH_ISP => {
+                let length = u16::from_be_bytes([arg(1), arg(2)]);
+                if length > MAX_READ_LENGTH {
+                    self.reply(sequence, &[command, STATUS_CMD_FAILED])?;
+                } else {
+                    self.read_flash(sequence, length)?;
+                }
+            }
+            CMD_PROGRAM_EEPROM_ISP | CMD_READ_EEPROM_ISP => {
+                self.reply(sequence, &[command, STATUS_CMD_FAILED])?;
+            }
+            CMD_READ_SIGNATURE_ISP => {
+                let byte = SIGNATURE.get(arg(4) as usize).copied().unwrap_or(0);
+                self.reply(sequence, &[command, STATUS_CMD_OK, byte, STATUS_CMD_OK])?;
+            }
+            CMD_READ_FUSE_ISP | CMD_READ_LOCK_ISP => {
+                self.reply(sequence, &[command, STATUS_CMD_OK, 0xff, STATUS_CMD_OK])?;
+            }
+            CMD_SPI_MULTI => {
+                // Only the "read signature byte" instruction gets a real answer
+                let answer = if arg(4) == SPI_READ_SIGNATURE {
+                    SIGNATURE.get(arg(6) as usize).copied().unwrap_or(0)
+                } else {
+                    0
+                };
+                self.reply(
+                    sequence,
+                    &[
+                        command,
+                        STATUS_CMD_OK,
+                        0,
+                        arg(4),
+                        arg(5),
+                        answer,
+                        STATUS_CMD_OK,
+                    ],
+                )?;
+            }
+            CMD_LEAVE_PROGMODE_ISP => {
+                self.reply(sequence, &[command, STATUS_CMD_OK])?;
+                return Ok(Status::Done);
+            }
+            // CMD_ENTER_PROGMODE_ISP, CMD_SET_PARAMETER, CMD_CHIP_ERASE_ISP etc. just need acknowledging
+            _ => {
+                self.reply(sequence, &[command, STATUS_CMD_OK])?;
+            }
+        }
+
+        Ok(Status::Continue)
+    }
+
+    /// Program one page, then advance the address past it
+    ///
+    /// Fails if the message is too short for its length, or the length is more than a page.
+    fn program_flash(&mut self, body: &[u8]) -> u8 {
+        let Some(&[length_high, length_low]) = body.get(1..3) else {
+            return STATUS_CMD_FAILED;
+        };
+        let length = u16::from_be_bytes([length_high, length_low]) as usize;
+        if length > SPM_PAGESIZE_BYTES {
+            return STATUS_CMD_FAILED;
+        }
+        let Some(data) = body.get(PROGRAM_HEADER_LENGTH..PROGRAM_HEADER_LENGTH + length) else {
+            return STATUS_CMD_FAILED;
+        };
+
+        let mut page = DataPage([0xffff; SPM_PAGESIZE_WORDS]);
+        for (word, bytes) in page.0.iter_mut().zip(data.chunks(2)) {
+            *word = u16::from_le_bytes([bytes[0], bytes.get(1).copied().unwrap_or(0xff)]);
+        }
+
+        self.flash.store_page(self.address, &page);
+        self.address = self
+            .address
+            .checked_add(length as u32)
+            .unwrap_or(self.address);
+        STATUS_CMD_OK
+    }
+
+    /// Reply with `length` bytes of flash, then advance the address past them
+    fn read_flash(&mut self, sequence: u8, length: u16) -> Result<(), S::Error> {
+        let mut reply = Reply::start(&mut self.serial, sequence, length as usize + 3)?;
+        reply.write_all(&[CMD_READ_FLASH_ISP, STATUS_CMD_OK])?;
+        for _ in 0..length {
+            reply.write(self.flash.read_byte(self.address))?;
+            self.address = self.address.checked_add(1).unwrap_or(self.address);
+        }
+        reply.write(STATUS_CMD_OK)?;
+        reply.finish()
+    }
+
+    fn reply(&mut self, sequence: u8, body: &[u8]) -> Result<(), S::Error> {
+        let mut reply = Reply::start(&mut self.serial, sequence, body.len())?;
+        reply.write_all(body)?;
+        reply.finish()
+    }
+}
+
+/// Writes a framed reply, keeping track of the checksum
+struct Reply<'a, S> {
+    serial: &'a mut S,
+    checksum: u8,
+}
+
+impl<'a, S: Serial> Reply<'a, S> {
+    fn start(serial: &'a mut S, sequence: u8, size: usize) -> Result<Self, S::Error> {
+        let mut reply = Reply {
+            serial,
+            checksum: 0,
+        };
+        let [size_high, size_low] = (size as u16).to_be_bytes();
+        reply.write_all(&[MESSAGE_START, sequence, size_high, size_low, TOKEN])?;
+        Ok(reply)
+    }
+
+    fn write(&mut self, byte: u8) -> Result<(), S::Error> {
+        self.checksum ^= byte;
+        self.serial.write(byte)
+    }
+
+    fn write_all(&mut self, bytes: &[u8]) -> Result<(), S::Error> {
+        bytes.iter().try_for_each(|byte| self.write(*byte))
+    }
+
+    fn finish(self) -> Result<(), S::Error> {
+        self.serial.write(self.checksum)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::mock::MockFlash;
+    use crate::protocol::mock::{EndOfInput, MockSerial};
+    use std::vec::Vec;
+
+    fn frame(sequence: u8, body: &[u8]) -> Vec<u8> {
+        let [size_high, size_low] = (body.len() as u16).to_be_bytes();
+        let mut message = vec![MESSAGE_START, sequence, size_high, size_low, TOKEN];
+        message.extend(body);
+        message.push(message.iter().fold(0, |checksum, byte| checksum ^ byte));
+        message
+    }
+
+    fn run(input: &[u8]) -> (Result<(), EndOfInput>, MockSerial, MockFlash) {
+        let mut stk = Stk500v2::new(MockSerial::new(input), MockFlash::new());
+        let result = stk.run();
+        let (serial, flash) = stk.release();
+        (result, serial, flash)
+    }
+
+    fn program_flash(sequence: u8, data: &[u8]) -> Vec<u8> {
+        let [size_high, size_low] = (data.len() as u16).to_be_bytes();
+        let mut body = vec![
+            CMD_PROGRAM_FLASH_ISP,
+            size_high,
+            size_low,
+            0xc1,
+            0x0a,
+            0x40,
+            0x4c,
+            0x20,
+            0,
+            0,
+        ];
+        body.extend(data);
+        frame(sequence, &body)
+    }
+
+    #[test]
+    fn it_signs_on() {
+        let mut input = frame(1, &[CMD_SIGN_ON]);
+        input.extend(frame(2, &[CMD_LEAVE_PROGMODE_ISP, 1, 1]));
+
+        let (result, serial, _) = run(&input);
+
+        let mut expected = frame(1, b"\x01\x00\x08AVRISP_2");
+        expected.extend(frame(2, &[CMD_LEAVE_PROGMODE_ISP, STATUS_CMD_OK]));
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(serial.output, expected);
+    }
+
+    #[test]
+    fn it_reads_the_signature() {
+        let mut input = Vec::new();
+        for i in 0..3 {
+            input.extend(frame(i, &[CMD_READ_SIGNATURE_ISP, 0x04, 0x30, 0x00, i]));
+        }
+
+        let (_, serial, _) = run(&input);
+
+        let mut expected = Vec::new();
+        for i in 0..3 {
+            expected.extend(frame(
+                i,
+                &[
+                    CMD_READ_SIGNATURE_ISP,
+                    STATUS_CMD_OK,
+                    SIGNATURE[i as usize],
+                    STATUS_CMD_OK,
+                ],
+            ));
+        }
+        assert_eq!(serial.output, expected);
+    }
+
+    #[test]
+    fn it_programs_above_64k_with_the_extended_flag() {
+        let data: Vec<u8> = (0..SPM_PAGESIZE_BYTES).map(|i| i as u8).collect();
+        let mut input = frame(1, &[CMD_LOAD_ADDRESS, 0x80, 0x00, 0x80, 0x00]);
+        input.extend(program_flash(2, &data));
+
+        let (_, serial, flash) = run(&input);
+
+        let mut expected = frame(1, &[CMD_LOAD_ADDRESS, STATUS_CMD_OK]);
+        expected.extend(frame(2, &[CMD_PROGRAM_FLASH_ISP, STATUS_CMD_OK]));
+        assert_eq!(serial.output, expected);
+        assert_eq!(flash.page(0x1_0000), &data[..]);
+    }
+
+    #[test]
+    fn it_advances_the_address_after_each_page() {
+        let mut input = frame(1, &[CMD_LOAD_ADDRESS, 0, 0, 0, 0]);
+        input.extend(program_flash(2, &[0x11; SPM_PAGESIZE_BYTES]));
+        input.extend(program_flash(3, &[0x22; SPM_PAGESIZE_BYTES]));
+
+        let (_, _, flash) = run(&input);
+
+        assert!(flash.page(0).iter().all(|b| *b == 0x11));
+        assert!(
+            flash
+                .page(SPM_PAGESIZE_BYTES as u32)
+                .iter()
+                .all(|b| *b == 0x22)
+        );
+    }
+
+    #[test]
+    fn it_reads_flash() {
+        let mut input = frame(1, &[CMD_LOAD_ADDRESS, 0, 0, 0x01, 0x00]);
+        input.extend(program_flash(2, &[1, 2, 3, 4]));
+        input.extend(frame(3, &[CMD_LOAD_ADDRESS, 0, 0, 0x01, 0x00]));
+        input.extend(frame(4, &[CMD_READ_FLASH_ISP, 0x00, 0x04, 0x20]));
+
+        let (_, serial, _) = run(&input);
+
+        let tail = frame(
+            4,
+            &[CMD_READ_FLASH_ISP, STATUS_CMD_OK, 1, 2, 3, 4, STATUS_CMD_OK],
+        );
+        assert!(serial.output.ends_with(&tail));
+    }
+
+    #[test]
+    fn it_fails_a_program_message_too_short_for_its_length() {
+        let mut input = frame(1, &[CMD_PROGRAM_FLASH_ISP]);
+        input.extend(frame(2, &[CMD_PROGRAM_FLASH_ISP, 0x01]));
+
+        let (_, serial, flash) = run(&input);
+
+        let mut expected = frame(1, &[CMD_PROGRAM_FLASH_ISP, STATUS_CMD_FAILED]);
+        expected.extend(frame(2, &[CMD_PROGRAM_FLASH_ISP, STATUS_CMD_FAILED]));
+        assert_eq!(serial.output, expected);
+        assert_eq!(flash.writes, 0);
+    }
+
+    #[test]
+    fn it_fails_to_program_more_than_a_page() {
+        let mut input = frame(1, &[CMD_LOAD_ADDRESS, 0, 0, 0, 0]);
+        input.extend(program_flash(2, &[0x11; SPM_PAGESIZE_BYTES + 2]));
+
+        let (_, serial, flash) = run(&input);
+
+        assert!(
+            serial
+                .output
+                .ends_with(&frame(2, &[CMD_PROGRAM_FLASH_ISP, STATUS_CMD_FAILED]))
+        );
+        assert_eq!(flash.writes, 0);
+    }
+
+    #[test]
+    fn it_fails_a_read_too_long_for_the_reply() {
+        let input = frame(1, &[CMD_READ_FLASH_ISP, 0xff, 0xfd, 0x20]);
+
+        let (_, serial, _) = run(&input);
+
+        assert_eq!(
+            serial.output,
+            frame(1, &[CMD_READ_FLASH_ISP, STATUS_CMD_FAILED])
+        );
+    }
+
+    #[test]
+    fn it_rejects_a_bad_checksum() {
+        let mut input = program_flash(7, &[1, 2, 3, 4]);
+        *input.last_mut().unwrap() ^= 0xff;
+
+        let (_, serial, flash) = run(&input);
+
+        assert_eq!(
+            serial.output,
+            frame(7, &[ANSWER_CKSUM_ERROR, STATUS_CKSUM_ERROR])
+        );
+        assert_eq!(flash.writes, 0);
+    }
+
+    #[test]
+    fn it_skips_noise_before_message_start() {
+        let mut input = vec![0x00, 0xff, 0x30, 0x20];
+        input.extend(frame(1, &[CMD_LEAVE_PROGMODE_ISP, 1, 1]));
+
+        let (result, serial, _) = run(&input);
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(
+            serial.output,
+            frame(1, &[CMD_LEAVE_PROGMODE_ISP, STATUS_CMD_OK])
+        );
+    }
+}
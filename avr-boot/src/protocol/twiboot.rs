@@ -0,0 +1,368 @@
+//! twiboot compatible I2C (TWI) protocol
+//!
+//! This is the command set of [twiboot](https://github.com/orempel/twiboot), so its host tool can be used to
+//! program MCUs sitting on an I2C bus. The MCU is always the slave; the bus address is configured in the
+//! [`I2cSlave`] implementation, twiboot uses `0x29` by default.
+//!
+//! | Master sends                              | Master reads       |                          |
+//! |-------------------------------------------|--------------------|--------------------------|
+//! | `0x00`                                    |                    | abort the boot timeout   |
+//! | `0x01`                                    | 16 bytes           | read version             |
+//! | `0x01 0x80`                               |                    | switch to application    |
+//! | `0x02 0x00 0x00 0x00`                     | 8 bytes            | read chip info           |
+//! | `0x02 0x01 addrh addrl`                   | any number         | read flash               |
+//! | `0x02 0x01 addrh addrl data...`           |                    | write flash              |
+//! | `0x02 0x02 addrh addrl`                   | any number         | read EEPROM              |
+//! | `0x02 0x02 addrh addrl data...`           |                    | write EEPROM             |
+//!
+//! Addresses are 16 bit byte addresses, so only the first 64k of flash can be reached.
+//! Flash writes outside of the application range are ignored, so the bootloader can't overwrite itself.
+//!
+//! Flash writes go through a [`FlashWriter`]. The chip info reports a page size of at most 128 bytes, which is
+//! all twiboot can describe, but as long as a 256 byte page is sent in order as two halves it will be written in one go.
+//! Any buffered page is written out before the next read transaction, or when switching to the application.
+//!
+//! # Example
+//! ```no_run
+//! use avr_boot::FlashRange;
+//! use avr_boot::eeprom::InternalEeprom;
+//! use avr_boot::flash::Spm;
+//! use avr_boot::protocol::{I2cSlave, Transaction, twiboot::Twiboot};
+//!
+//! # struct Twi;
+//! # impl I2cSlave for Twi {
+//! #     type Error = ();
+//! #     fn next_transaction(&mut self) -> Result<Transaction, ()> { Ok(Transaction::Write) }
+//! #     fn read(&mut self) -> Result<Option<u8>, ()> { Ok(None) }
+//! #     fn write(&mut self, _: u8) -> Result<bool, ()> { Ok(false) }
+//! # }
+//! let application = FlashRange::new(0u16, 0x7000u16);
+//! let mut bootloader = Twiboot::new(Twi, Spm, InternalEeprom, application);
+//! bootloader.run().ok();
+//! // jump to the application
+//! ```
+
+use crate::eeprom::Eeprom;
+use crate::flash::Flash;
+use crate::protocol::{I2cSlave, Status, Transaction};
+use crate::{Address, EEPROM_SIZE_BYTES, FlashRange, FlashWriter, SIGNATURE, SPM_PAGESIZE_BYTES};
+
+const CMD_READ_VERSION: u8 = 0x01;
+const CMD_ACCESS_MEMORY: u8 = 0x02;
+
+const BOOTTYPE_APPLICATION: u8 = 0x80;
+
+const MEMTYPE_CHIPINFO: u8 = 0x00;
+const MEMTYPE_FLASH: u8 = 0x01;
+const MEMTYPE_EEPROM: u8 = 0x02;
+
+const VERSION: &[u8; 16] = b"avr-boot twiboot";
+
+/// twiboot only has a single byte for the page size
+const PAGE_SIZE: usize = if SPM_PAGESIZE_BYTES > 128 {
+    128
+} else {
+    SPM_PAGESIZE_BYTES
+};
+
+/// What the next read transaction returns, along with the position reached
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Selected {
+    Nothing,
+    Version(u8),
+    ChipInfo(u8),
+    Flash(u16),
+    Eeprom(u16),
+}
+
+/// twiboot command interpreter
+pub struct Twiboot<I, F, E> {
+    bus: I,
+    writer: FlashWriter<F>,
+    eeprom: E,
+    application: FlashRange,
+    selected: Selected,
+}
+
+impl<I: I2cSlave, F: Flash, E: Eeprom> Twiboot<I, F, E> {
+    /// Create a new interpreter, listening on `bus`, and programming `flash` and `eeprom`
+    ///
+    /// `application` is the part of flash which can be written, normally everything below the bootloader.
+    pub fn new(bus: I, flash: F, eeprom: E, application: FlashRange) -> Self {
+        Self {
+            bus,
+            writer: FlashWriter::new(flash),
+            eeprom,
+            application,
+            selected: Selected::Nothing,
+        }
+    }
+
+    /// Handle transactions until the master switches to the application
+    pub fn run(&mut self) -> Result<(), I::Error> {
+        while self.handle_transaction()? == Status::Continue {}
+        Ok(())
+    }
+
+    /// Wait for and handle a single transaction
+    pub fn handle_transaction(&mut self) -> Result<Status, I::Error> {
+        match self.bus.next_transaction()? {
+            Transaction::Write => self.receive(),
+            Transaction::Read => {
+                self.send()?;
+                Ok(Status::Continue)
+            }
+        }
+    }
+
+    /// Give back the bus and the backends, after writing out any buffered data
+    pub fn release(self) -> (I, F, E) {
+        (self.bus, self.writer.release(), self.eeprom)
+    }
+
+    fn receive(&mut self) -> Result<Status, I::Error> {
+        self.selected = Selected::Nothing;
+
+        match self.bus.read()? {
+            Some(CMD_READ_VERSION) => match self.bus.read()? {
+                None => self.selected = Selected::Version(0),
+                Some(BOOTTYPE_APPLICATION) => {
+                    self.skip()?;
+                    self.writer.flush();
+                    return Ok(Status::Done);
+                }
+                Some(_) => self.skip()?,
+            },
+            Some(CMD_ACCESS_MEMORY) => self.access_memory()?,
+            // Includes CMD_WAIT, which only needs to reach the bootloader to stop it timing out
+            Some(_) => self.skip()?,
+            None => {}
+        }
+
+        Ok(Status::Continue)
+    }
+
+    fn access_memory(&mut self) -> Result<(), I::Error> {
+        let mut header = [0; 3];
+        for byte in &mut header {
+            match self.bus.read()? {
+                Some(value) => *byte = value,
+                None => return Ok(()),
+            }
+        }
+
+        let [memory_type, high, low] = header;
+        let address = u16::from_be_bytes([high, low]);
+        self.selected = match memory_type {
+            MEMTYPE_CHIPINFO => Selected::ChipInfo(low),
+            MEMTYPE_FLASH => Selected::Flash(address),
+            MEMTYPE_EEPROM => Selected::Eeprom(address),
+            _ => Selected::Nothing,
+        };
+
+        while let Some(byte) = self.bus.read()? {
+            match &mut self.selected {
+                Selected::Flash(address) => {
+                    let flash_address = Address::from(*address);
+                    if self.application.contains(flash_address) {
+                        self.writer.write_byte(flash_address, byte);
+                    }
+                    *address = address.wrapping_add(1);
+                }
+                Selected::Eeprom(address) => {
+                    if *address < EEPROM_SIZE_BYTES {
+                        self.eeprom.write_byte(*address, byte);
+                    }
+                    *address = address.wrapping_add(1);
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    fn send(&mut self) -> Result<(), I::Error> {
+        self.writer.flush();
+
+        loop {
+            let byte = self.next_byte();
+            if !self.bus.write(byte)? {
+                return Ok(());
+            }
+        }
+    }
+
+    fn next_byte(&mut self) -> u8 {
+        let chip_info = self.chip_info();
+
+        match &mut self.selected {
+            Selected::Nothing => 0xff,
+            Selected::Version(offset) => {
+                let byte = VERSION.get(*offset as usize).copied().unwrap_or(0xff);
+                *offset = offset.saturating_add(1);
+                byte
+            }
+            Selected::ChipInfo(offset) => {
+                let byte = chip_info.get(*offset as usize).copied().unwrap_or(0xff);
+                *offset = offset.saturating_add(1);
+                byte
+            }
+            Selected::Flash(address) => {
+                let byte = self.writer.flash().read_byte(Address::from(*address));
+                *address = address.wrapping_add(1);
+                byte
+            }
+            Selected::Eeprom(address) => {
+                let byte = if *address < EEPROM_SIZE_BYTES {
+                    self.eeprom.read_byte(*address)
+                } else {
+                    0xff
+                };
+                *address = address.wrapping_add(1);
+                byte
+            }
+        }
+    }
+
+    fn chip_info(&self) -> [u8; 8] {
+        let application_end = u16::try_from(u32::from(self.application.end)).unwrap_or(u16::MAX);
+        let [flash_high, flash_low] = application_end.to_be_bytes();
+        let [eeprom_high, eeprom_low] = EEPROM_SIZE_BYTES.to_be_bytes();
+
+        [
+            SIGNATURE[0],
+            SIGNATURE[1],
+            SIGNATURE[2],
+            PAGE_SIZE as u8,
+            flash_high,
+            flash_low,
+            eeprom_high,
+            eeprom_low,
+        ]
+    }
+
+    /// Ignore the rest of a write transaction
+    fn skip(&mut self) -> Result<(), I::Error> {
+        while self.bus.read()?.is_some() {}
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eeprom::mock::MockEeprom;
+    use crate::flash::mock::MockFlash;
+    use crate::protocol::mock::{EndOfInput, MockI2c, Step};
+    use std::vec::Vec;
+
+    const APPLICATION_END: u16 = 0x7000;
+
+    fn run(
+        script: impl IntoIterator<Item = Step>,
+    ) -> (Result<(), EndOfInput>, MockI2c, MockFlash, MockEeprom) {
+        let application = FlashRange::new(0u16, APPLICATION_END);
+        let mut twiboot = Twiboot::new(
+            MockI2c::new(script),
+            MockFlash::new(),
+            MockEeprom::new(),
+            application,
+        );
+        let result = twiboot.run();
+        let (bus, flash, eeprom) = twiboot.release();
+        (result, bus, flash, eeprom)
+    }
+
+    fn write(bytes: &[u8]) -> Step {
+        Step::Write(bytes.to_vec())
+    }
+
+    #[test]
+    fn it_reads_the_version() {
+        let (_, bus, _, _) = run([write(&[CMD_READ_VERSION]), Step::Read(16)]);
+
+        assert_eq!(bus.reads, [VERSION.to_vec()]);
+    }
+
+    #[test]
+    fn it_reads_the_chip_info() {
+        let (_, bus, _, _) = run([
+            write(&[CMD_ACCESS_MEMORY, MEMTYPE_CHIPINFO, 0, 0]),
+            Step::Read(8),
+        ]);
+
+        let [flash_high, flash_low] = APPLICATION_END.to_be_bytes();
+        let [eeprom_high, eeprom_low] = EEPROM_SIZE_BYTES.to_be_bytes();
+        let expected = [
+            SIGNATURE[0],
+            SIGNATURE[1],
+            SIGNATURE[2],
+            PAGE_SIZE as u8,
+            flash_high,
+            flash_low,
+            eeprom_high,
+            eeprom_low,
+        ];
+        assert_eq!(bus.reads, [expected.to_vec()]);
+    }
+
+    #[test]
+    fn it_writes_a_page_in_chunks_and_reads_it_back() {
+        let data: Vec<u8> = (0..SPM_PAGESIZE_BYTES).map(|i| (i * 7) as u8).collect();
+        let mut script = Vec::new();
+        for (i, chunk) in data.chunks(PAGE_SIZE).enumerate() {
+            let [high, low] = (0x1000 + (i * PAGE_SIZE) as u16).to_be_bytes();
+            let mut command = vec![CMD_ACCESS_MEMORY, MEMTYPE_FLASH, high, low];
+            command.extend(chunk);
+            script.push(Step::Write(command));
+        }
+        script.push(write(&[CMD_ACCESS_MEMORY, MEMTYPE_FLASH, 0x10, 0x00]));
+        script.push(Step::Read(4));
+
+        let (_, bus, flash, _) = run(script);
+
+        assert_eq!(flash.page(0x1000), &data[..]);
+        assert_eq!(flash.writes, 1);
+        assert_eq!(bus.reads, [data[..4].to_vec()]);
+    }
+
+    #[test]
+    fn it_protects_the_bootloader() {
+        let [high, low] = APPLICATION_END.to_be_bytes();
+        let (_, _, flash, _) = run([write(&[
+            CMD_ACCESS_MEMORY,
+            MEMTYPE_FLASH,
+            high,
+            low,
+            0x12,
+            0x34,
+        ])]);
+
+        assert_eq!(flash.writes, 0);
+    }
+
+    #[test]
+    fn it_writes_and_reads_the_eeprom() {
+        let (_, bus, _, eeprom) = run([
+            write(&[CMD_ACCESS_MEMORY, MEMTYPE_EEPROM, 0x00, 0x10, 0xaa, 0xbb]),
+            write(&[CMD_ACCESS_MEMORY, MEMTYPE_EEPROM, 0x00, 0x10]),
+            Step::Read(3),
+        ]);
+
+        assert_eq!(&eeprom.memory[0x10..0x12], &[0xaa, 0xbb]);
+        assert_eq!(bus.reads, [vec![0xaa, 0xbb, 0xff]]);
+    }
+
+    #[test]
+    fn it_switches_to_the_application() {
+        let (result, bus, _, _) = run([
+            write(&[0x00]),
+            write(&[CMD_READ_VERSION, BOOTTYPE_APPLICATION]),
+            Step::Read(1),
+        ]);
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(bus.script.len(), 1);
+    }
+}
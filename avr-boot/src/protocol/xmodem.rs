@@ -0,0 +1,418 @@
+//! XMODEM receiver, writing straight to flash
+//!
+//! Supports the original checksum variant, XMODEM-CRC and XMODEM-1K, so an application image can be sent from any
+//! terminal program, or with `sx`. The receiver starts by asking for CRC mode, and falls back to the checksum after
+//! a few attempts with no reply. 1K blocks are accepted if the buffer is large enough to hold them.
+//!
+//! [`Serial::read`] errors are treated as timeouts: the receiver asks for the block again, and only gives up after
+//! too many errors in a row. Bad blocks are retransmitted, duplicate blocks (after a lost ACK) are acknowledged and
+//! skipped, and the transfer can be cancelled from either end with `CAN CAN`.
+//!
+//! The image is written from the start of the application range, a page at a time through a [`FlashWriter`].
+//! XMODEM pads the last block with `0x1a`, which is written along with the data.
+//!
+//! # Example
+//! ```no_run
+//! use avr_boot::FlashRange;
+//! use avr_boot::flash::Spm;
+//! use avr_boot::protocol::{Serial, xmodem::Xmodem};
+//!
+//! # struct Uart;
+//! # impl Serial for Uart {
+//! #     type Error = ();
+//! #     fn read(&mut self) -> Result<u8, ()> { Ok(0) }
+//! #     fn write(&mut self, _: u8) -> Result<(), ()> { Ok(()) }
+//! # }
+//! let mut buffer = [0; 128];
+//! let application = FlashRange::new(0u16, 0x7000u16);
+//! let mut receiver = Xmodem::new(Uart, Spm, application, &mut buffer);
+//! if receiver.run().is_ok() {
+//!     // jump to the application
+//! }
+//! ```
+
+use super::modem::{
+    ACK, BLOCK_SIZE, BLOCK_SIZE_1K, CAN, CRC_MODE, EOT, MAX_ERRORS, NAK, Packet, SOH, STX,
+};
+use crate::crc;
+use crate::flash::Flash;
+use crate::protocol::Serial;
+use crate::{Address, FlashRange, FlashWriter};
+
+/// Attempts at starting in CRC mode, before falling back to the checksum
+const CRC_ATTEMPTS: u8 = 3;
+
+/// Why a transfer failed
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Error<E> {
+    /// Too many reads in a row failed, normally because the sender has gone away
+    Serial(E),
+    /// The sender cancelled the transfer
+    Cancelled,
+    /// Too many bad blocks in a row
+    TooManyErrors,
+    /// A block arrived out of sequence, which can't be recovered from
+    OutOfSequence,
+    /// The image doesn't fit in the application range
+    TooLarge,
+}
+
+/// XMODEM receiver
+pub struct Xmodem<'a, S, F> {
+    serial: S,
+    writer: FlashWriter<F>,
+    application: FlashRange,
+    buffer: &'a mut [u8],
+}
+
+impl<'a, S: Serial, F: Flash> Xmodem<'a, S, F> {
+    /// Create a new receiver, talking over `serial` and writing the image to `application` in `flash`
+    ///
+    /// `buffer` holds each block until its checksum has been checked. It must be at least 128 bytes long,
+    /// or 1024 bytes to accept XMODEM-1K blocks.
+    pub fn new(serial: S, flash: F, application: FlashRange, buffer: &'a mut [u8]) -> Self {
+        assert!(buffer.len() >= BLOCK_SIZE, "xmodem buffer too small");
+
+        Self {
+            serial,
+            writer: FlashWriter::new(flash),
+            application,
+            buffer,
+        }
+    }
+
+    /// Receive a whole image
+    ///
+    /// Returns the address after the last byte written.
+    pub fn run(&mut self) -> Result<Address, Error<S::Error>> {
+        self.receive(None)
+    }
+
+    /// Give back the serial port and flash backend, after writing out any buffered data
+    pub fn release(self) -> (S, F) {
+        (self.serial, self.writer.release())
+    }
+
+    /// Receive blocks until the end of the transfer
+    ///
+    /// With a `file_length`, as sent in a YMODEM header, anything past it is padding and isn't written,
+    /// and the first EOT is NAKed to make sure it wasn't line noise.
+    pub(super) fn receive(&mut self, file_length: Option<u32>) -> Result<Address, Error<S::Error>> {
+        let mut address = self.application.start;
+        let end = file_length.and_then(|length| address.checked_add(length));
+        let mut end_confirmed = file_length.is_none();
+        let mut expected: u8 = 1;
+        let mut started = false;
+        let mut crc = true;
+        let mut errors = 0;
+        let mut reply = CRC_MODE;
+
+        loop {
+            self.serial.write(reply).map_err(Error::Serial)?;
+
+            let packet = match self.read_packet(crc) {
+                Ok(packet) => packet,
+                Err(e) if errors + 1 >= MAX_ERRORS => {
+                    self.cancel();
+                    return Err(Error::Serial(e));
+                }
+                Err(_) => Packet::Bad,
+            };
+
+            match packet {
+                Packet::Block { number, length } => {
+                    started = true;
+                    errors = 0;
+                    reply = ACK;
+
+                    if number == expected {
+                        let remaining = end.map_or(length, |end| {
+                            (u32::from(end).saturating_sub(address.into()) as usize).min(length)
+                        });
+                        let fits = address
+                            .checked_add(remaining as u32)
+                            .is_some_and(|end| end <= self.application.end);
+                        if !fits {
+                            self.cancel();
+                            return Err(Error::TooLarge);
+                        }
+
+                        address = self.writer.write(address, &self.buffer[..remaining]);
+                        expected = expected.wrapping_add(1);
+                    } else if number != expected.wrapping_sub(1) {
+                        self.cancel();
+                        return Err(Error::OutOfSequence);
+                    }
+                }
+                Packet::End if !end_confirmed => {
+                    end_confirmed = true;
+                    reply = NAK;
+                }
+                Packet::End => {
+                    self.writer.flush();
+                    self.serial.write(ACK).map_err(Error::Serial)?;
+                    return Ok(address);
+                }
+                Packet::Cancel => return Err(Error::Cancelled),
+                Packet::Bad => {
+                    errors += 1;
+                    if errors >= MAX_ERRORS {
+                        self.cancel();
+                        return Err(Error::TooManyErrors);
+                    }
+
+                    reply = if started {
+                        NAK
+                    } else if errors < CRC_ATTEMPTS {
+                        CRC_MODE
+                    } else {
+                        crc = false;
+                        NAK
+                    };
+                }
+            }
+        }
+    }
+
+    pub(super) fn serial(&mut self) -> &mut S {
+        &mut self.serial
+    }
+
+    pub(super) fn application(&self) -> FlashRange {
+        self.application
+    }
+
+    pub(super) fn buffer(&self) -> &[u8] {
+        self.buffer
+    }
+
+    pub(super) fn read_packet(&mut self, crc: bool) -> Result<Packet, S::Error> {
+        let length = loop {
+            match self.serial.read()? {
+                SOH => break BLOCK_SIZE,
+                STX => break BLOCK_SIZE_1K,
+                EOT => return Ok(Packet::End),
+                CAN if self.serial.read()? == CAN => return Ok(Packet::Cancel),
+                CAN => return Ok(Packet::Bad),
+                // Line noise, or the tail of a block we've given up on
+                _ => {}
+            }
+        };
+
+        let number = self.serial.read()?;
+        let complement = self.serial.read()?;
+
+        let mut checksum: u8 = 0;
+        let mut crc16 = 0;
+        let buffer_length = self.buffer.len();
+        for i in 0..length {
+            let byte = self.serial.read()?;
+            checksum = checksum.wrapping_add(byte);
+            crc16 = crc::xmodem_update(crc16, byte);
+            if i < buffer_length {
+                self.buffer[i] = byte;
+            }
+        }
+
+        let valid = if crc {
+            let high = self.serial.read()?;
+            let low = self.serial.read()?;
+            u16::from_be_bytes([high, low]) == crc16
+        } else {
+            self.serial.read()? == checksum
+        };
+
+        if valid && number == !complement && length <= buffer_length {
+            Ok(Packet::Block { number, length })
+        } else {
+            Ok(Packet::Bad)
+        }
+    }
+
+    pub(super) fn cancel(&mut self) {
+        for _ in 0..3 {
+            self.serial.write(CAN).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::mock::MockFlash;
+    use crate::protocol::mock::{EndOfInput, MockSerial, PipeEnd, packet, pipe};
+    use std::thread;
+    use std::vec::Vec;
+
+    const APPLICATION_END: u16 = 0x7000;
+
+    /// A minimal sender, as a terminal program would run it
+    struct Sender {
+        block_size: usize,
+        accept_crc: bool,
+        corrupt_block: Option<u8>,
+        repeat_block: Option<u8>,
+    }
+
+    impl Sender {
+        fn send(self, mut serial: PipeEnd, image: Vec<u8>) {
+            let crc = loop {
+                match serial.read() {
+                    Ok(CRC_MODE) if self.accept_crc => break true,
+                    Ok(NAK) => break false,
+                    _ => {}
+                }
+            };
+
+            let mut corrupt = self.corrupt_block;
+            for (i, chunk) in image.chunks(self.block_size).enumerate() {
+                let number = (i + 1) as u8;
+                let mut data = chunk.to_vec();
+                data.resize(self.block_size, 0x1a);
+
+                loop {
+                    let mut bytes = packet(number, &data, crc);
+                    if corrupt == Some(number) {
+                        corrupt = None;
+                        bytes[10] ^= 0xff;
+                    }
+                    serial.write_all(&bytes).unwrap();
+                    if serial.read() == Ok(ACK) {
+                        break;
+                    }
+                }
+
+                if self.repeat_block == Some(number) {
+                    serial.write_all(&packet(number, &data, crc)).unwrap();
+                    assert_eq!(serial.read(), Ok(ACK));
+                }
+            }
+
+            serial.write(EOT).unwrap();
+            assert_eq!(serial.read(), Ok(ACK));
+        }
+    }
+
+    fn transfer(sender: Sender, image: &[u8]) -> (Result<Address, Error<EndOfInput>>, MockFlash) {
+        let (receiver_end, sender_end) = pipe();
+        let image = image.to_vec();
+        let sender = thread::spawn(move || sender.send(sender_end, image));
+
+        let mut buffer = [0; BLOCK_SIZE_1K];
+        let application = FlashRange::new(0u16, APPLICATION_END);
+        let mut xmodem = Xmodem::new(receiver_end, MockFlash::new(), application, &mut buffer);
+        let result = xmodem.run();
+        let (_, flash) = xmodem.release();
+
+        sender.join().unwrap();
+        (result, flash)
+    }
+
+    fn image(length: usize) -> Vec<u8> {
+        (0..length).map(|i| (i * 13 + i / 256) as u8).collect()
+    }
+
+    fn run_script(input: &[u8]) -> (Result<Address, Error<EndOfInput>>, MockSerial) {
+        let mut buffer = [0; BLOCK_SIZE];
+        let application = FlashRange::new(0u16, BLOCK_SIZE as u16 * 2);
+        let mut xmodem = Xmodem::new(
+            MockSerial::new(input),
+            MockFlash::new(),
+            application,
+            &mut buffer,
+        );
+        let result = xmodem.run();
+        let (serial, _) = xmodem.release();
+        (result, serial)
+    }
+
+    #[test]
+    fn it_receives_1k_blocks() {
+        let image = image(3000);
+        let sender = Sender {
+            block_size: BLOCK_SIZE_1K,
+            accept_crc: true,
+            corrupt_block: None,
+            repeat_block: None,
+        };
+
+        let (result, flash) = transfer(sender, &image);
+
+        assert_eq!(result, Ok(Address::from(3072u16)));
+        assert_eq!(&flash.memory[..3000], &image[..]);
+        assert!(flash.memory[3000..3072].iter().all(|b| *b == 0x1a));
+    }
+
+    #[test]
+    fn it_retransmits_bad_blocks_and_skips_duplicates() {
+        let image = image(1000);
+        let sender = Sender {
+            block_size: BLOCK_SIZE,
+            accept_crc: true,
+            corrupt_block: Some(2),
+            repeat_block: Some(3),
+        };
+
+        let (result, flash) = transfer(sender, &image);
+
+        assert_eq!(result, Ok(Address::from(1024u16)));
+        assert_eq!(&flash.memory[..1000], &image[..]);
+    }
+
+    #[test]
+    fn it_falls_back_to_the_checksum() {
+        let image = image(200);
+        let sender = Sender {
+            block_size: BLOCK_SIZE,
+            accept_crc: false,
+            corrupt_block: Some(1),
+            repeat_block: None,
+        };
+
+        let (result, flash) = transfer(sender, &image);
+
+        assert_eq!(result, Ok(Address::from(256u16)));
+        assert_eq!(&flash.memory[..200], &image[..]);
+    }
+
+    #[test]
+    fn it_is_cancelled_by_the_sender() {
+        let (result, serial) = run_script(&[CAN, CAN]);
+
+        assert_eq!(result, Err(Error::Cancelled));
+        assert_eq!(serial.output, &[CRC_MODE]);
+    }
+
+    #[test]
+    fn it_cancels_blocks_out_of_sequence() {
+        let (result, serial) = run_script(&packet(2, &[0; BLOCK_SIZE], true));
+
+        assert_eq!(result, Err(Error::OutOfSequence));
+        assert_eq!(serial.output, &[CRC_MODE, CAN, CAN, CAN]);
+    }
+
+    #[test]
+    fn it_refuses_images_which_are_too_large() {
+        let mut input = Vec::new();
+        for number in 1..=3 {
+            input.extend(packet(number, &[0; BLOCK_SIZE], true));
+        }
+
+        let (result, serial) = run_script(&input);
+
+        assert_eq!(result, Err(Error::TooLarge));
+        assert_eq!(serial.output, &[CRC_MODE, ACK, ACK, CAN, CAN, CAN]);
+    }
+
+    #[test]
+    fn it_rejects_1k_blocks_without_the_buffer_for_them() {
+        let mut input = packet(1, &[0; BLOCK_SIZE_1K], true);
+        input.extend(packet(1, &[0; BLOCK_SIZE], true));
+        input.push(EOT);
+
+        let (result, serial) = run_script(&input);
+
+        assert_eq!(result, Ok(Address::from(BLOCK_SIZE as u16)));
+        assert_eq!(serial.output, &[CRC_MODE, CRC_MODE, ACK, ACK]);
+    }
+}
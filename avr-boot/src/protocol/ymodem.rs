@@ -0,0 +1,313 @@
+//! YMODEM receiver, writing straight to flash
+//!
+//! YMODEM is XMODEM-1K with a header block (block 0) in front, carrying the file name, length and modification time.
+//! The length is used to drop the `0x1a` padding from the last block, so the rest of the last page stays erased,
+//! and to refuse an image which won't fit before any of it is written. Send with `sb`, or any terminal program.
+//!
+//! Only a single file is accepted: if the sender goes on to offer another, it is cancelled, and the first file is
+//! returned with [`File::cancelled_more`] set. Everything else, including the retry behaviour, is the same as
+//! [`xmodem`](super::xmodem).
+//!
+//! # Example
+//! ```no_run
+//! use avr_boot::FlashRange;
+//! use avr_boot::flash::Spm;
+//! use avr_boot::protocol::{Serial, ymodem::Ymodem};
+//!
+//! # struct Uart;
+//! # impl Serial for Uart {
+//! #     type Error = ();
+//! #     fn read(&mut self) -> Result<u8, ()> { Ok(0) }
+//! #     fn write(&mut self, _: u8) -> Result<(), ()> { Ok(()) }
+//! # }
+//! let mut buffer = [0; 1024];
+//! let mut receiver = Ymodem::new(Uart, Spm, FlashRange::application(4096), &mut buffer);
+//! if let Ok(Some(file)) = receiver.run() {
+//!     // check the first `file.length` bytes, then jump to the application
+//! }
+//! ```
+
+use super::modem::{ACK, CRC_MODE, MAX_ERRORS, Packet};
+use super::xmodem::{Error, Xmodem};
+use crate::FlashRange;
+use crate::flash::Flash;
+use crate::protocol::Serial;
+
+/// The contents of a YMODEM header block
+///
+/// # Example
+/// ```rust
+/// use avr_boot::protocol::ymodem::Header;
+///
+/// let header = Header::parse(b"app.bin\x001234 14567452103 100644\x00\x00\x00");
+/// assert_eq!(b"app.bin", header.name);
+/// assert_eq!(Some(1234), header.length);
+/// assert_eq!(Some(0o14567452103), header.modified);
+/// ```
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Header<'a> {
+    /// The file name, without any path. Empty for the block which ends a batch.
+    pub name: &'a [u8],
+    /// The file length in bytes, if the sender included it
+    pub length: Option<u32>,
+    /// The modification time, in seconds since the Unix epoch, if the sender included it
+    pub modified: Option<u32>,
+}
+
+impl<'a> Header<'a> {
+    /// Parse the data of block 0
+    pub fn parse(block: &'a [u8]) -> Self {
+        let mut fields = block.split(|byte| *byte == 0);
+        let name = fields.next().unwrap_or_default();
+        let mut info = fields
+            .next()
+            .unwrap_or_default()
+            .split(|byte| *byte == b' ');
+
+        Self {
+            name,
+            length: info.next().and_then(|field| parse_number(field, 10)),
+            modified: info.next().and_then(|field| parse_number(field, 8)),
+        }
+    }
+
+    /// True if this header ends the batch, rather than starting a file
+    pub fn is_end_of_batch(&self) -> bool {
+        self.name.is_empty()
+    }
+}
+
+fn parse_number(field: &[u8], radix: u32) -> Option<u32> {
+    if field.is_empty() {
+        return None;
+    }
+
+    field.iter().try_fold(0u32, |value, byte| {
+        let digit = (*byte as char).to_digit(radix)?;
+        value.checked_mul(radix)?.checked_add(digit)
+    })
+}
+
+/// A file received over YMODEM
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct File {
+    /// Number of bytes written, from the start of the application range
+    ///
+    /// This is the length from the header, or all of the blocks including padding if the sender didn't send one.
+    pub length: u32,
+    /// The modification time from the header, if there was one
+    pub modified: Option<u32>,
+    /// The sender offered more files after this one, which were cancelled
+    pub cancelled_more: bool,
+}
+
+/// The parts of a header needed once its block has been overwritten
+struct Announced {
+    length: Option<u32>,
+    modified: Option<u32>,
+}
+
+/// YMODEM receiver
+pub struct Ymodem<'a, S, F> {
+    xmodem: Xmodem<'a, S, F>,
+}
+
+impl<'a, S: Serial, F: Flash> Ymodem<'a, S, F> {
+    /// Create a new receiver, talking over `serial` and writing the image to `application` in `flash`
+    ///
+    /// `buffer` holds each block until its checksum has been checked. It should be 1024 bytes long,
+    /// as YMODEM senders normally use 1K blocks.
+    pub fn new(serial: S, flash: F, application: FlashRange, buffer: &'a mut [u8]) -> Self {
+        Self {
+            xmodem: Xmodem::new(serial, flash, application, buffer),
+        }
+    }
+
+    /// Receive a single file
+    ///
+    /// Returns `None` if the sender had no files to send.
+    pub fn run(&mut self) -> Result<Option<File>, Error<S::Error>> {
+        let Some(Announced { length, modified }) = self.read_header()? else {
+            return Ok(None);
+        };
+
+        if length.is_some_and(|length| length > self.xmodem.application().len()) {
+            self.xmodem.cancel();
+            return Err(Error::TooLarge);
+        }
+
+        self.xmodem.serial().write(ACK).map_err(Error::Serial)?;
+        let end = self.xmodem.receive(length)?;
+        let mut file = File {
+            length: u32::from(end) - u32::from(self.xmodem.application().start),
+            modified,
+            cancelled_more: false,
+        };
+
+        // The sender follows up with an empty header to end the batch. The file is safely written by now,
+        // so it doesn't matter if that goes missing.
+        if let Ok(Some(_)) = self.read_header() {
+            self.xmodem.cancel();
+            file.cancelled_more = true;
+        }
+        Ok(Some(file))
+    }
+
+    /// Give back the serial port and flash backend, after writing out any buffered data
+    pub fn release(self) -> (S, F) {
+        self.xmodem.release()
+    }
+
+    /// Ask for block 0 until it arrives, and return the length and modification time if it starts a file
+    ///
+    /// The end of batch block is acknowledged; a header starting a file is left for the caller to accept.
+    fn read_header(&mut self) -> Result<Option<Announced>, Error<S::Error>> {
+        let mut errors = 0;
+
+        loop {
+            self.xmodem
+                .serial()
+                .write(CRC_MODE)
+                .map_err(Error::Serial)?;
+
+            match self.xmodem.read_packet(true) {
+                Ok(Packet::Block { number: 0, length }) => {
+                    let header = Header::parse(&self.xmodem.buffer()[..length]);
+                    if header.is_end_of_batch() {
+                        self.xmodem.serial().write(ACK).map_err(Error::Serial)?;
+                        return Ok(None);
+                    }
+                    return Ok(Some(Announced {
+                        length: header.length,
+                        modified: header.modified,
+                    }));
+                }
+                Ok(Packet::Block { .. }) => {
+                    self.xmodem.cancel();
+                    return Err(Error::OutOfSequence);
+                }
+                Ok(Packet::Cancel) => return Err(Error::Cancelled),
+                Ok(Packet::End | Packet::Bad) => {}
+                Err(e) if errors + 1 >= MAX_ERRORS => return Err(Error::Serial(e)),
+                Err(_) => {}
+            }
+
+            errors += 1;
+            if errors >= MAX_ERRORS {
+                self.xmodem.cancel();
+                return Err(Error::TooManyErrors);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::modem::{BLOCK_SIZE, BLOCK_SIZE_1K, CAN, EOT, NAK};
+    use super::*;
+    use crate::flash::mock::MockFlash;
+    use crate::protocol::mock::{EndOfInput, MockSerial, packet};
+    use std::vec::Vec;
+
+    fn header(name: &str, length: usize) -> Vec<u8> {
+        let mut data = format!("{name}\0{length} 14567452103 100644").into_bytes();
+        data.resize(BLOCK_SIZE, 0);
+        packet(0, &data, true)
+    }
+
+    fn end_of_batch() -> Vec<u8> {
+        packet(0, &[0; BLOCK_SIZE], true)
+    }
+
+    fn run(
+        input: &[u8],
+    ) -> (
+        Result<Option<File>, Error<EndOfInput>>,
+        MockSerial,
+        MockFlash,
+    ) {
+        let mut buffer = [0; BLOCK_SIZE_1K];
+        let application = FlashRange::new(0u16, 0x2000u16);
+        let mut ymodem = Ymodem::new(
+            MockSerial::new(input),
+            MockFlash::new(),
+            application,
+            &mut buffer,
+        );
+        let result = ymodem.run();
+        let (serial, flash) = ymodem.release();
+        (result, serial, flash)
+    }
+
+    #[test]
+    fn it_parses_a_header_without_a_modification_time() {
+        let header = Header::parse(b"app.hex\x00512\x00");
+
+        assert_eq!(header.length, Some(512));
+        assert_eq!(header.modified, None);
+        assert!(!header.is_end_of_batch());
+    }
+
+    #[test]
+    fn it_truncates_the_last_block_to_the_file_length() {
+        let image: Vec<u8> = (0..1100).map(|i| i as u8).collect();
+        let mut last = image[BLOCK_SIZE_1K..].to_vec();
+        last.resize(BLOCK_SIZE, 0x1a);
+
+        let mut input = header("app.bin", image.len());
+        input.extend(packet(1, &image[..BLOCK_SIZE_1K], true));
+        input.extend(packet(2, &last, true));
+        input.extend([EOT, EOT]);
+        input.extend(end_of_batch());
+
+        let (result, serial, flash) = run(&input);
+
+        let file = File {
+            length: 1100,
+            modified: Some(0o14567452103),
+            cancelled_more: false,
+        };
+        assert_eq!(result, Ok(Some(file)));
+        assert_eq!(&flash.memory[..1100], &image[..]);
+        assert!(flash.memory[1100..1280].iter().all(|b| *b == 0xff));
+        assert_eq!(
+            serial.output,
+            &[CRC_MODE, ACK, CRC_MODE, ACK, ACK, NAK, ACK, CRC_MODE, ACK]
+        );
+    }
+
+    #[test]
+    fn it_rejects_files_larger_than_the_application() {
+        let (result, _, flash) = run(&header("big.bin", 0x2001));
+
+        assert_eq!(result, Err(Error::TooLarge));
+        assert_eq!(flash.writes, 0);
+    }
+
+    #[test]
+    fn it_ends_an_empty_batch() {
+        let (result, serial, _) = run(&end_of_batch());
+
+        assert_eq!(result, Ok(None));
+        assert_eq!(serial.output, &[CRC_MODE, ACK]);
+    }
+
+    #[test]
+    fn it_keeps_the_first_file_and_cancels_a_second() {
+        let mut input = header("a.bin", 4);
+        input.extend(packet(1, &[1; BLOCK_SIZE], true));
+        input.extend([EOT, EOT]);
+        input.extend(header("b.bin", 4));
+
+        let (result, serial, flash) = run(&input);
+
+        let file = File {
+            length: 4,
+            modified: Some(0o14567452103),
+            cancelled_more: true,
+        };
+        assert_eq!(result, Ok(Some(file)));
+        assert_eq!(&flash.memory[..5], &[1, 1, 1, 1, 0xff]);
+        assert!(serial.output.ends_with(&[CRC_MODE, CAN, CAN, CAN]));
+    }
+}
@@ -0,0 +1,174 @@
+//! Ranges of program memory
+
+use crate::{Address, FLASH_SIZE_BYTES};
+
+/// A range of program memory, from `start` (inclusive) to `end` (exclusive)
+///
+/// # Example
+/// ```rust
+/// use avr_boot::{Address, FlashRange, SPM_PAGESIZE_BYTES};
+///
+/// let range = FlashRange::new(0u16, 0x10000u32);
+/// assert_eq!(0x10000 / SPM_PAGESIZE_BYTES, range.pages().count());
+/// assert!(range.contains(Address::from(0xffffu16)));
+/// ```
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct FlashRange {
+    /// The first address in the range
+    pub start: Address,
+    /// The address after the last one in the range
+    pub end: Address,
+}
+
+impl FlashRange {
+    /// Create a new range from `start` up to, but not including, `end`
+    pub fn new(start: impl Into<Address>, end: impl Into<Address>) -> Self {
+        Self {
+            start: start.into(),
+            end: end.into(),
+        }
+    }
+
+    /// The application section: all of program memory below a bootloader of `bootloader_size` bytes
+    ///
+    /// # Example
+    /// ```rust
+    /// use avr_boot::{FlashRange, FLASH_SIZE_BYTES};
+    ///
+    /// let application = FlashRange::application(4096);
+    /// assert_eq!(FLASH_SIZE_BYTES - 4096, application.len());
+    /// ```
+    pub const fn application(bootloader_size: u32) -> Self {
+        Self {
+            start: Address::from_u16_const(0),
+            end: Address::from_u32_const(FLASH_SIZE_BYTES.saturating_sub(bootloader_size)),
+        }
+    }
+
+    /// Length of the range in bytes
+    pub fn len(&self) -> u32 {
+        u32::from(self.end).saturating_sub(self.start.into())
+    }
+
+    /// True if the range contains no addresses
+    pub fn is_empty(&self) -> bool {
+        self.end <= self.start
+    }
+
+    /// True if the address lies within the range
+    pub fn contains(&self, address: impl Into<Address>) -> bool {
+        let address = address.into();
+        self.start <= address && address < self.end
+    }
+
+    /// Iterate the start address of every page which overlaps the range
+    ///
+    /// The first page address is aligned downwards if `start` is not page aligned.
+    pub fn pages(&self) -> Pages {
+        Pages {
+            next: Some(self.start.into_page_aligned()),
+            end: self.end,
+        }
+    }
+
+    /// Split the range at the first page boundary after `start`
+    ///
+    /// Returns the part of the range within the first page, and the remainder, which may be empty.
+    ///
+    /// # Example
+    /// ```rust
+    /// use avr_boot::{Address, FlashRange, SPM_PAGESIZE_BYTES};
+    ///
+    /// let range = FlashRange::new(4u16, 1000u16);
+    /// let (first, rest) = range.split_at_page();
+    /// assert_eq!(Address::from(SPM_PAGESIZE_BYTES as u16), first.end);
+    /// assert_eq!(first.end, rest.start);
+    /// ```
+    pub fn split_at_page(&self) -> (FlashRange, FlashRange) {
+        let boundary = self.start.next_page().min(self.end).max(self.start);
+
+        (
+            FlashRange::new(self.start, boundary),
+            FlashRange::new(boundary, self.end.max(boundary)),
+        )
+    }
+}
+
+/// Iterator over the page addresses in a [`FlashRange`]
+pub struct Pages {
+    next: Option<Address>,
+    end: Address,
+}
+
+impl Iterator for Pages {
+    type Item = Address;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let current = self.next.filter(|address| *address < self.end)?;
+        self.next = current.checked_add(crate::SPM_PAGESIZE_BYTES as u32);
+        Some(current)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SPM_PAGESIZE_BYTES;
+
+    const PAGE: u32 = SPM_PAGESIZE_BYTES as u32;
+
+    #[test]
+    fn it_iterates_pages_across_the_64k_boundary() {
+        let range = FlashRange::new(0x10000 - PAGE, 0x10000 + PAGE);
+        let mut pages = range.pages();
+
+        assert_eq!(pages.next(), Some(Address::from(0x10000 - PAGE)));
+        assert_eq!(pages.next(), Some(Address::from(0x10000u32)));
+        assert_eq!(pages.next(), None);
+    }
+
+    #[test]
+    fn it_includes_partial_pages() {
+        let range = FlashRange::new(PAGE + 2, PAGE * 3 + 1);
+
+        assert_eq!(range.pages().count(), 3);
+        assert_eq!(range.pages().next(), Some(Address::from(PAGE)));
+    }
+
+    #[test]
+    fn it_has_no_pages_when_empty() {
+        let range = FlashRange::new(PAGE, PAGE);
+
+        assert!(range.is_empty());
+        assert_eq!(range.len(), 0);
+        assert_eq!(range.pages().count(), 0);
+    }
+
+    #[test]
+    fn it_checks_containment() {
+        let range = FlashRange::new(0x100u16, 0x200u16);
+
+        assert!(range.contains(0x100u16));
+        assert!(range.contains(0x1ffu16));
+        assert!(!range.contains(0x200u16));
+        assert!(!range.contains(0xffu16));
+    }
+
+    #[test]
+    fn it_splits_at_the_first_page_boundary() {
+        let range = FlashRange::new(PAGE - 4, PAGE * 2);
+        let (first, rest) = range.split_at_page();
+
+        assert_eq!(first, FlashRange::new(PAGE - 4, PAGE));
+        assert_eq!(rest, FlashRange::new(PAGE, PAGE * 2));
+    }
+
+    #[test]
+    fn it_splits_within_a_single_page() {
+        let range = FlashRange::new(2u32, 10u32);
+        let (first, rest) = range.split_at_page();
+
+        assert_eq!(first, range);
+        assert!(rest.is_empty());
+    }
+}
@@ -0,0 +1,331 @@
+//! SPM service, so the application can program flash through the bootloader
+//!
+//! On parts with a boot section, `spm` only works from code in it, so the application can't write to flash at all.
+//! Like Optiboot's `do_spm`, the bootloader can export a [`Handler`] to do it instead, with [`spm_service!`]. A
+//! pointer to the handler is kept in the last word of flash, at [`SERVICE_ADDRESS`], where the application finds it.
+//!
+//! The bootloader has to be linked with the pointer at that address, and told to keep it, for example on an
+//! atmega1280:
+//! ```text
+//! -C link-arg=-Wl,--section-start=.spm_service=0x1fffe -C link-arg=-Wl,--undefined=AVR_BOOT_SPM_SERVICE
+//! ```
+//!
+//! In the application, [`Service`] implements [`Flash`]. With the `spm-service` feature, the functions in
+//! [`spm`] call the bootloader too, so [`PageBuffer`](crate::PageBuffer) and everything else built on them work
+//! unchanged. Both panic if the bootloader refuses a command, and the `spm` functions also panic if there is no
+//! service, so use [`Service::find`] and [`Service::call`] to handle those instead. The handler itself always uses
+//! the SPM instructions directly, so a bootloader can be built with the feature too.
+//!
+//! Function pointers on AVR are 16-bit word addresses, which can't reach a handler above 128k, so the service is
+//! only available on parts with up to 128k of flash. [`spm_service!`] fails to compile on bigger ones.
+//!
+//! The handler only erases and writes pages in the application range it was given, so the application can't
+//! overwrite the bootloader. Interrupts are disabled while it runs, and it waits for each operation to finish and
+//! re-enables the RWW section before returning, so the application can carry on running from it.
+//!
+//! # Example
+//! In the bootloader:
+//! ```no_run
+//! use avr_boot::{FlashRange, spm_service};
+//!
+//! spm_service!(FlashRange::application(4096));
+//! ```
+//!
+//! And in the application:
+//! ```no_run
+//! use avr_boot::{Address, DataPage, flash::Flash, service::Service};
+//!
+//! if let Some(mut service) = Service::find() {
+//!     let data = DataPage(core::array::from_fn(|i| i as u16));
+//!     service.store_page(Address::from(0x1000u16), &data);
+//! }
+//! ```
+
+use crate::flash::Flash;
+use crate::spm::{self, instruction};
+use crate::{Address, FLASH_SIZE_BYTES, FlashRange, lpm};
+
+use cfg_if::cfg_if;
+#[allow(unused_imports)]
+use core::arch::asm;
+
+/// Where the pointer to the bootloader's handler is kept: the last word of flash
+pub const SERVICE_ADDRESS: u32 = FLASH_SIZE_BYTES - 2;
+
+/// Erase the page containing the address
+pub const ERASE: u8 = 1;
+/// Write a word to the page buffer
+pub const FILL: u8 = 2;
+/// Write the page buffer to the page containing the address
+pub const WRITE: u8 = 3;
+/// Re-enable the RWW section
+pub const RWW_ENABLE: u8 = 4;
+
+/// The bootloader's entry point, taking the low 16 bits of a byte address, a command, a data word for [`FILL`], and
+/// the RAMPZ part of the address. Returns whether the command was carried out.
+///
+/// This is a 16-bit word address, so the handler must be in the first 128k of flash.
+pub type Handler = extern "C" fn(address: u16, command: u8, data: u16, ramp: u8) -> bool;
+
+/// Export a [`Handler`] from the bootloader, which programs pages within `application` for the application
+///
+/// See the [module documentation](crate::service) for how to link it. Parts with more than 128k of flash aren't
+/// supported, as the application couldn't call a handler in the boot section through a 16-bit pointer.
+#[macro_export]
+macro_rules! spm_service {
+    ($application:expr) => {
+        const _: () = assert!(
+            $crate::FLASH_SIZE_BYTES <= 0x2_0000,
+            "the SPM service needs the boot section within reach of a 16-bit function pointer"
+        );
+
+        #[unsafe(no_mangle)]
+        #[used]
+        #[unsafe(link_section = ".spm_service")]
+        static AVR_BOOT_SPM_SERVICE: $crate::service::Handler = {
+            extern "C" fn handler(address: u16, command: u8, data: u16, ramp: u8) -> bool {
+                $crate::service::serve($application, address, command, data, ramp)
+            }
+            handler
+        };
+    };
+}
+
+/// Carry out a command from the application on `flash`, refusing to erase or write outside `application`
+pub fn handle(
+    flash: &mut impl Flash,
+    application: FlashRange,
+    address: Address,
+    command: u8,
+    data: u16,
+) -> bool {
+    match command {
+        ERASE | WRITE if !application.contains(address) => false,
+        ERASE => {
+            flash.erase_page(address);
+            flash.rww_enable();
+            true
+        }
+        FILL => {
+            flash.fill_page(address, data);
+            true
+        }
+        WRITE => {
+            flash.write_page(address);
+            flash.rww_enable();
+            true
+        }
+        RWW_ENABLE => {
+            flash.rww_enable();
+            true
+        }
+        _ => false,
+    }
+}
+
+/// The body of the handler [`spm_service!`] exports
+#[doc(hidden)]
+pub fn serve(application: FlashRange, address: u16, command: u8, data: u16, ramp: u8) -> bool {
+    let address = Address::from((ramp as u32) << 16 | address as u32);
+    without_interrupts(|| {
+        let done = handle(&mut Instructions, application, address, command, data);
+        spm::busy_wait();
+        done
+    })
+}
+
+/// Programs with the SPM instructions themselves, for the handlers, which must never call the service
+pub(crate) struct Instructions;
+
+impl Flash for Instructions {
+    fn erase_page(&mut self, address: Address) {
+        instruction::erase_page(address);
+    }
+
+    fn fill_page(&mut self, address: Address, data: u16) {
+        instruction::fill_page(address, data);
+    }
+
+    fn write_page(&mut self, address: Address) {
+        instruction::write_page(address);
+    }
+
+    fn rww_enable(&mut self) {
+        instruction::rww_enable();
+    }
+
+    fn read_byte(&self, address: Address) -> u8 {
+        lpm::read_byte(address)
+    }
+
+    fn read_into(&self, address: Address, buf: &mut [u8]) {
+        lpm::read_into(address, buf);
+    }
+}
+
+pub(crate) fn without_interrupts<T>(f: impl FnOnce() -> T) -> T {
+    cfg_if! {
+        if #[cfg(all(target_arch = "avr", not(doc)))] {
+            let sreg: u8;
+            unsafe {
+                asm!(
+                    "
+                    in      {sreg},     0x3f
+                    cli
+                    ",
+                    sreg = out(reg) sreg,
+                );
+            }
+            let result = f();
+            unsafe {
+                asm!("out 0x3f, {sreg}", sreg = in(reg) sreg);
+            }
+            result
+        } else {
+            f()
+        }
+    }
+}
+
+/// The bootloader's SPM service, called from the application
+///
+/// As a [`Flash`], it panics if the bootloader refuses a command.
+#[derive(Copy, Clone)]
+pub struct Service {
+    handler: Handler,
+}
+
+impl Service {
+    /// Find the handler the bootloader exported, if it did
+    ///
+    /// Always `None` when not compiling for AVR.
+    pub fn find() -> Option<Self> {
+        let pointer = u16::from_le_bytes([
+            lpm::read_byte(SERVICE_ADDRESS),
+            lpm::read_byte(SERVICE_ADDRESS + 1),
+        ]);
+        if pointer == 0xffff {
+            return None;
+        }
+        cfg_if! {
+            if #[cfg(all(target_arch = "avr", not(doc)))] {
+                // Function pointers are word addresses, the same size as the one stored
+                let handler = unsafe { core::mem::transmute::<u16, Handler>(pointer) };
+                Some(Self { handler })
+            } else {
+                None
+            }
+        }
+    }
+
+    /// Call `handler` directly, such as one in the same program
+    pub fn new(handler: Handler) -> Self {
+        Self { handler }
+    }
+
+    /// Send a command to the bootloader, returning whether it was carried out
+    pub fn call(&self, command: u8, address: Address, data: u16) -> bool {
+        (self.handler)(address.into(), command, data, address.ramp())
+    }
+
+    /// Send a command to the bootloader, panicking if it isn't carried out
+    fn command(&self, command: u8, address: Address, data: u16) {
+        if !self.call(command, address, data) {
+            panic!("the bootloader refused SPM command {command}");
+        }
+    }
+}
+
+/// Send a command to the bootloader, for the `spm` functions
+///
+/// Panics if the bootloader has no service, or refuses the command, rather than carry on as if flash was programmed.
+#[cfg(all(target_arch = "avr", feature = "spm-service"))]
+pub(crate) fn request(command: u8, address: Address, data: u16) {
+    match Service::find() {
+        Some(service) => service.command(command, address, data),
+        None => panic!("the bootloader has no SPM service"),
+    }
+}
+
+impl Flash for Service {
+    fn erase_page(&mut self, address: Address) {
+        self.command(ERASE, address, 0);
+    }
+
+    fn fill_page(&mut self, address: Address, data: u16) {
+        self.command(FILL, address, data);
+    }
+
+    fn write_page(&mut self, address: Address) {
+        self.command(WRITE, address, 0);
+    }
+
+    fn rww_enable(&mut self) {
+        self.command(RWW_ENABLE, Address::from(0u16), 0);
+    }
+
+    fn read_byte(&self, address: Address) -> u8 {
+        lpm::read_byte(address)
+    }
+
+    fn read_into(&self, address: Address, buf: &mut [u8]) {
+        lpm::read_into(address, buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::mock::MockFlash;
+    use crate::{DataPage, SPM_PAGESIZE_BYTES, SPM_PAGESIZE_WORDS};
+
+    spm_service!(FlashRange::new(0u16, 0x1_f000u32));
+
+    #[test]
+    fn it_programs_pages_of_the_application_on_request() {
+        let mut flash = MockFlash::new();
+        let application = FlashRange::new(0u16, 0x1_f000u32);
+        let page = Address::from(0x1_0000u32);
+
+        assert!(handle(&mut flash, application, page, ERASE, 0));
+        for i in 0..SPM_PAGESIZE_WORDS {
+            let word = Address::from((i * 2) as u16);
+            assert!(handle(&mut flash, application, word, FILL, i as u16));
+        }
+        assert!(handle(&mut flash, application, page, WRITE, 0));
+
+        let expected = DataPage(core::array::from_fn(|i| i as u16));
+        assert_eq!(*flash.read_page(page), *expected);
+    }
+
+    #[test]
+    fn it_refuses_to_touch_the_bootloader() {
+        let mut flash = MockFlash::new();
+        let application = FlashRange::new(0u16, 0x1_f000u32);
+        let bootloader = Address::from(0x1_f000u32);
+        flash.memory[0x1_f000] = 0x0c;
+
+        assert!(!handle(&mut flash, application, bootloader, ERASE, 0));
+        assert!(!handle(&mut flash, application, bootloader, WRITE, 0));
+        assert!(!handle(&mut flash, application, bootloader, 0x55, 0));
+        assert_eq!(flash.memory[0x1_f000], 0x0c);
+        assert_eq!(flash.erases + flash.writes, 0);
+    }
+
+    #[test]
+    fn it_exports_a_handler_the_application_can_call() {
+        let service = Service::new(AVR_BOOT_SPM_SERVICE);
+
+        assert!(service.call(ERASE, Address::from(SPM_PAGESIZE_BYTES as u16), 0));
+        assert!(!service.call(ERASE, Address::from(0x1_f000u32), 0));
+        assert!(Service::find().is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "refused SPM command 1")]
+    fn it_panics_when_a_page_is_refused() {
+        let mut service = Service::new(AVR_BOOT_SPM_SERVICE);
+
+        service.erase_page(Address::from(0x1_f000u32));
+    }
+}
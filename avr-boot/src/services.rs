@@ -0,0 +1,414 @@
+//! Versioned table of bootloader services, for the application to find at runtime
+//!
+//! Rather than hard-coding the addresses of functions in the bootloader, the application looks for a [`Table`] in the
+//! last [`TABLE_LENGTH`] bytes of flash, exported by the bootloader with [`bootloader_services!`]. It describes the
+//! bootloader and the device, and has entry points for writing, reading and checksumming pages and for rebooting into
+//! the bootloader. [`Services::find`] checks the table's magic number, version and device before any of them can be
+//! called.
+//!
+//! The [SPM service](crate::service) handler is the table's last word, at the same address as on its own, so
+//! applications which use [`Service`] directly keep working. A bootloader which exports the table doesn't need
+//! [`spm_service!`](crate::spm_service) as well.
+//!
+//! The magic number and version sit just before it. Later versions only add entries before the existing ones, so the
+//! table grows down from the end of flash and an application can use any table of at least the version it was built
+//! against.
+//!
+//! The entry points are 16-bit function pointers, which can only reach the first 128k of flash, so like the SPM
+//! service the table is only available on parts with up to 128k. [`bootloader_services!`] fails to compile on bigger
+//! ones.
+//!
+//! The bootloader has to be linked with the table at [`TABLE_ADDRESS`], and told to keep it, for example on an
+//! atmega1280:
+//! ```text
+//! -C link-arg=-Wl,--section-start=.bootloader_services=0x1ffe0 -C link-arg=-Wl,--undefined=AVR_BOOT_SERVICES
+//! ```
+//!
+//! # Example
+//! In the bootloader, with a function which arranges to stay in the bootloader and resets, for example by setting a
+//! flag and letting the watchdog expire:
+//! ```no_run
+//! use avr_boot::{FlashRange, bootloader_services};
+//!
+//! extern "C" fn reboot() -> ! {
+//!     loop {}
+//! }
+//!
+//! bootloader_services!(3, FlashRange::application(4096), reboot);
+//! ```
+//!
+//! And in the application:
+//! ```no_run
+//! use avr_boot::{Address, DataPage, services::Services};
+//!
+//! if let Ok(services) = Services::find() {
+//!     let data = DataPage(core::array::from_fn(|i| i as u16));
+//!     services.write_page(Address::from(0x1000u16), &data).unwrap();
+//!     assert_eq!(services.read_page(Address::from(0x1000u16)).0, data.0);
+//!     services.reboot();
+//! }
+//! ```
+
+use crate::flash::{Flash, Spm};
+use crate::service::{Handler, Instructions, Service};
+use crate::{
+    Address, DataPage, FLASH_SIZE_BYTES, FlashRange, SIGNATURE, SPM_PAGESIZE_BYTES,
+    SPM_PAGESIZE_WORDS, checksum, lpm, service, spm,
+};
+
+use cfg_if::cfg_if;
+
+/// Identifies the table, "b007"
+pub const MAGIC: u16 = 0xb007;
+
+/// The version of the table this crate exports and understands
+pub const VERSION: u8 = 1;
+
+/// Length of the table on the MCU, at this version
+pub const TABLE_LENGTH: u32 = 32;
+
+/// Where the table starts: the last [`TABLE_LENGTH`] bytes of flash
+pub const TABLE_ADDRESS: u32 = FLASH_SIZE_BYTES - TABLE_LENGTH;
+
+/// Where the magic number is kept, which doesn't move between versions
+const MAGIC_ADDRESS: u32 = FLASH_SIZE_BYTES - 4;
+
+/// Where the version is kept, which doesn't move between versions
+const VERSION_ADDRESS: u32 = FLASH_SIZE_BYTES - 5;
+
+/// Why the bootloader's services couldn't be used
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Error {
+    /// The bootloader doesn't export a table
+    Missing,
+    /// The table is older than this crate understands
+    Unsupported(u8),
+    /// The bootloader was built for a different device
+    Device,
+    /// The bootloader refused to write outside the application section
+    Refused,
+}
+
+/// The table of services, as laid out in flash
+///
+/// Entries are in the reverse order they were added, so the newest are at the lowest address. The entry points are
+/// word addresses, so they must all be in the first 128k of flash.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct Table {
+    /// Reset into the bootloader, staying there
+    pub reboot: extern "C" fn() -> !,
+    /// The CRC-32 of the flash from `start` up to `end`
+    pub crc32: extern "C" fn(start: u32, end: u32) -> u32,
+    /// Read the page containing `address`
+    pub read_page: extern "C" fn(address: u32, data: &mut [u16; SPM_PAGESIZE_WORDS]),
+    /// Erase and write the page containing `address`, returning whether it was in the application section
+    pub write_page: extern "C" fn(address: u32, data: &[u16; SPM_PAGESIZE_WORDS]) -> bool,
+    /// The first address of the application section
+    pub application_start: u32,
+    /// The address after the end of the application section
+    pub application_end: u32,
+    /// Size of the flash in bytes
+    pub flash_size: u32,
+    /// Size of a flash page in bytes
+    pub page_size: u16,
+    /// The signature of the device the bootloader was built for
+    pub signature: [u8; 3],
+    /// The bootloader's own version, however it chooses to number them
+    pub bootloader_version: u16,
+    /// The version of the table
+    pub version: u8,
+    /// Always [`MAGIC`]
+    pub magic: u16,
+    /// The SPM service handler, at [`SERVICE_ADDRESS`](crate::service::SERVICE_ADDRESS)
+    pub spm: Handler,
+}
+
+#[cfg(target_arch = "avr")]
+const _: () = assert!(core::mem::size_of::<Table>() == TABLE_LENGTH as usize);
+
+impl Table {
+    /// A table for this device and version, with the bootloader's own entry points for anything which depends on its
+    /// application section or how it is entered
+    pub const fn new(
+        bootloader_version: u16,
+        application: FlashRange,
+        write_page: extern "C" fn(address: u32, data: &[u16; SPM_PAGESIZE_WORDS]) -> bool,
+        spm: Handler,
+        reboot: extern "C" fn() -> !,
+    ) -> Self {
+        Self {
+            reboot,
+            crc32,
+            read_page,
+            write_page,
+            application_start: application.start.to_u32(),
+            application_end: application.end.to_u32(),
+            flash_size: FLASH_SIZE_BYTES,
+            page_size: SPM_PAGESIZE_BYTES as u16,
+            signature: SIGNATURE,
+            bootloader_version,
+            version: VERSION,
+            magic: MAGIC,
+            spm,
+        }
+    }
+}
+
+/// Export a [`Table`] from the bootloader, with its `version`, programming pages within the `const` range
+/// `application`, and calling `reboot` to reset into it
+///
+/// See the [module documentation](crate::services) for how to link it. Parts with more than 128k of flash aren't
+/// supported, as the entry points in the boot section would be out of reach of 16-bit function pointers.
+#[macro_export]
+macro_rules! bootloader_services {
+    ($version:expr, $application:expr, $reboot:path) => {
+        const _: () = assert!(
+            $crate::FLASH_SIZE_BYTES <= 0x2_0000,
+            "the bootloader services need the boot section within reach of 16-bit function pointers"
+        );
+
+        #[unsafe(no_mangle)]
+        #[used]
+        #[unsafe(link_section = ".bootloader_services")]
+        static AVR_BOOT_SERVICES: $crate::services::Table = {
+            const APPLICATION: $crate::FlashRange = $application;
+
+            extern "C" fn write_page(
+                address: u32,
+                data: &[u16; $crate::SPM_PAGESIZE_WORDS],
+            ) -> bool {
+                $crate::services::serve_write(APPLICATION, address, data)
+            }
+            extern "C" fn spm(address: u16, command: u8, data: u16, ramp: u8) -> bool {
+                $crate::service::serve(APPLICATION, address, command, data, ramp)
+            }
+            $crate::services::Table::new($version, APPLICATION, write_page, spm, $reboot)
+        };
+    };
+}
+
+/// Erase and write the page containing `address`, if it is within `application`
+pub fn write(
+    flash: &mut impl Flash,
+    application: FlashRange,
+    address: Address,
+    data: &DataPage,
+) -> bool {
+    if !application.contains(address) {
+        return false;
+    }
+    flash.store_page(address, data);
+    true
+}
+
+/// The body of the `write_page` entry [`bootloader_services!`] exports
+#[doc(hidden)]
+pub fn serve_write(
+    application: FlashRange,
+    address: u32,
+    data: &[u16; SPM_PAGESIZE_WORDS],
+) -> bool {
+    service::without_interrupts(|| {
+        let done = write(
+            &mut Instructions,
+            application,
+            Address::from(address),
+            data.into(),
+        );
+        spm::busy_wait();
+        done
+    })
+}
+
+extern "C" fn read_page(address: u32, data: &mut [u16; SPM_PAGESIZE_WORDS]) {
+    *data = Spm.read_page(Address::from(address)).0;
+}
+
+extern "C" fn crc32(start: u32, end: u32) -> u32 {
+    checksum::crc32(&Spm, FlashRange::new(start, end))
+}
+
+/// The bootloader's services, called from the application
+#[derive(Copy, Clone)]
+pub struct Services {
+    table: Table,
+}
+
+impl Services {
+    /// Find the table the bootloader exported, and check it can be used
+    ///
+    /// Always [`Error::Missing`] when not compiling for AVR.
+    pub fn find() -> Result<Self, Error> {
+        let magic = lpm::read_word(MAGIC_ADDRESS);
+        if magic != MAGIC {
+            return Err(Error::Missing);
+        }
+        let version = lpm::read_byte(VERSION_ADDRESS);
+        if version < VERSION {
+            return Err(Error::Unsupported(version));
+        }
+        cfg_if! {
+            if #[cfg(all(target_arch = "avr", not(doc)))] {
+                let mut bytes = [0; TABLE_LENGTH as usize];
+                lpm::read_into(TABLE_ADDRESS, &mut bytes);
+                // Function pointers are word addresses, stored as they are in RAM
+                let table = unsafe { core::ptr::read_unaligned(bytes.as_ptr() as *const Table) };
+                Self::new(table)
+            } else {
+                Err(Error::Missing)
+            }
+        }
+    }
+
+    /// Use `table` directly, such as one in the same program, once it has been checked
+    pub fn new(table: Table) -> Result<Self, Error> {
+        if table.magic != MAGIC {
+            return Err(Error::Missing);
+        }
+        if table.version < VERSION {
+            return Err(Error::Unsupported(table.version));
+        }
+        if table.signature != SIGNATURE || table.page_size as usize != SPM_PAGESIZE_BYTES {
+            return Err(Error::Device);
+        }
+        Ok(Self { table })
+    }
+
+    /// The version of the table
+    pub fn version(&self) -> u8 {
+        self.table.version
+    }
+
+    /// The bootloader's own version
+    pub fn bootloader_version(&self) -> u16 {
+        self.table.bootloader_version
+    }
+
+    /// The signature of the device the bootloader was built for
+    pub fn signature(&self) -> [u8; 3] {
+        self.table.signature
+    }
+
+    /// Size of the flash in bytes
+    pub fn flash_size(&self) -> u32 {
+        self.table.flash_size
+    }
+
+    /// The application section, which the bootloader will write to
+    pub fn application(&self) -> FlashRange {
+        FlashRange::new(self.table.application_start, self.table.application_end)
+    }
+
+    /// Erase and write the page containing `address`
+    pub fn write_page(&self, address: impl Into<Address>, data: &DataPage) -> Result<(), Error> {
+        match (self.table.write_page)(address.into().into(), &data.0) {
+            true => Ok(()),
+            false => Err(Error::Refused),
+        }
+    }
+
+    /// Read the page containing `address`
+    pub fn read_page(&self, address: impl Into<Address>) -> DataPage {
+        let mut page = DataPage([0xffff; SPM_PAGESIZE_WORDS]);
+        (self.table.read_page)(address.into().into(), &mut page.0);
+        page
+    }
+
+    /// The CRC-32 of `range`, the same as [`checksum::crc32`]
+    pub fn crc32(&self, range: FlashRange) -> u32 {
+        (self.table.crc32)(range.start.into(), range.end.into())
+    }
+
+    /// Reset into the bootloader
+    pub fn reboot(&self) -> ! {
+        (self.table.reboot)()
+    }
+
+    /// The bootloader's SPM service
+    pub fn spm(&self) -> Service {
+        Service::new(self.table.spm)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::mock::MockFlash;
+
+    extern "C" fn reboot() -> ! {
+        unreachable!()
+    }
+
+    bootloader_services!(3, FlashRange::application(4096), reboot);
+
+    #[test]
+    fn it_writes_pages_of_the_application_only() {
+        let mut flash = MockFlash::new();
+        let application = FlashRange::application(4096);
+        let data = DataPage(core::array::from_fn(|i| i as u16));
+
+        assert!(write(
+            &mut flash,
+            application,
+            Address::from(0x1000u16),
+            &data
+        ));
+        assert_eq!(*flash.read_page(Address::from(0x1000u16)), *data);
+
+        assert!(!write(&mut flash, application, application.end, &data));
+        assert_eq!(flash.writes, 1);
+    }
+
+    #[test]
+    fn it_describes_the_bootloader_and_device() {
+        let services = Services::new(AVR_BOOT_SERVICES).unwrap();
+
+        assert_eq!(services.version(), VERSION);
+        assert_eq!(services.bootloader_version(), 3);
+        assert_eq!(services.signature(), SIGNATURE);
+        assert_eq!(services.flash_size(), FLASH_SIZE_BYTES);
+        assert_eq!(services.application(), FlashRange::application(4096));
+    }
+
+    #[test]
+    fn it_calls_the_bootloader() {
+        let services = Services::new(AVR_BOOT_SERVICES).unwrap();
+        let data = DataPage([0; SPM_PAGESIZE_WORDS]);
+        let range = FlashRange::new(0u16, 4u16);
+
+        assert_eq!(services.write_page(0x1000u16, &data), Ok(()));
+        assert_eq!(services.write_page(0x1_f000u32, &data), Err(Error::Refused));
+        // On the host, program memory reads as erased
+        assert_eq!(
+            services.read_page(0x1000u16).0,
+            [0xffff; SPM_PAGESIZE_WORDS]
+        );
+        assert_eq!(services.crc32(range), checksum::crc32(&Spm, range));
+        assert!(
+            services
+                .spm()
+                .call(service::RWW_ENABLE, Address::from(0u16), 0)
+        );
+    }
+
+    #[test]
+    fn it_checks_the_table_before_calling_it() {
+        let table = |f: fn(&mut Table)| {
+            let mut table = AVR_BOOT_SERVICES;
+            f(&mut table);
+            Services::new(table).err()
+        };
+
+        assert_eq!(table(|_| {}), None);
+        assert_eq!(table(|t| t.version = 2), None);
+        assert_eq!(table(|t| t.magic = 0xffff), Some(Error::Missing));
+        assert_eq!(table(|t| t.version = 0), Some(Error::Unsupported(0)));
+        assert_eq!(
+            table(|t| t.signature = [0x1e, 0x95, 0x0f]),
+            Some(Error::Device)
+        );
+        assert_eq!(table(|t| t.page_size = 64), Some(Error::Device));
+        assert!(matches!(Services::find(), Err(Error::Missing)));
+    }
+}
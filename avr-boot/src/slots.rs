@@ -0,0 +1,374 @@
+//! A/B application slots, with automatic rollback
+//!
+//! The application range is split in half. The running slot at the bottom holds the application which is started,
+//! and new firmware is written into the staging slot above it, by the bootloader or by the application itself, with
+//! an [`image`] trailer at the end of the staging slot. The firmware is linked for the running slot, just as if it
+//! were written there directly.
+//!
+//! Once the new firmware is in place, [`Slots::mark_pending`] asks the bootloader to install it. At the next reset,
+//! [`Slots::boot`] checks the staged firmware against its trailer, then swaps the two slots page by page, so the old
+//! application ends up in the staging slot. The new application is then on trial: each boot counts as an attempt,
+//! until it calls [`Slots::confirm`] once it is happy it is working. If it doesn't within the allowed number of
+//! boots, the bootloader swaps the slots back, rolling back to the old application.
+//!
+//! The swap goes through a scratch page at the end of the application range, using a [`journal`], so a power cut
+//! part way through is finished at the next boot rather than leaving a mix of the two applications. The boot status
+//! and the journal are kept in [`EEPROM_LENGTH`] bytes of EEPROM, which read as confirmed when erased.
+//!
+//! # Example
+//! ```no_run
+//! use avr_boot::{FlashRange, eeprom::InternalEeprom, flash::Spm, slots::Slots};
+//!
+//! let mut slots = Slots::new(Spm, InternalEeprom, FlashRange::application(8192), 0);
+//! match slots.boot() {
+//!     Ok(_header) => { /* jump to the application */ }
+//!     Err(_) => { /* no application to start, wait for a new one */ }
+//! }
+//! ```
+//!
+//! And in the application, once it has checked everything is working:
+//! ```no_run
+//! # use avr_boot::{FlashRange, eeprom::InternalEeprom, flash::Spm, slots::Slots};
+//! Slots::new(Spm, InternalEeprom, FlashRange::application(8192), 0).confirm();
+//! ```
+
+use crate::eeprom::Eeprom;
+use crate::flash::Flash;
+use crate::image::{self, Header};
+use crate::journal::{self, EepromLog, Log, Transfer};
+use crate::{Address, FlashRange, SPM_PAGESIZE_BYTES};
+
+/// Boots allowed before an application which hasn't confirmed itself is rolled back
+pub const DEFAULT_MAX_ATTEMPTS: u8 = 3;
+
+/// Bytes of EEPROM used for the boot status and the swap journal
+pub const EEPROM_LENGTH: u16 = 2 + 2 * journal::RECORD_LENGTH as u16;
+
+const CONFIRMED: u8 = 0xff;
+const PENDING: u8 = 0xa5;
+const TRIAL: u8 = 0x5a;
+const INSTALLING: u8 = 0x3c;
+const ROLLING_BACK: u8 = 0xc3;
+
+/// The boot status, as kept in EEPROM
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum State {
+    /// The running application is known to work, or there is nothing to compare it with
+    Confirmed,
+    /// New firmware has been staged, and should be installed at the next boot
+    Pending,
+    /// New firmware has been installed, and has been booted this many times without confirming it works
+    Trial(u8),
+    /// The slots are being swapped to install new firmware
+    Installing,
+    /// The slots are being swapped back, to roll back an application which never confirmed it works
+    RollingBack,
+}
+
+/// Why the application can't be started
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Error {
+    /// The running slot doesn't hold an intact application
+    Image(image::Error),
+    /// Swapping the slots failed
+    Transfer(journal::Error),
+}
+
+/// Running and staging slots, and their boot status
+pub struct Slots<F, E> {
+    flash: F,
+    eeprom: E,
+    running: FlashRange,
+    staging: FlashRange,
+    scratch: Address,
+    status: u16,
+    max_attempts: u8,
+}
+
+impl<F: Flash, E: Eeprom> Slots<F, E> {
+    /// Split `application` into two slots of whole pages, keeping its last page for swapping them, and the boot
+    /// status in the [`EEPROM_LENGTH`] bytes of EEPROM from `status`
+    ///
+    /// Panics if `application` is shorter than 3 pages, one for each slot and one for swapping them.
+    pub fn new(flash: F, eeprom: E, application: FlashRange, status: u16) -> Self {
+        let page = SPM_PAGESIZE_BYTES as u32;
+        let pages = application.len() / page;
+        assert!(
+            pages >= 3,
+            "the application range has {pages} pages, slots need at least 3"
+        );
+        let half = (pages - 1) / 2 * page;
+        let middle = Address::from(u32::from(application.start) + half);
+        let end = Address::from(u32::from(middle) + half);
+        let scratch = Address::from(u32::from(application.end) - page).into_page_aligned();
+
+        Self {
+            flash,
+            eeprom,
+            running: FlashRange::new(application.start, middle),
+            staging: FlashRange::new(middle, end),
+            scratch,
+            status,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+        }
+    }
+
+    /// Set how many times a new application may be booted without confirming it works, before it is rolled back
+    pub fn with_max_attempts(mut self, max_attempts: u8) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    /// The slot the application is started from
+    pub fn running(&self) -> FlashRange {
+        self.running
+    }
+
+    /// The slot new firmware is written to, with its trailer at the end
+    pub fn staging(&self) -> FlashRange {
+        self.staging
+    }
+
+    /// The boot status, read from EEPROM
+    pub fn state(&self) -> State {
+        match (
+            self.eeprom.read_byte(self.status),
+            self.eeprom.read_byte(self.status + 1),
+        ) {
+            (PENDING, _) => State::Pending,
+            (TRIAL, attempts) => State::Trial(attempts),
+            (INSTALLING, _) => State::Installing,
+            (ROLLING_BACK, _) => State::RollingBack,
+            _ => State::Confirmed,
+        }
+    }
+
+    /// Check the staged firmware against its trailer, without installing it
+    pub fn check_staged(&self) -> Result<Header, image::Error> {
+        let header = image::read_trailer(&self.flash, self.staging)?;
+        header.check_device(FlashRange::new(
+            self.running.start,
+            image::trailer_address(self.running),
+        ))?;
+
+        let offset = u32::from(header.address) - u32::from(self.running.start);
+        let stored = Address::from(u32::from(self.staging.start) + offset);
+        header.check_flash_at(&self.flash, stored)?;
+        Ok(header)
+    }
+
+    /// Ask the bootloader to install the staged firmware at the next boot
+    pub fn mark_pending(&mut self) {
+        self.set_state(State::Pending);
+    }
+
+    /// Called by a new application once it is working, so it is kept
+    pub fn confirm(&mut self) {
+        self.set_state(State::Confirmed);
+    }
+
+    /// Install pending firmware, roll back a new application which has run out of attempts, or finish either of
+    /// those if the power was cut part way through, then check the running application
+    ///
+    /// Returns the running application's header if it should be started, or why not. Staged firmware which doesn't
+    /// match its trailer is ignored, leaving the running application as it was.
+    pub fn boot(&mut self) -> Result<Header, Error> {
+        match self.state() {
+            State::Pending => {
+                if self.check_staged().is_ok() {
+                    self.set_state(State::Installing);
+                    self.swap(State::Trial(1))?;
+                } else {
+                    self.set_state(State::Confirmed);
+                }
+            }
+            State::Installing => self.swap(State::Trial(1))?,
+            State::Trial(attempts) if attempts >= self.max_attempts => {
+                self.set_state(State::RollingBack);
+                self.swap(State::Confirmed)?;
+            }
+            State::RollingBack => self.swap(State::Confirmed)?,
+            State::Trial(attempts) => self.set_state(State::Trial(attempts + 1)),
+            State::Confirmed => {}
+        }
+        // A journal left over from a swap which finished just before a power cut
+        EepromLog::new(&mut self.eeprom, self.status + 2).clear(&mut self.flash);
+
+        image::validate(&self.flash, self.running).map_err(Error::Image)
+    }
+
+    /// Give back the flash and EEPROM
+    pub fn release(self) -> (F, E) {
+        (self.flash, self.eeprom)
+    }
+
+    fn set_state(&mut self, state: State) {
+        let (state, attempts) = match state {
+            State::Confirmed => (CONFIRMED, 0xff),
+            State::Pending => (PENDING, 0xff),
+            State::Trial(attempts) => (TRIAL, attempts),
+            State::Installing => (INSTALLING, 0xff),
+            State::RollingBack => (ROLLING_BACK, 0xff),
+        };
+        // The attempts first, so the state never pairs with a stale count
+        self.eeprom.update_byte(self.status + 1, attempts);
+        self.eeprom.update_byte(self.status, state);
+    }
+
+    /// Exchange the contents of the two slots, carrying on from the journal, then move on to `state`
+    ///
+    /// The state is written before the journal is cleared, so a finished swap is never started again.
+    fn swap(&mut self, state: State) -> Result<(), Error> {
+        let mut log = EepromLog::new(&mut self.eeprom, self.status + 2);
+        Transfer::swap(self.running, self.staging.start, self.scratch)
+            .run(&mut self.flash, &mut log)
+            .map_err(Error::Transfer)?;
+        self.set_state(state);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eeprom::mock::MockEeprom;
+    use crate::flash::mock::MockFlash;
+    use crate::journal::mock::PowerCut;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::vec::Vec;
+
+    /// Room for a 4k bootloader, leaving two slots of 0xf700 and the scratch page
+    fn application() -> FlashRange {
+        FlashRange::new(0u16, 0x1_f000u32)
+    }
+
+    fn slots() -> Slots<MockFlash, MockEeprom> {
+        Slots::new(MockFlash::new(), MockEeprom::new(), application(), 0x10)
+    }
+
+    /// Write an application of `length` bytes of `fill`, linked for the running slot, into `slot`
+    fn write(slots: &mut Slots<MockFlash, MockEeprom>, slot: FlashRange, fill: u8, length: usize) {
+        let data = [fill; 0x300];
+        let header = Header::for_target(slots.running.start, &data[..length], fill as u32);
+        let start = u32::from(slot.start) as usize;
+        slots.flash.memory[start..start + length].copy_from_slice(&data[..length]);
+        image::write_trailer(&mut slots.flash, slot, &header);
+    }
+
+    /// A device running version 1, with version 2 staged and pending
+    fn updating() -> Slots<MockFlash, MockEeprom> {
+        let mut slots = slots();
+        let (running, staging) = (slots.running, slots.staging);
+        write(&mut slots, running, 1, 0x300);
+        write(&mut slots, staging, 2, 0x280);
+        slots.mark_pending();
+        slots
+    }
+
+    #[test]
+    fn it_splits_the_application_into_two_slots() {
+        let slots = slots();
+
+        assert_eq!(slots.running(), FlashRange::new(0u16, 0xf700u16));
+        assert_eq!(slots.staging(), FlashRange::new(0xf700u16, 0x1_ee00u32));
+        assert_eq!(u32::from(slots.scratch), 0x1_ef00);
+    }
+
+    #[test]
+    #[should_panic(expected = "the application range has 2 pages, slots need at least 3")]
+    fn it_panics_without_room_for_two_slots_and_the_scratch_page() {
+        let end = 2 * SPM_PAGESIZE_BYTES as u32;
+
+        Slots::new(
+            MockFlash::new(),
+            MockEeprom::new(),
+            FlashRange::new(0u16, end),
+            0,
+        );
+    }
+
+    #[test]
+    fn it_installs_pending_firmware_and_keeps_the_old_version() {
+        let mut slots = updating();
+
+        let header = slots.boot().unwrap();
+
+        assert_eq!(header.app_version, 2);
+        assert_eq!(slots.state(), State::Trial(1));
+        assert_eq!(slots.check_staged().unwrap().app_version, 1);
+        assert!(slots.flash.memory[0x280..0x300].iter().all(|b| *b == 0xff));
+    }
+
+    #[test]
+    fn it_finishes_an_install_cut_short_by_a_power_cut() {
+        let mut saves = 0;
+
+        loop {
+            let mut slots = updating();
+            slots.set_state(State::Installing);
+            let transfer = Transfer::swap(slots.running, slots.staging.start, slots.scratch);
+            let mut log = PowerCut {
+                log: EepromLog::new(&mut slots.eeprom, 0x12),
+                saves,
+            };
+            let finished = panic::catch_unwind(AssertUnwindSafe(|| {
+                transfer.run(&mut slots.flash, &mut log)
+            }));
+
+            assert_eq!(slots.boot().unwrap().app_version, 2, "cut after {saves}");
+            assert_eq!(slots.state(), State::Trial(1));
+            assert_eq!(slots.check_staged().unwrap().app_version, 1);
+            assert_eq!(slots.boot().unwrap().app_version, 2);
+
+            if finished.is_ok() {
+                break;
+            }
+            saves += 1;
+        }
+        // The first three pages and the trailer page differ
+        assert_eq!(saves, 36);
+    }
+
+    #[test]
+    fn it_keeps_a_new_version_which_confirms_itself() {
+        let mut slots = updating();
+        slots.boot().unwrap();
+        slots.confirm();
+
+        for _ in 0..5 {
+            assert_eq!(slots.boot().unwrap().app_version, 2);
+        }
+        assert_eq!(slots.state(), State::Confirmed);
+    }
+
+    #[test]
+    fn it_rolls_back_a_new_version_which_never_confirms() {
+        let mut slots = updating().with_max_attempts(3);
+
+        let versions: Vec<u32> = (0..5).map(|_| slots.boot().unwrap().app_version).collect();
+
+        assert_eq!(versions, [2, 2, 2, 1, 1]);
+        assert_eq!(slots.state(), State::Confirmed);
+        assert_eq!(slots.check_staged().unwrap().app_version, 2);
+    }
+
+    #[test]
+    fn it_ignores_staged_firmware_which_fails_its_check() {
+        let mut slots = updating();
+        slots.flash.memory[0xf700 + 0x100] = 0;
+        let erases = slots.flash.erases;
+
+        let header = slots.boot().unwrap();
+
+        assert_eq!(header.app_version, 1);
+        assert_eq!(slots.state(), State::Confirmed);
+        assert_eq!(slots.flash.erases, erases);
+    }
+
+    #[test]
+    fn it_stays_in_the_bootloader_without_an_application() {
+        let mut slots = slots();
+
+        assert_eq!(slots.boot(), Err(Error::Image(image::Error::BadMagic)));
+    }
+}
@@ -1,4 +1,9 @@
 //! Low level API for calling bootloader functions
+//!
+//! With the `spm-service` feature, erasing, filling, writing and re-enabling the RWW section are done by calling the
+//! bootloader's [SPM service](crate::service) instead, so these functions can be used from the application. They
+//! panic if the bootloader has no service, or refuses a command. When not compiling for AVR, they do nothing either
+//! way.
 
 use crate::Address;
 use crate::*;
@@ -22,26 +27,14 @@ pub fn store_page<'a>(address: impl Into<Address>, data: impl Into<&'a DataPage>
 /// Erase the page from program memory
 ///
 /// The PCPAGE part of the address is used to address the page, the PCWORD part must be zero
-#[cfg_attr(not(target_arch = "avr"), allow(unused_variables))]
 pub fn erase_page(address: impl Into<Address>) {
     let page_address: Address = address.into();
-    let z_address: u16 = page_address.into_page_aligned().into();
 
-    busy_wait();
-    rampz(page_address.ramp());
     cfg_if! {
-        if #[cfg(all(target_arch = "avr", not(doc)))] {
-            unsafe {
-                asm!(
-                    "
-                    out {SPMCSR} r24
-                    spm
-                    ",
-                    in("r24") PAGE_ERASE,
-                    in("Z") z_address,
-                    SPMCSR = const SPMCSR_ADDR - 0x20,
-                );
-            }
+        if #[cfg(all(target_arch = "avr", feature = "spm-service"))] {
+            crate::service::request(crate::service::ERASE, page_address, 0);
+        } else {
+            instruction::erase_page(page_address);
         }
     }
 }
@@ -49,28 +42,14 @@ pub fn erase_page(address: impl Into<Address>) {
 /// Write data to the page buffer
 ///
 /// Only the PCWORD part of the address actually matters, the size of which varies according to SPM_PAGESIZE_BYTES
-#[cfg_attr(not(target_arch = "avr"), allow(unused_variables))]
 pub fn fill_page(address: impl Into<Address>, data: u16) {
     let page_address: Address = address.into();
-    let z_address: u16 = page_address.into();
 
-    busy_wait();
     cfg_if! {
-        if #[cfg(all(target_arch = "avr", not(doc)))] {
-            unsafe {
-                asm!(
-                    "
-                    movw r0 {data}
-                    out {SPMCSR} r24
-                    spm
-                    eor	r1, r1
-                    ",
-                    data = in(reg_iw) data,
-                    in("r24") PAGE_FILL,
-                    in("Z") z_address,
-                    SPMCSR = const SPMCSR_ADDR - 0x20,
-                )
-            }
+        if #[cfg(all(target_arch = "avr", feature = "spm-service"))] {
+            crate::service::request(crate::service::FILL, page_address, data);
+        } else {
+            instruction::fill_page(page_address, data);
         }
     }
 }
@@ -78,59 +57,54 @@ pub fn fill_page(address: impl Into<Address>, data: u16) {
 /// Write the page from the buffer to the program memory
 ///
 /// The PCPAGE part of the address is used to address the page, the PCWORD part must be zero
-#[cfg_attr(not(target_arch = "avr"), allow(unused_variables))]
 pub fn write_page(address: impl Into<Address>) {
     let page_address: Address = address.into();
-    let z_address: u16 = page_address.into_page_aligned().into();
 
-    busy_wait();
-    rampz(page_address.ramp());
     cfg_if! {
-        if #[cfg(all(target_arch = "avr", not(doc)))] {
-            unsafe {
-                asm!(
-                    "
-                    out {SPMCSR} r24
-                    spm
-                    ",
-                    in("r24") PAGE_WRITE,
-                    in("Z") z_address,
-                    SPMCSR = const SPMCSR_ADDR - 0x20,
-                )
-            }
+        if #[cfg(all(target_arch = "avr", feature = "spm-service"))] {
+            crate::service::request(crate::service::WRITE, page_address, 0);
+        } else {
+            instruction::write_page(page_address);
         }
     }
 }
 
 /// Fill the whole buffer at once
-///
 #[cfg_attr(not(target_arch = "avr"), allow(unused_variables))]
 pub fn copy_to_buffer<'a>(data: impl Into<&'a DataPage>) {
-    busy_wait();
-    rampz(0);
     cfg_if! {
-        if #[cfg(all(target_arch = "avr", not(doc)))] {
-            unsafe {
-                asm!(
-                    "
-                    1:                       
-                        ld      r0,         X+  // Load r0r1 pair with data from X pointer
-                        ld      r1,         X+
-                        out {SPMCSR} r24
-                        spm                     // call spm(PAGE_FILL) (r24 is always 1st byte argument)
-                        adiw    Z,          2   // increment Z
-                        subi    {words},    1   // decrement counter
-                        brne    1b              // loop until counter reaches 0
+        if #[cfg(all(target_arch = "avr", feature = "spm-service"))] {
+            for (i, word) in data.into().iter().enumerate() {
+                fill_page(Address::from((i * 2) as u16), *word);
+            }
+        } else {
+            busy_wait();
+            rampz(0);
+            cfg_if! {
+                if #[cfg(all(target_arch = "avr", not(doc)))] {
+                    unsafe {
+                        asm!(
+                            "
+                            1:                       
+                                ld      r0,         X+  // Load r0r1 pair with data from X pointer
+                                ld      r1,         X+
+                                out {SPMCSR} r24
+                                spm                     // call spm(PAGE_FILL) (r24 is always 1st byte argument)
+                                adiw    Z,          2   // increment Z
+                                subi    {words},    1   // decrement counter
+                                brne    1b              // loop until counter reaches 0
 
-                        clr	    r1
-                    ",
+                                clr	    r1
+                            ",
 
-                    words = inout(reg) SPM_PAGESIZE_WORDS as u8 => _,
-                    in("r24") PAGE_FILL,
-                    inout("X") data.into().as_ptr() => _,
-                    inout("Z") 0u16 => _,
-                    SPMCSR = const SPMCSR_ADDR - 0x20,
-                )
+                            words = inout(reg) SPM_PAGESIZE_WORDS as u8 => _,
+                            in("r24") PAGE_FILL,
+                            inout("X") data.into().as_ptr() => _,
+                            inout("Z") 0u16 => _,
+                            SPMCSR = const SPMCSR_ADDR - 0x20,
+                        )
+                    }
+                }
             }
         }
     }
@@ -160,28 +134,49 @@ pub fn lock_bits_set(lock_bits: u8) {
 }
 
 /// Re-enable the RWW section after programming, to enable it to be read
-#[cfg(rww_enable)]
+///
+/// Does nothing on devices without a RWW section.
 pub fn rww_enable() {
-    busy_wait();
     cfg_if! {
-        if #[cfg(all(target_arch = "avr", not(doc)))] {
-            unsafe {
-                asm!(
-                    "
-                    out {SPMCSR} r24
-                    spm
-                    ",
-                    in("r24") RWW_ENABLE,
-                    SPMCSR = const SPMCSR_ADDR - 0x20,
-                );
-            }
+        if #[cfg(all(target_arch = "avr", feature = "spm-service"))] {
+            crate::service::request(crate::service::RWW_ENABLE, Address::from(0u16), 0);
+        } else {
+            instruction::rww_enable();
         }
     }
 }
 
-/// Empty function for devices without a RWW section
-#[cfg(not(rww_enable))]
-pub fn rww_enable() {}
+/// Read the device signature from the signature row, which tells apart parts sharing a build
+///
+/// Returns [`SIGNATURE`] on devices without SIGRD, and when not compiling for AVR.
+pub fn read_signature() -> [u8; 3] {
+    cfg_if! {
+        if #[cfg(all(target_arch = "avr", signature_read, not(doc)))] {
+            let mut signature = [0; 3];
+            busy_wait();
+            rampz(0);
+            for (i, byte) in signature.iter_mut().enumerate() {
+                // The signature bytes are at every other address in the signature row
+                let z_address = 2 * i as u16;
+                unsafe {
+                    asm!(
+                        "
+                        out {SPMCSR} r24
+                        lpm {byte}, Z
+                        ",
+                        byte = out(reg) *byte,
+                        in("r24") SIGNATURE_READ,
+                        in("Z") z_address,
+                        SPMCSR = const SPMCSR_ADDR - 0x20,
+                    );
+                }
+            }
+            signature
+        } else {
+            SIGNATURE
+        }
+    }
+}
 
 /// Wait for the current SPM operation to complete.
 ///
@@ -199,7 +194,7 @@ pub fn busy_wait() {
     not(all(target_arch = "avr", extended_addressing)),
     allow(unused_variables)
 )]
-fn rampz(value: u8) {
+pub(crate) fn rampz(value: u8) {
     cfg_if! {
         if #[cfg(all(target_arch = "avr", extended_addressing, not(doc)))] {
             unsafe {
@@ -208,3 +203,114 @@ fn rampz(value: u8) {
         }
     }
 }
+
+/// The SPM instructions themselves, which only work from the boot section
+///
+/// The SPM service's handlers always program with these, so they never call themselves when the bootloader is built
+/// with the `spm-service` feature.
+pub(crate) mod instruction {
+    use super::{busy_wait, rampz};
+    use crate::*;
+
+    use cfg_if::cfg_if;
+    #[allow(unused_imports)]
+    use core::arch::asm;
+
+    /// Erase the page containing `page_address`
+    #[cfg_attr(not(target_arch = "avr"), allow(unused_variables))]
+    pub(crate) fn erase_page(page_address: Address) {
+        let z_address: u16 = page_address.into_page_aligned().into();
+
+        busy_wait();
+        rampz(page_address.ramp());
+        cfg_if! {
+            if #[cfg(all(target_arch = "avr", not(doc)))] {
+                unsafe {
+                    asm!(
+                        "
+                        out {SPMCSR} r24
+                        spm
+                        ",
+                        in("r24") PAGE_ERASE,
+                        in("Z") z_address,
+                        SPMCSR = const SPMCSR_ADDR - 0x20,
+                    );
+                }
+            }
+        }
+    }
+
+    /// Write `data` to the page buffer, at the PCWORD part of `page_address`
+    #[cfg_attr(not(target_arch = "avr"), allow(unused_variables))]
+    pub(crate) fn fill_page(page_address: Address, data: u16) {
+        let z_address: u16 = page_address.into();
+
+        busy_wait();
+        cfg_if! {
+            if #[cfg(all(target_arch = "avr", not(doc)))] {
+                unsafe {
+                    asm!(
+                        "
+                        movw r0 {data}
+                        out {SPMCSR} r24
+                        spm
+                        eor	r1, r1
+                        ",
+                        data = in(reg_iw) data,
+                        in("r24") PAGE_FILL,
+                        in("Z") z_address,
+                        SPMCSR = const SPMCSR_ADDR - 0x20,
+                    )
+                }
+            }
+        }
+    }
+
+    /// Write the page buffer to the page containing `page_address`
+    #[cfg_attr(not(target_arch = "avr"), allow(unused_variables))]
+    pub(crate) fn write_page(page_address: Address) {
+        let z_address: u16 = page_address.into_page_aligned().into();
+
+        busy_wait();
+        rampz(page_address.ramp());
+        cfg_if! {
+            if #[cfg(all(target_arch = "avr", not(doc)))] {
+                unsafe {
+                    asm!(
+                        "
+                        out {SPMCSR} r24
+                        spm
+                        ",
+                        in("r24") PAGE_WRITE,
+                        in("Z") z_address,
+                        SPMCSR = const SPMCSR_ADDR - 0x20,
+                    )
+                }
+            }
+        }
+    }
+
+    /// Re-enable the RWW section after programming
+    #[cfg(rww_enable)]
+    pub(crate) fn rww_enable() {
+        busy_wait();
+        cfg_if! {
+            if #[cfg(all(target_arch = "avr", not(doc)))] {
+                unsafe {
+                    asm!(
+                        "
+                        out {SPMCSR} r24
+                        spm
+                        ",
+                        in("r24") RWW_ENABLE,
+                        SPMCSR = const SPMCSR_ADDR - 0x20,
+                    );
+                }
+            }
+        }
+    }
+
+    /// Empty function for devices without a RWW section
+    #[cfg(not(rww_enable))]
+    pub(crate) fn rww_enable() {}
+}
@@ -0,0 +1,408 @@
+//! Streaming Motorola S-record decoder
+//!
+//! Decodes an S-record file one character at a time, with no allocation, in the same way as [`ihex`](crate::ihex).
+//! S1, S2 and S3 data records, with 16, 24 and 32 bit addresses, are all supported, so images for parts with more
+//! than 64k of flash can be loaded. Data addressed beyond 24 bits is rejected. Every record's checksum is checked
+//! before its data is used. Like the HEX [`Loader`](crate::ihex::Loader), the [`Loader`] sends
+//! [`XOFF`](crate::protocol::XOFF) before writing a page and [`XON`](crate::protocol::XON) afterwards, so the sender
+//! must use software flow control.
+//!
+//! # Example
+//! ```no_run
+//! use avr_boot::{FlashRange, srec::Loader, flash::Spm, protocol::{Serial, Status}};
+//!
+//! # fn run(mut serial: impl Serial) -> Result<(), ()> {
+//! let mut buffer = [0; 32];
+//! let mut loader = Loader::new(Spm, FlashRange::application(4096), &mut buffer);
+//! loop {
+//!     let character = serial.read().map_err(|_| ())?;
+//!     if loader.push(&mut serial, character) == Ok(Status::Done) {
+//!         break;
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::flash::Flash;
+use crate::protocol::{Serial, Status};
+use crate::writer::{RecordWriter, Refused};
+use crate::{Address, FlashRange};
+
+/// The address after the last one an [`Address`] can hold
+const ADDRESS_LIMIT: u32 = 0x100_0000;
+
+/// Why a record couldn't be decoded or loaded
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Error {
+    /// A character other than a hex digit was found within a record
+    InvalidCharacter(u8),
+    /// The record checksum didn't match
+    Checksum,
+    /// A record's data was longer than the buffer
+    RecordTooLong,
+    /// An unknown record type, or a byte count too short for the address
+    InvalidRecord,
+    /// Data was addressed beyond 24 bits, or outside the application range
+    OutOfRange(u32),
+    /// Data went back to a page which had already been written
+    OutOfOrder(u32),
+}
+
+impl From<Refused> for Error {
+    fn from(refused: Refused) -> Self {
+        match refused {
+            Refused::OutOfRange(address) => Error::OutOfRange(address),
+            Refused::OutOfOrder(address) => Error::OutOfOrder(address),
+        }
+    }
+}
+
+/// A decoded record
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Record<'a> {
+    /// The contents of an S0 header record, normally the file name
+    Header(&'a [u8]),
+    /// Data to be written at `address`, from an S1, S2 or S3 record
+    Data {
+        /// The address of the first byte
+        address: Address,
+        /// The data bytes
+        data: &'a [u8],
+    },
+    /// The number of data records sent so far, from an S5 or S6 record
+    Count(u32),
+    /// The end of the file, with the execution start address, from an S7, S8 or S9 record
+    End(u32),
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum State {
+    /// Waiting for the next `S`
+    Idle,
+    /// Waiting for the record type digit
+    Type,
+    /// Number of bytes decoded in the current record
+    Bytes(usize),
+}
+
+/// S-record decoder
+///
+/// Characters between records, such as line endings, are ignored.
+pub struct Decoder<'a> {
+    buffer: &'a mut [u8],
+    state: State,
+    record_type: u8,
+    count: u8,
+    address: u32,
+    high_nibble: Option<u8>,
+    checksum: u8,
+}
+
+impl<'a> Decoder<'a> {
+    /// Create a new decoder, using `buffer` to hold each record's data until its checksum has been checked
+    ///
+    /// avr-objcopy writes 16 bytes per record, and other tools commonly use up to 32.
+    pub fn new(buffer: &'a mut [u8]) -> Self {
+        Self {
+            buffer,
+            state: State::Idle,
+            record_type: 0,
+            count: 0,
+            address: 0,
+            high_nibble: None,
+            checksum: 0,
+        }
+    }
+
+    /// Decode a single character, returning a record when one is complete
+    ///
+    /// After an error, the rest of the record is skipped.
+    pub fn push(&mut self, character: u8) -> Result<Option<Record<'_>>, Error> {
+        let position = match self.state {
+            State::Idle => {
+                if character == b'S' {
+                    self.state = State::Type;
+                }
+                return Ok(None);
+            }
+            State::Type => {
+                if !matches!(character, b'0'..=b'3' | b'5'..=b'9') {
+                    self.state = State::Idle;
+                    return Err(Error::InvalidRecord);
+                }
+                self.record_type = character - b'0';
+                self.state = State::Bytes(0);
+                self.high_nibble = None;
+                self.checksum = 0;
+                self.address = 0;
+                return Ok(None);
+            }
+            State::Bytes(position) => position,
+        };
+
+        let Some(nibble) = (character as char).to_digit(16) else {
+            self.state = State::Idle;
+            return Err(Error::InvalidCharacter(character));
+        };
+
+        let Some(high) = self.high_nibble.take() else {
+            self.high_nibble = Some(nibble as u8);
+            return Ok(None);
+        };
+
+        let byte = (high << 4) | nibble as u8;
+        self.checksum = self.checksum.wrapping_add(byte);
+        self.state = State::Bytes(position + 1);
+
+        let address_length = self.address_length();
+        if position == 0 {
+            self.count = byte;
+            let Some(length) = (byte as usize).checked_sub(address_length + 1) else {
+                self.state = State::Idle;
+                return Err(Error::InvalidRecord);
+            };
+            if length > self.buffer.len() {
+                self.state = State::Idle;
+                return Err(Error::RecordTooLong);
+            }
+            return Ok(None);
+        }
+
+        if position <= address_length {
+            self.address = (self.address << 8) | byte as u32;
+        } else if position < self.count as usize {
+            self.buffer[position - address_length - 1] = byte;
+        } else {
+            self.state = State::Idle;
+            if self.checksum != 0xff {
+                return Err(Error::Checksum);
+            }
+            return self.record().map(Some);
+        }
+
+        Ok(None)
+    }
+
+    fn address_length(&self) -> usize {
+        match self.record_type {
+            0 | 1 | 5 | 9 => 2,
+            2 | 6 | 8 => 3,
+            _ => 4,
+        }
+    }
+
+    fn record(&self) -> Result<Record<'_>, Error> {
+        let data = &self.buffer[..self.count as usize - self.address_length() - 1];
+
+        Ok(match self.record_type {
+            0 => Record::Header(data),
+            1..=3 => {
+                let fits = self
+                    .address
+                    .checked_add(data.len() as u32)
+                    .is_some_and(|end| self.address < ADDRESS_LIMIT && end <= ADDRESS_LIMIT);
+                if !fits {
+                    return Err(Error::OutOfRange(self.address));
+                }
+                Record::Data {
+                    address: Address::from(self.address),
+                    data,
+                }
+            }
+            5 | 6 => Record::Count(self.address),
+            _ => Record::End(self.address),
+        })
+    }
+}
+
+/// Loads an S-record file into flash, as it is decoded
+///
+/// Records must arrive in ascending address order, because each page is written as soon as the data moves on to
+/// the next one. A record which goes back to a page already written is refused.
+pub struct Loader<'a, F> {
+    decoder: Decoder<'a>,
+    writer: RecordWriter<F>,
+}
+
+impl<'a, F: Flash> Loader<'a, F> {
+    /// Create a new loader, writing to `application` in `flash`, and using `buffer` to decode records
+    pub fn new(flash: F, application: FlashRange, buffer: &'a mut [u8]) -> Self {
+        Self {
+            decoder: Decoder::new(buffer),
+            writer: RecordWriter::new(flash, application),
+        }
+    }
+
+    /// Decode a single character received on `serial`, writing out any complete data record
+    ///
+    /// [`XOFF`](crate::protocol::XOFF) is sent on `serial` before a page is written, and
+    /// [`XON`](crate::protocol::XON) once it's done.
+    ///
+    /// Returns [`Status::Done`] once the termination record has been loaded, and everything has been written.
+    pub fn push<S: Serial>(&mut self, serial: &mut S, character: u8) -> Result<Status, Error> {
+        match self.decoder.push(character)? {
+            Some(Record::Data { address, data }) => self.writer.write(serial, address, data)?,
+            Some(Record::End(_)) => {
+                self.writer.finish(serial);
+                return Ok(Status::Done);
+            }
+            Some(Record::Header(_) | Record::Count(_)) | None => {}
+        }
+
+        Ok(Status::Continue)
+    }
+
+    /// Flush any buffered data, and give back the flash backend
+    pub fn release(self) -> F {
+        self.writer.release()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::mock::MockFlash;
+    use crate::protocol::mock::MockSerial;
+    use crate::protocol::{XOFF, XON};
+    use std::vec::Vec;
+
+    /// A record with its data copied out of the buffer, or the error
+    #[derive(PartialEq, Debug)]
+    enum Decoded {
+        Header(Vec<u8>),
+        Data(Address, Vec<u8>),
+        Count(u32),
+        End(u32),
+        Error(Error),
+    }
+
+    fn decode(text: &[u8]) -> Vec<Decoded> {
+        let mut buffer = [0; 16];
+        let mut decoder = Decoder::new(&mut buffer);
+        let mut records = Vec::new();
+        for character in text {
+            match decoder.push(*character) {
+                Ok(Some(Record::Header(data))) => records.push(Decoded::Header(data.to_vec())),
+                Ok(Some(Record::Data { address, data })) => {
+                    records.push(Decoded::Data(address, data.to_vec()))
+                }
+                Ok(Some(Record::Count(count))) => records.push(Decoded::Count(count)),
+                Ok(Some(Record::End(start))) => records.push(Decoded::End(start)),
+                Ok(None) => {}
+                Err(e) => records.push(Decoded::Error(e)),
+            }
+        }
+        records
+    }
+
+    fn load(text: &[u8]) -> (Result<Status, Error>, MockFlash) {
+        let mut buffer = [0; 16];
+        let application = FlashRange::new(0u16, 0x1_8000u32);
+        let mut serial = MockSerial::new(&[]);
+        let mut loader = Loader::new(MockFlash::new(), application, &mut buffer);
+        for character in text {
+            match loader.push(&mut serial, *character) {
+                Ok(Status::Continue) => {}
+                result => return (result, loader.release()),
+            }
+        }
+        (Ok(Status::Continue), loader.release())
+    }
+
+    #[test]
+    fn it_decodes_each_address_width() {
+        let records = decode(
+            b"S00B00006170702E73726563D8\r\nS1050100AABB94\r\nS20601234500CCC4\r\nS30700234567DDEE5E\r\nS5030003F9\r\nS9030000FC\r\n",
+        );
+
+        assert_eq!(
+            records,
+            [
+                Decoded::Header(b"app.srec".to_vec()),
+                Decoded::Data(Address::from(0x0100u16), vec![0xaa, 0xbb]),
+                Decoded::Data(Address::from(0x01_2345u32), vec![0x00, 0xcc]),
+                Decoded::Data(Address::from(0x23_4567u32), vec![0xdd, 0xee]),
+                Decoded::Count(3),
+                Decoded::End(0),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_rejects_addresses_beyond_24_bits() {
+        let records = decode(b"S30701234567DDEE5D\nS30700FFFFFF0101F9\n");
+
+        assert_eq!(
+            records,
+            [
+                Decoded::Error(Error::OutOfRange(0x0123_4567)),
+                Decoded::Error(Error::OutOfRange(0x00ff_ffff))
+            ]
+        );
+    }
+
+    #[test]
+    fn it_rejects_bad_records_and_recovers() {
+        let records = decode(b"S1050100AABB95\nS4030000FC\nS1050100XX\nS1020100\nS1050100AABB94\n");
+
+        assert_eq!(
+            records,
+            [
+                Decoded::Error(Error::Checksum),
+                Decoded::Error(Error::InvalidRecord),
+                Decoded::Error(Error::InvalidCharacter(b'X')),
+                Decoded::Error(Error::InvalidRecord),
+                Decoded::Data(Address::from(0x0100u16), vec![0xaa, 0xbb]),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_loads_above_64k() {
+        let (result, flash) = load(b"S20801000001020304EC\nS804000000FB\n");
+
+        assert_eq!(result, Ok(Status::Done));
+        assert_eq!(&flash.memory[0x1_0000..0x1_0005], &[1, 2, 3, 4, 0xff]);
+    }
+
+    #[test]
+    fn it_pauses_the_sender_while_writing_pages() {
+        let mut buffer = [0; 16];
+        let mut serial = MockSerial::new(&[]);
+        let mut loader = Loader::new(
+            MockFlash::new(),
+            FlashRange::new(0u16, 0x1_8000u32),
+            &mut buffer,
+        );
+
+        // The output after each record, and the pages written by then
+        let mut events = Vec::new();
+        for record in ["S10500000102F7\n", "S10501000304F2\n", "S9030000FC\n"] {
+            for character in record.bytes() {
+                loader.push(&mut serial, character).unwrap();
+            }
+            events.push(core::mem::take(&mut serial.output));
+        }
+
+        assert_eq!(events, [vec![], vec![XOFF, XON], vec![XOFF, XON]]);
+        assert_eq!(loader.release().writes, 2);
+    }
+
+    #[test]
+    fn it_refuses_records_going_back_to_a_written_page() {
+        let (result, flash) = load(b"S10501000304F2\nS10500000102F7\n");
+
+        assert_eq!(result, Err(Error::OutOfOrder(0)));
+        assert_eq!(&flash.memory[0x100..0x102], &[3, 4]);
+        assert_eq!(flash.writes, 1);
+    }
+
+    #[test]
+    fn it_refuses_data_outside_the_application() {
+        let (result, flash) = load(b"S206018000010275\n");
+
+        assert_eq!(result, Err(Error::OutOfRange(0x1_8000)));
+        assert_eq!(flash.writes, 0);
+    }
+}
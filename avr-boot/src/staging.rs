@@ -0,0 +1,188 @@
+//! Application side of a self update, through the staging slot
+//!
+//! An application which receives new firmware itself, over a radio link or a network stack, streams it into the
+//! staging slot of [`Slots`] with a [`Stager`]. When it has all arrived, [`Stager::finish`] writes the trailer,
+//! checks the staged copy reads back intact, and marks it pending. The application then resets into the bootloader,
+//! where [`Slots::boot`] installs it, keeping the current version to roll back to.
+//!
+//! The image is linked for the running slot, exactly as it would be for flashing directly, and must start at the
+//! beginning of it.
+//!
+//! `F` has to be able to program flash from the application. On parts without a separate boot section, such as the
+//! attiny85, [`Spm`](crate::flash::Spm) can be used directly. Elsewhere, `spm` only works from the boot section, so
+//! the flash has to be programmed through the bootloader, with [`Service`](crate::service::Service).
+//!
+//! # Example
+//! ```no_run
+//! use avr_boot::{FlashRange, eeprom::InternalEeprom, flash::Spm, staging::Stager};
+//!
+//! let mut stager = Stager::new(Spm, InternalEeprom, FlashRange::application(8192), 0).unwrap();
+//! # let chunks: [&[u8]; 0] = [];
+//! for chunk in chunks {
+//!     stager.write(chunk).unwrap();
+//! }
+//! stager.finish(2).unwrap();
+//! // Now reset into the bootloader
+//! ```
+
+use crate::eeprom::Eeprom;
+use crate::flash::Flash;
+use crate::image::{self, HEADER_LENGTH, Header};
+use crate::slots::{Slots, State};
+use crate::{Address, FlashRange, FlashWriter, SPM_PAGESIZE_BYTES, crc, spm};
+
+/// Why firmware couldn't be staged
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Error {
+    /// The running application hasn't confirmed it works, so the staging slot holds the version to roll back to
+    Unconfirmed,
+    /// The firmware doesn't fit in the staging slot, leaving room for the trailer
+    TooLarge,
+    /// The staged firmware didn't read back intact
+    Image(image::Error),
+}
+
+/// Streams new firmware into the staging slot
+pub struct Stager<F, E> {
+    writer: FlashWriter<F>,
+    eeprom: E,
+    application: FlashRange,
+    status: u16,
+    running: Address,
+    staging: FlashRange,
+    length: u32,
+    crc: u32,
+}
+
+impl<F: Flash, E: Eeprom> Stager<F, E> {
+    /// Start staging new firmware into the slots `application` is split into, with the boot status at `status`
+    ///
+    /// Firmware which was staged before, but not yet installed, is no longer pending.
+    pub fn new(flash: F, eeprom: E, application: FlashRange, status: u16) -> Result<Self, Error> {
+        let mut slots = Slots::new(flash, eeprom, application, status);
+        match slots.state() {
+            State::Confirmed => {}
+            State::Pending => slots.confirm(),
+            _ => return Err(Error::Unconfirmed),
+        }
+        let (running, staging) = (slots.running(), slots.staging());
+        let (flash, eeprom) = slots.release();
+
+        Ok(Self {
+            writer: FlashWriter::new(flash),
+            eeprom,
+            application,
+            status,
+            running: running.start,
+            staging,
+            length: 0,
+            crc: 0,
+        })
+    }
+
+    /// Append the next part of the firmware
+    pub fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        let room = self.staging.len() - HEADER_LENGTH as u32;
+        if self.length + data.len() as u32 > room {
+            return Err(Error::TooLarge);
+        }
+
+        let address = Address::from(u32::from(self.staging.start) + self.length);
+        self.writer.write(address, data);
+        self.crc = data
+            .iter()
+            .fold(self.crc, |crc, byte| crc::crc32_update(crc, *byte));
+        self.length += data.len() as u32;
+        Ok(())
+    }
+
+    /// Write the trailer, check the staged firmware, and mark it pending
+    ///
+    /// Returns the slots, ready for the bootloader to install the firmware at the next reset.
+    pub fn finish(self, app_version: u32) -> Result<Slots<F, E>, Error> {
+        let header = Header {
+            signature: spm::read_signature(),
+            page_size: SPM_PAGESIZE_BYTES as u16,
+            address: self.running,
+            length: self.length,
+            app_version,
+            crc: self.crc,
+        };
+        let mut flash = self.writer.release();
+        image::write_trailer(&mut flash, self.staging, &header);
+
+        let mut slots = Slots::new(flash, self.eeprom, self.application, self.status);
+        slots.check_staged().map_err(Error::Image)?;
+        slots.mark_pending();
+        Ok(slots)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eeprom::mock::MockEeprom;
+    use crate::flash::mock::MockFlash;
+    use std::vec::Vec;
+
+    fn application() -> FlashRange {
+        FlashRange::new(0u16, 0x2000u16)
+    }
+
+    /// Start staging again, once the slots have been booted
+    fn restage(
+        slots: Slots<MockFlash, MockEeprom>,
+    ) -> Result<Stager<MockFlash, MockEeprom>, Error> {
+        let (flash, eeprom) = slots.release();
+        Stager::new(flash, eeprom, application(), 0)
+    }
+
+    fn firmware(length: usize) -> Vec<u8> {
+        (0..length).map(|i| (i * 5 + 1) as u8).collect()
+    }
+
+    #[test]
+    fn it_stages_firmware_for_the_bootloader_to_install() {
+        let mut stager =
+            Stager::new(MockFlash::new(), MockEeprom::new(), application(), 0).unwrap();
+        let data = firmware(SPM_PAGESIZE_BYTES * 2 + 77);
+        for chunk in data.chunks(61) {
+            stager.write(chunk).unwrap();
+        }
+
+        let mut slots = stager.finish(7).unwrap();
+        assert_eq!(slots.state(), State::Pending);
+
+        assert_eq!(slots.boot().unwrap().app_version, 7);
+        let (flash, _) = slots.release();
+        assert_eq!(&flash.memory[..data.len()], &data[..]);
+    }
+
+    #[test]
+    fn it_refuses_firmware_which_would_overwrite_the_trailer() {
+        let mut stager =
+            Stager::new(MockFlash::new(), MockEeprom::new(), application(), 0).unwrap();
+        let room = stager.staging.len() as usize - HEADER_LENGTH;
+
+        assert_eq!(stager.write(&firmware(room + 1)), Err(Error::TooLarge));
+        assert_eq!(stager.write(&firmware(room)), Ok(()));
+    }
+
+    #[test]
+    fn it_leaves_the_rollback_copy_alone_until_the_new_version_is_confirmed() {
+        let installed = |confirm: bool| {
+            let mut stager =
+                Stager::new(MockFlash::new(), MockEeprom::new(), application(), 0).unwrap();
+            stager.write(&firmware(100)).unwrap();
+            let mut slots = stager.finish(1).unwrap();
+            slots.boot().unwrap();
+            if confirm {
+                slots.confirm();
+            }
+            restage(slots)
+        };
+
+        assert!(matches!(installed(false), Err(Error::Unconfirmed)));
+        assert!(installed(true).is_ok());
+    }
+}
@@ -0,0 +1,168 @@
+use crate::Address;
+use core::convert::{From, TryFrom};
+
+/// Program memory word address, of up to 23 bits
+///
+/// Word addresses count 16 bit words rather than bytes. They are used by the program counter,
+/// by the STK500 "load address" command, and by avr-libc's `pgm_get_far_address` conventions.
+/// The Z pointer and [`Address`] both use byte addresses, which are exactly double the word address.
+///
+/// Converting a `WordAddress` into an [`Address`] is always lossless, so a `WordAddress` can be passed
+/// anywhere an `impl Into<Address>` is accepted. For that, word addresses are limited to 23 bits, so that
+/// the byte address fits in 24.
+///
+/// # Example
+/// ```rust
+/// use avr_boot::{spm, Address, WordAddress};
+///
+/// let load_address = WordAddress::from(0x3800u16);
+/// assert_eq!(Address::from(0x7000u16), load_address.into());
+///
+/// spm::erase_page(load_address);
+/// ```
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct WordAddress {
+    high: u8,
+    base: u16,
+}
+
+/// Error returned when converting an odd byte address into a [`WordAddress`]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct OddAddressError(pub Address);
+
+/// Error returned when a word address is too large for its byte address to fit in 24 bits
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct WordAddressRangeError(pub u32);
+
+impl WordAddress {
+    /// The highest word address, at byte address `0xff_fffe`
+    pub const MAX: u32 = 0x7f_ffff;
+
+    const fn new(word: u32) -> Self {
+        Self {
+            base: word as u16,
+            high: (word >> 16) as u8,
+        }
+    }
+
+    /// Create a word address in a `const` context
+    ///
+    /// Panics if `word` is above [`WordAddress::MAX`], which fails to compile when used for a `const`.
+    pub const fn from_u32_const(word: u32) -> Self {
+        assert!(word <= Self::MAX, "word address out of range");
+        Self::new(word)
+    }
+
+    /// The full word address as a u32
+    pub const fn to_u32(self) -> u32 {
+        self.base as u32 + ((self.high as u32) << 16)
+    }
+
+    /// The equivalent byte address
+    pub const fn to_byte_address(self) -> Address {
+        Address::from_u32_const(self.to_u32() << 1)
+    }
+}
+
+impl From<u16> for WordAddress {
+    fn from(word: u16) -> Self {
+        Self::new(word as u32)
+    }
+}
+
+impl TryFrom<u32> for WordAddress {
+    type Error = WordAddressRangeError;
+
+    /// Fails if the word address is above [`WordAddress::MAX`]
+    fn try_from(word: u32) -> Result<Self, Self::Error> {
+        if word <= Self::MAX {
+            Ok(Self::new(word))
+        } else {
+            Err(WordAddressRangeError(word))
+        }
+    }
+}
+
+impl From<WordAddress> for u32 {
+    fn from(address: WordAddress) -> u32 {
+        address.to_u32()
+    }
+}
+
+impl From<WordAddress> for u16 {
+    fn from(address: WordAddress) -> u16 {
+        address.base
+    }
+}
+
+impl From<WordAddress> for Address {
+    fn from(address: WordAddress) -> Address {
+        address.to_byte_address()
+    }
+}
+
+impl TryFrom<Address> for WordAddress {
+    type Error = OddAddressError;
+
+    /// Fails if the byte address is odd, as it would not point at the start of a word
+    fn try_from(address: Address) -> Result<Self, Self::Error> {
+        let byte_address = address.to_u32();
+        if byte_address & 1 == 0 {
+            Ok(Self::new(byte_address >> 1))
+        } else {
+            Err(OddAddressError(address))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_doubles_into_a_byte_address() {
+        let address: Address = WordAddress::from(0x8000u16).into();
+
+        assert_eq!(u32::from(address), 0x10000);
+        assert_eq!(address.ramp(), 1);
+    }
+
+    #[test]
+    fn it_halves_an_even_byte_address() {
+        let address = Address::from(0x1_fffeu32);
+
+        assert_eq!(
+            WordAddress::try_from(address),
+            Ok(WordAddress::from(0xffffu16))
+        );
+    }
+
+    #[test]
+    fn it_rejects_an_odd_byte_address() {
+        let address = Address::from(0x101u16);
+
+        assert_eq!(
+            WordAddress::try_from(address),
+            Err(OddAddressError(address))
+        );
+    }
+
+    #[test]
+    fn it_round_trips() {
+        let word = WordAddress::try_from(0x1_2345u32).unwrap();
+        let address: Address = word.into();
+
+        assert_eq!(WordAddress::try_from(address), Ok(word));
+    }
+
+    #[test]
+    fn it_rejects_a_word_address_beyond_24_bit_byte_addresses() {
+        let highest = WordAddress::try_from(WordAddress::MAX).unwrap();
+
+        assert_eq!(u32::from(Address::from(highest)), 0xff_fffe);
+        assert_eq!(
+            WordAddress::try_from(0x80_0000u32),
+            Err(WordAddressRangeError(0x80_0000))
+        );
+    }
+}
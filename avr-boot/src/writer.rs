@@ -0,0 +1,309 @@
+//! Page spanning flash writer
+
+use crate::flash::Flash;
+use crate::protocol::{Serial, XOFF, XON};
+use crate::{Address, FlashRange};
+
+/// Writes a stream of bytes to program memory, a page at a time
+///
+/// Bytes are loaded straight into the page buffer with [`Flash::fill_page`]. When a byte for a different page
+/// arrives, or [`FlashWriter::flush`] is called, the current page is erased and written.
+///
+/// Any part of a page which isn't written to will be left erased (`0xff`).
+/// Data must arrive in page order: returning to a page which has already been written will erase it again.
+///
+/// # Example
+/// ```no_run
+/// use avr_boot::{FlashWriter, flash::Spm};
+///
+/// let mut writer = FlashWriter::new(Spm);
+/// writer.write(0x1000u16, &[0x0c, 0x94, 0x34, 0x00]);
+/// writer.flush();
+/// ```
+pub struct FlashWriter<F> {
+    flash: F,
+    page: Option<Address>,
+    pending: Option<(Address, u8)>,
+}
+
+impl<F: Flash> FlashWriter<F> {
+    /// Create a new writer, programming `flash`
+    pub fn new(flash: F) -> Self {
+        Self {
+            flash,
+            page: None,
+            pending: None,
+        }
+    }
+
+    /// Write a run of bytes, starting at `address`
+    ///
+    /// Returns the address following the last byte written.
+    pub fn write(&mut self, address: impl Into<Address>, data: &[u8]) -> Address {
+        let mut address = address.into();
+        for byte in data {
+            self.write_byte(address, *byte);
+            address = address.checked_add(1).unwrap_or(address);
+        }
+        address
+    }
+
+    /// Write a single byte
+    pub fn write_byte(&mut self, address: Address, byte: u8) {
+        let page = address.into_page_aligned();
+        if self.page != Some(page) {
+            self.flush();
+            self.page = Some(page);
+        }
+
+        let word_address = Address::from(address.word() & !1);
+        if address.word() & 1 == 0 {
+            self.fill_pending();
+            self.pending = Some((word_address, byte));
+        } else {
+            let low = match self.pending.take() {
+                Some((pending_address, low)) if pending_address == word_address => low,
+                Some((pending_address, low)) => {
+                    self.flash
+                        .fill_page(pending_address, u16::from_le_bytes([low, 0xff]));
+                    0xff
+                }
+                None => 0xff,
+            };
+            self.flash
+                .fill_page(word_address, u16::from_le_bytes([low, byte]));
+        }
+    }
+
+    /// True if writing `length` bytes at `address` would erase and write a page, rather than only filling the buffer
+    ///
+    /// Callers which can't receive data while flash is being programmed use this to pause the sender first.
+    pub fn writes_page(&self, address: Address, length: usize) -> bool {
+        let Some(last) = length.checked_sub(1) else {
+            return false;
+        };
+        let first = address.into_page_aligned();
+        let last = address
+            .checked_add(last as u32)
+            .unwrap_or(address)
+            .into_page_aligned();
+        self.page.is_some_and(|page| page != first) || first != last
+    }
+
+    /// The page being filled, which [`FlashWriter::flush`] would erase and write, if there is one
+    pub fn page(&self) -> Option<Address> {
+        self.page
+    }
+
+    /// Erase and write the page currently in the buffer, if there is one
+    pub fn flush(&mut self) {
+        self.fill_pending();
+        if let Some(page) = self.page.take() {
+            self.flash.erase_page(page);
+            self.flash.write_page(page);
+            self.flash.rww_enable();
+        }
+    }
+
+    /// The underlying flash, for reading back
+    pub fn flash(&self) -> &F {
+        &self.flash
+    }
+
+    /// The underlying flash, for operations other than writing, such as erasing
+    ///
+    /// Call [`FlashWriter::flush`] first, so the page buffer is empty.
+    pub fn flash_mut(&mut self) -> &mut F {
+        &mut self.flash
+    }
+
+    /// Flush any buffered data, and give back the underlying flash
+    pub fn release(mut self) -> F {
+        self.flush();
+        self.flash
+    }
+
+    fn fill_pending(&mut self) {
+        if let Some((address, low)) = self.pending.take() {
+            self.flash
+                .fill_page(address, u16::from_le_bytes([low, 0xff]));
+        }
+    }
+}
+
+/// Why a [`RecordWriter`] refused a record
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub(crate) enum Refused {
+    /// The record wasn't within the application range
+    OutOfRange(u32),
+    /// The record went back to a page which had already been written
+    OutOfOrder(u32),
+}
+
+/// Writes the data records of a text image to flash as they are decoded, for the HEX and S-record loaders
+///
+/// Records must be within the application, and must not go back to a page which has already been written, as it
+/// would be erased again. Nothing can be received while a page is programmed, so [`XOFF`] is sent on the serial port
+/// before writing one, and [`XON`] afterwards.
+pub(crate) struct RecordWriter<F> {
+    writer: FlashWriter<F>,
+    application: FlashRange,
+}
+
+impl<F: Flash> RecordWriter<F> {
+    pub(crate) fn new(flash: F, application: FlashRange) -> Self {
+        Self {
+            writer: FlashWriter::new(flash),
+            application,
+        }
+    }
+
+    /// Write one record's `data`, starting at `address`
+    pub(crate) fn write<S: Serial>(
+        &mut self,
+        serial: &mut S,
+        address: Address,
+        data: &[u8],
+    ) -> Result<(), Refused> {
+        let fits = address
+            .checked_add(data.len() as u32)
+            .is_some_and(|end| self.application.start <= address && end <= self.application.end);
+        if !fits {
+            return Err(Refused::OutOfRange(address.into()));
+        }
+        if self
+            .writer
+            .page()
+            .is_some_and(|page| address.into_page_aligned() < page)
+        {
+            return Err(Refused::OutOfOrder(address.into()));
+        }
+
+        let pause = self.writer.writes_page(address, data.len());
+        if pause {
+            serial.write(XOFF).ok();
+        }
+        self.writer.write(address, data);
+        if pause {
+            serial.write(XON).ok();
+        }
+        Ok(())
+    }
+
+    /// Write the last page, at the end of the file
+    pub(crate) fn finish<S: Serial>(&mut self, serial: &mut S) {
+        if self.writer.page().is_some() {
+            serial.write(XOFF).ok();
+            self.writer.flush();
+            serial.write(XON).ok();
+        }
+    }
+
+    /// Flush any buffered data, and give back the flash backend
+    pub(crate) fn release(self) -> F {
+        self.writer.release()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SPM_PAGESIZE_BYTES;
+    use crate::flash::mock::MockFlash;
+    use crate::protocol::mock::MockSerial;
+
+    const PAGE: u32 = SPM_PAGESIZE_BYTES as u32;
+
+    #[test]
+    fn it_writes_across_pages() {
+        let mut writer = FlashWriter::new(MockFlash::new());
+        let end = writer.write(0xfffeu16, &[1, 2, 3, 4]);
+        let flash = writer.release();
+
+        assert_eq!(end, Address::from(0x1_0002u32));
+        assert_eq!(&flash.memory[0xfffe..0x1_0002], &[1, 2, 3, 4]);
+        assert_eq!(flash.writes, 2);
+    }
+
+    #[test]
+    fn it_knows_when_a_write_will_program_a_page() {
+        let mut writer = FlashWriter::new(MockFlash::new());
+        assert!(!writer.writes_page(Address::from(PAGE - 2), 2));
+        assert!(writer.writes_page(Address::from(PAGE - 2), 3));
+
+        writer.write(PAGE - 2, &[1, 2]);
+        assert_eq!(writer.page(), Some(Address::from(0u16)));
+        assert!(!writer.writes_page(Address::from(PAGE - 4), 2));
+        assert!(writer.writes_page(Address::from(PAGE), 1));
+        assert!(!writer.writes_page(Address::from(PAGE), 0));
+    }
+
+    #[test]
+    fn it_leaves_gaps_erased() {
+        let mut writer = FlashWriter::new(MockFlash::new());
+        writer.write(PAGE + 1, &[0xaa]);
+        writer.write(PAGE + 4, &[0xbb]);
+        let flash = writer.release();
+
+        assert_eq!(
+            &flash.page(PAGE)[..6],
+            &[0xff, 0xaa, 0xff, 0xff, 0xbb, 0xff]
+        );
+        assert_eq!(flash.writes, 1);
+    }
+
+    #[test]
+    fn it_writes_nothing_until_the_page_changes() {
+        let mut writer = FlashWriter::new(MockFlash::new());
+        writer.write(0u16, &[1, 2, 3]);
+
+        assert_eq!(writer.flash().writes, 0);
+
+        writer.write(PAGE, &[4]);
+
+        assert_eq!(writer.flash().writes, 1);
+        assert_eq!(&writer.flash().page(0)[..4], &[1, 2, 3, 0xff]);
+    }
+
+    #[test]
+    fn it_refuses_records_going_back_to_a_written_page() {
+        let mut serial = MockSerial::new(&[]);
+        let mut writer = RecordWriter::new(MockFlash::new(), FlashRange::new(0u16, PAGE * 4));
+        writer
+            .write(&mut serial, Address::from(0u16), &[1, 2])
+            .unwrap();
+        writer
+            .write(&mut serial, Address::from(PAGE), &[3])
+            .unwrap();
+
+        // Within the page being filled is fine, the one before isn't
+        assert_eq!(
+            writer.write(&mut serial, Address::from(PAGE + 8), &[4]),
+            Ok(())
+        );
+        assert_eq!(
+            writer.write(&mut serial, Address::from(PAGE + 4), &[5]),
+            Ok(())
+        );
+        assert_eq!(
+            writer.write(&mut serial, Address::from(2u16), &[6]),
+            Err(Refused::OutOfOrder(2))
+        );
+        assert_eq!(
+            writer.write(&mut serial, Address::from(PAGE * 4), &[7]),
+            Err(Refused::OutOfRange(PAGE * 4))
+        );
+
+        let flash = writer.release();
+        assert_eq!(&flash.memory[..3], &[1, 2, 0xff]);
+        assert_eq!(flash.writes, 2);
+    }
+
+    #[test]
+    fn it_does_nothing_when_flushed_empty() {
+        let mut writer = FlashWriter::new(MockFlash::new());
+        writer.flush();
+
+        assert_eq!(writer.flash().erases, 0);
+    }
+}